@@ -43,6 +43,7 @@ let
     ./wasm/.cargo
     ./wasm
     ./shared
+    ./client
     ./spaghettinuum
   ];
   wasm = naersk.buildPackage {
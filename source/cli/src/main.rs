@@ -0,0 +1,197 @@
+use {
+    aargvark::{
+        vark,
+        Aargvark,
+    },
+    client::{
+        native::NativeTransport,
+        Client,
+    },
+    flowcontrol::ta_return,
+    futures::StreamExt,
+    jiff::Timestamp,
+    loga::{
+        ea,
+        fatal,
+        Log,
+        ResultContext,
+    },
+    serde::Serialize,
+    shared::interface::{
+        shared::{
+            ChannelId,
+            MessageClientId,
+            QualifiedChannelId,
+        },
+        wire::c2s::{
+            ApiTokenRes,
+            ApiTokenSelf,
+            GetSnapPage,
+            MessagePush,
+            SnapPage,
+            SnapPageContainingTime,
+        },
+    },
+    std::env,
+    tokio::{
+        io::AsyncReadExt,
+        runtime,
+    },
+};
+
+const ENV_URL: &str = "KWA_URL";
+const ENV_TOKEN: &str = "KWA_TOKEN";
+
+#[derive(Aargvark)]
+struct ChannelArgs {
+    /// Channel id, owned by the token's identity.
+    channel: u64,
+}
+
+#[derive(Aargvark)]
+enum Command {
+    /// Show the identity the token acts as.
+    Identities,
+    /// List the channels the token can access and what it can do in them.
+    Channels,
+    /// Post a message to a channel, reading the body from stdin.
+    Post(ChannelArgs),
+    /// Print notifications for a channel as they arrive, until interrupted.
+    /// Reconnects if the connection drops.
+    Tail(ChannelArgs),
+    /// Dump every message in a channel, oldest first. Always prints JSON lines.
+    History(ChannelArgs),
+}
+
+#[derive(Aargvark)]
+struct Args {
+    /// Base URL of the kwa server, like `https://kwa.example.org/`. Defaults to
+    /// `KWA_URL`.
+    #[vark(flag = "--url")]
+    url: Option<String>,
+    /// API token, created in the identity's settings. Defaults to `KWA_TOKEN`.
+    #[vark(flag = "--token")]
+    token: Option<String>,
+    /// Print one JSON value per line instead of text.
+    #[vark(flag = "--json")]
+    json: Option<()>,
+    command: Command,
+}
+
+fn print_json(v: &impl Serialize) {
+    println!("{}", serde_json::to_string(v).unwrap());
+}
+
+fn main() {
+    let log = Log::new_root(loga::INFO);
+    let runtime = runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    match runtime.block_on(async {
+        ta_return!((), loga::Error);
+        let args = vark::<Args>();
+        let Some(base_url) = args.url.or_else(|| env::var(ENV_URL).ok()) else {
+            return Err(loga::err(format!("Missing server URL, pass `--url` or set `{}`", ENV_URL)));
+        };
+        let Some(token) = args.token.or_else(|| env::var(ENV_TOKEN).ok()) else {
+            return Err(loga::err(format!("Missing API token, pass `--token` or set `{}`", ENV_TOKEN)));
+        };
+        let client = Client::new(NativeTransport::new(&log, base_url, Some(token)));
+        let json = args.json.is_some();
+        let whoami: ApiTokenRes = client.req(ApiTokenSelf).await.context("Error looking up API token")?;
+        let qualify = |channel: u64| QualifiedChannelId {
+            identity: whoami.identity.clone(),
+            channel: ChannelId(channel),
+        };
+        match args.command {
+            Command::Identities => {
+                if json {
+                    print_json(&whoami.identity);
+                } else {
+                    println!("{}", whoami.identity);
+                }
+            },
+            Command::Channels => {
+                for channel in &whoami.scope.channels {
+                    if json {
+                        print_json(&qualify(channel.0));
+                    } else {
+                        let mut access = vec![];
+                        if whoami.scope.read {
+                            access.push("read");
+                        }
+                        if whoami.scope.post {
+                            access.push("post");
+                        }
+                        println!("{}\t{}", channel.0, access.join(","));
+                    }
+                }
+            },
+            Command::Post(a) => {
+                let mut body = String::new();
+                tokio::io::stdin().read_to_string(&mut body).await.context("Error reading message from stdin")?;
+                let body = body.trim_end_matches(['\r', '\n']).to_string();
+                if body.trim().is_empty() {
+                    return Err(loga::err("Message from stdin is empty"));
+                }
+                let client_id = MessageClientId::from_timestamp(Timestamp::now());
+                client.req(MessagePush {
+                    client_id: client_id.clone(),
+                    channel: qualify(a.channel),
+                    identity: whoami.identity.clone(),
+                    body: body,
+                }).await.context("Error posting message")?;
+                if json {
+                    print_json(&client_id);
+                }
+            },
+            Command::Tail(a) => {
+                let channel = qualify(a.channel);
+                let mut notifications = client.notifications();
+                while let Some(n) = notifications.next().await {
+                    if n.channel != channel {
+                        continue;
+                    }
+                    if json {
+                        print_json(&n);
+                    } else if !n.body.is_empty() {
+                        // Edits and deletions have no text
+                        println!("{}", n.body);
+                    }
+                }
+            },
+            Command::History(a) => {
+                let channel = qualify(a.channel);
+                let Some(last) =
+                    client
+                        .get(SnapPageContainingTime {
+                            channel: channel.clone(),
+                            time: Timestamp::now(),
+                        })
+                        .await
+                        .context("Error finding latest page")? else {
+                        return Ok(());
+                    };
+                for page in 0 ..= last.0 {
+                    let Some(res) =
+                        client
+                            .get(GetSnapPage {
+                                channel: channel.clone(),
+                                page: SnapPage(page),
+                            })
+                            .await
+                            .context_with("Error fetching page", ea!(page = page))? else {
+                            continue;
+                        };
+                    for message in res.messages {
+                        print_json(&message);
+                    }
+                }
+            },
+        }
+        return Ok(());
+    }) {
+        Ok(_) => { },
+        Err(e) => {
+            fatal(e);
+        },
+    }
+}
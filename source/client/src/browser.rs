@@ -0,0 +1,182 @@
+use {
+    crate::{
+        notifications_url,
+        Error,
+    },
+    futures::{
+        channel::{
+            mpsc,
+            oneshot,
+        },
+        Stream,
+    },
+    gloo_timers::future::TimeoutFuture,
+    reqwasm::http::{
+        Request,
+        Response,
+    },
+    shared::interface::wire::s2c,
+    std::{
+        cell::{
+            Cell,
+            RefCell,
+        },
+        pin::Pin,
+        rc::Rc,
+        task::{
+            Context,
+            Poll,
+        },
+    },
+    wasm_bindgen::{
+        closure::Closure,
+        JsCast,
+    },
+    web_sys::{
+        MessageEvent,
+        WebSocket,
+    },
+};
+
+/// Talks to the server with `fetch`; authentication is the browser's session
+/// cookie.
+#[derive(Clone)]
+pub struct BrowserTransport {
+    base_url: String,
+}
+
+impl BrowserTransport {
+    pub fn new(base_url: impl ToString) -> Self {
+        return Self { base_url: base_url.to_string() };
+    }
+}
+
+async fn read_resp(resp: Result<Response, reqwasm::Error>) -> Result<Vec<u8>, Error> {
+    let resp = resp.map_err(|e| Error::Transport(e.to_string()))?;
+    let status = resp.status();
+    if status == 429 {
+        return Err(Error::RateLimited {
+            retry_after: resp.headers().get("Retry-After").and_then(|v| v.parse().ok()),
+        });
+    }
+    let body = resp.binary().await.map_err(|e| {
+        Error::Transport(format!("Got response [{}] but failed to read body: {}", status, e))
+    })?;
+    if status >= 400 {
+        return Err(Error::Status {
+            status: status,
+            body: String::from_utf8_lossy(&body).to_string(),
+        });
+    }
+    return Ok(body);
+}
+
+#[async_trait::async_trait(?Send)]
+impl crate::Transport for BrowserTransport {
+    type Notifications = BrowserNotifications;
+
+    async fn post(&self, path: &str, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let body = String::from_utf8(body).map_err(|e| Error::Transport(e.to_string()))?;
+        return read_resp(
+            Request::post(&format!("{}{}", self.base_url, path))
+                .header("Content-type", "application/json")
+                .body(body)
+                .send()
+                .await,
+        ).await;
+    }
+
+    async fn get(&self, path: &str) -> Result<Vec<u8>, Error> {
+        return read_resp(Request::get(&format!("{}{}", self.base_url, path)).send().await).await;
+    }
+
+    fn notifications(&self) -> Self::Notifications {
+        let (tx, rx) = mpsc::unbounded();
+        let shared = Rc::new(BrowserNotificationsShared {
+            stopped: Cell::new(false),
+            ws: RefCell::new(None),
+        });
+        let url = notifications_url(&self.base_url);
+        wasm_bindgen_futures::spawn_local({
+            let shared = shared.clone();
+            async move {
+                while !shared.stopped.get() {
+                    let ws = match WebSocket::new(&url) {
+                        Ok(ws) => ws,
+                        Err(e) => {
+                            web_sys::console::log_2(&"Error creating websocket (reconnecting)".into(), &e);
+                            TimeoutFuture::new(1000).await;
+                            continue;
+                        },
+                    };
+                    let on_message = Closure::<dyn Fn(MessageEvent)>::new({
+                        let tx = tx.clone();
+                        move |ev: MessageEvent| {
+                            let Some(body) = ev.data().as_string() else {
+                                web_sys::console::log_2(&"Received non-string message".into(), &ev.data());
+                                return;
+                            };
+                            match serde_json::from_str::<s2c::Notification>(&body) {
+                                Ok(n) => {
+                                    _ = tx.unbounded_send(n);
+                                },
+                                Err(e) => {
+                                    web_sys::console::log_1(
+                                        &format!("Failed to deserialize notification: {}\nMessage: {}", e, body).into(),
+                                    );
+                                },
+                            }
+                        }
+                    });
+                    let (closed_tx, closed_rx) = oneshot::channel();
+                    let closed_tx = Cell::new(Some(closed_tx));
+                    let on_close = Closure::<dyn Fn()>::new(move || {
+                        if let Some(closed_tx) = closed_tx.take() {
+                            _ = closed_tx.send(());
+                        }
+                    });
+                    ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+                    ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+                    *shared.ws.borrow_mut() = Some(ws.clone());
+                    _ = closed_rx.await;
+                    ws.set_onmessage(None);
+                    ws.set_onclose(None);
+                    *shared.ws.borrow_mut() = None;
+                    TimeoutFuture::new(1000).await;
+                }
+            }
+        });
+        return BrowserNotifications {
+            rx: rx,
+            shared: shared,
+        };
+    }
+}
+
+struct BrowserNotificationsShared {
+    stopped: Cell<bool>,
+    ws: RefCell<Option<WebSocket>>,
+}
+
+/// Closes the websocket when dropped.
+pub struct BrowserNotifications {
+    rx: mpsc::UnboundedReceiver<s2c::Notification>,
+    shared: Rc<BrowserNotificationsShared>,
+}
+
+impl Stream for BrowserNotifications {
+    type Item = s2c::Notification;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        return Pin::new(&mut self.rx).poll_next(cx);
+    }
+}
+
+impl Drop for BrowserNotifications {
+    fn drop(&mut self) {
+        self.shared.stopped.set(true);
+        if let Some(ws) = self.shared.ws.borrow_mut().take() {
+            _ = ws.close();
+        }
+    }
+}
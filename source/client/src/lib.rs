@@ -0,0 +1,138 @@
+//! Typed client for the c2s protocol, independent of how requests are actually
+//! made. Enable the `native` feature for tokio-based bots and tools, or `browser`
+//! for the web client.
+use {
+    futures::Stream,
+    shared::interface::wire::{
+        c2s::{
+            self,
+            C2sVersionRes,
+            PathReqTrait,
+            C2SV1_PREFIX,
+            C2S_VERSION_PATH,
+        },
+        s2c,
+    },
+    std::fmt::Display,
+};
+
+#[cfg(feature = "native")]
+pub mod native;
+#[cfg(feature = "browser")]
+pub mod browser;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be sent or the response couldn't be read.
+    Transport(String),
+    /// The server responded with an error status.
+    Status {
+        status: u16,
+        body: String,
+    },
+    /// The server is throttling this client (429). Retry after the given number of
+    /// seconds, if the server said.
+    RateLimited {
+        retry_after: Option<u64>,
+    },
+    /// The response body wasn't what the request type expects.
+    Parse {
+        error: String,
+        body: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Transport(e) => return write!(f, "Failed to send request: {}", e),
+            Error::Status { status, body } => return write!(f, "Got error response [{}]: [{}]", status, body),
+            Error::RateLimited { retry_after } => match retry_after {
+                Some(s) => return write!(f, "Rate limited, retry after {}s", s),
+                None => return write!(f, "Rate limited"),
+            },
+            Error::Parse { error, body } => return write!(
+                f,
+                "Error parsing JSON response from server: {}\nBody: {}",
+                error,
+                body
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error { }
+
+/// Moves bytes to and from the server. Paths are relative to the server's base
+/// url, without a leading slash.
+#[cfg_attr(not(feature = "browser"), async_trait::async_trait)]
+#[cfg_attr(feature = "browser", async_trait::async_trait(?Send))]
+pub trait Transport {
+    type Notifications: Stream<Item = s2c::Notification> + Unpin;
+
+    /// POST a JSON body, returning the body of a successful response.
+    async fn post(&self, path: &str, body: Vec<u8>) -> Result<Vec<u8>, Error>;
+
+    /// GET, returning the body of a successful response.
+    async fn get(&self, path: &str) -> Result<Vec<u8>, Error>;
+
+    /// Opens the notification websocket. The stream reconnects on its own and only
+    /// ends when dropped.
+    fn notifications(&self) -> Self::Notifications;
+}
+
+fn parse<T: serde::de::DeserializeOwned>(body: Vec<u8>) -> Result<T, Error> {
+    return serde_json::from_slice::<T>(&body).map_err(|e| Error::Parse {
+        error: e.to_string(),
+        body: String::from_utf8_lossy(&body).to_string(),
+    });
+}
+
+pub struct Client<T: Transport> {
+    pub transport: T,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Self {
+        return Self { transport: transport };
+    }
+
+    /// Sends any `c2s::proto` request, returning its typed response.
+    pub async fn req<R: c2s::proto::ReqTrait>(&self, req: R) -> Result<R::Resp, Error> {
+        let body = serde_json::to_vec(&req.to_enum()).unwrap();
+        return parse(self.transport.post(&format!("{}api", C2SV1_PREFIX), body).await?);
+    }
+
+    /// Fetches any `PathReqTrait` resource, returning its typed response.
+    pub async fn get<R: PathReqTrait>(&self, req: R) -> Result<R::Resp, Error> {
+        return parse(self.transport.get(&format!("{}{}", C2SV1_PREFIX, path_rel(&req.serialize_path()))).await?);
+    }
+
+    /// Which protocol versions the server speaks. Check this before anything else;
+    /// requests to an unsupported version will fail in confusing ways.
+    pub async fn version(&self) -> Result<C2sVersionRes, Error> {
+        return parse(self.transport.get(C2S_VERSION_PATH).await?);
+    }
+
+    pub fn notifications(&self) -> T::Notifications {
+        return self.transport.notifications();
+    }
+}
+
+/// `PathReqTrait` paths are absolute, transports want them relative to the base
+/// url.
+pub fn path_rel(path: &str) -> &str {
+    return path.trim_start_matches('/');
+}
+
+/// The websocket url for notifications, given the `http(s)` base url.
+pub fn notifications_url(base_url: &str) -> String {
+    let base_url = if let Some(rest) = base_url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = base_url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        base_url.to_string()
+    };
+    return format!("{}{}ws", base_url, C2SV1_PREFIX);
+}
@@ -0,0 +1,157 @@
+use {
+    crate::{
+        notifications_url,
+        Error,
+    },
+    futures::{
+        stream::BoxStream,
+        StreamExt,
+    },
+    http::{
+        header::{
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            HOST,
+            RETRY_AFTER,
+        },
+        Method,
+        Request,
+        Uri,
+    },
+    htwrap::{
+        htreq,
+        htserve::responses::{
+            body_empty,
+            body_full,
+        },
+    },
+    loga::Log,
+    shared::interface::wire::s2c,
+    std::time::Duration,
+    tokio::sync::mpsc,
+    tokio_stream::wrappers::UnboundedReceiverStream,
+    tokio_tungstenite::tungstenite::{
+        client::IntoClientRequest,
+        Message,
+    },
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Talks to the server over hyper/tokio. Requests are authenticated with an API
+/// token if one is provided.
+#[derive(Clone)]
+pub struct NativeTransport {
+    log: Log,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl NativeTransport {
+    pub fn new(log: &Log, base_url: impl ToString, token: Option<String>) -> Self {
+        let mut base_url = base_url.to_string();
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        return Self {
+            log: log.clone(),
+            base_url: base_url,
+            token: token,
+        };
+    }
+
+    async fn request(&self, method: Method, path: &str, body: Option<Vec<u8>>) -> Result<Vec<u8>, Error> {
+        let url = format!("{}{}", self.base_url, path);
+        let uri = url.parse::<Uri>().map_err(|e| Error::Transport(format!("Invalid url [{}]: {}", url, e)))?;
+        let mut req = Request::builder().method(method).uri(uri.clone());
+        req = req.header(HOST, uri.authority().map(|a| a.as_str()).unwrap_or_default());
+        if let Some(token) = &self.token {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let req = match body {
+            Some(body) => req.header(CONTENT_TYPE, "application/json").body(body_full(body)),
+            None => req.body(body_empty()),
+        }.unwrap();
+        let limits = htreq::Limits::default();
+        let mut conn = htreq::connect(limits, &uri).await.map_err(|e| Error::Transport(e.to_string()))?;
+        let (code, headers, continue_) =
+            htreq::send(&self.log, limits, &mut conn, req).await.map_err(|e| Error::Transport(e.to_string()))?;
+        let body = htreq::receive(limits, continue_).await.map_err(|e| Error::Transport(e.to_string()))?;
+        if code.as_u16() == 429 {
+            return Err(Error::RateLimited {
+                retry_after: headers.get(RETRY_AFTER).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()),
+            });
+        }
+        if !code.is_success() {
+            return Err(Error::Status {
+                status: code.as_u16(),
+                body: String::from_utf8_lossy(&body).to_string(),
+            });
+        }
+        return Ok(body);
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::Transport for NativeTransport {
+    type Notifications = BoxStream<'static, s2c::Notification>;
+
+    async fn post(&self, path: &str, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+        return self.request(Method::POST, path, Some(body)).await;
+    }
+
+    async fn get(&self, path: &str) -> Result<Vec<u8>, Error> {
+        return self.request(Method::GET, path, None).await;
+    }
+
+    fn notifications(&self) -> Self::Notifications {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let log = self.log.clone();
+        let url = notifications_url(&self.base_url);
+        let token = self.token.clone();
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let res = async {
+                    let mut req = url.as_str().into_client_request().map_err(|e| e.to_string())?;
+                    if let Some(token) = &token {
+                        req
+                            .headers_mut()
+                            .insert(AUTHORIZATION, format!("Bearer {}", token).parse().map_err(|_| "Invalid token")?);
+                    }
+                    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.map_err(|e| e.to_string())?;
+                    loop {
+                        let m = tokio::select!{
+                            m = ws.next() => m,
+                            _ = tx.closed() => return Ok(()),
+                        };
+                        let Some(m) = m else {
+                            break;
+                        };
+                        let Message::Text(m) = m.map_err(|e| e.to_string())? else {
+                            continue;
+                        };
+                        let n = match serde_json::from_str::<s2c::Notification>(&m) {
+                            Ok(n) => n,
+                            Err(e) => {
+                                log.log(
+                                    loga::WARN,
+                                    format!("Failed to deserialize notification: {}\nMessage: {}", e, m.as_str()),
+                                );
+                                continue;
+                            },
+                        };
+                        if tx.send(n).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    return Ok(()) as Result<(), String>;
+                }.await;
+                if let Err(e) = res {
+                    log.log(loga::DEBUG, format!("Notification websocket closed with error (reconnecting): {}", e));
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+        return UnboundedReceiverStream::new(rx).boxed();
+    }
+}
@@ -1,51 +1,141 @@
-use good_ormning::sqlite::{
-    schema::field::{
-        field_i64,
-        field_str,
-        field_utctime_s_jiff,
+use {
+    good_ormning::sqlite::{
+        schema::field::{
+            field_bool,
+            field_i64,
+            field_str,
+            field_utctime_s_jiff,
+        },
+        types::{
+            type_i64,
+            type_str,
+        },
+        GenerateArgs,
+        Version,
     },
-    types::{
-        type_i64,
-        type_str,
+    std::{
+        env,
+        fs::{
+            create_dir_all,
+            read,
+            read_dir,
+            write,
+        },
+        io::Write,
+        path::{
+            Path,
+            PathBuf,
+        },
     },
-    GenerateArgs,
-    Version,
 };
 
-fn main() {
-    println!("cargo:rerun-if-changed=build.rs");
-    let latest_version = Version::new();
+/// Every schema version ever released, in order, with a short human readable
+/// summary of what changed (shown by `--migrate-dry-run`). Never remove or reorder
+/// entries - append a new one and gate the changes in `schema` with
+/// `if version >= N`.
+const VERSIONS: &[&str] = &[
+    //. .
+    "Initial schema: accounts, identities, channel groups, channels",
+    "Push subscriptions, tied to login sessions",
+    "Per-identity API tokens",
+    "Incoming channel webhooks",
+    "Outgoing channel webhooks and delivery queue",
+    "Federation denylist",
+    "Channel member identity",
+    "Account external ids keyed by OIDC issuer (rows rewritten on startup, see `dbutil::migrate_account_issuer`)",
+    "Channel messages and activity",
+    "Remote channel members, federated notification queue and per-server backoff",
+];
+
+/// Writes `.br` and `.gz` copies of every file in `dir` under `out`, mirroring the
+/// layout relative to `root`. Copies that aren't meaningfully smaller (images,
+/// fonts, tiny files) are skipped so the server sends the original.
+fn precompress(root: &Path, dir: &Path, out: &Path) {
+    for entry in read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let path = entry.path();
+        if entry.file_type().unwrap().is_dir() {
+            precompress(root, &path, out);
+            continue;
+        }
+        let data = read(&path).unwrap();
+        let rel = path.strip_prefix(root).unwrap();
+        let dest_dir = out.join(rel.parent().unwrap());
+        create_dir_all(&dest_dir).unwrap();
+        let name = rel.file_name().unwrap().to_string_lossy();
+        let mut br = vec![];
+        {
+            let mut w = brotli::CompressorWriter::new(&mut br, 4096, 11, 22);
+            w.write_all(&data).unwrap();
+        }
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+        gz.write_all(&data).unwrap();
+        let gz = gz.finish().unwrap();
+        for (ext, compressed) in [("br", br), ("gz", gz)] {
+            if compressed.len() * 10 > data.len() * 9 {
+                continue;
+            }
+            write(dest_dir.join(format!("{}.{}", name, ext)), compressed).unwrap();
+        }
+    }
+}
+
+/// Builds the full schema as of `version`. Tables and fields introduced in later
+/// versions are gated so that good-ormning can diff consecutive versions to
+/// produce migrations.
+fn schema(version: usize) -> Version {
+    let v = Version::new();
 
     // Custom types
     let account_id_t =
-        latest_version
+        v
             .custom_type("account_id_t")
             .rust_type("crate::interface::db::DbAccountId")
             .base_type(type_i64().build());
     let identity_id_t =
-        latest_version
+        v
             .custom_type("identity_id_t")
             .rust_type("crate::interface::db::DbIdentity")
             .base_type(type_str().build());
     let identity_secret_t =
-        latest_version
+        v
             .custom_type("identity_secret_t")
             .rust_type("crate::interface::db::DbIdentitySecret")
             .base_type(type_str().build());
     let channelgroup_id_t =
-        latest_version
+        v
             .custom_type("channelgroup_id_t")
             .rust_type("crate::interface::db::DbChannelGroupId")
             .base_type(type_i64().build());
     let channel_id_t =
-        latest_version
+        v
             .custom_type("channel_id_t")
             .rust_type("crate::interface::db::DbChannelId")
             .base_type(type_str().build());
+    let session_id_t =
+        v
+            .custom_type("session_id_t")
+            .rust_type("crate::interface::db::DbSessionId")
+            .base_type(type_str().build());
+    let account_external_id_t =
+        v
+            .custom_type("account_external_id_t")
+            .rust_type("crate::interface::db::DbAccountExternalId")
+            .base_type(type_str().build());
+    let push_subscription_t =
+        v
+            .custom_type("push_subscription_t")
+            .rust_type("crate::interface::db::DbPushSubscription")
+            .base_type(type_str().build());
+    let api_token_scope_t =
+        v
+            .custom_type("api_token_scope_t")
+            .rust_type("crate::interface::db::DbApiTokenScope")
+            .base_type(type_str().build());
 
     // Accounts
     {
-        let t = latest_version.table("account");
+        let t = v.table("account");
         let _rowid = t.rowid_field(None);
         let id = t.field("external_id", field_str().build());
         let _soft_deleted_at = t.field("soft_deleted_at", field_utctime_s_jiff().opt().build());
@@ -54,7 +144,7 @@ fn main() {
 
     // Identities
     {
-        let t = latest_version.table("identity");
+        let t = v.table("identity");
         let account_id = t.field("account_id", account_id_t.field_type());
         let id = t.field("id", identity_id_t.field_type());
         let idem = t.field("idem", field_str().build());
@@ -68,7 +158,7 @@ fn main() {
 
     // Channel groups
     {
-        let t = latest_version.table("channelgroup");
+        let t = v.table("channelgroup");
         let account_id = t.field("account_id", account_id_t.field_type());
         let _rowid = t.rowid_field(None);
         let idem = t.field("idem", field_str().build());
@@ -79,7 +169,7 @@ fn main() {
 
     // Channels
     {
-        let t = latest_version.table("channel");
+        let t = v.table("channel");
         let account_id = t.field("account_id", account_id_t.field_type());
         let identity = t.field("identity", identity_id_t.field_type());
         let id = t.field("id", channel_id_t.field_type());
@@ -90,12 +180,191 @@ fn main() {
         let _memo_short = t.field("memo_short", field_str().build());
         let _memo_long = t.field("memo_long", field_str().build());
         let _deleted = t.field("deleted", field_utctime_s_jiff().opt().build());
+        if version >= 6 {
+            // The account's identity in the channel (`identity` is the owner). Null for
+            // rows from before this was recorded.
+            let mut own_identity_ft = identity_id_t.field_type();
+            own_identity_ft.type_.opt = true;
+            let _own_identity = t.field("own_identity", own_identity_ft);
+        }
         t.primary_key("channel_pk", &[&account_id, &identity, &id]);
         t.unique_index("channel_account_identity_idem", &[&account_id, &identity, &idem]);
     }
 
+    // Push subscriptions
+    if version >= 1 {
+        let t = v.table("push_subscription");
+        let session = t.field("session", session_id_t.field_type());
+        let _account = t.field("account", account_external_id_t.field_type());
+        let _data = t.field("data", push_subscription_t.field_type());
+        let _created = t.field("created", field_utctime_s_jiff().build());
+        t.index("push_subscription_session", &[&session]);
+    }
+
+    // Api tokens
+    if version >= 2 {
+        let t = v.table("api_token");
+        let _rowid = t.rowid_field(None);
+        let account = t.field("account", account_external_id_t.field_type());
+        let identity = t.field("identity", identity_id_t.field_type());
+        let hash = t.field("hash", field_str().build());
+        let _memo_short = t.field("memo_short", field_str().build());
+        let _scope = t.field("scope", api_token_scope_t.field_type());
+        let _created = t.field("created", field_utctime_s_jiff().build());
+        t.unique_index("api_token_hash", &[&hash]);
+        t.index("api_token_account_identity", &[&account, &identity]);
+    }
+
+    // Incoming channel webhooks
+    if version >= 3 {
+        let t = v.table("channel_webhook");
+        let _rowid = t.rowid_field(None);
+        let account = t.field("account", account_external_id_t.field_type());
+        let _channel_identity = t.field("channel_identity", identity_id_t.field_type());
+        let _channel = t.field("channel", channel_id_t.field_type());
+        let _identity = t.field("identity", identity_id_t.field_type());
+        let hash = t.field("hash", field_str().build());
+        let _memo_short = t.field("memo_short", field_str().build());
+        let _memo_long = t.field("memo_long", field_str().build());
+        let _expiry = t.field("expiry", field_utctime_s_jiff().opt().build());
+        let _max_per_minute = t.field("max_per_minute", field_i64().build());
+        t.unique_index("channel_webhook_hash", &[&hash]);
+        t.index("channel_webhook_account", &[&account]);
+    }
+
+    // Outgoing channel webhooks
+    if version >= 4 {
+        let outgoing_webhook_events_t =
+            v
+                .custom_type("outgoing_webhook_events_t")
+                .rust_type("crate::interface::db::DbOutgoingWebhookEvents")
+                .base_type(type_str().build());
+        {
+            let t = v.table("outgoing_webhook");
+            let _rowid = t.rowid_field(None);
+            let account = t.field("account", account_external_id_t.field_type());
+            let channel_identity = t.field("channel_identity", identity_id_t.field_type());
+            let channel = t.field("channel", channel_id_t.field_type());
+            let _url = t.field("url", field_str().build());
+            let _secret = t.field("secret", field_str().build());
+            let _memo_short = t.field("memo_short", field_str().build());
+            let _events = t.field("events", outgoing_webhook_events_t.field_type());
+            t.index("outgoing_webhook_account", &[&account]);
+            t.index("outgoing_webhook_channel", &[&channel_identity, &channel]);
+        }
+        {
+            let t = v.table("outgoing_webhook_delivery");
+            let _rowid = t.rowid_field(None);
+            let webhook = t.field("webhook", field_i64().build());
+            let _event = t.field("event", field_str().build());
+            let _payload = t.field("payload", field_str().build());
+            let _created = t.field("created", field_utctime_s_jiff().build());
+            // `pending`, `delivered`, or `failed`
+            let status = t.field("status", field_str().build());
+            let _attempts = t.field("attempts", field_i64().build());
+            let next_attempt = t.field("next_attempt", field_utctime_s_jiff().build());
+            let _last_error = t.field("last_error", field_str().opt().build());
+            t.index("outgoing_webhook_delivery_webhook", &[&webhook]);
+            t.index("outgoing_webhook_delivery_queue", &[&status, &next_attempt]);
+        }
+    }
+
+    // Federation denylist
+    if version >= 5 {
+        let t = v.table("federation_deny");
+        // `identity` or `server`
+        let kind = t.field("kind", field_str().build());
+        let value = t.field("value", field_str().build());
+        let _memo = t.field("memo", field_str().build());
+        let _created = t.field("created", field_utctime_s_jiff().build());
+        t.primary_key("federation_deny_pk", &[&kind, &value]);
+    }
+
+    // Messages in channels owned by local identities
+    if version >= 8 {
+        let message_t =
+            v
+                .custom_type("message_t")
+                .rust_type("crate::interface::db::DbMessage")
+                .base_type(type_str().build());
+        {
+            // One row per message, in the order received, with the latest edit applied
+            let t = v.table("message");
+            let owner = t.field("owner", identity_id_t.field_type());
+            let channel = t.field("channel", channel_id_t.field_type());
+            let snap_offset = t.field("snap_offset", field_i64().build());
+            let sender = t.field("sender", identity_id_t.field_type());
+            let sender_unique = t.field("sender_unique", field_i64().build());
+            let client_id = t.field("client_id", field_str().opt().build());
+            let receive_time = t.field("receive_time", field_utctime_s_jiff().build());
+            let _message = t.field("message", message_t.field_type());
+            let _deleted = t.field("deleted", field_utctime_s_jiff().opt().build());
+            t.primary_key("message_pk", &[&owner, &channel, &snap_offset]);
+            t.unique_index("message_id", &[&owner, &channel, &sender, &sender_unique]);
+            t.unique_index("message_client_id", &[&owner, &channel, &sender, &client_id]);
+            t.index("message_receive_time", &[&owner, &channel, &receive_time]);
+        }
+        {
+            // Every new message, edit and deletion, in order
+            let t = v.table("message_activity");
+            let owner = t.field("owner", identity_id_t.field_type());
+            let channel = t.field("channel", channel_id_t.field_type());
+            let activity_offset = t.field("activity_offset", field_i64().build());
+            let _message = t.field("message", message_t.field_type());
+            let _receive_time = t.field("receive_time", field_utctime_s_jiff().build());
+            t.primary_key("message_activity_pk", &[&owner, &channel, &activity_offset]);
+        }
+    }
+
+    // Federation
+    if version >= 9 {
+        {
+            // Identities on other servers in channels owned here. Members here are in
+            // `channel` via `own_identity`.
+            let t = v.table("channel_remote_member");
+            let owner = t.field("owner", identity_id_t.field_type());
+            let channel = t.field("channel", channel_id_t.field_type());
+            let member = t.field("member", identity_id_t.field_type());
+            let _added = t.field("added", field_utctime_s_jiff().build());
+            t.primary_key("channel_remote_member_pk", &[&owner, &channel, &member]);
+        }
+        {
+            let t = v.table("federation_outbox");
+            let _rowid = t.rowid_field(None);
+            let destination = t.field("destination", identity_id_t.field_type());
+            let owner = t.field("owner", identity_id_t.field_type());
+            let channel = t.field("channel", channel_id_t.field_type());
+            let _offset = t.field("activity_offset", field_i64().build());
+            let _new_message = t.field("new_message", field_bool().build());
+            let _created = t.field("created", field_utctime_s_jiff().build());
+            // `pending`, `delivered`, or `failed`
+            let status = t.field("status", field_str().build());
+            let _attempts = t.field("attempts", field_i64().build());
+            let next_attempt = t.field("next_attempt", field_utctime_s_jiff().build());
+            let _last_error = t.field("last_error", field_str().opt().build());
+            t.index("federation_outbox_queue", &[&status, &next_attempt]);
+            t.index("federation_outbox_pending", &[&destination, &owner, &channel, &status]);
+        }
+        {
+            // Servers that recently failed, keyed by their published url
+            let t = v.table("federation_server_backoff");
+            let server = t.field("server", field_str().build());
+            let _failures = t.field("failures", field_i64().build());
+            let _retry_at = t.field("retry_at", field_utctime_s_jiff().build());
+            t.primary_key("federation_server_backoff_pk", &[&server]);
+        }
+    }
+    return v;
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let mut versions = vec![];
+    for version in 0 .. VERSIONS.len() {
+        versions.push((version, schema(version).build()));
+    }
     match good_ormning::sqlite::generate(GenerateArgs {
-        versions: vec![(0usize, latest_version.build())],
+        versions: versions,
         ..Default::default()
     }) {
         Ok(_) => {},
@@ -106,4 +375,21 @@ fn main() {
             panic!("Generate failed.");
         },
     };
+
+    // Version history for startup checks and dry runs
+    write(
+        PathBuf::from(&env::var("OUT_DIR").unwrap()).join("db_versions.rs"),
+        format!(
+            "// Generated by build.rs\npub const DB_VERSIONS: &[&str] = &[\n{}\n];\n",
+            VERSIONS.iter().map(|v| format!("    {:?},", v)).collect::<Vec<_>>().join("\n")
+        ),
+    ).unwrap();
+
+    // Precompressed web client, embedded alongside the originals
+    println!("cargo:rerun-if-env-changed=STATIC_DIR");
+    let static_dir = PathBuf::from(env::var("STATIC_DIR").unwrap());
+    println!("cargo:rerun-if-changed={}", static_dir.to_string_lossy());
+    let precompressed_dir = PathBuf::from(&env::var("OUT_DIR").unwrap()).join("static_precompressed");
+    create_dir_all(&precompressed_dir).unwrap();
+    precompress(&static_dir, &static_dir, &precompressed_dir);
 }
@@ -0,0 +1,245 @@
+//! Response compression. The embedded web client is precompressed at build time
+//! (see `build.rs`), everything else is compressed on the way out if the client
+//! accepts it and it's worth it.
+use {
+    htwrap::htserve::responses::{
+        body_full,
+        Body,
+    },
+    http::{
+        header::{
+            ACCEPT_ENCODING,
+            CONTENT_ENCODING,
+            CONTENT_LENGTH,
+            CONTENT_TYPE,
+            ETAG,
+            VARY,
+        },
+        HeaderMap,
+        Response,
+        StatusCode,
+    },
+    http_body_util::BodyExt,
+    std::io::Write,
+};
+
+/// Smaller bodies aren't worth the CPU or the header overhead.
+const MIN_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => return "br",
+            Encoding::Gzip => return "gzip",
+        }
+    }
+
+    /// Suffix of the precompressed static files.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => return "br",
+            Encoding::Gzip => return "gz",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = vec![];
+                {
+                    let mut w = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                    w.write_all(data).unwrap();
+                }
+                return out;
+            },
+            Encoding::Gzip => {
+                let mut w = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                w.write_all(data).unwrap();
+                return w.finish().unwrap();
+            },
+        }
+    }
+}
+
+/// Picks the best encoding the client accepts, preferring brotli.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let accept = headers.get(ACCEPT_ENCODING)?.to_str().ok()?;
+    let mut br = false;
+    let mut gzip = false;
+    for part in accept.split(',') {
+        let mut params = part.split(';');
+        let name = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let refused = params.any(|p| {
+            return p.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()).map(|q| q <= 0.).unwrap_or(false);
+        });
+        if refused {
+            continue;
+        }
+        match name.as_str() {
+            "br" => br = true,
+            "gzip" => gzip = true,
+            "*" => {
+                br = true;
+                gzip = true;
+            },
+            _ => { },
+        }
+    }
+    if br {
+        return Some(Encoding::Brotli);
+    } else if gzip {
+        return Some(Encoding::Gzip);
+    } else {
+        return None;
+    }
+}
+
+/// The ETag for the `encoding` representation of a resource with ETag `etag`
+/// (quoted, optionally weak), which must differ from the unencoded one.
+pub fn encoded_etag(etag: &str, encoding: Encoding) -> Option<String> {
+    let etag = etag.strip_suffix('"')?;
+    return Some(format!("{}-{}\"", etag, encoding.extension()));
+}
+
+fn response_500() -> Response<Body> {
+    return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(body_full(vec![])).unwrap();
+}
+
+fn compressible(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    return mime.starts_with("text/") ||
+        mime.ends_with("+json") ||
+        mime.ends_with("+xml") ||
+        ["application/json", "application/javascript", "application/wasm"].contains(&mime);
+}
+
+/// Compresses a full (non-streaming) response if the client accepts it. Responses
+/// that are already encoded, not compressible, or tiny are passed through.
+pub async fn compress_response(encoding: Option<Encoding>, resp: Response<Body>) -> Response<Body> {
+    let Some(encoding) = encoding else {
+        return resp;
+    };
+    if resp.status().is_informational() ||
+        resp.status() == 204 ||
+        resp.status() == 304 ||
+        resp.headers().contains_key(CONTENT_ENCODING) ||
+        !compressible(resp.headers()) {
+        return resp;
+    }
+    let (mut head, body) = resp.into_parts();
+    let body = match body.collect().await {
+        Ok(b) => b.to_bytes(),
+        Err(_) => {
+            return response_500();
+        },
+    };
+    if body.len() < MIN_SIZE {
+        return Response::from_parts(head, body_full(body.to_vec()));
+    }
+    let compressed = match tokio::task::spawn_blocking(move || encoding.compress(&body)).await {
+        Ok(c) => c,
+        Err(_) => {
+            return response_500();
+        },
+    };
+    if let Some(etag) = head.headers.get(ETAG) {
+        match etag.to_str().ok().and_then(|e| encoded_etag(e, encoding)) {
+            Some(etag) => {
+                head.headers.insert(ETAG, etag.parse().unwrap());
+            },
+            None => {
+                head.headers.remove(ETAG);
+            },
+        }
+    }
+    head.headers.insert(CONTENT_ENCODING, encoding.header_value().parse().unwrap());
+    head.headers.append(VARY, ACCEPT_ENCODING.as_str().parse().unwrap());
+    head.headers.remove(CONTENT_LENGTH);
+    return Response::from_parts(head, body_full(compressed));
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            compress_response,
+            encoded_etag,
+            negotiate,
+            Encoding,
+        },
+        htwrap::htserve::responses::body_full,
+        http::{
+            header::{
+                ACCEPT_ENCODING,
+                CONTENT_ENCODING,
+                CONTENT_TYPE,
+                ETAG,
+            },
+            HeaderMap,
+            Response,
+        },
+    };
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, value.parse().unwrap());
+        return headers;
+    }
+
+    #[test]
+    fn negotiate_prefers_brotli() {
+        assert!(negotiate(&accept("gzip, deflate, br")) == Some(Encoding::Brotli));
+        assert!(negotiate(&accept("gzip")) == Some(Encoding::Gzip));
+        assert!(negotiate(&accept("*")) == Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn negotiate_refused() {
+        assert!(negotiate(&accept("br;q=0, gzip;q=0.5")) == Some(Encoding::Gzip));
+        assert!(negotiate(&accept("br; q=0.0, gzip;q=0")) == None);
+        assert!(negotiate(&accept("identity")) == None);
+        assert!(negotiate(&HeaderMap::new()) == None);
+    }
+
+    #[test]
+    fn etag_suffixed() {
+        assert_eq!(encoded_etag("\"abc\"", Encoding::Brotli).unwrap(), "\"abc-br\"");
+        assert_eq!(encoded_etag("W/\"abc\"", Encoding::Gzip).unwrap(), "W/\"abc-gz\"");
+        assert!(encoded_etag("abc", Encoding::Gzip).is_none());
+    }
+
+    #[tokio::test]
+    async fn compresses_with_new_etag() {
+        let resp =
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .header(ETAG, "\"abc\"")
+                .body(body_full(vec![b'a'; 4096]))
+                .unwrap();
+        let resp = compress_response(Some(Encoding::Gzip), resp).await;
+        assert_eq!(resp.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(resp.headers().get(ETAG).unwrap(), "\"abc-gz\"");
+    }
+
+    #[tokio::test]
+    async fn small_passed_through() {
+        let resp =
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .header(ETAG, "\"abc\"")
+                .body(body_full(b"{}".to_vec()))
+                .unwrap();
+        let resp = compress_response(Some(Encoding::Gzip), resp).await;
+        assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(resp.headers().get(ETAG).unwrap(), "\"abc\"");
+    }
+}
@@ -1,14 +1,131 @@
 use {
+    crate::metrics::METRICS,
     deadpool_sqlite::Pool,
-    loga::ResultContext,
-    rusqlite::Transaction,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    rusqlite::{
+        Connection,
+        OptionalExtension,
+        Transaction,
+    },
+    std::time::Instant,
 };
 
+include!(concat!(env!("OUT_DIR"), "/db_versions.rs"));
+
+/// The schema version this build migrates to.
+pub fn latest_db_version() -> usize {
+    return DB_VERSIONS.len() - 1;
+}
+
+/// Reads the schema version recorded by good-ormning's migration table. `None` if
+/// the database hasn't been migrated yet.
+pub fn get_db_version(conn: &Connection) -> Result<Option<usize>, loga::Error> {
+    let has_version_table =
+        conn
+            .query_row(
+                "select count(*) from sqlite_master where type = 'table' and name = '__good_version'",
+                (),
+                |r| r.get::<_, i64>(0),
+            )
+            .context("Error checking for schema version table")? >
+            0;
+    if !has_version_table {
+        return Ok(None);
+    }
+    let Some(version) =
+        conn
+            .query_row("select version from __good_version where rid = 0", (), |r| r.get::<_, i64>(0))
+            .optional()
+            .context("Error reading schema version")? else {
+            return Ok(None);
+        };
+    if version < 0 {
+        return Ok(None);
+    }
+    return Ok(Some(version as usize));
+}
+
+/// Refuses databases that were migrated by a newer release, since migrations
+/// aren't reversible and old code may corrupt new data.
+pub fn check_db_version(conn: &Connection) -> Result<Option<usize>, loga::Error> {
+    let current = get_db_version(conn)?;
+    if let Some(current) = current {
+        if current > latest_db_version() {
+            return Err(
+                loga::err_with(
+                    "Database schema is from a newer version of kwa; refusing to start",
+                    ea!(db_version = current, latest_known_version = latest_db_version()),
+                ),
+            );
+        }
+    }
+    return Ok(current);
+}
+
+/// Human readable list of migration steps from `current` to the latest version.
+pub fn plan_db_migration(current: Option<usize>) -> Vec<String> {
+    let start = match current {
+        Some(c) => c + 1,
+        None => 0,
+    };
+    let mut out = vec![];
+    for (version, desc) in DB_VERSIONS.iter().enumerate().skip(start) {
+        out.push(format!("{}: {}", version, desc));
+    }
+    return out;
+}
+
+/// The version that started storing `account.external_id` as JSON
+/// `{issuer, subject}` rather than just the subject.
+pub const ACCOUNT_ISSUER_DB_VERSION: usize = 7;
+
+/// Rewrites `account.external_id` rows from before accounts were keyed by issuer.
+/// Those were all created by the single provider supported at the time, so they're
+/// attributed to `issuer`. Call after migrating a database whose version was below
+/// `ACCOUNT_ISSUER_DB_VERSION`. Returns the number of rewritten rows.
+pub fn migrate_account_issuer(conn: &mut Connection, issuer: &str) -> Result<usize, loga::Error> {
+    let tx = conn.transaction().context("Error starting account issuer migration")?;
+    let mut rewrite = vec![];
+    {
+        let mut stmt =
+            tx
+                .prepare("select rowid, external_id from account where external_id not like '{%'")
+                .context("Error preparing legacy account query")?;
+        let mut rows = stmt.query(()).context("Error listing legacy accounts")?;
+        while let Some(row) = rows.next().context("Error reading legacy account")? {
+            let rowid = row.get::<_, i64>(0).context("Error reading legacy account rowid")?;
+            let subject = row.get::<_, String>(1).context("Error reading legacy account external id")?;
+            rewrite.push((rowid, crate::interface::AccountExternalId {
+                issuer: issuer.to_string(),
+                subject: subject,
+            }.to_db()));
+        }
+    }
+    for (rowid, external_id) in &rewrite {
+        tx
+            .execute("update account set external_id = ?1 where rowid = ?2", rusqlite::params![external_id, rowid])
+            .context_with("Error rewriting legacy account", ea!(rowid = rowid))?;
+    }
+    tx.commit().context("Error committing account issuer migration")?;
+    return Ok(rewrite.len());
+}
+
+/// Gets a connection, recording how long the pool made us wait.
+pub async fn get_conn(pool: &Pool) -> Result<deadpool_sqlite::Object, loga::Error> {
+    let start = Instant::now();
+    let conn = pool.get().await;
+    METRICS.db_pool_wait.observe(start.elapsed().as_secs_f64());
+    return Ok(conn?);
+}
+
 pub async fn tx<
     O: 'static + Send + Sync,
     F: 'static + Send + for<'b, 't> FnOnce(&'b mut crate::db::Db<Transaction<'t>>) -> Result<O, loga::Error>,
 >(pool: &Pool, cb: F) -> Result<O, loga::Error> {
-    let conn = pool.get().await?;
+    let conn = get_conn(pool).await?;
     return Ok(conn.interact(|conn| {
         let mut tx = conn.transaction()?;
         let mut db_tx = crate::db::Db(tx);
@@ -41,7 +158,7 @@ pub async fn abortable_tx<
     O: 'static + Send + Sync,
     F: 'static + Send + for<'b, 't> FnOnce(&'b mut crate::db::Db<Transaction<'t>>) -> Result<Txr<O>, loga::Error>,
 >(pool: &Pool, cb: F) -> Result<Option<O>, loga::Error> {
-    let conn = pool.get().await?;
+    let conn = get_conn(pool).await?;
     return Ok(conn.interact(|conn| {
         let mut tx = conn.transaction()?;
         let mut db_tx = crate::db::Db(tx);
@@ -74,3 +191,115 @@ pub async fn abortable_tx<
         }
     }).await??);
 }
+
+/// A migrated test database. The pool is dropped before the directory holding the
+/// database files, which is then deleted.
+#[cfg(test)]
+pub struct TestDb {
+    pub pool: Pool,
+    _dir: tempfile::TempDir,
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDb {
+    type Target = Pool;
+
+    fn deref(&self) -> &Self::Target {
+        return &self.pool;
+    }
+}
+
+/// A migrated database in a fresh temporary directory, removed when the returned
+/// value is dropped.
+#[cfg(test)]
+pub async fn test_db() -> TestDb {
+    let dir = tempfile::Builder::new().prefix("kwa-test-").tempdir().unwrap();
+    let pool =
+        deadpool_sqlite::Config::new(dir.path().join("db.sqlite3"))
+            .create_pool(deadpool_sqlite::Runtime::Tokio1)
+            .unwrap();
+    pool.get().await.unwrap().interact(|conn| {
+        crate::db::migrate(&mut *conn, None).map_err(|e| loga::err(e.0)).unwrap();
+    }).await.unwrap();
+    return TestDb {
+        pool: pool,
+        _dir: dir,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            check_db_version,
+            latest_db_version,
+            migrate_account_issuer,
+            plan_db_migration,
+            DB_VERSIONS,
+        },
+        crate::interface::AccountExternalId,
+        rusqlite::Connection,
+    };
+
+    #[test]
+    fn plan_from_empty() {
+        let plan = plan_db_migration(None);
+        assert_eq!(plan.len(), DB_VERSIONS.len());
+        assert_eq!(plan[0], format!("0: {}", DB_VERSIONS[0]));
+    }
+
+    #[test]
+    fn plan_from_partial() {
+        let plan = plan_db_migration(Some(0));
+        assert_eq!(plan.len(), latest_db_version());
+        assert_eq!(plan[0], format!("1: {}", DB_VERSIONS[1]));
+    }
+
+    #[test]
+    fn plan_from_latest() {
+        assert!(plan_db_migration(Some(latest_db_version())).is_empty());
+    }
+
+    #[test]
+    fn version_empty() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(check_db_version(&conn).unwrap(), None);
+    }
+
+    #[test]
+    fn version_migrated() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn, None).map_err(|e| loga::err(e.0)).unwrap();
+        assert_eq!(check_db_version(&conn).unwrap(), Some(latest_db_version()));
+    }
+
+    #[test]
+    fn version_newer_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn, None).map_err(|e| loga::err(e.0)).unwrap();
+        conn
+            .execute("update __good_version set version = ?1 where rid = 0", [latest_db_version() as i64 + 1])
+            .unwrap();
+        assert!(check_db_version(&conn).is_err());
+    }
+
+    #[test]
+    fn account_issuer_rewritten() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn, None).map_err(|e| loga::err(e.0)).unwrap();
+        let current = AccountExternalId {
+            issuer: "https://b.example.org".to_string(),
+            subject: "bob".to_string(),
+        }.to_db();
+        conn.execute("insert into account (external_id) values ('alice')", ()).unwrap();
+        conn.execute("insert into account (external_id) values (?1)", [&current]).unwrap();
+        assert_eq!(migrate_account_issuer(&mut conn, "https://a.example.org").unwrap(), 1);
+        let mut stmt = conn.prepare("select external_id from account order by rowid").unwrap();
+        let ids = stmt.query_map((), |r| r.get::<_, String>(0)).unwrap().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![AccountExternalId {
+            issuer: "https://a.example.org".to_string(),
+            subject: "alice".to_string(),
+        }.to_db(), current]);
+        assert_eq!(migrate_account_issuer(&mut conn, "https://a.example.org").unwrap(), 0);
+    }
+}
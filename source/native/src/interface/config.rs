@@ -9,8 +9,179 @@ use {
 
 #[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
-pub struct OidcConfig {
+pub struct OidcRequiredClaimValue {
+    /// Name of a claim in the ID token, ex: `groups`. The claim may be a string or a
+    /// list of strings.
+    pub claim: String,
+    pub value: String,
+}
+
+/// All specified restrictions must pass for a user to log in (and thereby get an
+/// account). Unspecified restrictions are ignored.
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema, TS)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OidcRestrictions {
+    /// The ID token must have a verified `email` in one of these domains.
+    #[serde(default)]
+    pub allowed_email_domains: Option<Vec<String>>,
+    #[serde(default)]
+    pub required_claim_value: Option<OidcRequiredClaimValue>,
+    /// The ID token `sub` must be one of these.
+    #[serde(default)]
+    pub allowed_subjects: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OidcProviderConfig {
+    /// Used to select the provider during login, must be unique.
+    pub id: String,
+    /// Shown on the provider selection page.
+    pub name: String,
     pub provider_url: String,
     pub client_id: String,
     pub client_secret: Option<String>,
+    #[serde(default)]
+    pub restrictions: OidcRestrictions,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OidcConfig {
+    /// If there's more than one provider the user will be asked to choose one when
+    /// logging in. Accounts from before multiple providers were supported are
+    /// attributed to the first provider, so keep the original provider first when
+    /// upgrading.
+    pub providers: Vec<OidcProviderConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert_path: String,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key_path: String,
+    /// How often to check the files for changes, in seconds. Renewed certificates
+    /// are used for new connections, existing connections are unaffected.
+    #[serde(default)]
+    #[ts(type = "number | null")]
+    pub reload_interval_secs: Option<u64>,
+}
+
+/// The key is 32 random bytes, base64 encoded, ex: `head -c 32 /dev/urandom |
+/// base64`. Surrounding whitespace is ignored.
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum SecretKeySource {
+    /// Path of a file containing the key.
+    File(String),
+    /// Name of an environment variable containing the key.
+    Env(String),
+}
+
+/// A token bucket: up to `burst` requests at once, refilling at `per_minute`.
+#[derive(Serialize, Deserialize, Clone, Copy, JsonSchema, TS)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RateLimitBucketConfig {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+/// Each class of request has its own buckets, and each bucket applies separately
+/// per account, per session or API token, and per client IP (whichever are known
+/// for the request).
+#[derive(Serialize, Deserialize, Clone, JsonSchema, TS)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Posting messages, from clients, API tokens and incoming webhooks.
+    #[serde(default = "default_rate_limit_message_push")]
+    pub message_push: RateLimitBucketConfig,
+    /// OIDC login requests, keyed by IP only. Each new login also takes a slot in a
+    /// small pending-login cache, so keep this tight.
+    #[serde(default = "default_rate_limit_login")]
+    pub login: RateLimitBucketConfig,
+    /// GET requests for pages, portraits, etc.
+    #[serde(default = "default_rate_limit_get")]
+    pub get: RateLimitBucketConfig,
+    /// All other API requests.
+    #[serde(default = "default_rate_limit_api")]
+    pub api: RateLimitBucketConfig,
+    /// Use the last address in `X-Forwarded-For` as the client IP. Only enable this
+    /// behind a single reverse proxy that appends to the header, otherwise clients
+    /// can choose their own IP.
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+}
+
+fn default_rate_limit_message_push() -> RateLimitBucketConfig {
+    return RateLimitBucketConfig {
+        burst: 30,
+        per_minute: 60,
+    };
+}
+
+fn default_rate_limit_login() -> RateLimitBucketConfig {
+    return RateLimitBucketConfig {
+        burst: 5,
+        per_minute: 10,
+    };
+}
+
+fn default_rate_limit_get() -> RateLimitBucketConfig {
+    return RateLimitBucketConfig {
+        burst: 120,
+        per_minute: 600,
+    };
+}
+
+fn default_rate_limit_api() -> RateLimitBucketConfig {
+    return RateLimitBucketConfig {
+        burst: 60,
+        per_minute: 300,
+    };
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        return Self {
+            message_push: default_rate_limit_message_push(),
+            login: default_rate_limit_login(),
+            get: default_rate_limit_get(),
+            api: default_rate_limit_api(),
+            trust_forwarded_for: false,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum FederationMode {
+    /// Talk to any server not on the denylist.
+    #[default]
+    Open,
+    /// Only talk to the servers and identities in `allow_servers` and
+    /// `allow_identities` (that aren't on the denylist).
+    Allowlist,
+    /// No server to server traffic at all; identities here can only talk to each
+    /// other.
+    Closed,
+}
+
+/// Who this server exchanges notifications, pages and joins with, both incoming
+/// and outgoing. The denylist is managed at runtime with the `federation-deny-*`
+/// subcommands and applies in every mode.
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema, TS)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct FederationConfig {
+    #[serde(default)]
+    pub mode: FederationMode,
+    /// Server hosts, ex: `kwa.example.org`. Urls are reduced to their host. An
+    /// incoming identity only counts as on a server once that server confirms it
+    /// hosts the identity.
+    #[serde(default)]
+    pub allow_servers: Vec<String>,
+    /// Identities allowed regardless of their server.
+    #[serde(default)]
+    pub allow_identities: Vec<String>,
 }
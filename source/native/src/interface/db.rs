@@ -1,12 +1,25 @@
 use {
+    crate::interface::AccountExternalId,
     good_ormning::runtime::sqlite::{
         GoodOrmningCustomI64,
         GoodOrmningCustomString,
     },
-    shared::interface::shared::{
-        AccountId,
-        ChannelGroupId,
-        ChannelId,
+    shared::interface::{
+        shared::{
+            AccountId,
+            ChannelGroupId,
+            ChannelId,
+            Message,
+            SessionId,
+        },
+        wire::c2s::{
+            ApiTokenScope,
+            OutgoingWebhookEvents,
+        },
+    },
+    serde::{
+        Deserialize,
+        Serialize,
     },
     spaghettinuum::interface::identity::{
         Identity,
@@ -26,7 +39,30 @@ impl GoodOrmningCustomI64<DbAccountId> for DbAccountId {
     }
 }
 
-pub struct DbIdentitySecret(pub LocalIdentitySecret);
+/// Encrypted with the configured identity secret key, see
+/// `subsystems::identitysecret`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct EncryptedIdentitySecret {
+    /// Identifies the key without revealing it, to detect a wrong key.
+    pub key_id: String,
+    /// Base64, 24 bytes
+    pub nonce: String,
+    /// Base64, JSON `LocalIdentitySecret`
+    pub ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum StoredIdentitySecret {
+    Encrypted {
+        encrypted: EncryptedIdentitySecret,
+    },
+    /// Written before encryption at rest existed or while no key is configured.
+    Plain(LocalIdentitySecret),
+}
+
+pub struct DbIdentitySecret(pub StoredIdentitySecret);
 
 impl GoodOrmningCustomString<DbIdentitySecret> for DbIdentitySecret {
     fn to_sql<'a>(value: &'a DbIdentitySecret) -> String {
@@ -34,7 +70,7 @@ impl GoodOrmningCustomString<DbIdentitySecret> for DbIdentitySecret {
     }
 
     fn from_sql(value: String) -> Result<DbIdentitySecret, String> {
-        return serde_json::from_str::<LocalIdentitySecret>(&value)
+        return serde_json::from_str::<StoredIdentitySecret>(&value)
             .map_err(|e| e.to_string())
             .map(|x| DbIdentitySecret(x));
     }
@@ -75,3 +111,78 @@ impl GoodOrmningCustomString<DbChannelId> for DbChannelId {
         return value.parse::<u64>().map(|v| DbChannelId(ChannelId(v))).map_err(|e| e.to_string());
     }
 }
+
+pub struct DbAccountExternalId(pub AccountExternalId);
+
+impl GoodOrmningCustomString<DbAccountExternalId> for DbAccountExternalId {
+    fn to_sql<'a>(value: &'a DbAccountExternalId) -> String {
+        return value.0.to_db();
+    }
+
+    fn from_sql(value: String) -> Result<DbAccountExternalId, String> {
+        return AccountExternalId::from_db(&value).map(|x| DbAccountExternalId(x));
+    }
+}
+
+pub struct DbSessionId(pub SessionId);
+
+impl GoodOrmningCustomString<DbSessionId> for DbSessionId {
+    fn to_sql<'a>(value: &'a DbSessionId) -> String {
+        return value.0.0.clone();
+    }
+
+    fn from_sql(value: String) -> Result<DbSessionId, String> {
+        return Ok(DbSessionId(SessionId(value)));
+    }
+}
+
+/// Push subscription as produced by `toJSON()` in the browser.
+pub struct DbPushSubscription(pub serde_json::Value);
+
+impl GoodOrmningCustomString<DbPushSubscription> for DbPushSubscription {
+    fn to_sql<'a>(value: &'a DbPushSubscription) -> String {
+        return serde_json::to_string(&value.0).unwrap();
+    }
+
+    fn from_sql(value: String) -> Result<DbPushSubscription, String> {
+        return serde_json::from_str(&value).map_err(|e| e.to_string()).map(|x| DbPushSubscription(x));
+    }
+}
+
+pub struct DbApiTokenScope(pub ApiTokenScope);
+
+impl GoodOrmningCustomString<DbApiTokenScope> for DbApiTokenScope {
+    fn to_sql<'a>(value: &'a DbApiTokenScope) -> String {
+        return serde_json::to_string(&value.0).unwrap();
+    }
+
+    fn from_sql(value: String) -> Result<DbApiTokenScope, String> {
+        return serde_json::from_str::<ApiTokenScope>(&value).map_err(|e| e.to_string()).map(|x| DbApiTokenScope(x));
+    }
+}
+
+pub struct DbOutgoingWebhookEvents(pub OutgoingWebhookEvents);
+
+impl GoodOrmningCustomString<DbOutgoingWebhookEvents> for DbOutgoingWebhookEvents {
+    fn to_sql<'a>(value: &'a DbOutgoingWebhookEvents) -> String {
+        return serde_json::to_string(&value.0).unwrap();
+    }
+
+    fn from_sql(value: String) -> Result<DbOutgoingWebhookEvents, String> {
+        return serde_json::from_str::<OutgoingWebhookEvents>(&value)
+            .map_err(|e| e.to_string())
+            .map(|x| DbOutgoingWebhookEvents(x));
+    }
+}
+
+pub struct DbMessage(pub Message);
+
+impl GoodOrmningCustomString<DbMessage> for DbMessage {
+    fn to_sql<'a>(value: &'a DbMessage) -> String {
+        return serde_json::to_string(&value.0).unwrap();
+    }
+
+    fn from_sql(value: String) -> Result<DbMessage, String> {
+        return serde_json::from_str::<Message>(&value).map_err(|e| e.to_string()).map(|x| DbMessage(x));
+    }
+}
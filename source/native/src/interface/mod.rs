@@ -1,6 +1,7 @@
 pub mod s2s;
 pub mod config;
 pub mod db;
+pub mod spagh;
 
 use {
     schemars::JsonSchema,
@@ -10,6 +11,21 @@ use {
     },
 };
 
+/// The OIDC `sub` is only unique per issuer, so accounts are keyed by both.
 #[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
-pub struct AccountExternalId(pub String);
+pub struct AccountExternalId {
+    pub issuer: String,
+    pub subject: String,
+}
+
+impl AccountExternalId {
+    /// For the `account.external_id` column.
+    pub fn to_db(&self) -> String {
+        return serde_json::to_string(self).unwrap();
+    }
+
+    pub fn from_db(v: &str) -> Result<Self, String> {
+        return serde_json::from_str(v).map_err(|e| e.to_string());
+    }
+}
@@ -1,13 +1,27 @@
 use {
     glove::reqresp,
-    shared::interface::shared::{
-        ChannelId,
+    shared::interface::{
+        shared::{
+            ChannelId,
+            QualifiedChannelId,
+        },
+        wire::c2s::{
+            GetActivityPage,
+            GetSnapPage,
+            PathReqTrait,
+        },
     },
+    spaghettinuum::interface::identity::Identity,
+    std::str::FromStr,
 };
 
+pub const S2SV1_PREFIX: &str = "s/1/";
+
 // POST only.
 //
-// Page and file fetching are handled via separate endpoints (GET).
+// Page and file fetching are handled via separate endpoints (GET), see `S2sGet`.
+// Page fetches and `Notify` need `Authorization: Bearer <token>` with a token from
+// `Identify`.
 pub mod s2sv1t {
     use {
         schemars::JsonSchema,
@@ -15,8 +29,9 @@ pub mod s2sv1t {
             Deserialize,
             Serialize,
         },
-        shared::interface::shared::{
-            ChannelId,
+        shared::interface::{
+            shared::ChannelId,
+            wire::c2s::ActivityOffset,
         },
         spaghettinuum::{
             byteszb32::BytesZb32,
@@ -27,9 +42,12 @@ pub mod s2sv1t {
         },
     };
 
+    /// The challenge can only be used to identify as `identity`.
     #[derive(Serialize, Deserialize, JsonSchema)]
     #[serde(rename_all = "snake_case", deny_unknown_fields)]
-    pub struct StartIdentify {}
+    pub struct StartIdentify {
+        pub identity: Identity,
+    }
 
     #[derive(Serialize, Deserialize, JsonSchema)]
     #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -37,17 +55,46 @@ pub mod s2sv1t {
         pub challenge: BytesZb32,
     }
 
+    /// What `Identify` signs. The audience (the url the identifying server published
+    /// for the server it's talking to) stops a server from relaying another server's
+    /// challenge to get a signature it can use there.
+    #[derive(Serialize, Deserialize, JsonSchema, Clone)]
+    #[serde(rename_all = "snake_case", deny_unknown_fields)]
+    pub struct IdentifyChallenge {
+        pub audience: String,
+        pub identity: Identity,
+        pub challenge: BytesZb32,
+    }
+
+    /// Proves the caller controls `identity` by signing the challenge from
+    /// `StartIdentify`. Returns a bearer token for that identity.
     #[derive(Serialize, Deserialize, JsonSchema)]
     #[serde(rename_all = "snake_case", deny_unknown_fields)]
     pub struct Identify {
-        pub challenge: Signature<BytesZb32>,
+        pub identity: Identity,
+        pub challenge: Signature<IdentifyChallenge>,
     }
 
+    /// The channel has new activity. Needs `Authorization: Bearer <token>` for
+    /// `owner`. There's no content, the receiver fetches the activity pages.
     #[derive(Serialize, Deserialize, JsonSchema)]
     #[serde(rename_all = "snake_case", deny_unknown_fields)]
     pub struct Notify {
         pub owner: Identity,
         pub channel: ChannelId,
+        /// The latest activity offset.
+        pub offset: ActivityOffset,
+        /// Whether the activity includes a new message (as opposed to only edits and
+        /// deletions), for deciding whether to send push notifications.
+        pub new_message: bool,
+    }
+
+    /// Whether `identity` is hosted on this server. Servers ask the url an identity
+    /// publishes before applying server rules to it (see `federationpolicy`).
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    #[serde(rename_all = "snake_case", deny_unknown_fields)]
+    pub struct IsHome {
+        pub identity: Identity,
     }
 
     #[derive(Serialize, Deserialize, JsonSchema)]
@@ -57,6 +104,7 @@ pub mod s2sv1t {
         Identity(Identity),
     }
 
+    /// Needs `Authorization: Bearer <token>` like page fetches.
     #[derive(Serialize, Deserialize, JsonSchema)]
     #[serde(rename_all = "snake_case", deny_unknown_fields)]
     pub struct Join {
@@ -64,7 +112,8 @@ pub mod s2sv1t {
         pub token: InvitationToken,
     }
 
-    #[derive(Serialize, Deserialize, JsonSchema)]
+    /// Pages before the last (`count / page_size`) are full and never change.
+    #[derive(Serialize, Deserialize, JsonSchema, Clone)]
     #[serde(rename_all = "snake_case", deny_unknown_fields)]
     pub struct GetLastPageRes {
         pub page_size: usize,
@@ -77,4 +126,70 @@ reqresp!(pub s2sv1 {
     Identify(s2sv1t::Identify) => String,
     Notify(s2sv1t::Notify) =>(),
     Join(s2sv1t::Join) => ChannelId,
+    IsHome(s2sv1t::IsHome) => bool,
 });
+
+const PATH_PREFIX_LAST_SNAP_PAGE: &str = "last_snap_page";
+const PATH_PREFIX_LAST_ACTIVITY_PAGE: &str = "last_activity_page";
+
+/// GET requests, paths relative to `S2SV1_PREFIX`. Snap and activity pages use the
+/// same paths and responses as the c2s equivalents.
+pub enum S2sGet {
+    SnapPage(GetSnapPage),
+    ActivityPage(GetActivityPage),
+    /// Responds with `GetLastPageRes`
+    LastSnapPage(QualifiedChannelId),
+    /// Responds with `GetLastPageRes`
+    LastActivityPage(QualifiedChannelId),
+}
+
+impl S2sGet {
+    pub fn channel(&self) -> &QualifiedChannelId {
+        match self {
+            S2sGet::SnapPage(r) => return &r.channel,
+            S2sGet::ActivityPage(r) => return &r.channel,
+            S2sGet::LastSnapPage(c) => return c,
+            S2sGet::LastActivityPage(c) => return c,
+        }
+    }
+
+    /// `path` starts with `/`.
+    pub fn deserialize_path(path: &str) -> Result<Self, String> {
+        let segs = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+        match segs.as_slice() {
+            [PATH_PREFIX_LAST_SNAP_PAGE, identity, channel] => {
+                return Ok(S2sGet::LastSnapPage(parse_channel(identity, channel)?));
+            },
+            [PATH_PREFIX_LAST_ACTIVITY_PAGE, identity, channel] => {
+                return Ok(S2sGet::LastActivityPage(parse_channel(identity, channel)?));
+            },
+            _ => { },
+        }
+        if let Ok(r) = GetSnapPage::deserialize_path(path) {
+            return Ok(S2sGet::SnapPage(r));
+        }
+        return Ok(S2sGet::ActivityPage(GetActivityPage::deserialize_path(path)?));
+    }
+
+    pub fn serialize_path(&self) -> String {
+        match self {
+            S2sGet::SnapPage(r) => return r.serialize_path(),
+            S2sGet::ActivityPage(r) => return r.serialize_path(),
+            S2sGet::LastSnapPage(c) => {
+                return format!("/{}/{}/{}", PATH_PREFIX_LAST_SNAP_PAGE, c.identity, c.channel.0);
+            },
+            S2sGet::LastActivityPage(c) => {
+                return format!("/{}/{}/{}", PATH_PREFIX_LAST_ACTIVITY_PAGE, c.identity, c.channel.0);
+            },
+        }
+    }
+}
+
+fn parse_channel(identity: &str, channel: &str) -> Result<QualifiedChannelId, String> {
+    // Identities are zbase32, no escaping needed
+    return Ok(QualifiedChannelId {
+        identity: Identity::from_str(identity)
+            .map_err(|e| format!("Couldn't parse channel identity: {}", e))?,
+        channel: ChannelId(channel.parse().map_err(|e| format!("Couldn't parse channel: {}", e))?),
+    });
+}
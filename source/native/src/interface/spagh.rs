@@ -0,0 +1,30 @@
+//! Records published for local identities through spaghettinuum, read by other
+//! kwa servers.
+use {
+    schemars::JsonSchema,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+};
+
+/// Record key for `PublishedServer`.
+pub const KEY_SERVER: &str = "kwa_server";
+
+/// Record key for `PublishedProfile`.
+pub const KEY_PROFILE: &str = "kwa_profile";
+
+/// Where the identity lives.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct PublishedServer {
+    /// Base url of the kwa server, ending in `/`.
+    pub url: String,
+}
+
+/// Public details of the identity, shown to people on other servers.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct PublishedProfile {
+    pub memo_short: String,
+}
@@ -1,6 +1,10 @@
 pub mod interface;
+pub mod compress;
 pub mod dbutil;
 pub mod fsutil;
+pub mod metrics;
+pub mod passphrase;
+pub mod schemas;
 pub mod subsystems;
 pub mod util;
 
@@ -8,17 +12,111 @@ good_ormning::good_module!(pub db);
 
 use {
     crate::{
-        dbutil::tx,
+        dbutil::{
+            check_db_version,
+            latest_db_version,
+            migrate_account_issuer,
+            plan_db_migration,
+            tx,
+            ACCOUNT_ISSUER_DB_VERSION,
+        },
         fsutil::create_dirs,
         interface::{
-            config::OidcConfig,
-            s2s::s2sv1t::GetLastPageRes,
+            config::{
+                FederationConfig,
+                OidcConfig,
+                RateLimitConfig,
+                SecretKeySource,
+                TlsConfig,
+            },
+            db::{
+                DbAccountExternalId,
+                DbPushSubscription,
+                DbSessionId,
+            },
+            s2s::{
+                s2sv1,
+                s2sv1t,
+                S2sGet,
+                S2SV1_PREFIX,
+            },
+            spagh::{
+                PublishedProfile,
+                PublishedServer,
+            },
             AccountExternalId,
         },
-        subsystems::oidc::{
-            self,
-            get_req_session,
-            OidcState,
+        subsystems::{
+            accountexport::{
+                self,
+                AccountExportState,
+            },
+            apitoken::{
+                self,
+                ApiTokenAuth,
+            },
+            federation::{
+                self,
+                FederationState,
+                WorkerDeps,
+            },
+            federationpolicy::{
+                self,
+                DenyTarget,
+                FederationPolicyState,
+            },
+            health::{
+                self,
+                HealthState,
+            },
+            identity,
+            identitybundle,
+            identitysecret::{
+                self,
+                SecretKey,
+            },
+            message,
+            oidc::{
+                self,
+                get_req_session,
+                OidcState,
+            },
+            outgoingwebhook::{
+                self,
+                OutgoingWebhookEvent,
+                OutgoingWebhookState,
+            },
+            ratelimit::{
+                self,
+                RateLimitState,
+            },
+            remotepages::{
+                self,
+                RemotePagesState,
+            },
+            resolver::{
+                self,
+                ResolveBackend,
+                ResolverState,
+            },
+            s2sauth::{
+                self,
+                S2sAuthState,
+            },
+            spagh::{
+                self,
+                SpaghState,
+            },
+            tls,
+            webhook::{
+                self,
+                WebhookState,
+            },
+            webpush::{
+                self,
+                WebPushState,
+            },
+            websocket,
         },
     },
     aargvark::{
@@ -35,9 +133,15 @@ use {
     glove::reqresp,
     http::{
         header::{
+            ALLOW,
+            CACHE_CONTROL,
+            CONTENT_DISPOSITION,
+            CONTENT_ENCODING,
+            CONTENT_TYPE,
             COOKIE,
             ETAG,
             IF_NONE_MATCH,
+            VARY,
         },
         status,
         HeaderMap,
@@ -58,6 +162,7 @@ use {
                 PathRouter,
             },
             responses::{
+                body_empty,
                 body_full,
                 response_200_json,
                 response_400,
@@ -78,10 +183,16 @@ use {
             Bytes,
             Incoming,
         },
-        server::conn::http1,
         service::service_fn,
     },
-    hyper_util::rt::TokioIo,
+    hyper_util::{
+        rt::{
+            TokioExecutor,
+            TokioIo,
+        },
+        server::conn::auto,
+    },
+    jiff::Timestamp,
     loga::{
         ea,
         fatal,
@@ -91,18 +202,35 @@ use {
     },
     rand::rng,
     rust_embed::RustEmbed,
-    schemars::JsonSchema,
+    schemars::{
+        JsonSchema,
+        Schema,
+        SchemaGenerator,
+    },
     serde::{
         Deserialize,
         Serialize,
     },
-    shared::interface::wire::{
-        c2s::{
-            self,
-            ChannelGroupRes,
-            ChannelOrChannelGroup,
-            ChannelOrChannelGroupGroup,
-            ChannelRes,
+    shared::interface::{
+        shared::{
+            MessageRel,
+            QualifiedChannelId,
+        },
+        wire::{
+            c2s::{
+                self,
+                C2sVersionRes,
+                ChannelGroupRes,
+                ChannelOrChannelGroup,
+                ChannelOrChannelGroupGroup,
+                ChannelRes,
+                MessagePush,
+                PathReqTrait,
+                C2SV1_PREFIX,
+                C2S_VERSION,
+            },
+            kwaurl::KwaUrl,
+            s2c,
         },
     },
     spaghettinuum::interface::identity::Identity,
@@ -115,11 +243,23 @@ use {
     },
     std::{
         collections::BTreeMap,
-        net::SocketAddr,
-        path::PathBuf,
+        net::{
+            IpAddr,
+            SocketAddr,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
         str::FromStr,
-        sync::Arc,
-        time::Duration,
+        sync::{
+            Arc,
+            OnceLock,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
     },
     taskmanager::TaskManager,
     tokio::{
@@ -129,43 +269,241 @@ use {
         },
         runtime,
         select,
+        signal::unix::{
+            signal,
+            SignalKind,
+        },
         spawn,
-        sync::mpsc,
+        sync::{
+            broadcast,
+            mpsc,
+        },
+        time::timeout,
+    },
+    tokio_stream::{
+        wrappers::{
+            TcpListenerStream,
+            WatchStream,
+        },
+        StreamExt,
     },
-    tokio_stream::wrappers::TcpListenerStream,
+    tokio_util::task::TaskTracker,
+    ts_rs::TS,
 };
 
-#[derive(Serialize, Deserialize, JsonSchema)]
+#[derive(Serialize, Deserialize, JsonSchema, TS)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct Config {
+    #[ts(type = "string")]
     pub bind_sockaddr: StrSocketAddr,
+    #[ts(type = "string")]
     pub spagh_node_bind_sockaddr: StrSocketAddr,
+    #[ts(type = "string")]
     pub spagh_publisher_bind_sockaddr: StrSocketAddr,
+    #[ts(type = "unknown")]
     pub spagh_publisher_advertise_global_addr: GlobalAddrConfig,
     pub spagh_publisher_advertise_global_port: Option<u16>,
+    #[ts(type = "{ secs: number, nanos: number }")]
     pub public_http_resp_cache_duration: Duration,
+    /// Base url other kwa servers use to reach this one, ex:
+    /// `https://kwa.example.org/`. Published as the home of every identity here;
+    /// without it identities aren't published and can't federate.
+    #[serde(default)]
+    pub public_url: Option<String>,
     pub cache_dir: PathBuf,
     pub persistent_dir: PathBuf,
     pub oidc_config: OidcConfig,
+    /// Terminate TLS on `bind_sockaddr` rather than serving plain http. Without this
+    /// a reverse proxy is needed for browsers to connect.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Serve Prometheus metrics at `/metrics` on this address. This is a separate
+    /// listener so it can be kept private.
+    #[serde(default)]
+    #[ts(type = "string | null")]
+    pub metrics_bind_sockaddr: Option<StrSocketAddr>,
+    /// On SIGTERM/SIGINT, how long to wait for in-flight requests to finish before
+    /// exiting anyway, in seconds. Defaults to 30.
+    #[serde(default)]
+    #[ts(type = "number | null")]
+    pub shutdown_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    /// Encrypt identity secrets in the database with this key. Once set, existing
+    /// secrets are encrypted at startup and the server won't start without it; use
+    /// `rotate-identity-secret-key` to change it.
+    #[serde(default)]
+    pub identity_secret_key: Option<SecretKeySource>,
+    #[serde(default)]
+    pub federation: FederationConfig,
+    /// Let outgoing webhooks deliver to, and federation requests go to, loopback,
+    /// private and link-local addresses. Off by default so channel owners and
+    /// published identities can't make the server send requests into its own
+    /// network.
+    #[serde(default)]
+    pub outgoing_webhooks_allow_private: bool,
 }
 
+/// Optional features, reported by the version endpoint so clients can hide what
+/// the server doesn't support.
+const C2S_CAPABILITIES: &[&str] = &[
+    //. .
+    "api_tokens",
+    "incoming_webhooks",
+    "outgoing_webhooks",
+    "push_notifications",
+];
+
 #[derive(Aargvark)]
-struct Args {
-    config: AargvarkJson<Config>,
+struct RunArgs {
+    /// See `config.schema.json` from `export-schemas`.
+    config: AargvarkJson<serde_json::Value>,
+    /// Check the config and exit.
     #[vark(flag = "--validate")]
     validate: Option<()>,
+    /// Print the database migrations that would be applied and exit.
+    #[vark(flag = "--migrate-dry-run")]
+    migrate_dry_run: Option<()>,
+}
+
+#[derive(Aargvark)]
+struct ExportSchemasArgs {
+    /// Directory to write `*.schema.json` and `*.d.ts` files to.
+    out_dir: PathBuf,
+}
+
+#[derive(Aargvark)]
+struct RotateIdentitySecretKeyArgs {
+    /// See `config.schema.json` from `export-schemas`. Secrets are decrypted with
+    /// the `identity_secret_key` in here (if any).
+    config: AargvarkJson<serde_json::Value>,
+    /// File containing the new key, in the same format as `identity_secret_key`.
+    new_key: PathBuf,
+}
+
+#[derive(Aargvark)]
+struct ResolveIdentityArgs {
+    /// See `config.schema.json` from `export-schemas`. This starts its own node on
+    /// an ephemeral port on the `spagh_node_bind_sockaddr` address, so it can run
+    /// alongside the server.
+    config: AargvarkJson<serde_json::Value>,
+    identity: String,
+    /// Only show what's in the resolver cache, don't look anything up.
+    #[vark(flag = "--cached")]
+    cached: Option<()>,
+}
+
+#[derive(Aargvark)]
+enum FederationDenyTargetArg {
+    /// A remote identity
+    Identity(String),
+    /// A remote server, by host (ex: `kwa.example.org`). Urls are reduced to their
+    /// host.
+    Server(String),
+}
+
+#[derive(Aargvark)]
+struct FederationDenyAddArgs {
+    /// See `config.schema.json` from `export-schemas`.
+    config: AargvarkJson<serde_json::Value>,
+    target: FederationDenyTargetArg,
+    /// Why it was blocked, shown by `federation-deny-list`.
+    #[vark(flag = "--memo")]
+    memo: Option<String>,
+}
+
+#[derive(Aargvark)]
+struct FederationDenyRemoveArgs {
+    /// See `config.schema.json` from `export-schemas`.
+    config: AargvarkJson<serde_json::Value>,
+    target: FederationDenyTargetArg,
+}
+
+#[derive(Aargvark)]
+struct FederationDenyListArgs {
+    /// See `config.schema.json` from `export-schemas`.
+    config: AargvarkJson<serde_json::Value>,
+}
+
+#[derive(Aargvark)]
+enum Args {
+    /// Run the server.
+    Run(RunArgs),
+    /// Write JSON Schemas and TypeScript declarations for the config and the wire
+    /// protocols.
+    ExportSchemas(ExportSchemasArgs),
+    /// Re-encrypt all identity secrets with a new key (or encrypt them for the first
+    /// time). Stop the server first, then update `identity_secret_key` to the new
+    /// key before restarting.
+    RotateIdentitySecretKey(RotateIdentitySecretKeyArgs),
+    /// Debugging: look up where a remote identity lives, bypassing (and updating)
+    /// the resolver cache, and print the result as JSON.
+    ResolveIdentity(ResolveIdentityArgs),
+    /// Stop federating with a remote identity or server. Takes effect immediately,
+    /// including on a running server.
+    FederationDenyAdd(FederationDenyAddArgs),
+    /// Undo `federation-deny-add`. Takes effect immediately.
+    FederationDenyRemove(FederationDenyRemoveArgs),
+    /// Print the federation denylist as JSON.
+    FederationDenyList(FederationDenyListArgs),
 }
 
 struct State {
     log: loga::Log,
     db: Pool,
     oidc_state: OidcState,
+    webhook_state: WebhookState,
+    outgoing_webhook_state: OutgoingWebhookState,
+    rate_limit_state: RateLimitState,
+    account_export_state: Arc<AccountExportState>,
+    spagh: Arc<SpaghState>,
+    resolver_state: Arc<ResolverState>,
+    federation_policy_state: Arc<FederationPolicyState>,
+    s2s_auth_state: Arc<S2sAuthState>,
+    remote_pages_state: RemotePagesState,
+    federation_state: FederationState,
+    webpush_state: Arc<WebPushState>,
+    identity_secret_key: Option<SecretKey>,
 }
 
-pub async fn identify_c2s(
-    state: &State,
-    headers: &HeaderMap,
-) -> Result<Option<AccountExternalId>, VisErr<loga::Error>> {
+/// A 403 with the reason if federation policy doesn't allow the remote identity.
+/// The identity must already be authenticated.
+async fn check_federation(state: &State, identity: &Identity) -> Result<Option<Response<Body>>, loga::Error> {
+    match federationpolicy::check_inbound(
+        &state.log,
+        &state.db,
+        &state.federation_policy_state,
+        &state.resolver_state,
+        identity,
+    ).await? {
+        Ok(()) => return Ok(None),
+        Err(reason) => return Ok(Some(federationpolicy::response_403(reason))),
+    }
+}
+
+/// How a c2s request was authenticated.
+pub enum C2sAuth {
+    /// Logged in via browser, full access to the account.
+    Session(AccountExternalId),
+    /// Bearer token, limited to one identity and the token's scope.
+    ApiToken(ApiTokenAuth),
+}
+
+impl C2sAuth {
+    pub fn account(&self) -> &AccountExternalId {
+        match self {
+            C2sAuth::Session(a) => return a,
+            C2sAuth::ApiToken(t) => return &t.account,
+        }
+    }
+}
+
+pub async fn identify_c2s(state: &State, headers: &HeaderMap) -> Result<Option<C2sAuth>, VisErr<loga::Error>> {
+    if let Some(token) = apitoken::get_req_bearer(headers) {
+        // Don't fall back to the cookie if a bad token is presented, to make
+        // misconfigured bots obvious
+        return Ok(apitoken::identify(&state.db, token).await.err_internal()?.map(C2sAuth::ApiToken));
+    }
     shed!{
         let Some(session) = get_req_session(&state.log, headers) else {
             break;
@@ -174,100 +512,838 @@ pub async fn identify_c2s(
             state.log.log(loga::DEBUG, format!("Request has session id [{}] but no matching session found", session));
             break;
         };
-        return Ok(Some(user));
+        *user.last_seen.lock().unwrap() = Timestamp::now();
+        return Ok(Some(C2sAuth::Session(user.account.clone())));
     }
     return Ok(None);
 }
 
-async fn handle_req(state: &Arc<State>, mut req: Request<Incoming>) -> Response<BoxBody<Bytes, std::io::Error>> {
+/// Live notifications at `c/1/ws`, see `websocket`.
+async fn handle_ws(state: &Arc<State>, mut req: Request<Incoming>) -> Result<Response<Body>, VisErr<loga::Error>> {
+    if req.uri().path().trim_matches('/') != "c/1/ws" {
+        return Ok(response_404());
+    }
+    let Some(auth) = identify_c2s(state, req.headers()).await? else {
+        return Ok(response_401());
+    };
+    let (resp, websocket) =
+        hyper_tungstenite::upgrade(&mut req, None).map_err(|e| loga::err(e.to_string())).err_external()?;
+    let activity = state.federation_state.channel_activity.subscribe();
+    let account = auth.account().clone();
+    let log = state.log.clone();
+    spawn(async move {
+        let allows = |channel: &QualifiedChannelId| match &auth {
+            C2sAuth::Session(_) => true,
+            C2sAuth::ApiToken(token) => token.allows_read(channel),
+        };
+        if let Err(e) = websocket::serve(activity, account, allows, websocket).await {
+            log.log_err(loga::DEBUG, e.context("Error serving websocket"));
+        }
+    });
+    return Ok(resp.map(|_| body_empty()));
+}
+
+/// Queues the follow-on work for a change that was stored. Errors are only logged,
+/// the change itself already went through.
+async fn message_changed(state: &State, stored: message::Stored) {
+    let new_message = match stored.body.rel {
+        MessageRel::None | MessageRel::ReplyTo(_) => true,
+        MessageRel::EditOf(_) | MessageRel::DeleteOf(_) => false,
+    };
+    if let Err(e) =
+        federation::notify_local(
+            &state.db,
+            &state.federation_state,
+            stored.channel.clone(),
+            stored.activity,
+            if new_message {
+                Some(stored.body.body.clone())
+            } else {
+                None
+            },
+        ).await {
+        state.log.log_err(loga::WARN, e.context("Error notifying local channel members"));
+    }
+    if let Err(e) =
+        federation::enqueue_notify(
+            &state.db,
+            &state.federation_state,
+            stored.channel.clone(),
+            stored.activity,
+            new_message,
+        ).await {
+        state.log.log_err(loga::WARN, e.context("Error queueing federated notifications"));
+    }
+    let id = stored.body.id;
+    let event = match stored.body.rel {
+        MessageRel::None | MessageRel::ReplyTo(_) => OutgoingWebhookEvent::MessageNew {
+            sender: id.identity.clone(),
+            id: id,
+            body: stored.body.body,
+        },
+        MessageRel::EditOf(_) => OutgoingWebhookEvent::MessageEdited {
+            sender: id.identity.clone(),
+            id: id,
+            body: stored.body.body,
+        },
+        MessageRel::DeleteOf(_) => OutgoingWebhookEvent::MessageDeleted { id: id },
+    };
+    if let Err(e) =
+        outgoingwebhook::enqueue_channel_event(&state.db, &state.outgoing_webhook_state, stored.channel, event).await {
+        state.log.log_err(loga::WARN, e.context("Error queueing outgoing webhook deliveries"));
+    }
+}
+
+/// The single entry point for new messages, shared by browser clients, API tokens
+/// and incoming webhooks so that validation and delivery stay in one place.
+async fn message_push(
+    state: &State,
+    account: &AccountExternalId,
+    req: MessagePush,
+) -> Result<(), VisErr<loga::Error>> {
+    match message::push(&state.db, state.identity_secret_key.as_ref(), account, req).await.err_internal()? {
+        Ok(Some(stored)) => {
+            message_changed(state, stored).await;
+        },
+        Ok(None) => { },
+        Err(reason) => {
+            return Err(loga::err(reason)).err_external();
+        },
+    }
+    return Ok(());
+}
+
+/// HTTP/1.1, or HTTP/2 with prior knowledge (h2c) or via ALPN. Health endpoints
+/// are answered even before startup finishes (`state` is set); everything else
+/// gets 503 until then. When draining starts the connection finishes in-flight
+/// requests and then closes.
+async fn serve_conn<
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+>(state: Arc<OnceLock<Arc<State>>>, health: Arc<HealthState>, peer: IpAddr, io: I) -> Result<(), loga::Error> {
+    let mut draining = health.draining();
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(io, service_fn(cap_fn!((req)(state, health) {
+        match req.uri().path() {
+            "/healthz" => {
+                return Ok(health::response_healthz()) as Result<_, std::io::Error>;
+            },
+            "/readyz" => {
+                return Ok(health::response_readyz(&health));
+            },
+            _ => { },
+        }
+        let Some(state) = state.get() else {
+            return Ok(response_503());
+        };
+        return Ok(handle_req(state, peer, req).await);
+    })));
+    tokio::pin!(conn);
+    select!{
+        r = conn.as_mut() => {
+            r.map_err(|e| loga::err(e.to_string()))?;
+            return Ok(());
+        },
+        _ = draining.wait_for(|d| *d) => { },
+    }
+    conn.as_mut().graceful_shutdown();
+    conn.await.map_err(|e| loga::err(e.to_string()))?;
+    return Ok(());
+}
+
+async fn handle_req(
+    state: &Arc<State>,
+    peer: IpAddr,
+    req: Request<Incoming>,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let route = metrics::route_label(req.uri().path());
+    let start = Instant::now();
+    let resp = handle_req_inner(state, peer, req).await;
+    metrics::observe_http(route, resp.status(), start.elapsed());
+    return resp;
+}
+
+async fn handle_req_inner(
+    state: &Arc<State>,
+    peer: IpAddr,
+    mut req: Request<Incoming>,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
     let url = req.uri().clone();
+    let encoding = compress::negotiate(req.headers());
     match {
         let state = state.clone();
         async move {
             if (|| false)() {
                 return Err(loga::err("")).err_internal() as Result<_, VisErr<loga::Error>>;
             }
+            if hyper_tungstenite::is_upgrade_request(&req) {
+                return handle_ws(&state, req).await;
+            }
             let (head, body) = req.into_parts();
             let mut path_iter = head.uri.path().trim_matches('/').split('/');
             match path_iter.next().unwrap() {
-                "s1" => match path_iter.next().unwrap() {
+                "s" => match path_iter.next().unwrap_or("") {
+                    "1" => {
+                        let client_ip = state.rate_limit_state.client_ip(peer, &head.headers);
+                        if let Err(wait) =
+                            state.rate_limit_state.check(ratelimit::Class::Api, vec![ratelimit::Key::Ip(client_ip)]).await {
+                            return Ok(ratelimit::response_429(wait));
+                        }
+                        if head.method == Method::GET {
+                            let Some(requester) =
+                                s2sauth::authenticate(&state.s2s_auth_state, &head.headers).await else {
+                                    return Ok(response_401());
+                                };
+
+                            // Tokens outlive denylist changes, so check each request
+                            if let Some(resp) = check_federation(&state, &requester).await.err_internal()? {
+                                return Ok(resp);
+                            }
+                            let rel_path =
+                                format!(
+                                    "/{}",
+                                    head.uri.path().trim_start_matches('/').strip_prefix(S2SV1_PREFIX).unwrap_or_default()
+                                );
+                            let req = S2sGet::deserialize_path(&rel_path).map_err(loga::err).err_external()?;
+                            if !identity::is_local(&state.db, &req.channel().identity).await.err_internal()? {
+                                return Ok(response_404());
+                            }
+                            if !federation::is_remote_member(&state.db, req.channel(), &requester)
+                                .await
+                                .err_internal()? {
+                                return Ok(response_403());
+                            }
+                            match req {
+                                S2sGet::SnapPage(r) => {
+                                    let Some(page) =
+                                        message::snap_page(&state.db, &r.channel, r.page).await.err_internal()? else {
+                                            return Ok(response_404());
+                                        };
+                                    return Ok(response_200_json(page));
+                                },
+                                S2sGet::ActivityPage(r) => {
+                                    let Some(page) =
+                                        message::activity_page(&state.db, &r.channel, r.page)
+                                            .await
+                                            .err_internal()? else {
+                                            return Ok(response_404());
+                                        };
+                                    return Ok(response_200_json(page));
+                                },
+                                S2sGet::LastSnapPage(channel) => {
+                                    return Ok(response_200_json(s2sv1t::GetLastPageRes {
+                                        page_size: message::PAGE_SIZE,
+                                        count: message::snap_len(&state.db, &channel).await.err_internal()?,
+                                    }));
+                                },
+                                S2sGet::LastActivityPage(channel) => {
+                                    return Ok(response_200_json(s2sv1t::GetLastPageRes {
+                                        page_size: message::PAGE_SIZE,
+                                        count: message::activity_len(&state.db, &channel).await.err_internal()?,
+                                    }));
+                                },
+                            }
+                        }
+                        if head.method != Method::POST {
+                            return Ok(response_404());
+                        }
+                        let req =
+                            serde_json::from_slice::<s2sv1::Req>(
+                                &body.collect().await.err_external()?.to_bytes(),
+                            ).err_external()?;
+                        let resp;
+                        match req.to_server_req() {
+                            s2sv1::ServerReq::StartIdentify(rr, r2) => {
+                                // Unauthenticated, so only the rules that don't need lookups
+                                if let Err(reason) =
+                                    federationpolicy::check_identity(
+                                        &state.db,
+                                        &state.federation_policy_state,
+                                        &r2.identity,
+                                    ).await.err_internal()? {
+                                    return Ok(federationpolicy::response_403(reason));
+                                }
+                                resp = rr(s2sauth::start_identify(&state.s2s_auth_state, r2).await);
+                            },
+                            s2sv1::ServerReq::Identify(rr, r2) => {
+                                let identity = match s2sauth::identify(&state.s2s_auth_state, r2).await {
+                                    Ok(i) => i,
+                                    Err(e) => {
+                                        return Ok(response_400(e));
+                                    },
+                                };
+                                if let Some(resp) = check_federation(&state, &identity).await.err_internal()? {
+                                    return Ok(resp);
+                                }
+                                resp = rr(s2sauth::issue_token(&state.s2s_auth_state, identity).await);
+                            },
+                            s2sv1::ServerReq::Notify(rr, r2) => {
+                                let Some(requester) =
+                                    s2sauth::authenticate(&state.s2s_auth_state, &head.headers).await else {
+                                        return Ok(response_401());
+                                    };
+
+                                // Only the owner sends notifications
+                                if requester != r2.owner {
+                                    return Ok(response_403());
+                                }
+                                if let Some(resp) = check_federation(&state, &requester).await.err_internal()? {
+                                    return Ok(resp);
+                                }
+                                remotepages::invalidate(&state.remote_pages_state, &QualifiedChannelId {
+                                    identity: r2.owner.clone(),
+                                    channel: r2.channel.clone(),
+                                });
+                                federation::handle_notify(&state.db, &state.federation_state, r2).await.err_internal()?;
+                                resp = rr(());
+                            },
+                            s2sv1::ServerReq::Join(_, _) => {
+                                let Some(requester) =
+                                    s2sauth::authenticate(&state.s2s_auth_state, &head.headers).await else {
+                                        return Ok(response_401());
+                                    };
+                                if let Some(resp) = check_federation(&state, &requester).await.err_internal()? {
+                                    return Ok(resp);
+                                }
+                                return Err(loga::err("Not supported by this server")).err_external();
+                            },
+                            s2sv1::ServerReq::IsHome(rr, r2) => {
+                                if !federationpolicy::federates(&state.federation_policy_state) {
+                                    return Ok(federationpolicy::response_403(format!("This server doesn't federate")));
+                                }
+                                resp = rr(identity::is_local(&state.db, &r2.identity).await.err_internal()?);
+                            },
+                        }
+                        return Ok(Response::builder().status(200).body(body_full(resp.0)).unwrap());
+                    },
                     _ => {
-                        todo!();
+                        return Ok(response_404());
                     },
                 },
-                "c1" => {
-                    if hyper_tungstenite::is_upgrade_request(&req) {
-                        // Websocket req
-                        let upgrade = hyper_tungstenite::upgrade(&mut req, None);
-                        let (head, _) = req.into_parts();
-                        return Ok(response_503());
-                        //. return Ok(handle_ws(state, head, upgrade, handle_ws_link).await);
-                    } else {
+                "c" => match path_iter.next().unwrap_or("") {
+                    "version" => {
+                        return Ok(response_200_json(C2sVersionRes {
+                            versions: vec![C2S_VERSION],
+                            capabilities: C2S_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+                        }));
+                    },
+                    "1" => {
                         let identity = identify_c2s(&state, &head.headers).await?;
-                        match path_iter.next().unwrap() {
-                            "client" => {
-                                let path = head.uri.path().trim_matches('/');
-
-                                #[derive(RustEmbed)]
-                                #[folder = "$STATIC_DIR"]
-                                struct Static;
-
-                                let mut f = Static::get(path);
-                                if f.is_none() {
-                                    f = Static::get("index.html");
+                        let client_ip = state.rate_limit_state.client_ip(peer, &head.headers);
+                        let seg = path_iter.next().unwrap_or("");
+                        if head.method == Method::GET && !["oidc", "logout"].contains(&seg) {
+                            let mut keys = vec![ratelimit::Key::Ip(client_ip)];
+                            if let Some(identity) = &identity {
+                                keys.push(ratelimit::Key::account(identity.account()));
+                            }
+                            if let Err(wait) = state.rate_limit_state.check(ratelimit::Class::Get, keys).await {
+                                return Ok(ratelimit::response_429(wait));
+                            }
+                        }
+                        match seg {
+                            "oidc" => {
+                                // Each new flow takes a slot in the small pre-session cache
+                                if let Err(wait) =
+                                    state
+                                        .rate_limit_state
+                                        .check(ratelimit::Class::Login, vec![ratelimit::Key::Ip(client_ip)])
+                                        .await {
+                                    return Ok(ratelimit::response_429(wait));
                                 }
-                                match f {
-                                    Some(f) => {
-                                        let etag = format!("\"{}\"", hex::encode(f.metadata.sha256_hash()));
-                                        if let Some(h) = head.headers.get(IF_NONE_MATCH) {
-                                            if h == etag.as_bytes() {
-                                                return Ok(
-                                                    Response::builder().status(304).body(body_full(vec![])).unwrap(),
-                                                );
-                                            }
+                                return Ok(oidc::handle_oidc(&state.oidc_state, head).await?);
+                            },
+                            "logout" => {
+                                return Ok(oidc::handle_logout_redirect(&state.oidc_state, head).await?);
+                            },
+                            "logout_backchannel" => {
+                                if head.method != Method::POST {
+                                    return Ok(
+                                        Response::builder()
+                                            .status(405)
+                                            .header(ALLOW, "POST")
+                                            .body(body_empty())
+                                            .unwrap(),
+                                    );
+                                }
+                                let Some(provider) = path_iter.next() else {
+                                    return Ok(response_404());
+                                };
+                                let provider = provider.to_string();
+                                return Ok(
+                                    oidc::handle_backchannel_logout(
+                                        &state.oidc_state,
+                                        &provider,
+                                        &body.collect().await.err_external()?.to_bytes(),
+                                    ).await?,
+                                );
+                            },
+                            "webhook" => {
+                                let Some(token) = path_iter.next() else {
+                                    return Ok(response_404());
+                                };
+                                let token = token.to_string();
+                                match webhook::handle_incoming(
+                                    &state.db,
+                                    &state.webhook_state,
+                                    token,
+                                    &head.headers,
+                                    &body.collect().await.err_external()?.to_bytes(),
+                                ).await.err_internal()? {
+                                    webhook::Incoming::Push(account, req) => {
+                                        if let Err(wait) =
+                                            state
+                                                .rate_limit_state
+                                                .check(
+                                                    ratelimit::Class::MessagePush,
+                                                    vec![ratelimit::Key::account(&account), ratelimit::Key::Ip(client_ip)],
+                                                )
+                                                .await {
+                                            return Ok(ratelimit::response_429(wait));
                                         }
-                                        let mut resp = Response::builder().status(200);
-                                        resp = resp.header("Content-type", f.metadata.mimetype());
-                                        resp = resp.header(ETAG, etag);
-                                        return Ok(resp.body(body_full(f.data.to_vec())).unwrap());
+                                        message_push(&state, &account, req).await?;
+                                        return Ok(Response::builder().status(200).body(body_empty()).unwrap());
+                                    },
+                                    webhook::Incoming::Reject(resp) => {
+                                        return Ok(resp);
                                     },
-                                    None => {
+                                }
+                            },
+                            "account_export" => {
+                                // Browser sessions only, like the rest of account management
+                                let Some(C2sAuth::Session(account)) = &identity else {
+                                    return Ok(response_401());
+                                };
+                                let rel_path =
+                                    format!(
+                                        "/{}",
+                                        head.uri.path().trim_start_matches('/').strip_prefix(C2SV1_PREFIX).unwrap_or_default()
+                                    );
+                                let req = c2s::AccountExportDownload::deserialize_path(&rel_path).map_err(loga::err).err_external()?;
+                                let Some(archive) =
+                                    accountexport::read(&state.account_export_state, account, &req.id)
+                                        .await
+                                        .err_internal()? else {
+                                        return Ok(response_404());
+                                    };
+                                return Ok(
+                                    Response::builder()
+                                        .status(200)
+                                        .header(CONTENT_TYPE, "application/gzip")
+                                        .header(
+                                            CONTENT_DISPOSITION,
+                                            format!("attachment; filename=\"kwa-export-{}.tar.gz\"", req.id.0),
+                                        )
+                                        .body(body_full(archive))
+                                        .unwrap(),
+                                );
+                            },
+                            "notification_server_key" => {
+                                return Ok(response_200_json(webpush::public_key(&state.webpush_state)));
+                            },
+                            "snap_page" | "activity_page" => {
+                                let Some(auth) = &identity else {
+                                    return Ok(response_401());
+                                };
+                                let rel_path =
+                                    format!(
+                                        "/{}",
+                                        head.uri.path().trim_start_matches('/').strip_prefix(C2SV1_PREFIX).unwrap_or_default()
+                                    );
+                                let req = S2sGet::deserialize_path(&rel_path).map_err(loga::err).err_external()?;
+                                if let C2sAuth::ApiToken(token) = auth {
+                                    if !token.allows_read(req.channel()) {
+                                        return Ok(response_403());
+                                    }
+                                }
+                                if identity::is_local(&state.db, &req.channel().identity).await.err_internal()? {
+                                    if message::member_identity(&state.db, auth.account(), req.channel())
+                                        .await
+                                        .err_internal()?
+                                        .is_none() {
+                                        return Ok(response_403());
+                                    }
+                                    match req {
+                                        S2sGet::SnapPage(r) => {
+                                            return Ok(
+                                                response_200_json(
+                                                    message::snap_page(&state.db, &r.channel, r.page)
+                                                        .await
+                                                        .err_internal()?,
+                                                ),
+                                            );
+                                        },
+                                        S2sGet::ActivityPage(r) => {
+                                            return Ok(
+                                                response_200_json(
+                                                    message::activity_page(&state.db, &r.channel, r.page)
+                                                        .await
+                                                        .err_internal()?,
+                                                ),
+                                            );
+                                        },
+                                        S2sGet::LastSnapPage(_) | S2sGet::LastActivityPage(_) => {
+                                            return Ok(response_404());
+                                        },
+                                    }
+                                }
+                                let Some(page) =
+                                    remotepages::get(
+                                        &state.log,
+                                        &state.db,
+                                        state.identity_secret_key.as_ref(),
+                                        &state.s2s_auth_state,
+                                        &state.federation_policy_state,
+                                        &state.resolver_state,
+                                        &state.remote_pages_state,
+                                        auth.account(),
+                                        req,
+                                    )
+                                        .await
+                                        .err_internal()? else {
                                         return Ok(response_404());
+                                    };
+                                return Ok(
+                                    Response::builder()
+                                        .status(200)
+                                        .header(CONTENT_TYPE, "application/json")
+                                        .header(
+                                            CACHE_CONTROL,
+                                            remotepages::cache_control(&state.remote_pages_state, &page),
+                                        )
+                                        .body(body_full(page.body))
+                                        .unwrap(),
+                                );
+                            },
+                            "snap_by_id" | "snap_by_client_id" | "snap_page_containing_time" => {
+                                let Some(auth) = &identity else {
+                                    return Ok(response_401());
+                                };
+                                let rel_path =
+                                    format!(
+                                        "/{}",
+                                        head.uri.path().trim_start_matches('/').strip_prefix(C2SV1_PREFIX).unwrap_or_default()
+                                    );
+                                let mut by_id = None;
+                                let mut by_client_id = None;
+                                let mut by_time = None;
+                                let channel = match seg {
+                                    "snap_by_id" => {
+                                        let req =
+                                            c2s::SnapById::deserialize_path(&rel_path).map_err(loga::err).err_external()?;
+                                        let channel = req.id.channel.clone();
+                                        by_id = Some(req);
+                                        channel
+                                    },
+                                    "snap_by_client_id" => {
+                                        let req =
+                                            c2s::SnapByClientId::deserialize_path(
+                                                &rel_path,
+                                            ).map_err(loga::err).err_external()?;
+                                        let channel = req.channel.clone();
+                                        by_client_id = Some(req);
+                                        channel
                                     },
+                                    _ => {
+                                        let req =
+                                            c2s::SnapPageContainingTime::deserialize_path(
+                                                &rel_path,
+                                            ).map_err(loga::err).err_external()?;
+                                        let channel = req.channel.clone();
+                                        by_time = Some(req);
+                                        channel
+                                    },
+                                };
+                                if let C2sAuth::ApiToken(token) = auth {
+                                    if !token.allows_read(&channel) {
+                                        return Ok(response_403());
+                                    }
+                                }
+                                let Some(own_identity) =
+                                    message::member_identity(&state.db, auth.account(), &channel)
+                                        .await
+                                        .err_internal()? else {
+                                        return Ok(response_403());
+                                    };
+                                if !identity::is_local(&state.db, &channel.identity).await.err_internal()? {
+                                    // Only pages are fetched from other servers
+                                    return Ok(response_200_json(None as Option<()>));
                                 }
+                                if let Some(req) = by_id {
+                                    return Ok(
+                                        response_200_json(message::snap_by_id(&state.db, &req.id).await.err_internal()?),
+                                    );
+                                }
+                                if let Some(req) = by_client_id {
+                                    return Ok(
+                                        response_200_json(
+                                            message::snap_by_client_id(
+                                                &state.db,
+                                                &req.channel,
+                                                &own_identity,
+                                                &req.client_id,
+                                            )
+                                                .await
+                                                .err_internal()?,
+                                        ),
+                                    );
+                                }
+                                let req = by_time.unwrap();
+                                return Ok(
+                                    response_200_json(
+                                        message::snap_page_containing_time(&state.db, &req.channel, req.time)
+                                            .await
+                                            .err_internal()?,
+                                    ),
+                                );
                             },
-                            "login" => {
-                                return Ok(oidc::handle_oidc(&state.oidc_state, head).await?);
+                            "activity_latest_all" => {
+                                let Some(auth) = &identity else {
+                                    return Ok(response_401());
+                                };
+                                let mut latest =
+                                    message::activity_latest_all(&state.db, auth.account()).await.err_internal()?;
+                                if let C2sAuth::ApiToken(token) = auth {
+                                    latest.retain(|channel, _| token.allows_read(channel));
+                                }
+                                return Ok(response_200_json(latest));
                             },
-                            "post" => {
-                                let Some(acc) = identify_c2s(&state, &head.headers).await? else {
+                            "api" => {
+                                let Some(auth) = identify_c2s(&state, &head.headers).await? else {
                                     return Ok(response_401());
                                 };
+                                let acc = auth.account().clone();
+                                let session_cookie = get_req_session(&state.log, &head.headers);
+                                let req =
+                                    serde_json::from_slice::<serde_json::Value>(
+                                        &body.collect().await.err_external()?.to_bytes(),
+                                    ).err_external()?;
+                                let variant = metrics::c2s_variant(&req);
+                                let req =
+                                    serde_json::from_value::<c2s::proto::Req>(req)
+                                        .err_external()?
+                                        .to_server_req();
+
+                                // Started after parsing so only real request types become labels
+                                let mut c2s_timer = metrics::C2sTimer::start(variant);
+                                if let C2sAuth::ApiToken(token) = &auth {
+                                    // Tokens can only do the things in their scope; account
+                                    // management stays browser-only.
+                                    let allowed = match &req {
+                                        c2s::proto::ServerReq::MessagePush(_, r2) => {
+                                            r2.identity == token.identity && token.allows_post(&r2.channel)
+                                        },
+                                        c2s::proto::ServerReq::MessageEdit(_, r2) => {
+                                            r2.id.identity == token.identity && token.allows_post(&r2.channel)
+                                        },
+                                        c2s::proto::ServerReq::MessageDelete(_, r2) => {
+                                            r2.id.identity == token.identity && token.allows_post(&r2.channel)
+                                        },
+                                        c2s::proto::ServerReq::ChannelMemberList(_, r2) => {
+                                            token.allows_read(&r2.channel)
+                                        },
+                                        c2s::proto::ServerReq::ApiTokenSelf(_, _) => true,
+                                        _ => false,
+                                    };
+                                    if !allowed {
+                                        return Ok(response_403());
+                                    }
+                                }
+                                let mut rate_limit_keys = vec![ratelimit::Key::account(&acc)];
+                                match &auth {
+                                    C2sAuth::ApiToken(token) => {
+                                        rate_limit_keys.push(ratelimit::Key::ApiToken(token.id));
+                                    },
+                                    C2sAuth::Session(_) => {
+                                        if let Some(session) = &session_cookie {
+                                            rate_limit_keys.push(ratelimit::Key::Session(session.clone()));
+                                        }
+                                    },
+                                }
+                                rate_limit_keys.push(ratelimit::Key::Ip(client_ip));
+                                if let Err(wait) =
+                                    state.rate_limit_state.check(match &req {
+                                        c2s::proto::ServerReq::MessagePush(_, _) => ratelimit::Class::MessagePush,
+                                        _ => ratelimit::Class::Api,
+                                    }, rate_limit_keys).await {
+                                    return Ok(ratelimit::response_429(wait));
+                                }
                                 let resp;
-                                match serde_json::from_slice::<c2s::proto::Req>(
-                                    &body.collect().await.err_external()?.to_bytes(),
-                                )
-                                    .err_external()?
-                                    .to_server_req() {
+                                match req {
                                     c2s::proto::ServerReq::Logout(rr, r2) => {
                                         oidc::handle_logout(&state.oidc_state, &state.log, head).await;
                                         resp = rr(());
                                     },
-                                    //.                                    c2s::proto::ServerReq::IdentityCreate(rr, r2) => {
-                                    //.                                        resp = rr(());
-                                    //.                                    },
-                                    //.                                    c2s::proto::ServerReq::IdentityModify(rr, r2) => {
-                                    //.                                        resp = rr(());
-                                    //.                                    },
-                                    //.                                    c2s::proto::ServerReq::IdentityDelete(rr, r2) => {
-                                    //.                                        resp = rr(());
-                                    //.                                    },
+                                    c2s::proto::ServerReq::NotificationRegister(rr, r2) => {
+                                        let Some(session_cookie) = &session_cookie else {
+                                            return Ok(response_401());
+                                        };
+                                        let Some(session) = state.oidc_state.sessions.get(session_cookie).await else {
+                                            return Ok(response_401());
+                                        };
+                                        tx(&state.db, {
+                                            let session_id = session.id.clone();
+                                            let acc = acc.clone();
+                                            move |db_tx| {
+                                                use good_ormning::sqlite::good_query;
+                                                good_query!(
+                                                    db,
+                                                    //# genemichaels-external: sql-formatter-sqlite
+                                                    r#"insert into
+                                                         push_subscription
+                                                         (session, account, data, created)
+                                                       values (
+                                                         ${session_id_t = DbSessionId(session_id)},
+                                                         ${account_external_id_t = DbAccountExternalId(acc)},
+                                                         ${push_subscription_t = DbPushSubscription(r2.data)},
+                                                         ${utctime_s_jiff = Timestamp::now()}
+                                                       )
+                                                       "#;
+                                                    &mut db_tx
+                                                ).map_err(|e| loga::err(e.0))?;
+                                                return Ok(());
+                                            }
+                                        }).await.err_internal()?;
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::SessionList(rr, _) => {
+                                        resp =
+                                            rr(
+                                                oidc::list_sessions(&state.oidc_state, &acc, session_cookie.as_ref()).await,
+                                            );
+                                    },
+                                    c2s::proto::ServerReq::AccountExportCreate(rr, r2) => {
+                                        resp =
+                                            rr(
+                                                accountexport::start(
+                                                    &state.log,
+                                                    &state.db,
+                                                    &state.identity_secret_key,
+                                                    &state.account_export_state,
+                                                    &acc,
+                                                    r2,
+                                                )
+                                                    .await
+                                                    .err_external()?,
+                                            );
+                                    },
+                                    c2s::proto::ServerReq::AccountExportList(rr, _) => {
+                                        resp = rr(accountexport::list(&state.account_export_state, &acc));
+                                    },
+                                    c2s::proto::ServerReq::ApiTokenCreate(rr, r2) => {
+                                        let Some(res) = apitoken::create(&state.db, &acc, r2).await.err_internal()? else {
+                                            return Ok(response_403());
+                                        };
+                                        resp = rr(res);
+                                    },
+                                    c2s::proto::ServerReq::ApiTokenList(rr, r2) => {
+                                        resp = rr(apitoken::list(&state.db, &acc, r2.identity).await.err_internal()?);
+                                    },
+                                    c2s::proto::ServerReq::ApiTokenSelf(rr, _) => {
+                                        let C2sAuth::ApiToken(token) = &auth else {
+                                            return Ok(response_400("Request wasn't authenticated with an API token"));
+                                        };
+                                        let Some(res) =
+                                            apitoken::list(&state.db, &acc, token.identity.clone())
+                                                .await
+                                                .err_internal()?
+                                                .into_iter()
+                                                .find(|x| x.id == token.id) else {
+                                                return Ok(response_401());
+                                            };
+                                        resp = rr(res);
+                                    },
+                                    c2s::proto::ServerReq::ApiTokenDelete(rr, r2) => {
+                                        apitoken::delete(&state.db, &acc, r2.id).await.err_internal()?;
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::SessionRevoke(rr, r2) => {
+                                        oidc::revoke_sessions(
+                                            &state.oidc_state,
+                                            &acc,
+                                            session_cookie.as_ref(),
+                                            &r2.target,
+                                        ).await;
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::IdentityCreate(rr, r2) => {
+                                        resp =
+                                            rr(
+                                                identity::create(
+                                                    &state.log,
+                                                    &state.db,
+                                                    state.identity_secret_key.as_ref(),
+                                                    &state.spagh,
+                                                    &acc,
+                                                    r2,
+                                                )
+                                                    .await
+                                                    .err_internal()?,
+                                            );
+                                    },
+                                    c2s::proto::ServerReq::IdentityModify(rr, r2) => {
+                                        let Some(res) =
+                                            identity::modify(
+                                                &state.log,
+                                                &state.db,
+                                                state.identity_secret_key.as_ref(),
+                                                &state.spagh,
+                                                &acc,
+                                                r2,
+                                            )
+                                                .await
+                                                .err_internal()? else {
+                                                return Ok(response_404());
+                                            };
+                                        resp = rr(res);
+                                    },
+                                    c2s::proto::ServerReq::IdentityDelete(rr, r2) => {
+                                        identity::delete(&state.log, &state.db, &state.spagh, &acc, r2.id)
+                                            .await
+                                            .err_internal()?;
+                                        resp = rr(());
+                                    },
                                     //.                                    c2s::proto::ServerReq::IdentityGet(rr, r2) => {
                                     //.                                        resp = rr(());
                                     //.                                    },
-                                    //.                                    c2s::proto::ServerReq::IdentityList(rr, r2) => {
-                                    //.                                        resp = rr(());
-                                    //.                                    },
+                                    c2s::proto::ServerReq::IdentityList(rr, _) => {
+                                        resp = rr(identity::list(&state.db, &acc).await.err_internal()?);
+                                    },
+                                    c2s::proto::ServerReq::IdentityExport(rr, r2) => {
+                                        let Some(res) =
+                                            identitybundle::export(
+                                                &state.log,
+                                                &state.db,
+                                                state.identity_secret_key.as_ref(),
+                                                &state.spagh,
+                                                &acc,
+                                                r2.id,
+                                                r2.passphrase,
+                                                r2.withdraw,
+                                            )
+                                                .await
+                                                .err_internal()? else {
+                                                return Ok(response_404());
+                                            };
+                                        resp = rr(res);
+                                    },
+                                    c2s::proto::ServerReq::IdentityImport(rr, r2) => {
+                                        match identitybundle::import(
+                                            &state.log,
+                                            &state.db,
+                                            state.identity_secret_key.as_ref(),
+                                            &state.spagh,
+                                            &acc,
+                                            r2,
+                                        )
+                                            .await
+                                            .err_internal()? {
+                                            Ok(res) => {
+                                                resp = rr(res);
+                                            },
+                                            Err(e) => {
+                                                return Ok(response_400(e));
+                                            },
+                                        }
+                                    },
                                     //.                                    c2s::proto::ServerReq::ChannelCreate(rr, r2) => {
                                     //.                                        resp = rr(());
                                     //.                                    },
@@ -295,6 +1371,118 @@ async fn handle_req(state: &Arc<State>, mut req: Request<Incoming>) -> Response<
                                     //.                                    c2s::proto::ServerReq::ChannelGroupGet(rr, r2) => {
                                     //.                                        resp = rr(());
                                     //.                                    },
+                                    c2s::proto::ServerReq::ChannelWebhookCreate(rr, r2) => {
+                                        let Some(res) = webhook::create(&state.db, &acc, r2).await.err_internal()? else {
+                                            return Ok(response_403());
+                                        };
+                                        resp = rr(res);
+                                    },
+                                    c2s::proto::ServerReq::ChannelWebhookModify(rr, r2) => {
+                                        let Some(res) = webhook::modify(&state.db, &acc, r2).await.err_internal()? else {
+                                            return Ok(response_404());
+                                        };
+                                        resp = rr(res);
+                                    },
+                                    c2s::proto::ServerReq::ChannelWebhookDelete(rr, r2) => {
+                                        webhook::delete(&state.db, &acc, r2.id).await.err_internal()?;
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::ChannelWebhookList(rr, _) => {
+                                        resp = rr(webhook::list(&state.db, &acc).await.err_internal()?);
+                                    },
+                                    c2s::proto::ServerReq::OutgoingWebhookCreate(rr, r2) => {
+                                        outgoingwebhook::validate_url(&r2.url).err_external()?;
+                                        let Some(res) =
+                                            outgoingwebhook::create(&state.db, &acc, r2).await.err_internal()? else {
+                                                return Ok(response_403());
+                                            };
+                                        resp = rr(res);
+                                    },
+                                    c2s::proto::ServerReq::OutgoingWebhookDelete(rr, r2) => {
+                                        outgoingwebhook::delete(&state.db, &acc, r2.id).await.err_internal()?;
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::OutgoingWebhookList(rr, r2) => {
+                                        resp = rr(outgoingwebhook::list(&state.db, &acc, r2.channel).await.err_internal()?);
+                                    },
+                                    c2s::proto::ServerReq::OutgoingWebhookTest(rr, r2) => {
+                                        let Some(()) =
+                                            outgoingwebhook::enqueue_test(
+                                                &state.db,
+                                                &state.outgoing_webhook_state,
+                                                &acc,
+                                                r2.id,
+                                            ).await.err_internal()? else {
+                                                return Ok(response_404());
+                                            };
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::OutgoingWebhookDeliveryList(rr, r2) => {
+                                        let Some(res) =
+                                            outgoingwebhook::list_deliveries(&state.db, &acc, r2.id)
+                                                .await
+                                                .err_internal()? else {
+                                                return Ok(response_404());
+                                            };
+                                        resp = rr(res);
+                                    },
+                                    c2s::proto::ServerReq::MessagePush(rr, r2) => {
+                                        message_push(&state, &acc, r2).await?;
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::MessageEdit(rr, r2) => {
+                                        match message::edit(&state.db, state.identity_secret_key.as_ref(), &acc, r2)
+                                            .await
+                                            .err_internal()? {
+                                            Ok(stored) => {
+                                                message_changed(&state, stored).await;
+                                            },
+                                            Err(reason) => {
+                                                return Err(loga::err(reason)).err_external();
+                                            },
+                                        }
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::MessageDelete(rr, r2) => {
+                                        match message::delete(&state.db, state.identity_secret_key.as_ref(), &acc, r2)
+                                            .await
+                                            .err_internal()? {
+                                            Ok(stored) => {
+                                                message_changed(&state, stored).await;
+                                            },
+                                            Err(reason) => {
+                                                return Err(loga::err(reason)).err_external();
+                                            },
+                                        }
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::ChannelMemberAdd(rr, r2) => {
+                                        if let Err(reason) =
+                                            federation::member_add(&state.db, &acc, &r2.channel, &r2.member)
+                                                .await
+                                                .err_internal()? {
+                                            return Err(loga::err(reason)).err_external();
+                                        }
+                                        resp = rr(());
+                                    },
+                                    c2s::proto::ServerReq::ChannelMemberList(rr, r2) => {
+                                        let Some(members) =
+                                            federation::member_list(&state.db, &acc, &r2.channel)
+                                                .await
+                                                .err_internal()? else {
+                                                return Ok(response_404());
+                                            };
+                                        resp = rr(members);
+                                    },
+                                    c2s::proto::ServerReq::ChannelMemberDelete(rr, r2) => {
+                                        if federation::member_delete(&state.db, &acc, &r2.channel, &r2.member)
+                                            .await
+                                            .err_internal()?
+                                            .is_none() {
+                                            return Ok(response_404());
+                                        }
+                                        resp = rr(());
+                                    },
                                     c2s::proto::ServerReq::ChannelOrChannelGroupTree(rr, channel_or_channel_group_tree) => {
                                         let (channels, channelgroups) = tx(&state.db, |db_tx| {
                                             use good_ormning::sqlite::{
@@ -418,16 +1606,87 @@ async fn handle_req(state: &Arc<State>, mut req: Request<Incoming>) -> Response<
                                 //.                                        resp = rr(());
                                 //.                                    },
                                 }
+                                c2s_timer.ok();
                                 return Ok(Response::builder().status(200).body(body_full(resp.0)).unwrap());
                             },
+                            _ => {
+                                return Ok(response_404());
+                            },
                         }
+                    },
+                    _ => {
+                        return Ok(response_404());
+                    },
+                },
+                _ => {
+                    // The web client, at the root so relative urls in it resolve against the
+                    // base url
+                    let path = head.uri.path().trim_matches('/');
+
+                    #[derive(RustEmbed)]
+                    #[folder = "$STATIC_DIR"]
+                    struct Static;
+
+                    // Brotli and gzip copies of the above, made by build.rs. Files that don't
+                    // shrink have no copy.
+                    #[derive(RustEmbed)]
+                    #[folder = "$OUT_DIR/static_precompressed"]
+                    struct StaticPrecompressed;
+
+                    let mut f_path = path;
+                    let mut f = Static::get(f_path);
+                    if f.is_none() {
+                        f_path = "index.html";
+                        f = Static::get(f_path);
+                    }
+                    match f {
+                        Some(f) => {
+                            let mut data = f.data;
+                            let mut etag = format!("\"{}\"", hex::encode(f.metadata.sha256_hash()));
+                            let mut resp = Response::builder().status(200);
+                            resp = resp.header("Content-type", f.metadata.mimetype());
+                            resp = resp.header(VARY, "Accept-Encoding");
+                            let mut precompressed = false;
+                            if let Some(encoding) = encoding {
+                                if let Some(c) =
+                                    StaticPrecompressed::get(&format!("{}.{}", f_path, encoding.extension())) {
+                                    data = c.data;
+                                    etag = compress::encoded_etag(&etag, encoding).unwrap();
+                                    resp = resp.header(CONTENT_ENCODING, encoding.header_value());
+                                    precompressed = true;
+                                }
+                            }
+                            if let Some(h) = head.headers.get(IF_NONE_MATCH) {
+                                // Files that weren't precompressed may be compressed on the way out, with a
+                                // different ETag
+                                let compressed_etag = match (precompressed, encoding) {
+                                    (false, Some(encoding)) => compress::encoded_etag(&etag, encoding),
+                                    _ => None,
+                                };
+                                if h == etag.as_bytes() || compressed_etag.is_some_and(|e| h == e.as_bytes()) {
+                                    return Ok(
+                                        Response::builder()
+                                            .status(304)
+                                            .header(ETAG, h.clone())
+                                            .header(VARY, "Accept-Encoding")
+                                            .body(body_full(vec![]))
+                                            .unwrap(),
+                                    );
+                                }
+                            }
+                            resp = resp.header(ETAG, etag);
+                            return Ok(resp.body(body_full(data.to_vec())).unwrap());
+                        },
+                        None => {
+                            return Ok(response_404());
+                        },
                     }
                 },
             }
         }
     }.await {
         Ok(r) => {
-            return r;
+            return compress::compress_response(encoding, r).await;
         },
         Err(e) => {
             match e {
@@ -455,75 +1714,310 @@ async fn handle_req(state: &Arc<State>, mut req: Request<Incoming>) -> Response<
     }
 }
 
+fn parse_config(config: serde_json::Value) -> Result<Config, loga::Error> {
+    match serde_path_to_error::deserialize::<_, Config>(config) {
+        Ok(c) => return Ok(c),
+        Err(e) => {
+            return Err(
+                loga::err_with(
+                    "Invalid config, see `config.schema.json` from `export-schemas`",
+                    ea!(path = e.path(), err = e.inner()),
+                ),
+            );
+        },
+    }
+}
+
+/// Opens the database, migrating it to the latest version.
+async fn open_db(db_path: &Path, oidc_config: &OidcConfig) -> Result<Pool, loga::Error> {
+    let db =
+        deadpool_sqlite::Config::new(db_path)
+            .builder(deadpool_sqlite::Runtime::Tokio1)
+            .context("Error creating sqlite pool builder")?
+            .build()
+            .context("Error creating sqlite pool")?;
+    let legacy_issuer = oidc_config.providers.first().map(|p| p.provider_url.clone());
+    db.get().await?.interact(move |conn| -> Result<_, loga::Error> {
+        let current = check_db_version(conn)?;
+        db::migrate(&mut *conn, None).map_err(|e| loga::err(e.0))?;
+        if current.is_some_and(|v| v < ACCOUNT_ISSUER_DB_VERSION) {
+            let Some(issuer) = legacy_issuer else {
+                return Err(loga::err("No OIDC provider configured to attribute existing accounts to"));
+            };
+            migrate_account_issuer(&mut *conn, &issuer)?;
+        }
+        return Ok(());
+    }).await?.context_with("Migration failed", ea!(action = "db_init", path = db_path.to_string_lossy()))?;
+    return Ok(db);
+}
+
+/// For admin subcommands, which shouldn't create a database somewhere unexpected.
+async fn open_existing_db(config: &Config) -> Result<Pool, loga::Error> {
+    let db_path = config.persistent_dir.join("db.sqlite3");
+    if !db_path.exists() {
+        return Err(loga::err_with("No database found", ea!(path = db_path.to_string_lossy())));
+    }
+    return Ok(open_db(&db_path, &config.oidc_config).await?);
+}
+
+fn parse_deny_target(target: FederationDenyTargetArg) -> Result<DenyTarget, loga::Error> {
+    match target {
+        FederationDenyTargetArg::Identity(i) => {
+            let identity =
+                Identity::from_str(&i).map_err(|e| loga::err_with("Invalid identity", ea!(identity = i, err = e)))?;
+            return Ok(DenyTarget::Identity(identity));
+        },
+        FederationDenyTargetArg::Server(s) => {
+            return Ok(DenyTarget::Server(s));
+        },
+    }
+}
+
 fn main() {
     let log = Log::new_root(loga::DEBUG);
-    let runtime = runtime::Builder::new_current_thread().build().unwrap();
+    let runtime = runtime::Builder::new_current_thread().enable_all().build().unwrap();
     match runtime.block_on({
         let log = log.clone();
         async move {
             ta_return!((), loga::Error);
             let tm = TaskManager::new();
-            let args = vark::<Args>();
-            let config = args.config.value;
+            let args = match vark::<Args>() {
+                Args::Run(a) => a,
+                Args::ExportSchemas(a) => {
+                    let c2s_res =
+                        [
+                            resp_schemas!(
+                                c2s::proto::ReqTrait,
+                                c2s;
+                                Logout,
+                                NotificationRegister,
+                                SessionList,
+                                SessionRevoke,
+                                ApiTokenCreate,
+                                ApiTokenList,
+                                ApiTokenDelete,
+                                ApiTokenSelf,
+                                IdentityCreate,
+                                IdentityModify,
+                                IdentityDelete,
+                                IdentityList,
+                                IdentityExport,
+                                IdentityImport,
+                                IdentityInviteCreate,
+                                IdentityInviteModify,
+                                IdentityInviteDelete,
+                                IdentityInviteList,
+                                ChannelGroupCreate,
+                                ChannelGroupModify,
+                                ChannelGroupDelete,
+                                ChannelGroupList,
+                                ChannelCreate,
+                                ChannelJoinChannel,
+                                ChannelJoinIdentity,
+                                ChannelModify,
+                                ChannelDelete,
+                                ChannelList,
+                                ChannelMemberAdd,
+                                ChannelMemberList,
+                                ChannelMemberDelete,
+                                ChannelInviteCreate,
+                                ChannelInviteModify,
+                                ChannelInviteDelete,
+                                ChannelInviteList,
+                                ChannelWebhookCreate,
+                                ChannelWebhookModify,
+                                ChannelWebhookDelete,
+                                ChannelWebhookList,
+                                OutgoingWebhookCreate,
+                                OutgoingWebhookDelete,
+                                OutgoingWebhookList,
+                                OutgoingWebhookTest,
+                                OutgoingWebhookDeliveryList,
+                                AccountExportCreate,
+                                AccountExportList,
+                                ContactList,
+                                ContactModify,
+                                MessagePush,
+                                MessageEdit,
+                                MessageDelete,
+                            ),
+                            // GET requests, except portraits and downloads which aren't JSON
+                            resp_schemas!(
+                                c2s::PathReqTrait,
+                                c2s;
+                                NotificationServerKey,
+                                SnapById,
+                                SnapByClientId,
+                                SnapPageContainingTime,
+                                GetSnapPage,
+                                ActivityLatestAll,
+                                GetActivityPage,
+                            ),
+                        ].concat();
+                    let s2sv1_res =
+                        [
+                            resp_schemas!(s2sv1::ReqTrait, s2sv1t; StartIdentify, Identify, Notify, Join, IsHome),
+                            // GET requests other than pages, which are the same as c2s
+                            vec![
+                                (
+                                    "LastSnapPage",
+                                    (|g: &mut SchemaGenerator| g.subschema_for::<s2sv1t::GetLastPageRes>()) as
+                                        fn(&mut SchemaGenerator) -> Schema,
+                                ),
+                                (
+                                    "LastActivityPage",
+                                    (|g: &mut SchemaGenerator| g.subschema_for::<s2sv1t::GetLastPageRes>()) as
+                                        fn(&mut SchemaGenerator) -> Schema,
+                                )
+                            ],
+                        ].concat();
+                    schemas::export(
+                        &a.out_dir,
+                        vec![
+                            //. .
+                            schemas::Export::of_ts::<Config>("config"),
+                            schemas::Export::of::<c2s::proto::Req>("c2s"),
+                            schemas::Export::from_schema("c2s_res", schemas::responses("C2sRes", c2s_res)),
+                            schemas::Export::of::<s2c::Notification>("s2c"),
+                            schemas::Export::of::<s2sv1::Req>("s2sv1"),
+                            schemas::Export::from_schema("s2sv1_res", schemas::responses("S2sv1Res", s2sv1_res)),
+                            schemas::Export::of::<KwaUrl>("kwaurl"),
+                            schemas::Export::of::<PublishedServer>("spagh_server"),
+                            schemas::Export::of::<PublishedProfile>("spagh_profile"),
+                        ],
+                    ).await?;
+                    return Ok(());
+                },
+                Args::RotateIdentitySecretKey(a) => {
+                    let config = parse_config(a.config.value)?;
+                    let db_path = config.persistent_dir.join("db.sqlite3");
+                    if !db_path.exists() {
+                        return Err(loga::err_with("No database found", ea!(path = db_path.to_string_lossy())));
+                    }
+                    let old_key = match &config.identity_secret_key {
+                        Some(source) => Some(identitysecret::load_key(source).await?),
+                        None => None,
+                    };
+                    let new_key =
+                        identitysecret::load_key(&SecretKeySource::File(a.new_key.to_string_lossy().to_string())).await?;
+                    let db = open_db(&db_path, &config.oidc_config).await?;
+                    let count = identitysecret::rotate(&db, old_key, new_key).await?;
+                    eprintln!(
+                        "Re-encrypted {} identity secrets, set `identity_secret_key` to the new key before starting the server",
+                        count
+                    );
+                    return Ok(());
+                },
+                Args::ResolveIdentity(a) => {
+                    let config = parse_config(a.config.value)?;
+                    let identity =
+                        Identity::from_str(
+                            &a.identity,
+                        ).map_err(|e| loga::err_with("Invalid identity", ea!(identity = a.identity, err = e)))?;
+                    let entry = if a.cached.is_some() {
+                        resolver::read_disk_cache(&config.cache_dir, &identity).await
+                    } else {
+                        let bind_addr =
+                            config.spagh_node_bind_sockaddr.resolve().context("Error resolving node bind addr")?;
+                        let node =
+                            spaghettinuum_native::service::node::Node::new(
+                                &log,
+                                &tm,
+                                StrSocketAddr::from(SocketAddr::new(bind_addr.ip(), 0)),
+                                &spaghettinuum_native::service::node::default_bootstrap(),
+                                &config.cache_dir,
+                            ).await?;
+                        let resolver_state = resolver::new_state(node as Arc<dyn ResolveBackend>, &config.cache_dir);
+                        let entry = resolver::resolve_fresh(&resolver_state, &identity).await;
+                        tm.terminate();
+                        tm.join(&log).await?;
+                        Some(entry?)
+                    };
+                    println!("{}", serde_json::to_string_pretty(&entry).unwrap());
+                    return Ok(());
+                },
+                Args::FederationDenyAdd(a) => {
+                    let config = parse_config(a.config.value)?;
+                    let db = open_existing_db(&config).await?;
+                    let target = parse_deny_target(a.target)?;
+                    if federationpolicy::deny_add(&db, target, a.memo.unwrap_or_default()).await? {
+                        eprintln!("Added to the denylist");
+                    } else {
+                        eprintln!("Already on the denylist");
+                    }
+                    return Ok(());
+                },
+                Args::FederationDenyRemove(a) => {
+                    let config = parse_config(a.config.value)?;
+                    let db = open_existing_db(&config).await?;
+                    let target = parse_deny_target(a.target)?;
+                    if federationpolicy::deny_remove(&db, target).await? {
+                        eprintln!("Removed from the denylist");
+                    } else {
+                        eprintln!("Not on the denylist");
+                    }
+                    return Ok(());
+                },
+                Args::FederationDenyList(a) => {
+                    let config = parse_config(a.config.value)?;
+                    let db = open_existing_db(&config).await?;
+                    println!("{}", serde_json::to_string_pretty(&federationpolicy::deny_list(&db).await?).unwrap());
+                    return Ok(());
+                },
+            };
+            let config = parse_config(args.config.value)?;
+            let federation_policy_state = Arc::new(federationpolicy::new_state(config.federation, config.outgoing_webhooks_allow_private)?);
             if args.validate.is_some() {
                 eprintln!("Config OK");
                 return Ok(());
             }
-            create_dirs(&config.persistent_dir).await?;
             let db_path = config.persistent_dir.join("db.sqlite3");
-
-            // Spagh
-            let spagh_node =
-                spaghettinuum_native::service::node::Node::new(
-                    &log,
-                    &tm,
-                    config.spagh_node_bind_sockaddr,
-                    &spaghettinuum_native::service::node::default_bootstrap(),
-                    &config.cache_dir,
-                ).await?;
-            let spagh_publisher_bind_sockaddr = config.spagh_publisher_bind_sockaddr.resolve()?;
-            let spagh_publisher =
-                spaghettinuum_native::service::publisher::Publisher::new(
-                    &log,
-                    &tm,
-                    spagh_node.clone(),
-                    spagh_publisher_bind_sockaddr,
-                    SocketAddr::new(
-                        resolve_global_ip(&log, &config.spagh_publisher_advertise_global_addr).await?.into(),
-                        config.spagh_publisher_advertise_global_port.unwrap_or(spagh_publisher_bind_sockaddr.port()),
-                    ),
-                    &config.persistent_dir,
-                ).await?;
-
-            // Db
-            let db =
-                deadpool_sqlite::Config::new(&db_path)
-                    .builder(deadpool_sqlite::Runtime::Tokio1)
-                    .context("Error creating sqlite pool builder")?
-                    .build()
-                    .context("Error creating sqlite pool")?;
-            db.get().await?.interact(move |conn| -> Result<_, loga::Error> {
-                db::migrate(&mut *conn, None).map_err(|e| loga::err(e.0))?;
+            if args.migrate_dry_run.is_some() {
+                let current = if db_path.exists() {
+                    let conn =
+                        rusqlite::Connection::open_with_flags(&db_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+                            .context_with("Error opening database", ea!(path = db_path.to_string_lossy()))?;
+                    check_db_version(&conn)?
+                } else {
+                    None
+                };
+                let plan = plan_db_migration(current);
+                match current {
+                    Some(v) => eprintln!("Database is at version {}, latest is {}", v, latest_db_version()),
+                    None => eprintln!("Database is uninitialized, latest version is {}", latest_db_version()),
+                }
+                if plan.is_empty() {
+                    eprintln!("No migrations needed");
+                } else {
+                    eprintln!("Migrations to apply:");
+                    for step in plan {
+                        eprintln!(" - {}", step);
+                    }
+                }
                 return Ok(());
-            }).await?.context_with("Migration failed", ea!(action = "db_init", path = db_path.to_string_lossy()))?;
-
-            // State
-            let state = Arc::new(State {
-                log: log.clone(),
-                db: db,
-                oidc_state: oidc::new_state(&log, config.oidc_config).await?,
-            });
+            }
+            create_dirs(&config.persistent_dir).await?;
 
-            // Serve
-            const S2SV1_PREFIX: &str = "/s/1";
-            const C2SV1_PREFIX: &str = "/c/1";
+            // Serve. This starts early so health checks can watch startup; requests other
+            // than health checks get 503 until `state` is set below.
+            let health = Arc::new(HealthState::new());
+            let state = Arc::new(OnceLock::<Arc<State>>::new());
+            let conns = TaskTracker::new();
+            let tls_acceptor = match config.tls {
+                Some(tls_config) => Some(tls::new_acceptor(&log, &tm, tls_config).await?),
+                None => None,
+            };
             tm.critical_stream(
                 "http",
+                // Ends (closing the listener) when draining starts
                 TcpListenerStream::new(
                     TcpListener::bind(config.bind_sockaddr.resolve().context("Error resolving server bind addr")?)
                         .await
                         .context_with("Error binding to address", ea!(addr = config.bind_sockaddr))?,
-                ),
+                )
+                    .map(Some)
+                    .merge(WatchStream::new(health.draining()).filter(|d| *d).map(|_| None))
+                    .map_while(|c| c),
                 {
                     let mut routes = BTreeMap::<String, Box<dyn Handler<Body>>>::new();
 
@@ -547,28 +2041,52 @@ fn main() {
                         return Ok((seg, remainder));
                     }
 
+                    let log = log.clone();
+                    let state = state.clone();
+                    let health = health.clone();
+                    let conns = conns.clone();
                     move |conn| {
+                        let log = log.clone();
                         let state = state.clone();
+                        let health = health.clone();
+                        let conns = conns.clone();
+                        let tls_acceptor = tls_acceptor.clone();
                         async move {
                             let conn = match conn {
                                 Ok(c) => c,
                                 Err(e) => {
-                                    state.log.log_err(loga::DEBUG, e.context("Error receiving request"));
+                                    log.log_err(loga::DEBUG, e.context("Error receiving request"));
                                     return Ok(());
                                 },
                             };
-                            let io = TokioIo::new(conn);
-                            tokio::task::spawn(async move {
+                            if health.is_draining() {
+                                // Shutting down, refuse new connections
+                                return Ok(());
+                            }
+                            let peer = match conn.peer_addr() {
+                                Ok(a) => a.ip(),
+                                Err(e) => {
+                                    log.log_err(loga::DEBUG, e.context("Error getting connection peer address"));
+                                    return Ok(());
+                                },
+                            };
+                            conns.spawn(async move {
                                 match async {
                                     ta_return!((), loga::Error);
-                                    http1::Builder::new().serve_connection(io, service_fn(cap_fn!((req)(state) {
-                                        return Ok(handle_req(&state, req).await) as Result<_, std::io::Error>;
-                                    }))).with_upgrades().await?;
+                                    match tls_acceptor {
+                                        Some(tls_acceptor) => {
+                                            let conn = tls_acceptor.accept(conn).await.context("Error in TLS handshake")?;
+                                            serve_conn(state, health, peer, TokioIo::new(conn)).await?;
+                                        },
+                                        None => {
+                                            serve_conn(state, health, peer, TokioIo::new(conn)).await?;
+                                        },
+                                    }
                                     return Ok(());
                                 }.await {
                                     Ok(_) => (),
                                     Err(e) => {
-                                        state.log.log_err(loga::DEBUG, e.context("Error serving connection"));
+                                        log.log_err(loga::DEBUG, e.context("Error serving connection"));
                                     },
                                 }
                             });
@@ -577,6 +2095,255 @@ fn main() {
                     }
                 },
             );
+
+            // Spagh
+            let spagh_node =
+                spaghettinuum_native::service::node::Node::new(
+                    &log,
+                    &tm,
+                    config.spagh_node_bind_sockaddr,
+                    &spaghettinuum_native::service::node::default_bootstrap(),
+                    &config.cache_dir,
+                ).await?;
+            let spagh_publisher_bind_sockaddr = config.spagh_publisher_bind_sockaddr.resolve()?;
+            let spagh_publisher =
+                spaghettinuum_native::service::publisher::Publisher::new(
+                    &log,
+                    &tm,
+                    spagh_node.clone(),
+                    spagh_publisher_bind_sockaddr,
+                    SocketAddr::new(
+                        resolve_global_ip(&log, &config.spagh_publisher_advertise_global_addr).await?.into(),
+                        config.spagh_publisher_advertise_global_port.unwrap_or(spagh_publisher_bind_sockaddr.port()),
+                    ),
+                    &config.persistent_dir,
+                ).await?;
+
+            // Db
+            let db = open_db(&db_path, &config.oidc_config).await?;
+            let identity_secret_key = match &config.identity_secret_key {
+                Some(source) => Some(identitysecret::load_key(source).await?),
+                None => None,
+            };
+            identitysecret::check_startup(&log, &db, identity_secret_key.as_ref()).await?;
+            health.set_db_migrated();
+
+            // State
+            let oidc_state = oidc::new_state(&log, config.oidc_config).await?;
+            health.set_oidc_discovered();
+            let webpush_state =
+                Arc::new(
+                    webpush::new_state(
+                        &config.persistent_dir,
+                        config.public_url.as_deref(),
+                        config.outgoing_webhooks_allow_private,
+                    ).await?,
+                );
+            let state = {
+                let state_cell = state;
+                let state = Arc::new(State {
+                    log: log.clone(),
+                    db: db,
+                    oidc_state: oidc_state,
+                    webhook_state: webhook::new_state(),
+                    outgoing_webhook_state: outgoingwebhook::new_state(config.outgoing_webhooks_allow_private),
+                    rate_limit_state: ratelimit::new_state(config.rate_limits),
+                    account_export_state: Arc::new(accountexport::new_state(&config.cache_dir)?),
+                    resolver_state: Arc::new(
+                        resolver::new_state(spagh_node.clone() as Arc<dyn ResolveBackend>, &config.cache_dir),
+                    ),
+                    federation_policy_state: federation_policy_state,
+                    s2s_auth_state: Arc::new(
+                        s2sauth::new_state(config.public_url.clone(), config.outgoing_webhooks_allow_private),
+                    ),
+                    remote_pages_state: remotepages::new_state(
+                        &config.cache_dir,
+                        config.public_http_resp_cache_duration,
+                        config.outgoing_webhooks_allow_private,
+                    ),
+                    spagh: Arc::new(SpaghState {
+                        node: spagh_node,
+                        publisher: spagh_publisher,
+                        public_url: config.public_url,
+                    }),
+                    federation_state: federation::new_state(config.outgoing_webhooks_allow_private),
+                    webpush_state: webpush_state,
+                    identity_secret_key: identity_secret_key,
+                });
+                _ = state_cell.set(state.clone());
+                state
+            };
+            outgoingwebhook::spawn_worker(&log, &tm, state.db.clone(), &state.outgoing_webhook_state);
+            federation::spawn_worker(&log, &tm, state.db.clone(), &state.federation_state, WorkerDeps {
+                secret_key: state.identity_secret_key.clone(),
+                auth_state: state.s2s_auth_state.clone(),
+                policy_state: state.federation_policy_state.clone(),
+                resolver_state: state.resolver_state.clone(),
+            });
+            webpush::spawn_worker(
+                &log,
+                &tm,
+                state.db.clone(),
+                state.webpush_state.clone(),
+                state.federation_state.channel_activity.subscribe(),
+            );
+            spagh::spawn_republish(
+                &log,
+                &tm,
+                state.db.clone(),
+                state.identity_secret_key.clone(),
+                state.spagh.clone(),
+                health.clone(),
+            );
+
+            // Remove push subscriptions when their sessions end
+            tm.task("session_cleanup", {
+                let state = state.clone();
+                let tm = tm.clone();
+                let mut session_ended = state.oidc_state.session_ended.subscribe();
+                async move {
+                    loop {
+                        let session_id = select!{
+                            _ = tm.until_terminate() => {
+                                break;
+                            },
+                            r = session_ended.recv() => {
+                                match r {
+                                    Ok(s) => s,
+                                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                                        state
+                                            .log
+                                            .log_with(
+                                                loga::WARN,
+                                                "Missed session end events, some push subscriptions may linger",
+                                                ea!(count = n),
+                                            );
+                                        continue;
+                                    },
+                                    Err(broadcast::error::RecvError::Closed) => {
+                                        break;
+                                    },
+                                }
+                            }
+                        };
+                        match tx(&state.db, {
+                            let session_id = session_id.clone();
+                            move |db_tx| {
+                                use good_ormning::sqlite::good_query;
+                                good_query!(
+                                    db,
+                                    //# genemichaels-external: sql-formatter-sqlite
+                                    r#"delete from
+                                         push_subscription
+                                       where
+                                         session = ${session_id_t = DbSessionId(session_id)}
+                                       "#;
+                                    &mut db_tx
+                                ).map_err(|e| loga::err(e.0))?;
+                                return Ok(());
+                            }
+                        }).await {
+                            Ok(_) => { },
+                            Err(e) => {
+                                state
+                                    .log
+                                    .log_err(
+                                        loga::WARN,
+                                        e.context_with(
+                                            "Error removing push subscriptions for ended session",
+                                            ea!(session = session_id.0),
+                                        ),
+                                    );
+                            },
+                        }
+                    }
+                }
+            });
+
+            // Metrics
+            if let Some(metrics_bind_sockaddr) = &config.metrics_bind_sockaddr {
+                tm.critical_stream(
+                    "metrics",
+                    TcpListenerStream::new(
+                        TcpListener::bind(metrics_bind_sockaddr.resolve().context("Error resolving metrics bind addr")?)
+                            .await
+                            .context_with("Error binding to address", ea!(addr = metrics_bind_sockaddr))?,
+                    ),
+                    {
+                        let log = log.clone();
+                        move |conn| {
+                            let log = log.clone();
+                            async move {
+                                let conn = match conn {
+                                    Ok(c) => c,
+                                    Err(e) => {
+                                        log.log_err(loga::DEBUG, e.context("Error receiving metrics request"));
+                                        return Ok(());
+                                    },
+                                };
+                                tokio::task::spawn(async move {
+                                    match auto::Builder::new(TokioExecutor::new())
+                                        .serve_connection(TokioIo::new(conn), service_fn(|req: Request<Incoming>| async move {
+                                            if req.uri().path() != "/metrics" {
+                                                return Ok(response_404()) as Result<_, std::io::Error>;
+                                            }
+                                            return Ok(metrics::response());
+                                        }))
+                                        .await {
+                                        Ok(_) => (),
+                                        Err(e) => {
+                                            log.log_with(
+                                                loga::DEBUG,
+                                                "Error serving metrics connection",
+                                                ea!(err = e.to_string()),
+                                            );
+                                        },
+                                    }
+                                });
+                                return Ok(());
+                            }
+                        }
+                    },
+                );
+            }
+
+            // Graceful shutdown: stop taking connections, let open ones finish, then stop
+            // everything else
+            tm.task("shutdown", {
+                let log = log.clone();
+                let tm = tm.clone();
+                let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs.unwrap_or(30));
+                async move {
+                    let mut sigterm = match signal(SignalKind::terminate()) {
+                        Ok(s) => Some(s),
+                        Err(e) => {
+                            log.log_err(loga::WARN, e.context("Error listening for SIGTERM"));
+                            None
+                        },
+                    };
+                    select!{
+                        _ = tm.until_terminate() => { },
+                        _ = tokio::signal::ctrl_c() => { },
+                        Some(_) = async {
+                            match &mut sigterm {
+                                Some(s) => s.recv().await,
+                                None => std::future::pending().await,
+                            }
+                        } => { },
+                    }
+                    log.log(loga::INFO, "Shutting down, draining connections");
+                    health.start_drain();
+                    conns.close();
+                    if timeout(shutdown_timeout, conns.wait()).await.is_err() {
+                        log.log_with(
+                            loga::WARN,
+                            "Timed out waiting for connections to drain, closing remaining",
+                            ea!(remaining = conns.len()),
+                        );
+                    }
+                    tm.terminate();
+                }
+            });
             tm.join(&log).await?;
             return Ok(());
         }
@@ -0,0 +1,223 @@
+//! Prometheus metrics. These are global so any subsystem can record without
+//! threading state around, and are served on their own listener
+//! (`metrics_bind_sockaddr`) so they can be kept off the public internet.
+use {
+    htwrap::htserve::responses::{
+        body_full,
+        Body,
+    },
+    http::Response,
+    prometheus::{
+        Encoder,
+        Histogram,
+        HistogramOpts,
+        HistogramVec,
+        IntCounterVec,
+        IntGauge,
+        Opts,
+        Registry,
+        TextEncoder,
+    },
+    shared::interface::wire::c2s::PATH_REQ_PREFIXES,
+    std::{
+        sync::LazyLock,
+        time::{
+            Duration,
+            Instant,
+        },
+    },
+};
+
+pub struct Metrics {
+    registry: Registry,
+    /// Labels: `route`, `status`
+    pub http_requests: IntCounterVec,
+    /// Labels: `route`
+    pub http_request_duration: HistogramVec,
+    /// Labels: `variant`, `result`
+    pub c2s_requests: IntCounterVec,
+    /// Labels: `variant`
+    pub c2s_request_duration: HistogramVec,
+    pub db_pool_wait: Histogram,
+    /// Labels: `result` (`ok`, `rejected`, `failed`)
+    pub oidc_logins: IntCounterVec,
+    /// 1 if the last identity publish succeeded, 0 if it failed.
+    pub spagh_publisher_up: IntGauge,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new_custom(Some("kwa".to_string()), None).unwrap();
+    let http_requests =
+        IntCounterVec::new(Opts::new("http_requests_total", "HTTP requests by route and status"), &["route", "status"])
+            .unwrap();
+    let http_request_duration =
+        HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request handling time by route"),
+            &["route"],
+        ).unwrap();
+    let c2s_requests =
+        IntCounterVec::new(
+            Opts::new("c2s_requests_total", "c2s API requests by request type and result"),
+            &["variant", "result"],
+        ).unwrap();
+    let c2s_request_duration =
+        HistogramVec::new(
+            HistogramOpts::new("c2s_request_duration_seconds", "c2s API request handling time by request type"),
+            &["variant"],
+        ).unwrap();
+    let db_pool_wait =
+        Histogram::with_opts(
+            HistogramOpts::new(
+                "db_pool_wait_seconds",
+                "Time spent waiting for a database connection from the pool",
+            ).buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1., 5.]),
+        ).unwrap();
+    let oidc_logins =
+        IntCounterVec::new(Opts::new("oidc_logins_total", "Completed OIDC logins by result"), &["result"]).unwrap();
+    let spagh_publisher_up =
+        IntGauge::new("spagh_publisher_up", "Whether the last spaghettinuum identity publish succeeded").unwrap();
+    registry.register(Box::new(http_requests.clone())).unwrap();
+    registry.register(Box::new(http_request_duration.clone())).unwrap();
+    registry.register(Box::new(c2s_requests.clone())).unwrap();
+    registry.register(Box::new(c2s_request_duration.clone())).unwrap();
+    registry.register(Box::new(db_pool_wait.clone())).unwrap();
+    registry.register(Box::new(oidc_logins.clone())).unwrap();
+    registry.register(Box::new(spagh_publisher_up.clone())).unwrap();
+    return Metrics {
+        registry: registry,
+        http_requests: http_requests,
+        http_request_duration: http_request_duration,
+        c2s_requests: c2s_requests,
+        c2s_request_duration: c2s_request_duration,
+        db_pool_wait: db_pool_wait,
+        oidc_logins: oidc_logins,
+        spagh_publisher_up: spagh_publisher_up,
+    };
+});
+
+/// A fixed label for a request path, so arbitrary urls don't blow up metric
+/// cardinality.
+pub fn route_label(path: &str) -> &'static str {
+    let mut parts = path.trim_matches('/').split('/');
+    match parts.next().unwrap_or("") {
+        "s" => return "s2s",
+        "c" => match parts.next().unwrap_or("") {
+            "version" => return "version",
+            "1" => {
+                let seg = parts.next().unwrap_or("");
+                for known in ["api", "oidc", "logout", "logout_backchannel", "webhook", "ws"] {
+                    if seg == known {
+                        return known;
+                    }
+                }
+                for known in PATH_REQ_PREFIXES {
+                    if seg == *known {
+                        return *known;
+                    }
+                }
+                return "other";
+            },
+            _ => return "other",
+        },
+        _ => return "static",
+    }
+}
+
+pub fn observe_http(route: &str, status: http::StatusCode, elapsed: Duration) {
+    METRICS.http_requests.with_label_values(&[route, status.as_str()]).inc();
+    METRICS.http_request_duration.with_label_values(&[route]).observe(elapsed.as_secs_f64());
+}
+
+/// The request type of a JSON-encoded `c2s::proto::Req` (the enum tag).
+pub fn c2s_variant(body: &serde_json::Value) -> String {
+    match body {
+        serde_json::Value::Object(o) if o.len() == 1 => return o.keys().next().unwrap().clone(),
+        serde_json::Value::String(s) => return s.clone(),
+        _ => return "unknown".to_string(),
+    }
+}
+
+/// Records a c2s request when dropped, as failed unless `ok` was called first, so
+/// early error returns are counted.
+pub struct C2sTimer {
+    variant: String,
+    start: Instant,
+    ok: bool,
+}
+
+impl C2sTimer {
+    pub fn start(variant: String) -> Self {
+        return Self {
+            variant: variant,
+            start: Instant::now(),
+            ok: false,
+        };
+    }
+
+    pub fn ok(&mut self) {
+        self.ok = true;
+    }
+}
+
+impl Drop for C2sTimer {
+    fn drop(&mut self) {
+        METRICS.c2s_requests.with_label_values(&[self.variant.as_str(), if self.ok {
+            "ok"
+        } else {
+            "failed"
+        }]).inc();
+        METRICS.c2s_request_duration.with_label_values(&[self.variant.as_str()]).observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+pub fn response() -> Response<Body> {
+    let mut out = vec![];
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut out).unwrap();
+    return Response::builder()
+        .status(200)
+        .header(http::header::CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(body_full(out))
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            c2s_variant,
+            route_label,
+        },
+        serde_json::json,
+        shared::interface::wire::c2s::PATH_REQ_PREFIXES,
+    };
+
+    #[test]
+    fn route_known() {
+        assert_eq!(route_label("/c/1/api"), "api");
+        assert_eq!(route_label("/c/1/oidc"), "oidc");
+        assert_eq!(route_label("/c/1/webhook/abc/def"), "webhook");
+        assert_eq!(route_label("/c/version"), "version");
+        assert_eq!(route_label("/s/v1/identify"), "s2s");
+        for prefix in PATH_REQ_PREFIXES {
+            assert_eq!(route_label(&format!("/c/1/{}/x/y", prefix)), *prefix);
+        }
+    }
+
+    #[test]
+    fn route_unknown_bounded() {
+        assert_eq!(route_label("/c/1/whatever/x"), "other");
+        assert_eq!(route_label("/c/2/api"), "other");
+        assert_eq!(route_label("/c"), "other");
+        assert_eq!(route_label("/"), "static");
+        assert_eq!(route_label("/assets/app.js"), "static");
+    }
+
+    #[test]
+    fn variant() {
+        assert_eq!(c2s_variant(&json!({
+            "identity_list": {}
+        })), "identity_list");
+        assert_eq!(c2s_variant(&json!("logout")), "logout");
+        assert_eq!(c2s_variant(&json!([])), "unknown");
+    }
+}
@@ -0,0 +1,121 @@
+//! Passphrase-based encryption for data that leaves the server (exports, identity
+//! bundles). Argon2id derives the key, XChaCha20-Poly1305 encrypts.
+use {
+    argon2::Argon2,
+    base64::{
+        engine::general_purpose::STANDARD,
+        Engine,
+    },
+    chacha20poly1305::{
+        aead::Aead,
+        KeyInit,
+        XChaCha20Poly1305,
+        XNonce,
+    },
+    loga::ea,
+    rand::{
+        rng,
+        Rng,
+    },
+    schemars::JsonSchema,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+};
+
+const KDF_ARGON2ID: &str = "argon2id";
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Sealed {
+    /// Always `argon2id` (default parameters) for now.
+    pub kdf: String,
+    /// Base64
+    pub salt: String,
+    /// Base64, 24 bytes
+    pub nonce: String,
+    /// Base64
+    pub ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], loga::Error> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| loga::err_with("Error deriving key from passphrase", ea!(err = e)))?;
+    return Ok(key);
+}
+
+pub fn seal(passphrase: &str, plaintext: &[u8]) -> Result<Sealed, loga::Error> {
+    let mut salt = [0u8; 16];
+    rng().fill(&mut salt);
+    let mut nonce = [0u8; 24];
+    rng().fill(&mut nonce);
+    let key = derive_key(passphrase, &salt)?;
+    let ciphertext =
+        XChaCha20Poly1305::new(&key.into())
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .map_err(|_| loga::err("Error encrypting data"))?;
+    return Ok(Sealed {
+        kdf: KDF_ARGON2ID.to_string(),
+        salt: STANDARD.encode(salt),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    });
+}
+
+/// Fails with a generic error if the passphrase is wrong or the data was
+/// tampered with (they're indistinguishable).
+pub fn open(passphrase: &str, sealed: &Sealed) -> Result<Vec<u8>, loga::Error> {
+    if sealed.kdf != KDF_ARGON2ID {
+        return Err(loga::err_with("Unsupported key derivation", ea!(kdf = sealed.kdf)));
+    }
+    let salt = STANDARD.decode(&sealed.salt).map_err(|_| loga::err("Invalid salt"))?;
+    let nonce = STANDARD.decode(&sealed.nonce).map_err(|_| loga::err("Invalid nonce"))?;
+    if nonce.len() != 24 {
+        return Err(loga::err("Invalid nonce"));
+    }
+    let ciphertext = STANDARD.decode(&sealed.ciphertext).map_err(|_| loga::err("Invalid ciphertext"))?;
+    let key = derive_key(passphrase, &salt)?;
+    return Ok(
+        XChaCha20Poly1305::new(&key.into())
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| loga::err("Wrong passphrase or corrupted data"))?,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        open,
+        seal,
+    };
+
+    #[test]
+    fn round_trip() {
+        let sealed = seal("correct horse", b"secret").unwrap();
+        assert_eq!(open("correct horse", &sealed).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let sealed = seal("correct horse", b"secret").unwrap();
+        assert!(open("battery staple", &sealed).is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let mut sealed = seal("correct horse", b"secret").unwrap();
+        sealed.ciphertext = seal("correct horse", b"public").unwrap().ciphertext;
+        assert!(open("correct horse", &sealed).is_err());
+    }
+
+    #[test]
+    fn salted() {
+        let a = seal("correct horse", b"secret").unwrap();
+        let b = seal("correct horse", b"secret").unwrap();
+        assert_ne!(a.salt, b.salt);
+        assert_ne!(a.ciphertext, b.ciphertext);
+    }
+}
@@ -0,0 +1,385 @@
+//! Writes JSON Schemas and matching TypeScript declarations for the config and
+//! wire protocols, for third party clients and config linting.
+use {
+    loga::{
+        ea,
+        ResultContext,
+    },
+    schemars::{
+        json_schema,
+        schema_for,
+        JsonSchema,
+        Schema,
+        SchemaGenerator,
+    },
+    serde_json::Value,
+    std::{
+        any::TypeId,
+        collections::HashSet,
+        path::Path,
+    },
+    ts_rs::{
+        TypeVisitor,
+        TS,
+    },
+};
+
+/// `(request type name, response schema)` for each of the listed requests, for
+/// `responses`. The requests must be in the module `$m` and implement `$trait`.
+#[macro_export]
+macro_rules! resp_schemas{
+    ($trait: path, $m: ident; $($req: ident), * $(,) ?) => {
+        vec![
+            $(
+                (
+                    stringify!($req),
+                    (|g: &mut schemars::SchemaGenerator| g.subschema_for::<<$m::$req as $trait>::Resp>()) as
+                        fn(&mut schemars::SchemaGenerator) -> schemars::Schema,
+                ),
+            ) *
+        ]
+    };
+}
+
+pub struct Export {
+    name: &'static str,
+    schema: Schema,
+    ts: String,
+}
+
+impl Export {
+    /// TypeScript converted from the JSON Schema, for types that don't derive `TS`.
+    pub fn of<T: JsonSchema>(name: &'static str) -> Export {
+        return Export::from_schema(name, schema_for!(T));
+    }
+
+    pub fn from_schema(name: &'static str, schema: Schema) -> Export {
+        let ts = schema_to_ts(name, schema.as_value());
+        return Export {
+            name: name,
+            schema: schema,
+            ts: ts,
+        };
+    }
+
+    /// TypeScript from the type's `TS` derivation, with the declarations of
+    /// everything it refers to.
+    pub fn of_ts<T: JsonSchema + TS + 'static>(name: &'static str) -> Export {
+        return Export {
+            name: name,
+            schema: schema_for!(T),
+            ts: ts_rs_decls::<T>(),
+        };
+    }
+}
+
+/// Writes `NAME.schema.json` and `NAME.d.ts` for each export.
+pub async fn export(dir: &Path, exports: Vec<Export>) -> Result<(), loga::Error> {
+    tokio::fs::create_dir_all(dir).await.context_with("Error creating output dir", ea!(path = dir.to_string_lossy()))?;
+    for export in exports {
+        let json_path = dir.join(format!("{}.schema.json", export.name));
+        tokio::fs::write(&json_path, serde_json::to_vec_pretty(&export.schema).unwrap())
+            .await
+            .context_with("Error writing JSON Schema", ea!(path = json_path.to_string_lossy()))?;
+        let ts_path = dir.join(format!("{}.d.ts", export.name));
+        tokio::fs::write(&ts_path, export.ts)
+            .await
+            .context_with("Error writing TypeScript declarations", ea!(path = ts_path.to_string_lossy()))?;
+    }
+    return Ok(());
+}
+
+/// The responses of a protocol, which aren't part of the request schema: an object
+/// with a property for each request type, holding the type of its response. Use
+/// `resp_schemas!` to make the entries.
+pub fn responses(title: &str, entries: Vec<(&str, fn(&mut SchemaGenerator) -> Schema)>) -> Schema {
+    let mut generator = SchemaGenerator::default();
+    let mut properties = serde_json::Map::new();
+    for (name, subschema) in &entries {
+        properties.insert(name.to_string(), subschema(&mut generator).to_value());
+    }
+    let required = entries.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    return json_schema!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": title,
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "$defs": generator.take_definitions(true),
+    });
+}
+
+fn ts_rs_decls<T: TS + 'static>() -> String {
+    struct Decls {
+        seen: HashSet<TypeId>,
+        out: String,
+    }
+
+    impl TypeVisitor for Decls {
+        fn visit<T: TS + 'static + ?Sized>(&mut self) {
+            // Only declared types have a path, primitives and wrappers are inlined
+            if T::output_path().is_none() || !self.seen.insert(TypeId::of::<T>()) {
+                return;
+            }
+            self.out.push('\n');
+            if let Some(docs) = T::docs() {
+                self.out.push_str(&docs);
+            }
+            self.out.push_str(&format!("export {}\n", T::decl()));
+            T::visit_dependencies(self);
+        }
+    }
+
+    let mut decls = Decls {
+        seen: HashSet::new(),
+        out: String::from("// Generated by `native export-schemas`, do not edit\n"),
+    };
+    decls.visit::<T>();
+    return decls.out;
+}
+
+fn ts_type_name(name: &str) -> String {
+    let mut out = String::new();
+    let mut upper = true;
+    for c in name.chars() {
+        if !c.is_ascii_alphanumeric() {
+            upper = true;
+            continue;
+        }
+        if upper {
+            out.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    return out;
+}
+
+fn ts_doc(indent: &str, schema: &Value) -> String {
+    let Some(desc) = schema.get("description").and_then(|d| d.as_str()) else {
+        return String::new();
+    };
+    let mut out = format!("{}/**\n", indent);
+    for line in desc.lines() {
+        if line.is_empty() {
+            out.push_str(&format!("{} *\n", indent));
+        } else {
+            out.push_str(&format!("{} * {}\n", indent, line));
+        }
+    }
+    out.push_str(&format!("{} */\n", indent));
+    return out;
+}
+
+fn ts_union(parts: Vec<String>) -> String {
+    if parts.is_empty() {
+        return "never".to_string();
+    }
+    return parts.into_iter().map(|p| format!("({})", p)).collect::<Vec<_>>().join(" | ");
+}
+
+/// Converts the subset of JSON Schema that schemars produces into a TypeScript
+/// type expression.
+fn ts_expr(indent: &str, schema: &Value) -> String {
+    match schema {
+        Value::Bool(true) => return "unknown".to_string(),
+        Value::Bool(false) => return "never".to_string(),
+        Value::Object(_) => { },
+        _ => return "unknown".to_string(),
+    }
+    if let Some(r) = schema.get("$ref").and_then(|r| r.as_str()) {
+        return ts_type_name(r.rsplit('/').next().unwrap_or(r));
+    }
+    if let Some(c) = schema.get("const") {
+        return c.to_string();
+    }
+    if let Some(values) = schema.get("enum").and_then(|e| e.as_array()) {
+        return ts_union(values.iter().map(|v| v.to_string()).collect());
+    }
+
+    // Combinators can have siblings (ex: fields shared by all variants of an enum),
+    // which the value must match too
+    let mut rest = schema.clone();
+    let rest_obj = rest.as_object_mut().unwrap();
+    let mut parts = vec![];
+    for key in ["oneOf", "anyOf"] {
+        if let Some(Value::Array(variants)) = rest_obj.remove(key) {
+            parts.push(ts_union(variants.iter().map(|v| ts_expr(indent, v)).collect()));
+        }
+    }
+    if let Some(Value::Array(all)) = rest_obj.remove("allOf") {
+        parts.extend(all.iter().map(|v| ts_expr(indent, v)));
+    }
+    if !parts.is_empty() {
+        if ["type", "properties", "additionalProperties"].iter().any(|k| rest_obj.contains_key(*k)) {
+            parts.push(ts_expr(indent, &rest));
+        }
+        if parts.len() == 1 {
+            return parts.pop().unwrap();
+        }
+        return parts.into_iter().map(|p| format!("({})", p)).collect::<Vec<_>>().join(" & ");
+    }
+    match schema.get("type") {
+        Some(Value::Array(types)) => {
+            return ts_union(types.iter().map(|t| {
+                let mut single = schema.clone();
+                single.as_object_mut().unwrap().insert("type".to_string(), t.clone());
+                return ts_expr(indent, &single);
+            }).collect());
+        },
+        Some(Value::String(t)) => match t.as_str() {
+            "null" => return "null".to_string(),
+            "boolean" => return "boolean".to_string(),
+            "integer" | "number" => return "number".to_string(),
+            "string" => return "string".to_string(),
+            "array" => {
+                if let Some(items) = schema.get("prefixItems").and_then(|v| v.as_array()) {
+                    return format!(
+                        "[{}]",
+                        items.iter().map(|v| ts_expr(indent, v)).collect::<Vec<_>>().join(", ")
+                    );
+                }
+                return format!("Array<{}>", schema.get("items").map(|v| ts_expr(indent, v)).unwrap_or("unknown".to_string()));
+            },
+            "object" => {
+                let required =
+                    schema
+                        .get("required")
+                        .and_then(|v| v.as_array())
+                        .map(|v| v.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>())
+                        .unwrap_or_default();
+                let inner_indent = format!("{}  ", indent);
+                let mut out = String::new();
+                if let Some(props) = schema.get("properties").and_then(|v| v.as_object()) {
+                    for (k, v) in props {
+                        out.push_str(&ts_doc(&inner_indent, v));
+                        out.push_str(
+                            &format!(
+                                "{}{}{}: {};\n",
+                                inner_indent,
+                                serde_json::to_string(k).unwrap(),
+                                if required.contains(&k.as_str()) {
+                                    ""
+                                } else {
+                                    "?"
+                                },
+                                ts_expr(&inner_indent, v)
+                            ),
+                        );
+                    }
+                }
+                match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) | None => { },
+                    Some(v) => {
+                        out.push_str(&format!("{}[key: string]: {};\n", inner_indent, ts_expr(&inner_indent, v)));
+                    },
+                }
+                if out.is_empty() {
+                    return "{}".to_string();
+                }
+                return format!("{{\n{}{}}}", out, indent);
+            },
+            _ => return "unknown".to_string(),
+        },
+        _ => return "unknown".to_string(),
+    }
+}
+
+fn schema_to_ts(name: &str, schema: &Value) -> String {
+    let mut out = String::from("// Generated by `native export-schemas`, do not edit\n");
+    out.push_str(&ts_doc("", schema));
+    out.push_str(&format!("export type {} = {};\n", ts_type_name(name), ts_expr("", schema)));
+    if let Some(defs) = schema.get("$defs").and_then(|v| v.as_object()) {
+        for (def_name, def) in defs {
+            out.push('\n');
+            out.push_str(&ts_doc("", def));
+            out.push_str(&format!("export type {} = {};\n", ts_type_name(def_name), ts_expr("", def)));
+        }
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            responses,
+            ts_expr,
+            ts_rs_decls,
+        },
+        crate::interface::config::{
+            OidcConfig,
+            TlsConfig,
+        },
+        schemars::{
+            Schema,
+            SchemaGenerator,
+        },
+        serde_json::json,
+    };
+
+    #[test]
+    fn one_of_keeps_sibling_properties() {
+        let ts = ts_expr("", &json!({
+            "type": "object",
+            "properties": {
+                "id": {
+                    "type": "string"
+                }
+            },
+            "required": ["id"],
+            "oneOf": [{
+                "const": "a"
+            }, {
+                "const": "b"
+            }]
+        }));
+        assert_eq!(ts, "((\"a\") | (\"b\")) & ({\n  \"id\": string;\n})");
+    }
+
+    #[test]
+    fn one_of_alone() {
+        let ts = ts_expr("", &json!({
+            "oneOf": [{
+                "type": "string"
+            }, {
+                "type": "null"
+            }]
+        }));
+        assert_eq!(ts, "(string) | (null)");
+    }
+
+    #[test]
+    fn responses_keyed_by_request() {
+        fn a(g: &mut SchemaGenerator) -> Schema {
+            return g.subschema_for::<()>();
+        }
+
+        fn b(g: &mut SchemaGenerator) -> Schema {
+            return g.subschema_for::<TlsConfig>();
+        }
+
+        let schema =
+            responses(
+                "Res",
+                vec![
+                    //. .
+                    ("A", a as fn(&mut SchemaGenerator) -> Schema),
+                    ("B", b as fn(&mut SchemaGenerator) -> Schema)
+                ],
+            );
+        let schema = schema.as_value();
+        assert_eq!(schema["required"], json!(["A", "B"]));
+        assert_eq!(schema["properties"]["A"]["type"], "null");
+        assert!(schema["$defs"].get("TlsConfig").is_some());
+    }
+
+    #[test]
+    fn ts_rs_includes_dependencies() {
+        let ts = ts_rs_decls::<OidcConfig>();
+        for name in ["OidcConfig", "OidcProviderConfig", "OidcRestrictions", "OidcRequiredClaimValue"] {
+            assert_eq!(ts.matches(&format!("export type {} =", name)).count(), 1, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,682 @@
+//! Account data export. Archives are built in the background and kept in the cache
+//! dir for a day. Export state is in memory, so a restart forgets them, which is
+//! fine for something the user can just redo; leftover archives (which may contain
+//! sealed identity secrets) are deleted at startup.
+//!
+//! Archive layout (`.tar.gz`):
+//!
+//! * `manifest.json` - format version, account, what's included
+//!
+//! * `identities.json`, `channel_groups.json`, `channels.json`
+//!
+//! * `identity_secrets.json` - only if a passphrase was given; each secret sealed
+//!   with `passphrase::seal`
+//!
+//! * `messages/<identity>/<channel>.jsonl` - the activity of each channel owned by
+//!   one of the account's identities, oldest first, one signed message per line
+//!   (edits and deletions included). Channels owned on other servers are stored
+//!   there.
+//!
+//! Contacts and invites aren't stored server-side and are listed under
+//! `unavailable` in the manifest.
+use {
+    crate::{
+        dbutil::tx,
+        interface::{
+            db::{
+                DbIdentity,
+                DbIdentitySecret,
+            },
+            AccountExternalId,
+        },
+        passphrase,
+        subsystems::identitysecret::{
+            self,
+            SecretKey,
+        },
+    },
+    deadpool_sqlite::Pool,
+    flate2::{
+        write::GzEncoder,
+        Compression,
+    },
+    good_ormning::sqlite::good_query_many,
+    jiff::{
+        SignedDuration,
+        Timestamp,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    moka::future::Cache,
+    rand::distr::{
+        Alphanumeric,
+        SampleString,
+    },
+    serde::Serialize,
+    shared::interface::{
+        shared::{
+            AccountExportId,
+            ChannelGroupId,
+            ChannelId,
+            Message,
+        },
+        wire::c2s::{
+            AccountExportCreate,
+            AccountExportRes,
+            AccountExportStatus,
+        },
+    },
+    spaghettinuum::interface::identity::{
+        Identity,
+        LocalIdentitySecret,
+    },
+    std::{
+        collections::{
+            BTreeMap,
+            HashSet,
+        },
+        path::PathBuf,
+        sync::{
+            Arc,
+            Mutex,
+        },
+        time::Duration,
+    },
+};
+
+const FORMAT_VERSION: u32 = 1;
+const RETENTION: Duration = Duration::from_secs(60 * 60 * 24);
+
+struct ExportEntry {
+    account: AccountExternalId,
+    created: Timestamp,
+    includes_secrets: bool,
+    status: AccountExportStatus,
+}
+
+impl ExportEntry {
+    fn to_res(&self, id: &str) -> AccountExportRes {
+        return AccountExportRes {
+            id: AccountExportId(id.to_string()),
+            created: self.created,
+            status: self.status.clone(),
+            includes_secrets: self.includes_secrets,
+            expires: self.created + SignedDuration::try_from(RETENTION).unwrap(),
+        };
+    }
+}
+
+pub struct AccountExportState {
+    dir: PathBuf,
+    exports: Cache<String, Arc<Mutex<ExportEntry>>>,
+    /// Accounts with an export being built.
+    pending: Mutex<HashSet<AccountExternalId>>,
+}
+
+fn archive_path(dir: &PathBuf, id: &str) -> PathBuf {
+    return dir.join(format!("{}.tar.gz", id));
+}
+
+/// Deletes archives left over from a previous run.
+pub fn new_state(cache_dir: &PathBuf) -> Result<AccountExportState, loga::Error> {
+    let dir = cache_dir.join("account_exports");
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)
+            .context_with("Error deleting old account exports", ea!(path = dir.to_string_lossy()))?;
+    }
+    return Ok(AccountExportState {
+        dir: dir.clone(),
+        exports: Cache::builder().time_to_live(RETENTION).eviction_listener(move |id: Arc<String>, _, _| {
+            _ = std::fs::remove_file(archive_path(&dir, &id));
+        }).build(),
+        pending: Mutex::new(HashSet::new()),
+    });
+}
+
+#[derive(Serialize)]
+struct ExportManifest {
+    format: u32,
+    exported: Timestamp,
+    account: AccountExternalId,
+    includes_secrets: bool,
+    unavailable: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct ExportIdentity {
+    id: Identity,
+    memo_short: String,
+    memo_long: String,
+    deleted: Option<Timestamp>,
+}
+
+#[derive(Serialize)]
+struct ExportIdentitySecret {
+    id: Identity,
+    secret: passphrase::Sealed,
+}
+
+#[derive(Serialize)]
+struct ExportChannelGroup {
+    id: ChannelGroupId,
+    memo_short: String,
+    memo_long: String,
+}
+
+#[derive(Serialize)]
+struct ExportChannel {
+    identity: Identity,
+    id: ChannelId,
+    group: Option<ChannelGroupId>,
+    memo_short: String,
+    memo_long: String,
+    deleted: Option<Timestamp>,
+}
+
+#[derive(Serialize)]
+struct ExportMessage {
+    offset: usize,
+    received: Timestamp,
+    message: Message,
+}
+
+struct ExportData {
+    /// Secrets are only included if they'll be exported
+    identities: Vec<(ExportIdentity, Option<LocalIdentitySecret>)>,
+    channel_groups: Vec<ExportChannelGroup>,
+    channels: Vec<ExportChannel>,
+    /// Activity of owned channels, keyed by (owner, channel)
+    messages: BTreeMap<(String, u64), Vec<ExportMessage>>,
+}
+
+async fn read_data(
+    db: &Pool,
+    secret_key: Option<SecretKey>,
+    account: &AccountExternalId,
+    with_secrets: bool,
+) -> Result<ExportData, loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        let identities = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 identity.id,
+                 identity.memo_short,
+                 identity.memo_long,
+                 identity.soft_deleted_at,
+                 identity.secret
+               from
+                 identity
+                 join account on identity.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.into_iter().map(|r| {
+            let DbIdentitySecret(secret) = r.secret;
+            let secret = if with_secrets {
+                Some(identitysecret::open(secret_key.as_ref(), &secret)?)
+            } else {
+                None
+            };
+            return Ok((ExportIdentity {
+                id: r.id.0,
+                memo_short: r.memo_short,
+                memo_long: r.memo_long,
+                deleted: r.soft_deleted_at,
+            }, secret));
+        }).collect::<Result<Vec<_>, loga::Error>>()?;
+        let channel_groups = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 channelgroup.rowid,
+                 channelgroup.memo_short,
+                 channelgroup.memo_long
+               from
+                 channelgroup
+                 join account on channelgroup.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.into_iter().map(|r| ExportChannelGroup {
+            id: ChannelGroupId(r.rowid as u64),
+            memo_short: r.memo_short,
+            memo_long: r.memo_long,
+        }).collect();
+        let channels = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 channel.identity,
+                 channel.id,
+                 channel.channel_group,
+                 channel.memo_short,
+                 channel.memo_long,
+                 channel.deleted
+               from
+                 channel
+                 join account on channel.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.into_iter().map(|r| ExportChannel {
+            identity: r.identity.0,
+            id: r.id.0,
+            group: r.channel_group.map(|g| g.0),
+            memo_short: r.memo_short,
+            memo_long: r.memo_long,
+            deleted: r.deleted,
+        }).collect();
+        let mut messages = BTreeMap::<_, Vec<_>>::new();
+        for (identity, _) in &identities {
+            for r in good_query_many!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     channel,
+                     activity_offset,
+                     receive_time,
+                     message
+                   from
+                     message_activity
+                   where
+                     owner = ${identity_id_t = DbIdentity(identity.id.clone())}
+                   order by
+                     channel,
+                     activity_offset
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))? {
+                messages.entry((identity.id.to_string(), r.channel.0.0)).or_default().push(ExportMessage {
+                    offset: r.activity_offset as usize,
+                    received: r.receive_time,
+                    message: r.message.0,
+                });
+            }
+        }
+        return Ok(ExportData {
+            identities: identities,
+            channel_groups: channel_groups,
+            channels: channels,
+            messages: messages,
+        });
+    }).await?);
+}
+
+fn add_file(ar: &mut tar::Builder<GzEncoder<Vec<u8>>>, path: &str, data: &[u8]) -> Result<(), loga::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Timestamp::now().as_second() as u64);
+    header.set_cksum();
+    ar.append_data(&mut header, path, data).context_with("Error adding file to archive", ea!(path = path))?;
+    return Ok(());
+}
+
+fn build_archive(
+    account: AccountExternalId,
+    data: ExportData,
+    secrets_passphrase: Option<String>,
+) -> Result<Vec<u8>, loga::Error> {
+    let mut ar = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    add_file(&mut ar, "manifest.json", &serde_json::to_vec_pretty(&ExportManifest {
+        format: FORMAT_VERSION,
+        exported: Timestamp::now(),
+        account: account,
+        includes_secrets: secrets_passphrase.is_some(),
+        unavailable: vec!["contacts", "invites"],
+    }).unwrap())?;
+    let mut identities = vec![];
+    let mut secrets = vec![];
+    for (identity, secret) in data.identities {
+        if let (Some(passphrase), Some(secret)) = (&secrets_passphrase, secret) {
+            secrets.push(ExportIdentitySecret {
+                id: identity.id.clone(),
+                secret: passphrase::seal(passphrase, &serde_json::to_vec(&secret).unwrap())?,
+            });
+        }
+        identities.push(identity);
+    }
+    add_file(&mut ar, "identities.json", &serde_json::to_vec_pretty(&identities).unwrap())?;
+    if secrets_passphrase.is_some() {
+        add_file(&mut ar, "identity_secrets.json", &serde_json::to_vec_pretty(&secrets).unwrap())?;
+    }
+    add_file(&mut ar, "channel_groups.json", &serde_json::to_vec_pretty(&data.channel_groups).unwrap())?;
+    add_file(&mut ar, "channels.json", &serde_json::to_vec_pretty(&data.channels).unwrap())?;
+    for ((identity, channel), messages) in data.messages {
+        let mut lines = vec![];
+        for m in messages {
+            serde_json::to_writer(&mut lines, &m).unwrap();
+            lines.push(b'\n');
+        }
+        add_file(&mut ar, &format!("messages/{}/{}.jsonl", identity, channel), &lines)?;
+    }
+    return Ok(ar.into_inner().context("Error finishing archive")?.finish().context("Error compressing archive")?);
+}
+
+/// Starts building an export in the background. Only one export at a time per
+/// account, they're not cheap; the error is for the user.
+pub async fn start(
+    log: &Log,
+    db: &Pool,
+    secret_key: &Option<SecretKey>,
+    state: &Arc<AccountExportState>,
+    account: &AccountExternalId,
+    req: AccountExportCreate,
+) -> Result<AccountExportRes, loga::Error> {
+    if !state.pending.lock().unwrap().insert(account.clone()) {
+        return Err(loga::err("An export is already in progress"));
+    }
+    let id = Alphanumeric.sample_string(&mut rand::rng(), 32);
+    let entry = Arc::new(Mutex::new(ExportEntry {
+        account: account.clone(),
+        created: Timestamp::now(),
+        includes_secrets: req.secrets_passphrase.is_some(),
+        status: AccountExportStatus::Pending,
+    }));
+    state.exports.insert(id.clone(), entry.clone()).await;
+    let res = entry.lock().unwrap().to_res(&id);
+    tokio::spawn({
+        let log = log.fork(ea!(sys = "account_export", export = id));
+        let db = db.clone();
+        let secret_key = secret_key.clone();
+        let state = state.clone();
+        let account = account.clone();
+        async move {
+            let status = match async {
+                let data = read_data(&db, secret_key, &account, req.secrets_passphrase.is_some()).await?;
+                let archive =
+                    tokio::task::spawn_blocking({
+                        let account = account.clone();
+                        move || build_archive(account, data, req.secrets_passphrase)
+                    })
+                        .await
+                        .context("Archive task panicked")??;
+                tokio::fs::create_dir_all(&state.dir).await.context("Error creating export dir")?;
+                tokio::fs::write(archive_path(&state.dir, &id), archive).await.context("Error writing archive")?;
+                return Ok(()) as Result<(), loga::Error>;
+            }.await {
+                Ok(_) => AccountExportStatus::Ready,
+                Err(e) => {
+                    log.log_err(loga::WARN, e.context("Error building account export"));
+                    AccountExportStatus::Failed(format!("Internal error building export"))
+                },
+            };
+            entry.lock().unwrap().status = status;
+            state.pending.lock().unwrap().remove(&account);
+        }
+    });
+    return Ok(res);
+}
+
+pub fn list(state: &AccountExportState, account: &AccountExternalId) -> Vec<AccountExportRes> {
+    let mut out = vec![];
+    for (id, e) in state.exports.iter() {
+        let e = e.lock().unwrap();
+        if &e.account != account {
+            continue;
+        }
+        out.push(e.to_res(&id));
+    }
+    out.sort_by(|a, b| b.created.cmp(&a.created));
+    return out;
+}
+
+/// `None` if there's no such finished export for the account.
+pub async fn read(
+    state: &AccountExportState,
+    account: &AccountExternalId,
+    id: &AccountExportId,
+) -> Result<Option<Vec<u8>>, loga::Error> {
+    let Some(e) = state.exports.get(&id.0).await else {
+        return Ok(None);
+    };
+    {
+        let e = e.lock().unwrap();
+        if &e.account != account || e.status != AccountExportStatus::Ready {
+            return Ok(None);
+        }
+    }
+    return Ok(
+        Some(
+            tokio::fs::read(archive_path(&state.dir, &id.0))
+                .await
+                .context_with("Error reading export archive", ea!(export = id.0))?,
+        ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            build_archive,
+            list,
+            new_state,
+            read_data,
+            start,
+            AccountExportState,
+        },
+        crate::{
+            dbutil::{
+                test_db,
+                tx,
+            },
+            interface::{
+                db::{
+                    DbAccountId,
+                    DbChannelId,
+                    DbIdentity,
+                    DbIdentitySecret,
+                },
+                AccountExternalId,
+            },
+            subsystems::{
+                identity::ensure_account,
+                identitysecret,
+                message,
+            },
+        },
+        flate2::read::GzDecoder,
+        good_ormning::sqlite::good_query,
+        loga::Log,
+        rand::distr::{
+            Alphanumeric,
+            SampleString,
+        },
+        shared::interface::{
+            shared::{
+                ChannelId,
+                MessageClientId,
+                QualifiedChannelId,
+            },
+            wire::c2s::{
+                AccountExportCreate,
+                AccountExportStatus,
+                MessagePush,
+            },
+        },
+        spaghettinuum::interface::identity::LocalIdentitySecret,
+        std::{
+            io::Read,
+            sync::Arc,
+            time::Duration,
+        },
+    };
+
+    fn account() -> AccountExternalId {
+        return AccountExternalId {
+            issuer: "https://issuer.example.org".to_string(),
+            subject: "a".to_string(),
+        };
+    }
+
+    fn state() -> Arc<AccountExportState> {
+        return Arc::new(
+            new_state(
+                &std::env::temp_dir().join(format!("kwa-test-{}", Alphanumeric.sample_string(&mut rand::rng(), 16))),
+            ).unwrap(),
+        );
+    }
+
+    async fn wait_done(state: &AccountExportState) -> AccountExportStatus {
+        loop {
+            let status = list(state, &account()).remove(0).status;
+            if status != AccountExportStatus::Pending {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn leftover_archives_deleted() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("kwa-test-{}", Alphanumeric.sample_string(&mut rand::rng(), 16)));
+        std::fs::create_dir_all(cache_dir.join("account_exports")).unwrap();
+        std::fs::write(cache_dir.join("account_exports").join("old.tar.gz"), b"x").unwrap();
+        new_state(&cache_dir).unwrap();
+        assert!(!cache_dir.join("account_exports").exists());
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn one_at_a_time() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        let state = state();
+        let req = || AccountExportCreate { secrets_passphrase: None };
+        assert!(start(&log, &db, &None, &state, &account(), req()).await.is_ok());
+        assert!(start(&log, &db, &None, &state, &account(), req()).await.is_err());
+        assert!(wait_done(&state).await == AccountExportStatus::Ready);
+        assert!(start(&log, &db, &None, &state, &account(), req()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn secrets_only_opened_when_exported() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        let state = state();
+
+        // Sealed with a key the export doesn't have
+        let (id, secret) = LocalIdentitySecret::new();
+        let stored = identitysecret::seal(Some(&identitysecret::test_key(1)), &secret);
+        tx(&db, move |db_tx| {
+            let account_id = ensure_account(db_tx, &account())?;
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"insert into
+                     identity
+                     (account_id, id, idem, memo_short, memo_long, secret)
+                   values (
+                     ${account_id_t = DbAccountId(account_id)},
+                     ${identity_id_t = DbIdentity(id)},
+                     ${str = String::new()},
+                     ${str = String::new()},
+                     ${str = String::new()},
+                     ${identity_secret_t = DbIdentitySecret(stored)}
+                   )
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            return Ok(());
+        }).await.unwrap();
+        start(&log, &db, &None, &state, &account(), AccountExportCreate { secrets_passphrase: None })
+            .await
+            .unwrap();
+        assert!(wait_done(&state).await == AccountExportStatus::Ready);
+        start(&log, &db, &None, &state, &account(), AccountExportCreate { secrets_passphrase: Some("pw".to_string()) })
+            .await
+            .unwrap();
+        assert!(matches!(wait_done(&state).await, AccountExportStatus::Failed(_)));
+    }
+
+    #[tokio::test]
+    async fn owned_channel_messages_exported() {
+        let db = test_db().await;
+        let (id, secret) = LocalIdentitySecret::new();
+        let stored = identitysecret::seal(None, &secret);
+        tx(&db, {
+            let id = id.clone();
+            move |db_tx| {
+                let account_id = ensure_account(db_tx, &account())?;
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"insert into
+                         identity
+                         (account_id, id, idem, memo_short, memo_long, secret)
+                       values (
+                         ${account_id_t = DbAccountId(account_id)},
+                         ${identity_id_t = DbIdentity(id.clone())},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${identity_secret_t = DbIdentitySecret(stored)}
+                       )
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"insert into
+                         channel
+                         (account_id, identity, id, idem, memo_short, memo_long, own_identity)
+                       values (
+                         ${account_id_t = DbAccountId(account_id)},
+                         ${identity_id_t = DbIdentity(id.clone())},
+                         ${channel_id_t = DbChannelId(ChannelId(1))},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${identity_id_t? = Some(DbIdentity(id))}
+                       )
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+                return Ok(());
+            }
+        }).await.unwrap();
+        for i in 0 .. 2 {
+            message::push(&db, None, &account(), MessagePush {
+                client_id: MessageClientId(i.to_string()),
+                channel: QualifiedChannelId {
+                    identity: id.clone(),
+                    channel: ChannelId(1),
+                },
+                identity: id.clone(),
+                body: format!("body {}", i),
+            }).await.unwrap().unwrap().unwrap();
+        }
+        let data = read_data(&db, None, &account(), false).await.unwrap();
+        let archive = build_archive(account(), data, None).unwrap();
+        let mut ar = tar::Archive::new(GzDecoder::new(archive.as_slice()));
+        let mut found = None;
+        for entry in ar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap().to_string_lossy() == format!("messages/{}/1.jsonl", id) {
+                let mut text = String::new();
+                entry.read_to_string(&mut text).unwrap();
+                found = Some(text);
+            }
+        }
+        let lines =
+            found
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+                .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["offset"], 1);
+    }
+}
@@ -0,0 +1,302 @@
+//! Long-lived bearer tokens for bots and scripts. A token acts as a single
+//! identity and is limited to its scope (channels, read and/or post). Only a hash
+//! of the token is stored.
+use {
+    crate::{
+        dbutil::{
+            abortable_tx,
+            tx,
+            Txr,
+        },
+        interface::{
+            db::{
+                DbAccountExternalId,
+                DbApiTokenScope,
+                DbIdentity,
+            },
+            AccountExternalId,
+        },
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query,
+        good_query_many,
+        good_query_opt,
+    },
+    http::{
+        header::AUTHORIZATION,
+        HeaderMap,
+    },
+    jiff::Timestamp,
+    rand::distr::{
+        Alphanumeric,
+        SampleString,
+    },
+    sha2::{
+        Digest,
+        Sha256,
+    },
+    shared::interface::{
+        shared::{
+            ApiTokenId,
+            QualifiedChannelId,
+        },
+        wire::c2s::{
+            ApiTokenCreate,
+            ApiTokenCreateRes,
+            ApiTokenRes,
+            ApiTokenScope,
+        },
+    },
+    spaghettinuum::interface::identity::Identity,
+};
+
+const TOKEN_PREFIX: &str = "kwat_";
+
+/// Credentials from a valid `Authorization: Bearer` token.
+#[derive(Clone)]
+pub struct ApiTokenAuth {
+    pub id: ApiTokenId,
+    pub account: AccountExternalId,
+    pub identity: Identity,
+    pub scope: ApiTokenScope,
+    /// The scope's channels the identity currently owns or is a member of.
+    pub channels: Vec<QualifiedChannelId>,
+}
+
+impl ApiTokenAuth {
+    fn allows_channel(&self, channel: &QualifiedChannelId) -> bool {
+        return self.channels.contains(channel);
+    }
+
+    pub fn allows_read(&self, channel: &QualifiedChannelId) -> bool {
+        return self.scope.read && self.allows_channel(channel);
+    }
+
+    pub fn allows_post(&self, channel: &QualifiedChannelId) -> bool {
+        return self.scope.post && self.allows_channel(channel);
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    return hex::encode(Sha256::digest(token.as_bytes()));
+}
+
+/// Live channels of the account that `identity` owns or is a member of.
+fn identity_channels(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    account: &AccountExternalId,
+    identity: &Identity,
+) -> Result<Vec<QualifiedChannelId>, loga::Error> {
+    let rows = good_query_many!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"select
+             channel.identity,
+             channel.id,
+             channel.own_identity
+           from
+             channel
+             join account on channel.account_id = account.rowid
+           where
+             account.external_id = ${str = account.to_db()}
+             and channel.deleted is null
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?;
+    return Ok(
+        rows
+            .into_iter()
+            .filter(|r| r.identity.0 == *identity || r.own_identity.as_ref().map(|i| &i.0) == Some(identity))
+            .map(|r| QualifiedChannelId {
+                identity: r.identity.0,
+                channel: r.id.0,
+            })
+            .collect(),
+    );
+}
+
+/// Returns the token if the request has an `Authorization: Bearer` header.
+pub fn get_req_bearer(headers: &HeaderMap) -> Option<String> {
+    let v = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = v.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    return Some(token.trim().to_string());
+}
+
+pub async fn identify(db: &Pool, token: String) -> Result<Option<ApiTokenAuth>, loga::Error> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return Ok(None);
+    }
+    let hash = hash_token(&token);
+    return Ok(tx(db, move |db_tx| {
+        let Some(row) = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 rowid,
+                 account,
+                 identity,
+                 scope
+               from
+                 api_token
+               where
+                 hash = ${str = hash}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))? else {
+            return Ok(None);
+        };
+        let account = row.account.0;
+        let identity = row.identity.0;
+
+        // Tokens stop working while their identity is deleted
+        if good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 identity.id
+               from
+                 identity
+                 join account on identity.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+                 and identity.id = ${identity_id_t = DbIdentity(identity.clone())}
+                 and identity.soft_deleted_at is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.is_none() {
+            return Ok(None);
+        }
+        let scope = row.scope.0;
+        let channels =
+            identity_channels(db_tx, &account, &identity)?
+                .into_iter()
+                .filter(|c| scope.channels.contains(&c.channel))
+                .collect();
+        return Ok(Some(ApiTokenAuth {
+            id: ApiTokenId(row.rowid as u64),
+            account: account,
+            identity: identity,
+            scope: scope,
+            channels: channels,
+        }));
+    }).await?);
+}
+
+/// Returns `None` if the identity or any of the channels don't belong to the
+/// account.
+pub async fn create(
+    db: &Pool,
+    account: &AccountExternalId,
+    req: ApiTokenCreate,
+) -> Result<Option<ApiTokenCreateRes>, loga::Error> {
+    let token = format!("{}{}", TOKEN_PREFIX, Alphanumeric.sample_string(&mut rand::rng(), 40));
+    let hash = hash_token(&token);
+    let account = account.clone();
+    return Ok(abortable_tx(db, move |db_tx| {
+        if good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 identity.id
+               from
+                 identity
+                 join account on identity.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+                 and identity.id = ${identity_id_t = DbIdentity(req.identity.clone())}
+                 and identity.soft_deleted_at is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.is_none() {
+            return Ok(Txr::Abort);
+        }
+        let channels = identity_channels(db_tx, &account, &req.identity)?;
+        for channel in &req.scope.channels {
+            if !channels.iter().any(|c| c.channel == *channel) {
+                return Ok(Txr::Abort);
+            }
+        }
+        let created = Timestamp::now();
+        let rowid = good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"insert into
+                 api_token
+                 (account, identity, hash, memo_short, scope, created)
+               values (
+                 ${account_external_id_t = DbAccountExternalId(account.clone())},
+                 ${identity_id_t = DbIdentity(req.identity.clone())},
+                 ${str = hash},
+                 ${str = req.memo_short.clone()},
+                 ${api_token_scope_t = DbApiTokenScope(req.scope.clone())},
+                 ${utctime_s_jiff = created}
+               )
+               returning rowid
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(Txr::Ok(ApiTokenCreateRes {
+            res: ApiTokenRes {
+                id: ApiTokenId(rowid as u64),
+                identity: req.identity,
+                memo_short: req.memo_short,
+                scope: req.scope,
+                created: created,
+            },
+            token: token,
+        }));
+    }).await?);
+}
+
+pub async fn list(db: &Pool, account: &AccountExternalId, identity: Identity) -> Result<Vec<ApiTokenRes>, loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        let rows = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 rowid,
+                 memo_short,
+                 scope,
+                 created
+               from
+                 api_token
+               where
+                 account = ${account_external_id_t = DbAccountExternalId(account)}
+                 and identity = ${identity_id_t = DbIdentity(identity.clone())}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(rows.into_iter().map(|r| ApiTokenRes {
+            id: ApiTokenId(r.rowid as u64),
+            identity: identity.clone(),
+            memo_short: r.memo_short,
+            scope: r.scope.0,
+            created: r.created,
+        }).collect());
+    }).await?);
+}
+
+/// Revokes the token immediately; it's looked up on every request so there's
+/// nothing cached to invalidate.
+pub async fn delete(db: &Pool, account: &AccountExternalId, id: ApiTokenId) -> Result<(), loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"delete from
+                 api_token
+               where
+                 rowid = ${i64 = id.0 as i64}
+                 and account = ${account_external_id_t = DbAccountExternalId(account)}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(());
+    }).await?);
+}
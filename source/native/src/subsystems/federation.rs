@@ -0,0 +1,949 @@
+//! Server to server notifications. When a channel owned here changes, the servers
+//! of its remote members get an `s2sv1::Notify` so they can fetch the new pages.
+//! Notifications are queued in the database and retried with exponential backoff
+//! so they survive restarts and remote outages; ones that keep failing are kept as
+//! `failed` for inspection.
+//!
+//! Destinations are identities, resolved to their home server when sending (see
+//! `resolver`) so identities that moved are followed. Failures also back off the
+//! server as a whole, so one unreachable server doesn't get a request for every
+//! queued notification.
+//!
+//! Local accounts in the channel, whether it's owned here or `Notify` came from
+//! the owner, are told through `channel_activity` (websockets and web push).
+use {
+    crate::{
+        dbutil::tx,
+        interface::{
+            db::{
+                DbChannelId,
+                DbIdentity,
+            },
+            s2s::{
+                s2sv1,
+                s2sv1t::Notify,
+            },
+            AccountExternalId,
+        },
+        subsystems::{
+            federationpolicy::{
+                self,
+                FederationPolicyState,
+            },
+            identitysecret::{
+                self,
+                SecretKey,
+            },
+            resolver::{
+                self,
+                ResolverState,
+            },
+            s2sauth::{
+                self,
+                S2sAuthState,
+            },
+            s2sclient,
+        },
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query,
+        good_query_many,
+        good_query_opt,
+    },
+    jiff::{
+        SignedDuration,
+        Timestamp,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+    },
+    shared::interface::{
+        shared::QualifiedChannelId,
+        wire::c2s::ActivityOffset,
+    },
+    spaghettinuum::interface::identity::Identity,
+    std::{
+        sync::Arc,
+        time::Duration,
+    },
+    taskmanager::TaskManager,
+    tokio::{
+        select,
+        sync::{
+            broadcast,
+            Notify as WakeNotify,
+        },
+        time::sleep,
+    },
+};
+
+const STATUS_PENDING: &str = "pending";
+const STATUS_DELIVERED: &str = "delivered";
+const STATUS_FAILED: &str = "failed";
+
+/// Give up after this many attempts - with the backoff below that's roughly two
+/// days of retrying.
+const MAX_ATTEMPTS: i64 = 12;
+const BACKOFF_BASE: SignedDuration = SignedDuration::from_secs(30);
+const BACKOFF_MAX: SignedDuration = SignedDuration::from_secs(60 * 60 * 6);
+
+/// Finished notifications are kept this long.
+const LOG_RETENTION: SignedDuration = SignedDuration::from_secs(60 * 60 * 24 * 7);
+const BATCH_SIZE: i64 = 50;
+
+/// Something happened in a channel that local accounts are in.
+#[derive(Clone)]
+pub struct ChannelActivity {
+    pub accounts: Vec<AccountExternalId>,
+    pub channel: QualifiedChannelId,
+    pub offset: ActivityOffset,
+    /// Text for push notifications, if the activity includes a new message. Edits
+    /// and deletions only refresh connected clients.
+    pub push_body: Option<String>,
+}
+
+pub struct FederationState {
+    /// Wakes the delivery worker when something is queued.
+    wake: Arc<WakeNotify>,
+    /// See `s2sclient::connect`.
+    allow_private: bool,
+    /// Websocket connections and push delivery subscribe to this to tell clients to
+    /// refresh the channel.
+    pub channel_activity: broadcast::Sender<ChannelActivity>,
+}
+
+pub fn new_state(allow_private: bool) -> FederationState {
+    let (channel_activity, _) = broadcast::channel(100);
+    return FederationState {
+        wake: Arc::new(WakeNotify::new()),
+        allow_private: allow_private,
+        channel_activity: channel_activity,
+    };
+}
+
+fn backoff(attempts: i64) -> SignedDuration {
+    let mut out = BACKOFF_BASE;
+    for _ in 1 .. attempts {
+        out = out * 2;
+        if out >= BACKOFF_MAX {
+            return BACKOFF_MAX;
+        }
+    }
+    return out;
+}
+
+/// Whether the account has the identity that owns the channel.
+fn owns_channel(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    account: &AccountExternalId,
+    channel: &QualifiedChannelId,
+) -> Result<bool, loga::Error> {
+    return Ok(good_query_opt!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"select
+             identity.id
+           from
+             identity
+             join account on identity.account_id = account.rowid
+           where
+             account.external_id = ${str = account.to_db()}
+             and identity.id = ${identity_id_t = DbIdentity(channel.identity.clone())}
+             and identity.soft_deleted_at is null
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?.is_some());
+}
+
+/// Adds an identity on another server to a channel the account owns. Returns the
+/// reason if it can't be added.
+pub async fn member_add(
+    db: &Pool,
+    account: &AccountExternalId,
+    channel: &QualifiedChannelId,
+    member: &Identity,
+) -> Result<Result<(), String>, loga::Error> {
+    let account = account.clone();
+    let channel = channel.clone();
+    let member = member.clone();
+    return Ok(tx(db, move |db_tx| {
+        if !owns_channel(db_tx, &account, &channel)? {
+            return Ok(Err(format!("Only the channel owner can add members")));
+        }
+        let local = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 id
+               from
+                 identity
+               where
+                 id = ${identity_id_t = DbIdentity(member.clone())}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        if local.is_some() {
+            return Ok(Err(format!("Identity is on this server, it joins with its own channel")));
+        }
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"insert into
+                 channel_remote_member
+                 (owner, channel, member, added)
+               values (
+                 ${identity_id_t = DbIdentity(channel.identity)},
+                 ${channel_id_t = DbChannelId(channel.channel)},
+                 ${identity_id_t = DbIdentity(member)},
+                 ${utctime_s_jiff = Timestamp::now()}
+               )
+               on conflict do nothing
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(Ok(()));
+    }).await?);
+}
+
+/// Removes an identity on another server from a channel the account owns.
+/// Returns `None` if the account doesn't own the channel.
+pub async fn member_delete(
+    db: &Pool,
+    account: &AccountExternalId,
+    channel: &QualifiedChannelId,
+    member: &Identity,
+) -> Result<Option<()>, loga::Error> {
+    let account = account.clone();
+    let channel = channel.clone();
+    let member = member.clone();
+    return Ok(tx(db, move |db_tx| {
+        if !owns_channel(db_tx, &account, &channel)? {
+            return Ok(None);
+        }
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"delete from
+                 channel_remote_member
+               where
+                 owner = ${identity_id_t = DbIdentity(channel.identity)}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel)}
+                 and member = ${identity_id_t = DbIdentity(member)}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(Some(()));
+    }).await?);
+}
+
+fn remote_members(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    channel: &QualifiedChannelId,
+) -> Result<Vec<Identity>, loga::Error> {
+    return Ok(good_query_many!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"select
+             member
+           from
+             channel_remote_member
+           where
+             owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+             and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?.into_iter().map(|m| m.0).collect());
+}
+
+/// Members here and on other servers of a channel the account owns. `None` if the
+/// account doesn't own the channel.
+pub async fn member_list(
+    db: &Pool,
+    account: &AccountExternalId,
+    channel: &QualifiedChannelId,
+) -> Result<Option<Vec<Identity>>, loga::Error> {
+    let account = account.clone();
+    let channel = channel.clone();
+    return Ok(tx(db, move |db_tx| {
+        if !owns_channel(db_tx, &account, &channel)? {
+            return Ok(None);
+        }
+        let mut out = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 own_identity
+               from
+                 channel
+               where
+                 identity = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and id = ${channel_id_t = DbChannelId(channel.channel.clone())}
+                 and deleted is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.into_iter().flatten().map(|i| i.0).collect::<Vec<_>>();
+        out.extend(remote_members(db_tx, &channel)?);
+        return Ok(Some(out));
+    }).await?);
+}
+
+/// Whether the identity on another server was added to the channel.
+pub async fn is_remote_member(db: &Pool, channel: &QualifiedChannelId, identity: &Identity) -> Result<bool, loga::Error> {
+    let channel = channel.clone();
+    let identity = identity.clone();
+    return Ok(tx(db, move |db_tx| {
+        return Ok(remote_members(db_tx, &channel)?.contains(&identity));
+    }).await?);
+}
+
+/// Queues a notification about the channel for each remote member. Members that
+/// already have a pending notification for the channel get its offset updated
+/// rather than a second one. Call this after the change has been committed.
+pub async fn enqueue_notify(
+    db: &Pool,
+    state: &FederationState,
+    channel: QualifiedChannelId,
+    offset: ActivityOffset,
+    new_message: bool,
+) -> Result<(), loga::Error> {
+    let queued = tx(db, move |db_tx| {
+        let mut queued = 0;
+        for destination in remote_members(db_tx, &channel)? {
+            let pending = good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     rowid,
+                     new_message
+                   from
+                     federation_outbox
+                   where
+                     destination = ${identity_id_t = DbIdentity(destination.clone())}
+                     and owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                     and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+                     and status = ${str = STATUS_PENDING.to_string()}
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            if let Some(pending) = pending {
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"update federation_outbox
+                       set
+                         activity_offset = ${i64 = offset.0 as i64},
+                         new_message = ${bool = pending.new_message || new_message}
+                       where
+                         rowid = ${i64 = pending.rowid}
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+                continue;
+            }
+            let now = Timestamp::now();
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"insert into
+                     federation_outbox
+                     (destination, owner, channel, activity_offset, new_message, created, status, attempts, next_attempt)
+                   values (
+                     ${identity_id_t = DbIdentity(destination)},
+                     ${identity_id_t = DbIdentity(channel.identity.clone())},
+                     ${channel_id_t = DbChannelId(channel.channel.clone())},
+                     ${i64 = offset.0 as i64},
+                     ${bool = new_message},
+                     ${utctime_s_jiff = now},
+                     ${str = STATUS_PENDING.to_string()},
+                     ${i64 = 0},
+                     ${utctime_s_jiff = now}
+                   )
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            queued += 1;
+        }
+        return Ok(queued);
+    }).await?;
+    if queued > 0 {
+        state.wake.notify_one();
+    }
+    return Ok(());
+}
+
+/// Tells the local accounts in the channel about new activity. Returns the
+/// number of accounts notified (zero if nobody here is in the channel).
+pub async fn notify_local(
+    db: &Pool,
+    state: &FederationState,
+    channel: QualifiedChannelId,
+    offset: ActivityOffset,
+    push_body: Option<String>,
+) -> Result<usize, loga::Error> {
+    let accounts = tx(db, {
+        let channel = channel.clone();
+        move |db_tx| {
+            return Ok(good_query_many!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select distinct
+                     account.external_id
+                   from
+                     channel
+                     join account on channel.account_id = account.rowid
+                   where
+                     channel.identity = ${identity_id_t = DbIdentity(channel.identity)}
+                     and channel.id = ${channel_id_t = DbChannelId(channel.channel)}
+                     and channel.deleted is null
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?);
+        }
+    }).await?;
+    let mut out = vec![];
+    for a in accounts {
+        out.push(AccountExternalId::from_db(&a).map_err(loga::err)?);
+    }
+    if out.is_empty() {
+        return Ok(0);
+    }
+    let count = out.len();
+
+    // No receivers just means nobody is connected right now
+    _ = state.channel_activity.send(ChannelActivity {
+        accounts: out,
+        channel: channel,
+        offset: offset,
+        push_body: push_body,
+    });
+    return Ok(count);
+}
+
+/// Handles an incoming `Notify`, already checked to be from the owner.
+/// Notifications for channels nobody here is in are ignored.
+pub async fn handle_notify(db: &Pool, state: &FederationState, req: Notify) -> Result<(), loga::Error> {
+    notify_local(db, state, QualifiedChannelId {
+        identity: req.owner,
+        channel: req.channel,
+    }, req.offset, if req.new_message {
+        Some(format!("New message"))
+    } else {
+        None
+    }).await?;
+    return Ok(());
+}
+
+/// When the server may next be contacted, if it's backing off.
+async fn server_retry_at(db: &Pool, server: String) -> Result<Option<Timestamp>, loga::Error> {
+    return Ok(tx(db, move |db_tx| {
+        return Ok(good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 retry_at
+               from
+                 federation_server_backoff
+               where
+                 server = ${str = server}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?);
+    }).await?.filter(|t| *t > Timestamp::now()));
+}
+
+async fn record_server_result(db: &Pool, server: String, ok: bool) -> Result<(), loga::Error> {
+    tx(db, move |db_tx| {
+        if ok {
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"delete from federation_server_backoff where server = ${str = server}"#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            return Ok(());
+        }
+        let failures = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 failures
+               from
+                 federation_server_backoff
+               where
+                 server = ${str = server.clone()}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.unwrap_or(0) + 1;
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"insert into
+                 federation_server_backoff
+                 (server, failures, retry_at)
+               values (
+                 ${str = server},
+                 ${i64 = failures},
+                 ${utctime_s_jiff = Timestamp::now() + backoff(failures)}
+               )
+               on conflict do update set
+                 failures = excluded.failures,
+                 retry_at = excluded.retry_at
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(());
+    }).await?;
+    return Ok(());
+}
+
+enum Outcome {
+    Delivered,
+    /// The server is backing off, try again at this time without using an attempt
+    Postponed(Timestamp),
+    /// Not retried: federation policy doesn't allow the destination, or the owner
+    /// is gone
+    Dropped(String),
+}
+
+/// Everything the worker needs besides the queue.
+pub struct WorkerDeps {
+    pub secret_key: Option<SecretKey>,
+    pub auth_state: Arc<S2sAuthState>,
+    pub policy_state: Arc<FederationPolicyState>,
+    pub resolver_state: Arc<ResolverState>,
+}
+
+async fn deliver(
+    log: &Log,
+    db: &Pool,
+    allow_private: bool,
+    deps: &WorkerDeps,
+    destination: &Identity,
+    owner: &Identity,
+    notify: Notify,
+) -> Result<Outcome, loga::Error> {
+    let Some(resolved) = resolver::resolve(&deps.resolver_state, destination).await? else {
+        return Err(loga::err("Destination identity isn't published"));
+    };
+    let server = resolved.server.url;
+    if let Err(reason) = federationpolicy::check_outbound(db, &deps.policy_state, destination, &server).await? {
+        return Ok(Outcome::Dropped(reason));
+    }
+    if let Some(retry_at) = server_retry_at(db, server.clone()).await? {
+        return Ok(Outcome::Postponed(retry_at));
+    }
+    let stored_secret = tx(db, {
+        let owner = owner.clone();
+        move |db_tx| {
+            return Ok(good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     secret
+                   from
+                     identity
+                   where
+                     id = ${identity_id_t = DbIdentity(owner)}
+                     and soft_deleted_at is null
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?);
+        }
+    }).await?;
+    let Some(stored_secret) = stored_secret else {
+        return Ok(Outcome::Dropped(format!("Channel owner was deleted")));
+    };
+    let secret = identitysecret::open(deps.secret_key.as_ref(), &stored_secret.0)?;
+    let result = async {
+        let token = s2sauth::remote_token(log, &deps.auth_state, &server, &secret).await?;
+        s2sclient::post_with_token(log, allow_private, &server, &token, &s2sv1::Req::Notify(notify)).await?;
+        return Ok(()) as Result<(), loga::Error>;
+    }.await;
+    if result.is_err() {
+        // The token may be the problem (ex: the remote restarted), get a new one next
+        // time
+        s2sauth::drop_remote_token(&deps.auth_state, &server, owner).await;
+    }
+    record_server_result(db, server, result.is_ok()).await?;
+    result?;
+    return Ok(Outcome::Delivered);
+}
+
+/// Attempts all due notifications, returning how many were processed.
+async fn process_due(log: &Log, db: &Pool, allow_private: bool, deps: &WorkerDeps) -> Result<usize, loga::Error> {
+    let due = tx(db, |db_tx| {
+        return Ok(good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 rowid,
+                 destination,
+                 owner,
+                 channel,
+                 activity_offset,
+                 new_message,
+                 attempts
+               from
+                 federation_outbox
+               where
+                 status = ${str = STATUS_PENDING.to_string()}
+                 and next_attempt <= ${utctime_s_jiff = Timestamp::now()}
+               order by
+                 next_attempt
+               limit
+                 ${i64 = BATCH_SIZE}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?);
+    }).await?;
+    let count = due.len();
+    for d in due {
+        let destination = d.destination.0;
+        let owner = d.owner.0;
+        let result = deliver(log, db, allow_private, deps, &destination, &owner, Notify {
+            owner: owner.clone(),
+            channel: d.channel.0,
+            offset: ActivityOffset(d.activity_offset as usize),
+            new_message: d.new_message,
+        }).await;
+        let attempts;
+        let (status, last_error, next_attempt) = match result {
+            Ok(Outcome::Postponed(retry_at)) => {
+                attempts = d.attempts;
+                (STATUS_PENDING, None, retry_at)
+            },
+            Ok(Outcome::Delivered) => {
+                attempts = d.attempts + 1;
+                (STATUS_DELIVERED, None, Timestamp::now())
+            },
+            Ok(Outcome::Dropped(reason)) => {
+                attempts = d.attempts;
+                log.log_with(
+                    loga::DEBUG,
+                    "Dropping federated notification",
+                    ea!(notification = d.rowid, destination = destination, reason = reason),
+                );
+                (STATUS_FAILED, Some(reason), Timestamp::now())
+            },
+            Err(e) => {
+                attempts = d.attempts + 1;
+                let e = e.context_with(
+                    "Federated notification failed",
+                    ea!(notification = d.rowid, destination = destination, attempts = attempts),
+                );
+                let message = e.to_string();
+                if attempts >= MAX_ATTEMPTS {
+                    log.log_err(loga::WARN, e.context("Giving up"));
+                    (STATUS_FAILED, Some(message), Timestamp::now())
+                } else {
+                    log.log_err(loga::DEBUG, e);
+                    (STATUS_PENDING, Some(message), Timestamp::now() + backoff(attempts))
+                }
+            },
+        };
+        let rowid = d.rowid;
+        tx(db, move |db_tx| {
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"update federation_outbox
+                   set
+                     status = ${str = status.to_string()},
+                     attempts = ${i64 = attempts},
+                     next_attempt = ${utctime_s_jiff = next_attempt},
+                     last_error = ${str? = last_error}
+                   where
+                     rowid = ${i64 = rowid}
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            return Ok(());
+        }).await?;
+    }
+    return Ok(count);
+}
+
+async fn prune(db: &Pool) -> Result<(), loga::Error> {
+    let cutoff = Timestamp::now() - LOG_RETENTION;
+    tx(db, move |db_tx| {
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"delete from
+                 federation_outbox
+               where
+                 status != ${str = STATUS_PENDING.to_string()}
+                 and created < ${utctime_s_jiff = cutoff}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(());
+    }).await?;
+    return Ok(());
+}
+
+/// Sends queued notifications until shutdown. Anything in flight at shutdown is
+/// retried on the next start.
+pub fn spawn_worker(log: &Log, tm: &TaskManager, db: Pool, state: &FederationState, deps: WorkerDeps) {
+    let log = log.fork(ea!(sys = "federation"));
+    tm.task("federation", {
+        let tm = tm.clone();
+        let wake = state.wake.clone();
+        let allow_private = state.allow_private;
+        async move {
+            let mut last_prune = None;
+            loop {
+                if last_prune
+                    .map(|t: Timestamp| Timestamp::now().duration_since(t) > SignedDuration::from_hours(1))
+                    .unwrap_or(true) {
+                    if let Err(e) = prune(&db).await {
+                        log.log_err(loga::WARN, e.context("Error pruning federation queue"));
+                    }
+                    last_prune = Some(Timestamp::now());
+                }
+                let processed = match process_due(&log, &db, allow_private, &deps).await {
+                    Ok(n) => n,
+                    Err(e) => {
+                        log.log_err(loga::WARN, e.context("Error processing federation queue"));
+                        0
+                    },
+                };
+                if processed as i64 >= BATCH_SIZE {
+                    // More may be due already
+                    continue;
+                }
+                select!{
+                    _ = tm.until_terminate() => {
+                        break;
+                    },
+                    _ = wake.notified() => { },
+                    // Pick up retries whose backoff has elapsed
+                    _ = sleep(Duration::from_secs(30)) => { },
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            backoff,
+            enqueue_notify,
+            is_remote_member,
+            member_add,
+            member_delete,
+            member_list,
+            new_state,
+            notify_local,
+            record_server_result,
+            server_retry_at,
+            BACKOFF_BASE,
+            BACKOFF_MAX,
+        },
+        crate::{
+            dbutil::{
+                test_db,
+                tx,
+            },
+            interface::{
+                db::{
+                    DbAccountId,
+                    DbChannelId,
+                    DbIdentity,
+                    DbIdentitySecret,
+                },
+                AccountExternalId,
+            },
+            subsystems::{
+                identity::ensure_account,
+                identitysecret,
+            },
+        },
+        deadpool_sqlite::Pool,
+        good_ormning::sqlite::{
+            good_query,
+            good_query_many,
+        },
+        shared::interface::{
+            shared::{
+                ChannelId,
+                QualifiedChannelId,
+            },
+            wire::c2s::ActivityOffset,
+        },
+        spaghettinuum::interface::identity::{
+            Identity,
+            LocalIdentitySecret,
+        },
+    };
+
+    fn account(subject: &str) -> AccountExternalId {
+        return AccountExternalId {
+            issuer: "https://issuer.example.org".to_string(),
+            subject: subject.to_string(),
+        };
+    }
+
+    fn remote_identity() -> Identity {
+        return LocalIdentitySecret::new().0;
+    }
+
+    /// A local identity for the account that owns a channel and is in it.
+    async fn owned_channel(db: &Pool, account: AccountExternalId) -> (Identity, QualifiedChannelId) {
+        let (id, secret) = LocalIdentitySecret::new();
+        let stored = identitysecret::seal(None, &secret);
+        let channel = QualifiedChannelId {
+            identity: id.clone(),
+            channel: ChannelId(1),
+        };
+        tx(db, {
+            let id = id.clone();
+            move |db_tx| {
+                let account_id = ensure_account(db_tx, &account)?;
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"insert into
+                         identity
+                         (account_id, id, idem, memo_short, memo_long, secret)
+                       values (
+                         ${account_id_t = DbAccountId(account_id)},
+                         ${identity_id_t = DbIdentity(id.clone())},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${identity_secret_t = DbIdentitySecret(stored)}
+                       )
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"insert into
+                         channel
+                         (account_id, identity, id, idem, memo_short, memo_long, own_identity)
+                       values (
+                         ${account_id_t = DbAccountId(account_id)},
+                         ${identity_id_t = DbIdentity(id.clone())},
+                         ${channel_id_t = DbChannelId(ChannelId(1))},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${identity_id_t? = Some(DbIdentity(id))}
+                       )
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+                return Ok(());
+            }
+        }).await.unwrap();
+        return (id, channel);
+    }
+
+    /// (offset, new_message) of each pending notification.
+    async fn pending(db: &Pool) -> Vec<(i64, bool)> {
+        return tx(db, |db_tx| {
+            return Ok(good_query_many!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     activity_offset,
+                     new_message
+                   from
+                     federation_outbox
+                   where
+                     status = 'pending'
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?);
+        }).await.unwrap().into_iter().map(|r| (r.activity_offset, r.new_message)).collect();
+    }
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        assert_eq!(backoff(1), BACKOFF_BASE);
+        assert_eq!(backoff(2), BACKOFF_BASE * 2);
+        assert_eq!(backoff(100), BACKOFF_MAX);
+    }
+
+    #[tokio::test]
+    async fn members_managed_by_owner() {
+        let db = test_db().await;
+        let (owner, channel) = owned_channel(&db, account("a")).await;
+        let (other, _) = owned_channel(&db, account("b")).await;
+        let member = remote_identity();
+        assert!(member_add(&db, &account("b"), &channel, &member).await.unwrap().is_err());
+        assert!(member_add(&db, &account("a"), &channel, &other).await.unwrap().is_err());
+        member_add(&db, &account("a"), &channel, &member).await.unwrap().unwrap();
+
+        // Adding twice is fine
+        member_add(&db, &account("a"), &channel, &member).await.unwrap().unwrap();
+        assert!(is_remote_member(&db, &channel, &member).await.unwrap());
+        assert!(!is_remote_member(&db, &channel, &other).await.unwrap());
+        assert!(member_list(&db, &account("b"), &channel).await.unwrap().is_none());
+        assert!(member_list(&db, &account("a"), &channel).await.unwrap().unwrap() == vec![owner, member.clone()]);
+        assert!(member_delete(&db, &account("b"), &channel, &member).await.unwrap().is_none());
+        member_delete(&db, &account("a"), &channel, &member).await.unwrap().unwrap();
+        assert!(!is_remote_member(&db, &channel, &member).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn notify_queued_once_per_member() {
+        let db = test_db().await;
+        let state = new_state(false);
+        let (_, channel) = owned_channel(&db, account("a")).await;
+
+        // Nobody remote, nothing queued
+        enqueue_notify(&db, &state, channel.clone(), ActivityOffset(0), true).await.unwrap();
+        assert!(pending(&db).await.is_empty());
+        member_add(&db, &account("a"), &channel, &remote_identity()).await.unwrap().unwrap();
+        enqueue_notify(&db, &state, channel.clone(), ActivityOffset(1), true).await.unwrap();
+        enqueue_notify(&db, &state, channel.clone(), ActivityOffset(2), false).await.unwrap();
+        assert_eq!(pending(&db).await, vec![(2, true)]);
+        member_add(&db, &account("a"), &channel, &remote_identity()).await.unwrap().unwrap();
+        enqueue_notify(&db, &state, channel.clone(), ActivityOffset(3), false).await.unwrap();
+        let mut queued = pending(&db).await;
+        queued.sort();
+        assert_eq!(queued, vec![(3, false), (3, true)]);
+    }
+
+    #[tokio::test]
+    async fn local_members_notified() {
+        let db = test_db().await;
+        let state = new_state(false);
+        let mut activity = state.channel_activity.subscribe();
+        let (_, channel) = owned_channel(&db, account("a")).await;
+        let unknown = QualifiedChannelId {
+            identity: remote_identity(),
+            channel: ChannelId(1),
+        };
+        assert_eq!(notify_local(&db, &state, unknown, ActivityOffset(0), None).await.unwrap(), 0);
+        assert_eq!(
+            notify_local(&db, &state, channel.clone(), ActivityOffset(4), Some(format!("hi"))).await.unwrap(),
+            1
+        );
+        let got = activity.try_recv().unwrap();
+        assert!(got.accounts == vec![account("a")]);
+        assert!(got.channel == channel);
+        assert_eq!(got.offset.0, 4);
+        assert_eq!(got.push_body.as_deref(), Some("hi"));
+        assert!(activity.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn server_backoff_recorded_and_cleared() {
+        let db = test_db().await;
+        let server = "https://kwa.example.org/".to_string();
+        assert!(server_retry_at(&db, server.clone()).await.unwrap().is_none());
+        record_server_result(&db, server.clone(), false).await.unwrap();
+        assert!(server_retry_at(&db, server.clone()).await.unwrap().is_some());
+        record_server_result(&db, server.clone(), true).await.unwrap();
+        assert!(server_retry_at(&db, server.clone()).await.unwrap().is_none());
+    }
+}
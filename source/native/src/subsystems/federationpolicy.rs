@@ -0,0 +1,559 @@
+//! Which remote identities and servers this server talks to. The mode and
+//! allowlist come from the config; the denylist is in the database so it can be
+//! changed while the server runs (see the `federation-deny-*` subcommands) and is
+//! read on every check.
+//!
+//! Server rules are matched against hosts, never against what a remote claims
+//! about itself. Outgoing, that's the host we connect to. Incoming, the
+//! authenticated identity's published url is only used after that server confirms
+//! it hosts the identity (`IsHome`), so an identity can't borrow an allowlisted
+//! server's url. Anything that can't be confirmed is rejected.
+use {
+    crate::{
+        dbutil::tx,
+        interface::{
+            config::{
+                FederationConfig,
+                FederationMode,
+            },
+            s2s::{
+                s2sv1,
+                s2sv1t::IsHome,
+            },
+        },
+        subsystems::{
+            resolver::{
+                self,
+                ResolverState,
+            },
+            s2sclient,
+        },
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query_many,
+        good_query_opt,
+    },
+    htwrap::htserve::responses::{
+        body_full,
+        Body,
+    },
+    http::Uri,
+    hyper::Response,
+    jiff::Timestamp,
+    loga::{
+        ea,
+        Log,
+        ResultContext,
+    },
+    moka::future::Cache,
+    serde::Serialize,
+    spaghettinuum::interface::identity::Identity,
+    std::{
+        collections::HashSet,
+        str::FromStr,
+        time::Duration,
+    },
+};
+
+const KIND_IDENTITY: &str = "identity";
+const KIND_SERVER: &str = "server";
+const HOME_TTL: Duration = Duration::from_secs(60 * 10);
+
+pub enum DenyTarget {
+    Identity(Identity),
+    /// Server host or url, see `normalize_server`
+    Server(String),
+}
+
+impl DenyTarget {
+    fn kind(&self) -> &'static str {
+        match self {
+            DenyTarget::Identity(_) => return KIND_IDENTITY,
+            DenyTarget::Server(_) => return KIND_SERVER,
+        }
+    }
+
+    fn value(&self) -> String {
+        match self {
+            DenyTarget::Identity(i) => return i.to_string(),
+            DenyTarget::Server(s) => return normalize_server(s),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DenyEntry {
+    pub kind: String,
+    pub value: String,
+    pub memo: String,
+    pub created: Timestamp,
+}
+
+pub struct FederationPolicyState {
+    mode: FederationMode,
+    allow_servers: HashSet<String>,
+    allow_identities: HashSet<Identity>,
+    /// Remote answers to `IsHome`, by identity and server url.
+    home: Cache<(Identity, String), bool>,
+    /// See `s2sclient::connect`.
+    allow_private: bool,
+}
+
+pub fn new_state(config: FederationConfig, allow_private: bool) -> Result<FederationPolicyState, loga::Error> {
+    let mut allow_identities = HashSet::new();
+    for i in config.allow_identities {
+        let Ok(identity) = Identity::from_str(&i) else {
+            return Err(loga::err_with("Invalid identity in `federation.allow_identities`", ea!(identity = i)));
+        };
+        allow_identities.insert(identity);
+    }
+    return Ok(FederationPolicyState {
+        mode: config.mode,
+        allow_servers: config.allow_servers.iter().map(|s| normalize_server(s)).collect(),
+        allow_identities: allow_identities,
+        home: Cache::builder().max_capacity(10_000).time_to_live(HOME_TTL).build(),
+        allow_private: allow_private,
+    });
+}
+
+/// The lowercase host, from either a url or a bare host (with or without port).
+pub fn normalize_server(server: &str) -> String {
+    let server = server.trim().trim_end_matches('/');
+    if let Ok(uri) = server.parse::<Uri>() {
+        if let Some(host) = uri.host() {
+            return host.to_ascii_lowercase();
+        }
+    }
+    return server.to_ascii_lowercase();
+}
+
+async fn denied(db: &Pool, target: DenyTarget) -> Result<bool, loga::Error> {
+    return Ok(tx(db, move |db_tx| {
+        return Ok(good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 kind
+               from
+                 federation_deny
+               where
+                 kind = ${str = target.kind().to_string()}
+                 and value = ${str = target.value()}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.is_some());
+    }).await?);
+}
+
+async fn any_server_denied(db: &Pool) -> Result<bool, loga::Error> {
+    return Ok(tx(db, move |db_tx| {
+        return Ok(good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 kind
+               from
+                 federation_deny
+               where
+                 kind = ${str = KIND_SERVER.to_string()}
+               limit 1
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.is_some());
+    }).await?);
+}
+
+/// Whether this server talks to other servers at all.
+pub fn federates(state: &FederationPolicyState) -> bool {
+    return state.mode != FederationMode::Closed;
+}
+
+/// The rules that only need the identity. Enough for unauthenticated requests
+/// (`StartIdentify`), nothing is looked up. The inner error is the reason, for the
+/// remote server or logs.
+pub async fn check_identity(
+    db: &Pool,
+    state: &FederationPolicyState,
+    identity: &Identity,
+) -> Result<Result<(), String>, loga::Error> {
+    if !federates(state) {
+        return Ok(Err(format!("This server doesn't federate")));
+    }
+    if denied(db, DenyTarget::Identity(identity.clone())).await? {
+        return Ok(Err(format!("Identity [{}] is blocked by this server", identity)));
+    }
+    return Ok(Ok(()));
+}
+
+/// Whether server rules need the identity's host at all, to skip lookups when they
+/// can't change the result.
+async fn needs_server(db: &Pool, state: &FederationPolicyState, identity: &Identity) -> Result<bool, loga::Error> {
+    if state.mode == FederationMode::Allowlist && !state.allow_identities.contains(identity) {
+        return Ok(true);
+    }
+    return Ok(any_server_denied(db).await?);
+}
+
+/// The server rules, for an identity at `server` (a host or url, see
+/// `normalize_server`) that's been confirmed by the caller.
+pub async fn check_server(
+    db: &Pool,
+    state: &FederationPolicyState,
+    identity: &Identity,
+    server: &str,
+) -> Result<Result<(), String>, loga::Error> {
+    let server = normalize_server(server);
+    if denied(db, DenyTarget::Server(server.clone())).await? {
+        return Ok(Err(format!("Server [{}] is blocked by this server", server)));
+    }
+    if state.mode == FederationMode::Allowlist && !state.allow_identities.contains(identity) &&
+        !state.allow_servers.contains(&server) {
+        return Ok(Err(format!("This server only federates with allowlisted servers")));
+    }
+    return Ok(Ok(()));
+}
+
+/// Whether this server may contact `identity` at `server`, the url the request
+/// will be sent to.
+pub async fn check_outbound(
+    db: &Pool,
+    state: &FederationPolicyState,
+    identity: &Identity,
+    server: &str,
+) -> Result<Result<(), String>, loga::Error> {
+    if let Err(reason) = check_identity(db, state, identity).await? {
+        return Ok(Err(reason));
+    }
+    return Ok(check_server(db, state, identity, server).await?);
+}
+
+async fn ask_home(
+    log: &Log,
+    state: &FederationPolicyState,
+    server: &str,
+    identity: &Identity,
+) -> Result<bool, loga::Error> {
+    let body =
+        s2sclient::post(
+            log,
+            state.allow_private,
+            server,
+            &s2sv1::Req::IsHome(IsHome { identity: identity.clone() }),
+        ).await?;
+    return Ok(serde_json::from_slice::<bool>(&body).context_with("Invalid IsHome response", ea!(server = server))?);
+}
+
+/// Whether a request from `identity` is allowed. Only call after the identity has
+/// authenticated; this may look up the identity and contact its server.
+pub async fn check_inbound(
+    log: &Log,
+    db: &Pool,
+    state: &FederationPolicyState,
+    resolver_state: &ResolverState,
+    identity: &Identity,
+) -> Result<Result<(), String>, loga::Error> {
+    if let Err(reason) = check_identity(db, state, identity).await? {
+        return Ok(Err(reason));
+    }
+    if !needs_server(db, state, identity).await? {
+        return Ok(Ok(()));
+    }
+    let server = match resolver::resolve(resolver_state, identity).await {
+        Ok(Some(r)) => r.server.url,
+        Ok(None) => {
+            return Ok(Err(format!("Identity [{}] doesn't publish a server", identity)));
+        },
+        Err(e) => {
+            log.log_err(loga::DEBUG, e.context_with("Couldn't resolve remote identity", ea!(identity = identity)));
+            return Ok(Err(format!("Couldn't look up the server for identity [{}]", identity)));
+        },
+    };
+    let home_key = (identity.clone(), server.clone());
+    let home = match state.home.get(&home_key).await {
+        Some(h) => h,
+        None => {
+            let home = match ask_home(log, state, &server, identity).await {
+                Ok(h) => h,
+                Err(e) => {
+                    log.log_err(
+                        loga::DEBUG,
+                        e.context_with("Couldn't confirm remote identity's server", ea!(identity = identity)),
+                    );
+                    return Ok(Err(format!("Couldn't confirm the server for identity [{}]", identity)));
+                },
+            };
+            state.home.insert(home_key, home).await;
+            home
+        },
+    };
+    if !home {
+        return Ok(Err(format!("Identity [{}] isn't hosted at the server it publishes", identity)));
+    }
+    return Ok(check_server(db, state, identity, &server).await?);
+}
+
+pub fn response_403(reason: String) -> Response<Body> {
+    return Response::builder().status(403).body(body_full(reason.into_bytes())).unwrap();
+}
+
+/// Returns false if it was already on the denylist.
+pub async fn deny_add(db: &Pool, target: DenyTarget, memo: String) -> Result<bool, loga::Error> {
+    return Ok(tx(db, move |db_tx| {
+        return Ok(good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"insert into
+                 federation_deny
+                 (kind, value, memo, created)
+               values (
+                 ${str = target.kind().to_string()},
+                 ${str = target.value()},
+                 ${str = memo},
+                 ${utctime_s_jiff = Timestamp::now()}
+               )
+               on conflict do nothing
+               returning kind
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.is_some());
+    }).await?);
+}
+
+/// Returns false if it wasn't on the denylist.
+pub async fn deny_remove(db: &Pool, target: DenyTarget) -> Result<bool, loga::Error> {
+    return Ok(tx(db, move |db_tx| {
+        return Ok(good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"delete from
+                 federation_deny
+               where
+                 kind = ${str = target.kind().to_string()}
+                 and value = ${str = target.value()}
+               returning kind
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.is_some());
+    }).await?);
+}
+
+pub async fn deny_list(db: &Pool) -> Result<Vec<DenyEntry>, loga::Error> {
+    return Ok(tx(db, |db_tx| {
+        return Ok(good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 kind,
+                 value,
+                 memo,
+                 created
+               from
+                 federation_deny
+               order by
+                 created
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.into_iter().map(|r| DenyEntry {
+            kind: r.kind,
+            value: r.value,
+            memo: r.memo,
+            created: r.created,
+        }).collect());
+    }).await?);
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            check_identity,
+            check_inbound,
+            check_outbound,
+            check_server,
+            deny_add,
+            deny_remove,
+            new_state,
+            normalize_server,
+            DenyTarget,
+            FederationPolicyState,
+        },
+        crate::{
+            dbutil::test_db,
+            interface::config::{
+                FederationConfig,
+                FederationMode,
+            },
+            subsystems::resolver::{
+                self,
+                ResolverState,
+                TestBackend,
+            },
+        },
+        loga::Log,
+        rand::distr::{
+            Alphanumeric,
+            SampleString,
+        },
+        spaghettinuum::interface::identity::{
+            Identity,
+            LocalIdentitySecret,
+        },
+        std::sync::{
+            atomic::Ordering,
+            Arc,
+        },
+    };
+
+    fn identity() -> Identity {
+        return LocalIdentitySecret::new().0;
+    }
+
+    fn state(mode: FederationMode, allow_servers: &[&str], allow_identities: &[&Identity]) -> FederationPolicyState {
+        return new_state(FederationConfig {
+            mode: mode,
+            allow_servers: allow_servers.iter().map(|s| s.to_string()).collect(),
+            allow_identities: allow_identities.iter().map(|i| i.to_string()).collect(),
+        }, false).unwrap();
+    }
+
+    fn resolver_state(backend: &Arc<TestBackend>) -> ResolverState {
+        return resolver::new_state(
+            backend.clone(),
+            &std::env::temp_dir().join(format!("kwa-test-{}", Alphanumeric.sample_string(&mut rand::rng(), 16))),
+        );
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_server("https://Kwa.Example.org/"), "kwa.example.org");
+        assert_eq!(normalize_server("https://kwa.example.org:8443/base/"), "kwa.example.org");
+        assert_eq!(normalize_server("kwa.example.org"), "kwa.example.org");
+        assert_eq!(normalize_server(" KWA.example.org:8443 "), "kwa.example.org");
+    }
+
+    #[test]
+    fn invalid_allowed_identity() {
+        assert!(new_state(FederationConfig {
+            mode: FederationMode::Allowlist,
+            allow_servers: vec![],
+            allow_identities: vec!["nope".to_string()],
+        }, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn identity_rules() {
+        let db = test_db().await;
+        let id = identity();
+        assert!(check_identity(&db, &state(FederationMode::Open, &[], &[]), &id).await.unwrap().is_ok());
+        assert!(check_identity(&db, &state(FederationMode::Closed, &[], &[&id]), &id).await.unwrap().is_err());
+        assert!(deny_add(&db, DenyTarget::Identity(id.clone()), String::new()).await.unwrap());
+        assert!(!deny_add(&db, DenyTarget::Identity(id.clone()), String::new()).await.unwrap());
+        assert!(check_identity(&db, &state(FederationMode::Open, &[], &[]), &id).await.unwrap().is_err());
+        assert!(check_identity(&db, &state(FederationMode::Open, &[], &[]), &identity()).await.unwrap().is_ok());
+        assert!(deny_remove(&db, DenyTarget::Identity(id.clone())).await.unwrap());
+        assert!(check_identity(&db, &state(FederationMode::Open, &[], &[]), &id).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn server_rules() {
+        let db = test_db().await;
+        let id = identity();
+        let allowed = identity();
+        let open = state(FederationMode::Open, &[], &[]);
+        let allowlist = state(FederationMode::Allowlist, &["https://a.example.org/"], &[&allowed]);
+        assert!(check_server(&db, &open, &id, "https://b.example.org/").await.unwrap().is_ok());
+        assert!(check_server(&db, &allowlist, &id, "https://A.example.org:8443/").await.unwrap().is_ok());
+        assert!(check_server(&db, &allowlist, &id, "https://b.example.org/").await.unwrap().is_err());
+        assert!(check_server(&db, &allowlist, &allowed, "https://b.example.org/").await.unwrap().is_ok());
+
+        // The denylist applies even to allowlisted servers and identities
+        deny_add(&db, DenyTarget::Server("a.example.org".to_string()), String::new()).await.unwrap();
+        deny_add(&db, DenyTarget::Server("https://b.example.org/".to_string()), String::new()).await.unwrap();
+        assert!(check_server(&db, &open, &id, "https://b.example.org/x/").await.unwrap().is_err());
+        assert!(check_server(&db, &allowlist, &id, "https://a.example.org/").await.unwrap().is_err());
+        assert!(check_server(&db, &allowlist, &allowed, "https://b.example.org/").await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn outbound() {
+        let db = test_db().await;
+        let id = identity();
+        let allowlist = state(FederationMode::Allowlist, &["a.example.org"], &[]);
+        assert!(check_outbound(&db, &allowlist, &id, "https://a.example.org/").await.unwrap().is_ok());
+        assert!(check_outbound(&db, &allowlist, &id, "https://b.example.org/").await.unwrap().is_err());
+        assert!(
+            check_outbound(&db, &state(FederationMode::Closed, &["a.example.org"], &[]), &id, "https://a.example.org/")
+                .await
+                .unwrap()
+                .is_err()
+        );
+        deny_add(&db, DenyTarget::Identity(id.clone()), String::new()).await.unwrap();
+        assert!(check_outbound(&db, &allowlist, &id, "https://a.example.org/").await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn inbound_without_lookup() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        let backend = TestBackend::new();
+        let resolver_state = resolver_state(&backend);
+        let id = identity();
+
+        // Server rules can't change the result, so nothing is looked up
+        let open = state(FederationMode::Open, &[], &[]);
+        assert!(check_inbound(&log, &db, &open, &resolver_state, &id).await.unwrap().is_ok());
+        let allowlist = state(FederationMode::Allowlist, &[], &[&id]);
+        assert!(check_inbound(&log, &db, &allowlist, &resolver_state, &id).await.unwrap().is_ok());
+        assert_eq!(backend.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn inbound_unconfirmed_rejected() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        let backend = TestBackend::new();
+        let resolver_state = resolver_state(&backend);
+        let allowlist = state(FederationMode::Allowlist, &["a.example.org"], &[]);
+
+        // Not published
+        let id = identity();
+        assert!(check_inbound(&log, &db, &allowlist, &resolver_state, &id).await.unwrap().is_err());
+
+        // Lookup fails, rejected rather than an error
+        let id = identity();
+        backend.fail.store(true, Ordering::SeqCst);
+        assert!(check_inbound(&log, &db, &allowlist, &resolver_state, &id).await.unwrap().is_err());
+        backend.fail.store(false, Ordering::SeqCst);
+
+        // Publishes an allowlisted server that doesn't host it
+        let id = identity();
+        backend.publish(&id, "https://a.example.org/");
+        allowlist.home.insert((id.clone(), "https://a.example.org/".to_string()), false).await;
+        assert!(check_inbound(&log, &db, &allowlist, &resolver_state, &id).await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn inbound_confirmed() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        let backend = TestBackend::new();
+        let resolver_state = resolver_state(&backend);
+        let allowlist = state(FederationMode::Allowlist, &["a.example.org"], &[]);
+        let at_a = identity();
+        backend.publish(&at_a, "https://a.example.org/");
+        allowlist.home.insert((at_a.clone(), "https://a.example.org/".to_string()), true).await;
+        let at_b = identity();
+        backend.publish(&at_b, "https://b.example.org/");
+        allowlist.home.insert((at_b.clone(), "https://b.example.org/".to_string()), true).await;
+        assert!(check_inbound(&log, &db, &allowlist, &resolver_state, &at_a).await.unwrap().is_ok());
+        assert!(check_inbound(&log, &db, &allowlist, &resolver_state, &at_b).await.unwrap().is_err());
+
+        // Open, but the confirmed server is denied
+        let open = state(FederationMode::Open, &[], &[]);
+        open.home.insert((at_b.clone(), "https://b.example.org/".to_string()), true).await;
+        deny_add(&db, DenyTarget::Server("b.example.org".to_string()), String::new()).await.unwrap();
+        assert!(check_inbound(&log, &db, &open, &resolver_state, &at_b).await.unwrap().is_err());
+    }
+}
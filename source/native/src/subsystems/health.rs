@@ -0,0 +1,98 @@
+//! Liveness and readiness for orchestrators and load balancers, and the drain
+//! signal used for graceful shutdown.
+//!
+//! `/healthz` is OK whenever the process is serving. `/readyz` is OK once every
+//! startup stage has finished and until shutdown starts.
+use {
+    htwrap::htserve::responses::{
+        body_full,
+        Body,
+    },
+    http::Response,
+    serde::Serialize,
+    std::sync::atomic::{
+        AtomicBool,
+        Ordering,
+    },
+    tokio::sync::watch,
+};
+
+pub struct HealthState {
+    db_migrated: AtomicBool,
+    oidc_discovered: AtomicBool,
+    spagh_bootstrapped: AtomicBool,
+    draining: watch::Sender<bool>,
+}
+
+impl HealthState {
+    pub fn new() -> Self {
+        return Self {
+            db_migrated: AtomicBool::new(false),
+            oidc_discovered: AtomicBool::new(false),
+            spagh_bootstrapped: AtomicBool::new(false),
+            draining: watch::Sender::new(false),
+        };
+    }
+
+    pub fn set_db_migrated(&self) {
+        self.db_migrated.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_oidc_discovered(&self) {
+        self.oidc_discovered.store(true, Ordering::Relaxed);
+    }
+
+    /// A published identity resolved back to this server (see
+    /// `spagh::spawn_republish`).
+    pub fn set_spagh_bootstrapped(&self) {
+        self.spagh_bootstrapped.store(true, Ordering::Relaxed);
+    }
+
+    /// Marks the server not ready, stops accepting connections, and tells open
+    /// connections to close once their in-flight requests finish.
+    pub fn start_drain(&self) {
+        self.draining.send_replace(true);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        return *self.draining.borrow();
+    }
+
+    /// Resolves (via `wait_for(|d| *d)`) when shutdown starts.
+    pub fn draining(&self) -> watch::Receiver<bool> {
+        return self.draining.subscribe();
+    }
+}
+
+#[derive(Serialize)]
+struct ReadyzRes {
+    ready: bool,
+    db_migrated: bool,
+    oidc_discovered: bool,
+    spagh_bootstrapped: bool,
+    draining: bool,
+}
+
+pub fn response_healthz() -> Response<Body> {
+    return Response::builder().status(200).body(body_full(b"ok".to_vec())).unwrap();
+}
+
+pub fn response_readyz(state: &HealthState) -> Response<Body> {
+    let mut res = ReadyzRes {
+        ready: false,
+        db_migrated: state.db_migrated.load(Ordering::Relaxed),
+        oidc_discovered: state.oidc_discovered.load(Ordering::Relaxed),
+        spagh_bootstrapped: state.spagh_bootstrapped.load(Ordering::Relaxed),
+        draining: state.is_draining(),
+    };
+    res.ready = res.db_migrated && res.oidc_discovered && res.spagh_bootstrapped && !res.draining;
+    return Response::builder()
+        .status(if res.ready {
+            200
+        } else {
+            503
+        })
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body_full(serde_json::to_vec(&res).unwrap()))
+        .unwrap();
+}
@@ -0,0 +1,342 @@
+//! Local identities. Secrets never leave the server except through
+//! `identitybundle`. Changes are published through `spagh`.
+use {
+    crate::{
+        dbutil::{
+            abortable_tx,
+            tx,
+            Txr,
+        },
+        interface::{
+            db::{
+                DbAccountId,
+                DbIdentity,
+                DbIdentitySecret,
+            },
+            spagh::PublishedProfile,
+            AccountExternalId,
+        },
+        subsystems::{
+            identitysecret::{
+                self,
+                SecretKey,
+            },
+            spagh::{
+                self,
+                SpaghState,
+            },
+        },
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query,
+        good_query_many,
+        good_query_opt,
+    },
+    jiff::Timestamp,
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+    },
+    rand::distr::{
+        Alphanumeric,
+        SampleString,
+    },
+    shared::interface::{
+        shared::AccountId,
+        wire::c2s::{
+            IdentityCreate,
+            IdentityModify,
+            IdentityRes,
+        },
+    },
+    spaghettinuum::interface::identity::{
+        Identity,
+        LocalIdentitySecret,
+    },
+};
+
+/// The account's rowid, creating the account row if this is its first use.
+pub fn ensure_account(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    account: &AccountExternalId,
+) -> Result<AccountId, loga::Error> {
+    good_query!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"insert into
+             account
+             (external_id)
+           values (
+             ${str = account.to_db()}
+           )
+           on conflict do nothing
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?;
+    let rowid = good_query!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"select
+             rowid
+           from
+             account
+           where
+             external_id = ${str = account.to_db()}
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?;
+    return Ok(AccountId(rowid as u64));
+}
+
+/// Whether the identity lives on this server.
+pub async fn is_local(db: &Pool, id: &Identity) -> Result<bool, loga::Error> {
+    let id = id.clone();
+    return Ok(tx(db, move |db_tx| {
+        return Ok(good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 id
+               from
+                 identity
+               where
+                 id = ${identity_id_t = DbIdentity(id)}
+                 and soft_deleted_at is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.is_some());
+    }).await?);
+}
+
+/// Publishing failures are logged rather than returned; the change is already
+/// committed and the periodic republish will catch up.
+async fn publish(log: &Log, spagh: &SpaghState, secret: &LocalIdentitySecret, memo_short: &str) {
+    if let Err(e) = spagh::publish_identity(log, spagh, secret, &PublishedProfile {
+        memo_short: memo_short.to_string(),
+    }).await {
+        log.log_err(loga::WARN, e.context_with("Error publishing identity", ea!(identity = secret.identity())));
+    }
+}
+
+pub async fn create(
+    log: &Log,
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    spagh: &SpaghState,
+    account: &AccountExternalId,
+    req: IdentityCreate,
+) -> Result<IdentityRes, loga::Error> {
+    let idem = req.idem.unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::rng(), 16));
+    let (id, secret) = LocalIdentitySecret::new();
+    let (res, created) = tx(db, {
+        let account = account.clone();
+        let id = id.clone();
+        let stored_secret = identitysecret::seal(secret_key, &secret);
+        move |db_tx| {
+            let account_id = ensure_account(db_tx, &account)?;
+
+            // Retried creates return the original identity
+            if let Some(existing) = good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     id,
+                     memo_short,
+                     memo_long
+                   from
+                     identity
+                   where
+                     account_id = ${account_id_t = DbAccountId(account_id)}
+                     and idem = ${str = idem.clone()}
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))? {
+                return Ok((IdentityRes {
+                    id: existing.id.0,
+                    idem: Some(idem),
+                    memo_short: existing.memo_short,
+                    memo_long: existing.memo_long,
+                }, false));
+            }
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"insert into
+                     identity
+                     (account_id, id, idem, memo_short, memo_long, secret)
+                   values (
+                     ${account_id_t = DbAccountId(account_id)},
+                     ${identity_id_t = DbIdentity(id.clone())},
+                     ${str = idem.clone()},
+                     ${str = req.memo_short.clone()},
+                     ${str = req.memo_long.clone()},
+                     ${identity_secret_t = DbIdentitySecret(stored_secret)}
+                   )
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            return Ok((IdentityRes {
+                id: id,
+                idem: Some(idem),
+                memo_short: req.memo_short,
+                memo_long: req.memo_long,
+            }, true));
+        }
+    }).await?;
+    if created {
+        publish(log, spagh, &secret, &res.memo_short).await;
+    }
+    return Ok(res);
+}
+
+/// Returns `None` if the identity doesn't belong to the account.
+pub async fn modify(
+    log: &Log,
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    spagh: &SpaghState,
+    account: &AccountExternalId,
+    req: IdentityModify,
+) -> Result<Option<IdentityRes>, loga::Error> {
+    let account = account.clone();
+    let Some((res, stored_secret)) = abortable_tx(db, move |db_tx| {
+        let Some(row) = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 identity.account_id,
+                 identity.idem,
+                 identity.memo_short,
+                 identity.memo_long,
+                 identity.secret
+               from
+                 identity
+                 join account on identity.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+                 and identity.id = ${identity_id_t = DbIdentity(req.id.clone())}
+                 and identity.soft_deleted_at is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))? else {
+            return Ok(Txr::Abort);
+        };
+        let memo_short = req.memo_short.unwrap_or(row.memo_short);
+        let memo_long = req.memo_long.unwrap_or(row.memo_long);
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"update
+                 identity
+               set
+                 memo_short = ${str = memo_short.clone()},
+                 memo_long = ${str = memo_long.clone()}
+               where
+                 account_id = ${account_id_t = row.account_id}
+                 and id = ${identity_id_t = DbIdentity(req.id.clone())}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(Txr::Ok((IdentityRes {
+            id: req.id,
+            idem: Some(row.idem),
+            memo_short: memo_short,
+            memo_long: memo_long,
+        }, row.secret.0)));
+    }).await? else {
+        return Ok(None);
+    };
+    publish(log, spagh, &identitysecret::open(secret_key, &stored_secret)?, &res.memo_short).await;
+    return Ok(Some(res));
+}
+
+/// Soft deletes the identity (it stays for channel history) and withdraws its
+/// publication. Does nothing if the identity doesn't belong to the account.
+pub async fn delete(
+    log: &Log,
+    db: &Pool,
+    spagh: &SpaghState,
+    account: &AccountExternalId,
+    id: Identity,
+) -> Result<(), loga::Error> {
+    if !soft_delete(db, account, &id).await? {
+        return Ok(());
+    }
+    if let Err(e) = spagh::unpublish_identity(spagh, &id).await {
+        log.log_err(loga::WARN, e);
+    }
+    return Ok(());
+}
+
+/// The database half of `delete`; the identity is no longer listed or
+/// republished. Returns false if there was no such live identity in the account.
+pub async fn soft_delete(db: &Pool, account: &AccountExternalId, id: &Identity) -> Result<bool, loga::Error> {
+    let account = account.clone();
+    let deleted = abortable_tx(db, {
+        let id = id.clone();
+        move |db_tx| {
+            let Some(row) = good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     identity.account_id
+                   from
+                     identity
+                     join account on identity.account_id = account.rowid
+                   where
+                     account.external_id = ${str = account.to_db()}
+                     and identity.id = ${identity_id_t = DbIdentity(id.clone())}
+                     and identity.soft_deleted_at is null
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))? else {
+                return Ok(Txr::Abort);
+            };
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"update
+                     identity
+                   set
+                     soft_deleted_at = ${utctime_s_jiff = Timestamp::now()}
+                   where
+                     account_id = ${account_id_t = row}
+                     and id = ${identity_id_t = DbIdentity(id)}
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            return Ok(Txr::Ok(()));
+        }
+    }).await?;
+    return Ok(deleted.is_some());
+}
+
+pub async fn list(db: &Pool, account: &AccountExternalId) -> Result<Vec<IdentityRes>, loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        return Ok(good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 identity.id,
+                 identity.idem,
+                 identity.memo_short,
+                 identity.memo_long
+               from
+                 identity
+                 join account on identity.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+                 and identity.soft_deleted_at is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.into_iter().map(|r| IdentityRes {
+            id: r.id.0,
+            idem: Some(r.idem),
+            memo_short: r.memo_short,
+            memo_long: r.memo_long,
+        }).collect());
+    }).await?);
+}
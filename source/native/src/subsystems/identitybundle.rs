@@ -0,0 +1,357 @@
+//! Moving an identity between servers. The bundle is JSON with the identity's
+//! public details and its secret sealed with a user passphrase (see
+//! `passphrase`). Importing publishes the new server as the identity's home, so
+//! remote servers follow it there. Exporting can withdraw the identity from the
+//! old server, otherwise both claim it until it's deleted there. Deleted
+//! identities can be imported again, to move them back.
+use {
+    crate::{
+        dbutil::{
+            abortable_tx,
+            tx,
+            Txr,
+        },
+        interface::{
+            db::{
+                DbAccountId,
+                DbIdentity,
+                DbIdentitySecret,
+            },
+            spagh::PublishedProfile,
+            AccountExternalId,
+        },
+        passphrase,
+        subsystems::{
+            identity,
+            identitysecret::{
+                self,
+                SecretKey,
+            },
+            spagh::{
+                self,
+                SpaghState,
+            },
+        },
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query,
+        good_query_opt,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    rand::distr::{
+        Alphanumeric,
+        SampleString,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    shared::interface::wire::c2s::{
+        IdentityExportRes,
+        IdentityImport,
+        IdentityRes,
+    },
+    spaghettinuum::interface::identity::{
+        Identity,
+        LocalIdentitySecret,
+    },
+};
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+struct Bundle {
+    format: u32,
+    identity: Identity,
+    memo_short: String,
+    memo_long: String,
+    /// JSON `LocalIdentitySecret`
+    secret: passphrase::Sealed,
+}
+
+/// Returns `None` if the identity doesn't belong to the account. If `withdraw`
+/// the identity is deleted here after the bundle is made, which also stops it
+/// being republished from here.
+pub async fn export(
+    log: &Log,
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    spagh: &SpaghState,
+    account: &AccountExternalId,
+    id: Identity,
+    passphrase: String,
+    withdraw: bool,
+) -> Result<Option<IdentityExportRes>, loga::Error> {
+    let Some(res) = make_bundle(db, secret_key, account, id.clone(), passphrase).await? else {
+        return Ok(None);
+    };
+    if withdraw {
+        identity::delete(log, db, spagh, account, id).await?;
+    }
+    return Ok(Some(res));
+}
+
+async fn make_bundle(
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    account: &AccountExternalId,
+    id: Identity,
+    passphrase: String,
+) -> Result<Option<IdentityExportRes>, loga::Error> {
+    let Some(row) = tx(db, {
+        let account = account.clone();
+        let id = id.clone();
+        move |db_tx| {
+            return Ok(good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     identity.memo_short,
+                     identity.memo_long,
+                     identity.secret
+                   from
+                     identity
+                     join account on identity.account_id = account.rowid
+                   where
+                     account.external_id = ${str = account.to_db()}
+                     and identity.id = ${identity_id_t = DbIdentity(id)}
+                     and identity.soft_deleted_at is null
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?);
+        }
+    }).await? else {
+        return Ok(None);
+    };
+    let DbIdentitySecret(secret) = row.secret;
+    let secret = identitysecret::open(secret_key, &secret)?;
+
+    // Key derivation is deliberately slow
+    let sealed =
+        tokio::task::spawn_blocking(move || passphrase::seal(&passphrase, &serde_json::to_vec(&secret).unwrap()))
+            .await
+            .context("Sealing task panicked")??;
+    let bundle = serde_json::to_string(&Bundle {
+        format: FORMAT_VERSION,
+        identity: id.clone(),
+        memo_short: row.memo_short,
+        memo_long: row.memo_long,
+        secret: sealed,
+    }).unwrap();
+    return Ok(Some(IdentityExportRes { bundle: bundle }));
+}
+
+/// The inner error is a message for the user (bad bundle, wrong passphrase,
+/// identity already here). An identity deleted here is restored, to the importing
+/// account.
+pub async fn import(
+    log: &Log,
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    spagh: &SpaghState,
+    account: &AccountExternalId,
+    req: IdentityImport,
+) -> Result<Result<IdentityRes, String>, loga::Error> {
+    let Ok(bundle) = serde_json::from_str::<Bundle>(&req.bundle) else {
+        return Ok(Err(format!("This isn't an identity bundle")));
+    };
+    if bundle.format != FORMAT_VERSION {
+        return Ok(Err(format!("Unsupported identity bundle version [{}]", bundle.format)));
+    }
+    let Ok(secret) = tokio::task::spawn_blocking({
+        let sealed = bundle.secret.clone();
+        move || passphrase::open(&req.passphrase, &sealed)
+    }).await.context("Unsealing task panicked")? else {
+        return Ok(Err(format!("Wrong passphrase or damaged bundle")));
+    };
+    let Ok(secret) = serde_json::from_slice::<LocalIdentitySecret>(&secret) else {
+        return Ok(Err(format!("Bundle contains an invalid identity secret")));
+    };
+    if secret.identity() != bundle.identity {
+        return Ok(Err(format!("Bundle secret doesn't match the bundle identity")));
+    }
+    let idem = req.idem.clone().unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::rng(), 16));
+    let inserted = abortable_tx(db, {
+        let account = account.clone();
+        let identity = bundle.identity.clone();
+        let stored_secret = identitysecret::seal(secret_key, &secret);
+        let idem = idem.clone();
+        let memo_short = bundle.memo_short.clone();
+        let memo_long = bundle.memo_long.clone();
+        move |db_tx| {
+            // Identities are unique per server, not per account - two homes would be
+            // ambiguous to everyone else
+            let existing = good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     soft_deleted_at
+                   from
+                     identity
+                   where
+                     id = ${identity_id_t = DbIdentity(identity.clone())}
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            let account_id = identity::ensure_account(db_tx, &account)?;
+            match existing {
+                Some(None) => {
+                    return Ok(Txr::Abort);
+                },
+                Some(Some(_)) => {
+                    // Moving back, the row is kept for channel history
+                    good_query!(
+                        crate::db,
+                        //# genemichaels-external: sql-formatter-sqlite
+                        r#"update
+                             identity
+                           set
+                             account_id = ${account_id_t = DbAccountId(account_id)},
+                             idem = ${str = idem},
+                             memo_short = ${str = memo_short},
+                             memo_long = ${str = memo_long},
+                             secret = ${identity_secret_t = DbIdentitySecret(stored_secret)},
+                             soft_deleted_at = null
+                           where
+                             id = ${identity_id_t = DbIdentity(identity)}
+                           "#;
+                        &mut db_tx
+                    ).map_err(|e| loga::err(e.0))?;
+                    return Ok(Txr::Ok(()));
+                },
+                None => { },
+            }
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"insert into
+                     identity
+                     (account_id, id, idem, memo_short, memo_long, secret)
+                   values (
+                     ${account_id_t = DbAccountId(account_id)},
+                     ${identity_id_t = DbIdentity(identity)},
+                     ${str = idem},
+                     ${str = memo_short},
+                     ${str = memo_long},
+                     ${identity_secret_t = DbIdentitySecret(stored_secret)}
+                   )
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            return Ok(Txr::Ok(()));
+        }
+    }).await?;
+    if inserted.is_none() {
+        return Ok(Err(format!("This identity is already on this server")));
+    }
+
+    // The identity is usable here either way, so a publishing failure doesn't fail
+    // the import
+    if let Err(e) = spagh::publish_identity(log, spagh, &secret, &PublishedProfile {
+        memo_short: bundle.memo_short.clone(),
+    }).await {
+        log.log_err(
+            loga::WARN,
+            e.context_with("Error publishing imported identity", ea!(identity = bundle.identity)),
+        );
+    }
+    return Ok(Ok(IdentityRes {
+        id: bundle.identity,
+        idem: Some(idem),
+        memo_short: bundle.memo_short,
+        memo_long: bundle.memo_long,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::make_bundle,
+        crate::{
+            dbutil::{
+                test_db,
+                tx,
+            },
+            interface::{
+                db::{
+                    DbAccountId,
+                    DbIdentity,
+                    DbIdentitySecret,
+                },
+                AccountExternalId,
+            },
+            subsystems::{
+                identity::{
+                    ensure_account,
+                    soft_delete,
+                },
+                identitysecret,
+                spagh::republish_list,
+            },
+        },
+        good_ormning::sqlite::good_query,
+        shared::interface::wire::c2s::IdentityExport,
+        spaghettinuum::interface::identity::LocalIdentitySecret,
+    };
+
+    fn account() -> AccountExternalId {
+        return AccountExternalId {
+            issuer: "https://issuer.example.org".to_string(),
+            subject: "a".to_string(),
+        };
+    }
+
+    #[test]
+    fn withdraw_by_default() {
+        let (id, _) = LocalIdentitySecret::new();
+        let req = serde_json::from_value::<IdentityExport>(serde_json::json!({
+            "id": id,
+            "passphrase": "pw",
+        })).unwrap();
+        assert!(req.withdraw);
+    }
+
+    #[tokio::test]
+    async fn withdrawn_not_republished() {
+        let db = test_db().await;
+        let (id, secret) = LocalIdentitySecret::new();
+        let stored = identitysecret::seal(None, &secret);
+        tx(&db, {
+            let id = id.clone();
+            move |db_tx| {
+                let account_id = ensure_account(db_tx, &account())?;
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"insert into
+                         identity
+                         (account_id, id, idem, memo_short, memo_long, secret)
+                       values (
+                         ${account_id_t = DbAccountId(account_id)},
+                         ${identity_id_t = DbIdentity(id)},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${identity_secret_t = DbIdentitySecret(stored)}
+                       )
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+                return Ok(());
+            }
+        }).await.unwrap();
+        assert!(republish_list(&db).await.unwrap().iter().any(|r| r.id == id));
+
+        // What `export` does with `withdraw`, minus withdrawing the publication
+        make_bundle(&db, None, &account(), id.clone(), "pw".to_string()).await.unwrap().unwrap();
+        assert!(soft_delete(&db, &account(), &id).await.unwrap());
+        assert!(!republish_list(&db).await.unwrap().iter().any(|r| r.id == id));
+    }
+}
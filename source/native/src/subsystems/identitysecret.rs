@@ -0,0 +1,354 @@
+//! Encryption at rest for identity secrets, so a copy of the database (or a
+//! backup) isn't enough to impersonate identities. Secrets are sealed with
+//! XChaCha20-Poly1305 under a master key that lives outside the database.
+//!
+//! Without a configured key secrets are stored as plain JSON like before. Once a
+//! key is configured, plain rows are encrypted at startup and the server refuses to
+//! start without the key.
+use {
+    crate::{
+        dbutil::tx,
+        interface::{
+            config::SecretKeySource,
+            db::{
+                DbIdentitySecret,
+                EncryptedIdentitySecret,
+                StoredIdentitySecret,
+            },
+        },
+    },
+    base64::{
+        engine::general_purpose::STANDARD,
+        Engine,
+    },
+    chacha20poly1305::{
+        aead::Aead,
+        KeyInit,
+        XChaCha20Poly1305,
+        XNonce,
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query,
+        good_query_many,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    rand::{
+        rng,
+        Rng,
+    },
+    sha2::{
+        Digest,
+        Sha256,
+    },
+    spaghettinuum::interface::identity::LocalIdentitySecret,
+};
+
+#[derive(Clone)]
+pub struct SecretKey {
+    id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+pub async fn load_key(source: &SecretKeySource) -> Result<SecretKey, loga::Error> {
+    let text = match source {
+        SecretKeySource::File(path) => {
+            tokio::fs::read_to_string(path)
+                .await
+                .context_with("Error reading identity secret key file", ea!(path = path))?
+        },
+        SecretKeySource::Env(name) => {
+            std::env::var(name).context_with("Error reading identity secret key from environment", ea!(var = name))?
+        },
+    };
+    let key =
+        STANDARD
+            .decode(text.trim())
+            .map_err(|_| loga::err("Identity secret key isn't valid base64"))?;
+    if key.len() != 32 {
+        return Err(loga::err_with("Identity secret key must be 32 bytes", ea!(length = key.len())));
+    }
+    return Ok(key_from_bytes(&key));
+}
+
+/// `key` must be 32 bytes.
+fn key_from_bytes(key: &[u8]) -> SecretKey {
+    return SecretKey {
+        id: hex::encode(&Sha256::digest(key)[.. 8]),
+        cipher: XChaCha20Poly1305::new_from_slice(key).unwrap(),
+    };
+}
+
+#[cfg(test)]
+pub fn test_key(seed: u8) -> SecretKey {
+    return key_from_bytes(&[seed; 32]);
+}
+
+/// Encrypts the secret if there's a key, for writing to the database.
+pub fn seal(key: Option<&SecretKey>, secret: &LocalIdentitySecret) -> StoredIdentitySecret {
+    let Some(key) = key else {
+        return StoredIdentitySecret::Plain(secret.clone());
+    };
+    let mut nonce = [0u8; 24];
+    rng().fill(&mut nonce);
+    let ciphertext =
+        key
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), serde_json::to_vec(secret).unwrap().as_slice())
+            .unwrap();
+    return StoredIdentitySecret::Encrypted { encrypted: EncryptedIdentitySecret {
+        key_id: key.id.clone(),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    } };
+}
+
+pub fn open(key: Option<&SecretKey>, stored: &StoredIdentitySecret) -> Result<LocalIdentitySecret, loga::Error> {
+    match stored {
+        StoredIdentitySecret::Plain(s) => return Ok(s.clone()),
+        StoredIdentitySecret::Encrypted { encrypted } => {
+            let Some(key) = key else {
+                return Err(loga::err("Identity secret is encrypted but no identity secret key is configured"));
+            };
+            if encrypted.key_id != key.id {
+                return Err(
+                    loga::err_with(
+                        "Identity secret was encrypted with a different key",
+                        ea!(stored_key_id = encrypted.key_id, key_id = key.id),
+                    ),
+                );
+            }
+            let nonce = STANDARD.decode(&encrypted.nonce).map_err(|_| loga::err("Invalid nonce"))?;
+            if nonce.len() != 24 {
+                return Err(loga::err("Invalid nonce"));
+            }
+            let ciphertext = STANDARD.decode(&encrypted.ciphertext).map_err(|_| loga::err("Invalid ciphertext"))?;
+            let plaintext =
+                key
+                    .cipher
+                    .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+                    .map_err(|_| loga::err("Error decrypting identity secret, data is corrupt"))?;
+            return Ok(
+                serde_json::from_slice(&plaintext).context("Decrypted identity secret has an invalid format")?,
+            );
+        },
+    }
+}
+
+/// Re-stores every identity secret under `new`, decrypting with `old`. Returns the
+/// number of secrets changed. Rows already under `new` are left alone.
+async fn restore_all(db: &Pool, old: Option<SecretKey>, new: Option<SecretKey>) -> Result<usize, loga::Error> {
+    return Ok(tx(db, move |db_tx| {
+        let rows = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 account_id,
+                 id,
+                 secret
+               from
+                 identity
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        let mut count = 0;
+        for row in rows {
+            let done = match (&row.secret.0, &new) {
+                (StoredIdentitySecret::Encrypted { encrypted }, Some(new)) => encrypted.key_id == new.id,
+                (StoredIdentitySecret::Plain(_), None) => true,
+                _ => false,
+            };
+            if done {
+                continue;
+            }
+            let secret = match open(old.as_ref(), &row.secret.0) {
+                Ok(s) => s,
+                Err(e) => {
+                    return Err(e.context_with("Error reading identity secret", ea!(identity = row.id.0)));
+                },
+            };
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"update
+                     identity
+                   set
+                     secret = ${identity_secret_t = DbIdentitySecret(seal(new.as_ref(), &secret))}
+                   where
+                     account_id = ${account_id_t = row.account_id}
+                     and id = ${identity_id_t = row.id}
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            count += 1;
+        }
+        return Ok(count);
+    }).await?);
+}
+
+/// Run at startup. Fails if any secret can't be read with the configured key (or
+/// lack of key), otherwise encrypts any plain secrets if a key is configured.
+pub async fn check_startup(log: &Log, db: &Pool, key: Option<&SecretKey>) -> Result<(), loga::Error> {
+    let count =
+        restore_all(db, key.cloned(), key.cloned())
+            .await
+            .context(
+                "Identity secrets in the database can't be read with the configured `identity_secret_key`, refusing to start",
+            )?;
+    if count > 0 {
+        log.log_with(loga::INFO, "Encrypted existing identity secrets", ea!(count = count));
+    }
+    return Ok(());
+}
+
+/// Returns the number of secrets re-encrypted.
+pub async fn rotate(db: &Pool, old: Option<SecretKey>, new: SecretKey) -> Result<usize, loga::Error> {
+    return Ok(restore_all(db, old, Some(new)).await?);
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            check_startup,
+            open,
+            rotate,
+            seal,
+            test_key,
+            SecretKey,
+        },
+        crate::{
+            dbutil::{
+                test_db,
+                tx,
+            },
+            interface::{
+                db::{
+                    DbAccountId,
+                    DbIdentity,
+                    DbIdentitySecret,
+                    StoredIdentitySecret,
+                },
+                AccountExternalId,
+            },
+            subsystems::identity::ensure_account,
+        },
+        deadpool_sqlite::Pool,
+        good_ormning::sqlite::{
+            good_query,
+            good_query_many,
+        },
+        loga::Log,
+        spaghettinuum::interface::identity::LocalIdentitySecret,
+    };
+
+    async fn insert(db: &Pool, key: Option<&SecretKey>) -> LocalIdentitySecret {
+        let (id, secret) = LocalIdentitySecret::new();
+        let stored = seal(key, &secret);
+        tx(db, move |db_tx| {
+            let account_id = ensure_account(db_tx, &AccountExternalId {
+                issuer: "https://issuer.example.org".to_string(),
+                subject: "a".to_string(),
+            })?;
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"insert into
+                     identity
+                     (account_id, id, idem, memo_short, memo_long, secret)
+                   values (
+                     ${account_id_t = DbAccountId(account_id)},
+                     ${identity_id_t = DbIdentity(id)},
+                     ${str = String::new()},
+                     ${str = String::new()},
+                     ${str = String::new()},
+                     ${identity_secret_t = DbIdentitySecret(stored)}
+                   )
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            return Ok(());
+        }).await.unwrap();
+        return secret;
+    }
+
+    async fn stored(db: &Pool) -> Vec<StoredIdentitySecret> {
+        return tx(db, move |db_tx| {
+            return Ok(good_query_many!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     secret
+                   from
+                     identity
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?.into_iter().map(|s| s.0).collect::<Vec<_>>());
+        }).await.unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_, secret) = LocalIdentitySecret::new();
+        let key = test_key(1);
+        let sealed = seal(Some(&key), &secret);
+        assert!(matches!(sealed, StoredIdentitySecret::Encrypted { .. }));
+        assert!(open(Some(&key), &sealed).unwrap().identity() == secret.identity());
+        assert!(open(Some(&test_key(2)), &sealed).is_err());
+        assert!(open(None, &sealed).is_err());
+    }
+
+    #[tokio::test]
+    async fn rotate_plain_to_key() {
+        let db = test_db().await;
+        let secret = insert(&db, None).await;
+        let key = test_key(1);
+        assert_eq!(rotate(&db, None, key.clone()).await.unwrap(), 1);
+        let stored = stored(&db).await;
+        assert!(matches!(stored[0], StoredIdentitySecret::Encrypted { .. }));
+        assert!(open(Some(&key), &stored[0]).unwrap().identity() == secret.identity());
+    }
+
+    #[tokio::test]
+    async fn rotate_key_to_key() {
+        let db = test_db().await;
+        let old = test_key(1);
+        let new = test_key(2);
+        let secret = insert(&db, Some(&old)).await;
+
+        // Already under the new key, left alone
+        insert(&db, Some(&new)).await;
+        assert_eq!(rotate(&db, Some(old.clone()), new.clone()).await.unwrap(), 1);
+        for stored in stored(&db).await {
+            assert!(open(Some(&old), &stored).is_err());
+            open(Some(&new), &stored).unwrap();
+        }
+        assert!(stored(&db).await.iter().any(|s| open(Some(&new), s).unwrap().identity() == secret.identity()));
+    }
+
+    #[tokio::test]
+    async fn rotate_wrong_old_key() {
+        let db = test_db().await;
+        insert(&db, Some(&test_key(1))).await;
+        assert!(rotate(&db, Some(test_key(3)), test_key(2)).await.is_err());
+
+        // Nothing changed
+        open(Some(&test_key(1)), &stored(&db).await[0]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn startup() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        insert(&db, None).await;
+        check_startup(&log, &db, Some(&test_key(1))).await.unwrap();
+        open(Some(&test_key(1)), &stored(&db).await[0]).unwrap();
+        assert!(check_startup(&log, &db, Some(&test_key(2))).await.is_err());
+        assert!(check_startup(&log, &db, None).await.is_err());
+    }
+}
@@ -0,0 +1,994 @@
+//! Messages in channels owned by identities on this server. Members' servers
+//! fetch them as pages (see `remotepages`), local clients get the same pages from
+//! the c2s GET endpoints.
+//!
+//! Each channel has two sequences. The snap sequence has one entry per message in
+//! the order received, with the latest edit applied; clients page through it to
+//! show history. The activity sequence has an entry for every change, so clients
+//! that already have some of the snap can catch up on what changed since. Offsets
+//! in both never change, so pages before the last are only affected by edits and
+//! deletions, which are also in the activity.
+//!
+//! Messages are signed with the sender's identity when stored, since the server
+//! holds the secret anyway.
+use {
+    crate::{
+        dbutil::tx,
+        interface::{
+            db::{
+                DbChannelId,
+                DbIdentity,
+                DbMessage,
+            },
+            AccountExternalId,
+        },
+        subsystems::identitysecret::{
+            self,
+            SecretKey,
+        },
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query,
+        good_query_many,
+        good_query_opt,
+    },
+    jiff::Timestamp,
+    loga::ea,
+    shared::interface::{
+        shared::{
+            Message,
+            MessageBody,
+            MessageClientId,
+            MessageId,
+            MessageRel,
+            QualifiedChannelId,
+            QualifiedMessageId,
+        },
+        wire::c2s::{
+            ActivityOffset,
+            ActivityOffsetPos,
+            ActivityPage,
+            ActivityPageMessage,
+            ActivityPageOffsetPos,
+            ActivityPageRes,
+            MessageDelete,
+            MessageEdit,
+            MessagePush,
+            PagePosition,
+            SnapByRes,
+            SnapMessage,
+            SnapOffset,
+            SnapOffsetPos,
+            SnapPage,
+            SnapPageOffsetPos,
+            SnapPageRes,
+        },
+    },
+    spaghettinuum::interface::{
+        identity::{
+            Identity,
+            LocalIdentitySecret,
+        },
+        signature::Signature,
+    },
+    std::collections::HashMap,
+};
+
+/// Entries per snap and activity page.
+pub const PAGE_SIZE: usize = 50;
+
+/// Where the entry at `offset` sits in its page, given the number of entries.
+/// Clients use this to decide whether the next or previous entry is on another
+/// page.
+fn position(offset: usize, count: usize) -> PagePosition {
+    let in_page = offset % PAGE_SIZE;
+
+    // Nothing comes before the very first entry, so if it's also the last entry
+    // `Last` is the more useful answer
+    if in_page == 0 && offset > 0 {
+        return PagePosition::First;
+    }
+    if in_page == PAGE_SIZE - 1 || offset + 1 == count {
+        return PagePosition::Last;
+    }
+    if in_page == 0 {
+        return PagePosition::First;
+    }
+    return PagePosition::Middle;
+}
+
+fn snap_offset_pos(offset: usize, count: usize) -> SnapPageOffsetPos {
+    return SnapPageOffsetPos {
+        page: SnapPage(offset / PAGE_SIZE),
+        offset_pos: SnapOffsetPos {
+            offset: SnapOffset(offset),
+            pos: position(offset, count),
+        },
+    };
+}
+
+fn activity_offset_pos(offset: usize, count: usize) -> ActivityPageOffsetPos {
+    return ActivityPageOffsetPos {
+        page: ActivityPage(offset / PAGE_SIZE),
+        offset_pos: ActivityOffsetPos {
+            offset: ActivityOffset(offset),
+            pos: position(offset, count),
+        },
+    };
+}
+
+/// Number of messages in the channel, including deleted ones.
+fn snap_count(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    channel: &QualifiedChannelId,
+) -> Result<usize, loga::Error> {
+    return Ok(good_query_opt!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"select
+             snap_offset
+           from
+             message
+           where
+             owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+             and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+           order by
+             snap_offset desc
+           limit
+             1
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?.map(|o| o as usize + 1).unwrap_or(0));
+}
+
+fn activity_count(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    channel: &QualifiedChannelId,
+) -> Result<usize, loga::Error> {
+    return Ok(good_query_opt!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"select
+             activity_offset
+           from
+             message_activity
+           where
+             owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+             and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+           order by
+             activity_offset desc
+           limit
+             1
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?.map(|o| o as usize + 1).unwrap_or(0));
+}
+
+fn insert_activity(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    channel: &QualifiedChannelId,
+    message: Message,
+    now: Timestamp,
+) -> Result<ActivityOffset, loga::Error> {
+    let offset = activity_count(db_tx, channel)?;
+    good_query!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"insert into
+             message_activity
+             (owner, channel, activity_offset, message, receive_time)
+           values (
+             ${identity_id_t = DbIdentity(channel.identity.clone())},
+             ${channel_id_t = DbChannelId(channel.channel.clone())},
+             ${i64 = offset as i64},
+             ${message_t = DbMessage(message)},
+             ${utctime_s_jiff = now}
+           )
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?;
+    return Ok(ActivityOffset(offset));
+}
+
+/// The identity the account is in the channel as, if it's a member.
+fn member_identity_tx(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    account: &AccountExternalId,
+    channel: &QualifiedChannelId,
+) -> Result<Option<Identity>, loga::Error> {
+    return Ok(good_query_opt!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"select
+             channel.own_identity
+           from
+             channel
+             join account on channel.account_id = account.rowid
+           where
+             account.external_id = ${str = account.to_db()}
+             and channel.identity = ${identity_id_t = DbIdentity(channel.identity.clone())}
+             and channel.id = ${channel_id_t = DbChannelId(channel.channel.clone())}
+             and channel.deleted is null
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?.flatten().map(|i| i.0));
+}
+
+/// The identity the account is in the channel as, if it's a member.
+pub async fn member_identity(
+    db: &Pool,
+    account: &AccountExternalId,
+    channel: &QualifiedChannelId,
+) -> Result<Option<Identity>, loga::Error> {
+    let account = account.clone();
+    let channel = channel.clone();
+    return Ok(tx(db, move |db_tx| {
+        return member_identity_tx(db_tx, &account, &channel);
+    }).await?);
+}
+
+/// A change that was stored.
+pub struct Stored {
+    pub channel: QualifiedChannelId,
+    pub activity: ActivityOffset,
+    pub body: MessageBody,
+}
+
+/// Checks that `sender` is the account's identity in the channel and that the
+/// channel is owned here, and opens the sender's secret for signing. Returns the
+/// reason if not.
+async fn sender_secret(
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    account: &AccountExternalId,
+    channel: &QualifiedChannelId,
+    sender: &Identity,
+) -> Result<Result<LocalIdentitySecret, String>, loga::Error> {
+    let secret = tx(db, {
+        let account = account.clone();
+        let channel = channel.clone();
+        let sender = sender.clone();
+        move |db_tx| {
+            if member_identity_tx(db_tx, &account, &channel)?.as_ref() != Some(&sender) {
+                return Ok(Err(format!("Sender isn't a member of the channel")));
+            }
+            let owner_local = good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     id
+                   from
+                     identity
+                   where
+                     id = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                     and soft_deleted_at is null
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            if owner_local.is_none() {
+                return Ok(Err(format!("Posting to channels on other servers isn't supported yet")));
+            }
+            let Some(secret) = good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     identity.secret
+                   from
+                     identity
+                     join account on identity.account_id = account.rowid
+                   where
+                     account.external_id = ${str = account.to_db()}
+                     and identity.id = ${identity_id_t = DbIdentity(sender)}
+                     and identity.soft_deleted_at is null
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))? else {
+                return Ok(Err(format!("Sender identity was deleted")));
+            };
+            return Ok(Ok(secret));
+        }
+    }).await?;
+    match secret {
+        Ok(s) => return Ok(Ok(identitysecret::open(secret_key, &s.0)?)),
+        Err(reason) => return Ok(Err(reason)),
+    }
+}
+
+/// Stores and signs a new message. Returns the reason if the sender can't post
+/// there, or `None` if a message with the same client id was already stored (a
+/// retry).
+pub async fn push(
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    account: &AccountExternalId,
+    req: MessagePush,
+) -> Result<Result<Option<Stored>, String>, loga::Error> {
+    let secret = match sender_secret(db, secret_key, account, &req.channel, &req.identity).await? {
+        Ok(s) => s,
+        Err(reason) => return Ok(Err(reason)),
+    };
+
+    // Only uses 63 bits so it fits in the database as-is
+    let unique = rand::random::<u64>() >> 1;
+    let body = MessageBody {
+        client_id: Some(req.client_id.clone()),
+        id: MessageId {
+            identity: req.identity.clone(),
+            unique: unique,
+        },
+        rel: MessageRel::None,
+        body: req.body,
+    };
+    let message = Message(Signature::sign(&secret, body.clone()));
+    return Ok(Ok(tx(db, move |db_tx| {
+        let channel = req.channel;
+        let seen = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 snap_offset
+               from
+                 message
+               where
+                 owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+                 and sender = ${identity_id_t = DbIdentity(req.identity.clone())}
+                 and client_id = ${str = req.client_id.0.clone()}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        if seen.is_some() {
+            return Ok(None);
+        }
+        let now = Timestamp::now();
+        let snap_offset = snap_count(db_tx, &channel)?;
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"insert into
+                 message
+                 (owner, channel, snap_offset, sender, sender_unique, client_id, receive_time, message)
+               values (
+                 ${identity_id_t = DbIdentity(channel.identity.clone())},
+                 ${channel_id_t = DbChannelId(channel.channel.clone())},
+                 ${i64 = snap_offset as i64},
+                 ${identity_id_t = DbIdentity(req.identity)},
+                 ${i64 = unique as i64},
+                 ${str? = Some(req.client_id.0)},
+                 ${utctime_s_jiff = now},
+                 ${message_t = DbMessage(message.clone())}
+               )
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        let activity = insert_activity(db_tx, &channel, message, now)?;
+        return Ok(Some(Stored {
+            channel: channel,
+            activity: activity,
+            body: body,
+        }));
+    }).await?));
+}
+
+/// Replaces the message in the snap and records `message` (an `EditOf` or
+/// `DeleteOf` signed by the sender) in the activity. `None` if the message doesn't
+/// exist or was already deleted.
+fn change(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    channel: &QualifiedChannelId,
+    id: &MessageId,
+    message: Message,
+    delete: bool,
+) -> Result<Option<ActivityOffset>, loga::Error> {
+    let Some(snap_offset) = good_query_opt!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"select
+             snap_offset
+           from
+             message
+           where
+             owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+             and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+             and sender = ${identity_id_t = DbIdentity(id.identity.clone())}
+             and sender_unique = ${i64 = id.unique as i64}
+             and deleted is null
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))? else {
+        return Ok(None);
+    };
+    let now = Timestamp::now();
+    if delete {
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"update
+                 message
+               set
+                 deleted = ${utctime_s_jiff = now}
+               where
+                 owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+                 and snap_offset = ${i64 = snap_offset}
+               "#;
+            db_tx
+        ).map_err(|e| loga::err(e.0))?;
+    } else {
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"update
+                 message
+               set
+                 message = ${message_t = DbMessage(message.clone())}
+               where
+                 owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+                 and snap_offset = ${i64 = snap_offset}
+               "#;
+            db_tx
+        ).map_err(|e| loga::err(e.0))?;
+    }
+    return Ok(Some(insert_activity(db_tx, channel, message, now)?));
+}
+
+/// Replaces the body of one of the sender's messages. Returns the reason if it
+/// can't be edited.
+pub async fn edit(
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    account: &AccountExternalId,
+    req: MessageEdit,
+) -> Result<Result<Stored, String>, loga::Error> {
+    let secret = match sender_secret(db, secret_key, account, &req.channel, &req.id.identity).await? {
+        Ok(s) => s,
+        Err(reason) => return Ok(Err(reason)),
+    };
+    let body = MessageBody {
+        client_id: None,
+        id: req.id.clone(),
+        rel: MessageRel::EditOf(req.id.clone()),
+        body: req.body,
+    };
+    let message = Message(Signature::sign(&secret, body.clone()));
+    return Ok(tx(db, move |db_tx| {
+        let Some(activity) = change(db_tx, &req.channel, &req.id, message, false)? else {
+            return Ok(Err(format!("Message doesn't exist")));
+        };
+        return Ok(Ok(Stored {
+            channel: req.channel,
+            activity: activity,
+            body: body,
+        }));
+    }).await?);
+}
+
+/// Removes one of the sender's messages from the snap. Returns the reason if it
+/// can't be deleted.
+pub async fn delete(
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    account: &AccountExternalId,
+    req: MessageDelete,
+) -> Result<Result<Stored, String>, loga::Error> {
+    let secret = match sender_secret(db, secret_key, account, &req.channel, &req.id.identity).await? {
+        Ok(s) => s,
+        Err(reason) => return Ok(Err(reason)),
+    };
+    let body = MessageBody {
+        client_id: None,
+        id: req.id.clone(),
+        rel: MessageRel::DeleteOf(req.id.clone()),
+        body: String::new(),
+    };
+    let message = Message(Signature::sign(&secret, body.clone()));
+    return Ok(tx(db, move |db_tx| {
+        let Some(activity) = change(db_tx, &req.channel, &req.id, message, true)? else {
+            return Ok(Err(format!("Message doesn't exist")));
+        };
+        return Ok(Ok(Stored {
+            channel: req.channel,
+            activity: activity,
+            body: body,
+        }));
+    }).await?);
+}
+
+fn body_of(message: &DbMessage) -> Result<MessageBody, loga::Error> {
+    return Ok(message.0.0.get_no_verify().map_err(|e| loga::err_with("Stored message is invalid", ea!(err = e)))?);
+}
+
+/// Number of messages ever pushed to the channel (see `snap_count`), for
+/// `LastSnapPage`.
+pub async fn snap_len(db: &Pool, channel: &QualifiedChannelId) -> Result<usize, loga::Error> {
+    let channel = channel.clone();
+    return Ok(tx(db, move |db_tx| {
+        return snap_count(db_tx, &channel);
+    }).await?);
+}
+
+/// Number of activity entries in the channel, for `LastActivityPage`.
+pub async fn activity_len(db: &Pool, channel: &QualifiedChannelId) -> Result<usize, loga::Error> {
+    let channel = channel.clone();
+    return Ok(tx(db, move |db_tx| {
+        return activity_count(db_tx, &channel);
+    }).await?);
+}
+
+/// `None` if the page is past the end.
+pub async fn snap_page(
+    db: &Pool,
+    channel: &QualifiedChannelId,
+    page: SnapPage,
+) -> Result<Option<SnapPageRes>, loga::Error> {
+    let channel = channel.clone();
+    return Ok(tx(db, move |db_tx| {
+        let count = snap_count(db_tx, &channel)?;
+        let start = page.0 * PAGE_SIZE;
+        if start >= count {
+            return Ok(None);
+        }
+        let rows = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 snap_offset,
+                 sender,
+                 sender_unique,
+                 client_id,
+                 receive_time,
+                 message
+               from
+                 message
+               where
+                 owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+                 and snap_offset >= ${i64 = start as i64}
+                 and snap_offset < ${i64 = (start + PAGE_SIZE) as i64}
+                 and deleted is null
+               order by
+                 snap_offset
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        let mut messages = vec![];
+        for r in rows {
+            messages.push(SnapMessage {
+                offset_pos: snap_offset_pos(r.snap_offset as usize, count).offset_pos,
+                original_id: QualifiedMessageId {
+                    channel: channel.clone(),
+                    message: MessageId {
+                        identity: r.sender.0,
+                        unique: r.sender_unique as u64,
+                    },
+                },
+                original_receive_time: r.receive_time,
+                client_id: r.client_id.map(MessageClientId),
+                message: body_of(&r.message)?,
+            });
+        }
+        let activity_count = activity_count(db_tx, &channel)?;
+        return Ok(Some(SnapPageRes {
+            latest_activity: activity_offset_pos(activity_count - 1, activity_count),
+            messages: messages,
+        }));
+    }).await?);
+}
+
+/// `None` if the page is past the end.
+pub async fn activity_page(
+    db: &Pool,
+    channel: &QualifiedChannelId,
+    page: ActivityPage,
+) -> Result<Option<ActivityPageRes>, loga::Error> {
+    let channel = channel.clone();
+    return Ok(tx(db, move |db_tx| {
+        let count = activity_count(db_tx, &channel)?;
+        let start = page.0 * PAGE_SIZE;
+        if start >= count {
+            return Ok(None);
+        }
+        let rows = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 activity_offset,
+                 message
+               from
+                 message_activity
+               where
+                 owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+                 and activity_offset >= ${i64 = start as i64}
+                 and activity_offset < ${i64 = (start + PAGE_SIZE) as i64}
+               order by
+                 activity_offset
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(Some(ActivityPageRes { messages: rows.into_iter().map(|r| ActivityPageMessage {
+            message: r.message.0,
+            offset_pos: activity_offset_pos(r.activity_offset as usize, count).offset_pos,
+        }).collect() }));
+    }).await?);
+}
+
+/// Where the message is in the snap, if it exists and hasn't been deleted.
+pub async fn snap_by_id(db: &Pool, id: &QualifiedMessageId) -> Result<Option<SnapByRes>, loga::Error> {
+    let id = id.clone();
+    return Ok(tx(db, move |db_tx| {
+        let Some(found) = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 snap_offset,
+                 receive_time
+               from
+                 message
+               where
+                 owner = ${identity_id_t = DbIdentity(id.channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(id.channel.channel.clone())}
+                 and sender = ${identity_id_t = DbIdentity(id.message.identity.clone())}
+                 and sender_unique = ${i64 = id.message.unique as i64}
+                 and deleted is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))? else {
+            return Ok(None);
+        };
+        let count = snap_count(db_tx, &id.channel)?;
+        return Ok(Some(SnapByRes {
+            original_receive_time: found.receive_time,
+            offset: snap_offset_pos(found.snap_offset as usize, count),
+        }));
+    }).await?);
+}
+
+/// Like `snap_by_id`, for a message `sender` pushed with `client_id`. Lets clients
+/// match up messages they sent with what was stored.
+pub async fn snap_by_client_id(
+    db: &Pool,
+    channel: &QualifiedChannelId,
+    sender: &Identity,
+    client_id: &MessageClientId,
+) -> Result<Option<SnapByRes>, loga::Error> {
+    let channel = channel.clone();
+    let sender = sender.clone();
+    let client_id = client_id.clone();
+    return Ok(tx(db, move |db_tx| {
+        let Some(found) = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 snap_offset,
+                 receive_time
+               from
+                 message
+               where
+                 owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+                 and sender = ${identity_id_t = DbIdentity(sender)}
+                 and client_id = ${str = client_id.0}
+                 and deleted is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))? else {
+            return Ok(None);
+        };
+        let count = snap_count(db_tx, &channel)?;
+        return Ok(Some(SnapByRes {
+            original_receive_time: found.receive_time,
+            offset: snap_offset_pos(found.snap_offset as usize, count),
+        }));
+    }).await?);
+}
+
+/// The page with the last message received at or before `time`, or the first page
+/// if everything is newer. `None` if there are no messages.
+pub async fn snap_page_containing_time(
+    db: &Pool,
+    channel: &QualifiedChannelId,
+    time: Timestamp,
+) -> Result<Option<SnapPage>, loga::Error> {
+    let channel = channel.clone();
+    return Ok(tx(db, move |db_tx| {
+        let before = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 snap_offset
+               from
+                 message
+               where
+                 owner = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+                 and receive_time <= ${utctime_s_jiff = time}
+               order by
+                 snap_offset desc
+               limit
+                 1
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        if let Some(offset) = before {
+            return Ok(Some(SnapPage(offset as usize / PAGE_SIZE)));
+        }
+        if snap_count(db_tx, &channel)? == 0 {
+            return Ok(None);
+        }
+        return Ok(Some(SnapPage(0)));
+    }).await?);
+}
+
+/// The latest activity offset of every channel the account is in that has
+/// activity. Only channels owned here are included; other servers tell us about
+/// theirs with `Notify`.
+pub async fn activity_latest_all(
+    db: &Pool,
+    account: &AccountExternalId,
+) -> Result<HashMap<QualifiedChannelId, ActivityOffset>, loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        let channels = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 channel.identity,
+                 channel.id
+               from
+                 channel
+                 join account on channel.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+                 and channel.deleted is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        let mut out = HashMap::new();
+        for c in channels {
+            let channel = QualifiedChannelId {
+                identity: c.identity.0,
+                channel: c.id.0,
+            };
+            let count = activity_count(db_tx, &channel)?;
+            if count == 0 {
+                continue;
+            }
+            out.insert(channel, ActivityOffset(count - 1));
+        }
+        return Ok(out);
+    }).await?);
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            activity_latest_all,
+            activity_len,
+            activity_page,
+            delete,
+            edit,
+            position,
+            push,
+            snap_by_client_id,
+            snap_len,
+            snap_page,
+            snap_page_containing_time,
+            PAGE_SIZE,
+        },
+        crate::{
+            dbutil::{
+                test_db,
+                tx,
+            },
+            interface::{
+                db::{
+                    DbAccountId,
+                    DbChannelId,
+                    DbIdentity,
+                    DbIdentitySecret,
+                },
+                AccountExternalId,
+            },
+            subsystems::{
+                identity::ensure_account,
+                identitysecret,
+            },
+        },
+        deadpool_sqlite::Pool,
+        good_ormning::sqlite::good_query,
+        jiff::Timestamp,
+        shared::interface::{
+            shared::{
+                ChannelId,
+                MessageClientId,
+                MessageRel,
+                QualifiedChannelId,
+            },
+            wire::c2s::{
+                ActivityPage,
+                MessageDelete,
+                MessageEdit,
+                MessagePush,
+                PagePosition,
+                SnapPage,
+            },
+        },
+        spaghettinuum::interface::identity::{
+            Identity,
+            LocalIdentitySecret,
+        },
+    };
+
+    fn account(subject: &str) -> AccountExternalId {
+        return AccountExternalId {
+            issuer: "https://issuer.example.org".to_string(),
+            subject: subject.to_string(),
+        };
+    }
+
+    /// A local identity for the account that owns a channel and is in it.
+    async fn owned_channel(db: &Pool, account: AccountExternalId) -> (Identity, QualifiedChannelId) {
+        let (id, secret) = LocalIdentitySecret::new();
+        let stored = identitysecret::seal(None, &secret);
+        let channel = QualifiedChannelId {
+            identity: id.clone(),
+            channel: ChannelId(1),
+        };
+        tx(db, {
+            let id = id.clone();
+            move |db_tx| {
+                let account_id = ensure_account(db_tx, &account)?;
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"insert into
+                         identity
+                         (account_id, id, idem, memo_short, memo_long, secret)
+                       values (
+                         ${account_id_t = DbAccountId(account_id)},
+                         ${identity_id_t = DbIdentity(id.clone())},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${identity_secret_t = DbIdentitySecret(stored)}
+                       )
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"insert into
+                         channel
+                         (account_id, identity, id, idem, memo_short, memo_long, own_identity)
+                       values (
+                         ${account_id_t = DbAccountId(account_id)},
+                         ${identity_id_t = DbIdentity(id.clone())},
+                         ${channel_id_t = DbChannelId(ChannelId(1))},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${str = String::new()},
+                         ${identity_id_t? = Some(DbIdentity(id))}
+                       )
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+                return Ok(());
+            }
+        }).await.unwrap();
+        return (id, channel);
+    }
+
+    fn message(channel: &QualifiedChannelId, sender: &Identity, client_id: &str) -> MessagePush {
+        return MessagePush {
+            client_id: MessageClientId(client_id.to_string()),
+            channel: channel.clone(),
+            identity: sender.clone(),
+            body: format!("body {}", client_id),
+        };
+    }
+
+    #[test]
+    fn positions() {
+        assert_eq!(position(0, 1), PagePosition::Last);
+        assert_eq!(position(0, 2), PagePosition::First);
+        assert_eq!(position(1, 3), PagePosition::Middle);
+        assert_eq!(position(PAGE_SIZE - 1, PAGE_SIZE + 1), PagePosition::Last);
+        assert_eq!(position(PAGE_SIZE, PAGE_SIZE + 1), PagePosition::First);
+    }
+
+    #[tokio::test]
+    async fn pushed_messages_paged() {
+        let db = test_db().await;
+        let (sender, channel) = owned_channel(&db, account("a")).await;
+        for i in 0 .. PAGE_SIZE + 1 {
+            push(&db, None, &account("a"), message(&channel, &sender, &i.to_string()))
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(snap_len(&db, &channel).await.unwrap(), PAGE_SIZE + 1);
+        assert_eq!(activity_len(&db, &channel).await.unwrap(), PAGE_SIZE + 1);
+        let first = snap_page(&db, &channel, SnapPage(0)).await.unwrap().unwrap();
+        assert_eq!(first.messages.len(), PAGE_SIZE);
+        assert_eq!(first.messages[0].message.body, "body 0");
+        assert_eq!(first.messages[0].offset_pos.pos, PagePosition::First);
+        assert_eq!(first.latest_activity.page.0, 1);
+        let last = snap_page(&db, &channel, SnapPage(1)).await.unwrap().unwrap();
+        assert_eq!(last.messages.len(), 1);
+        assert_eq!(last.messages[0].offset_pos.pos, PagePosition::First);
+        assert!(snap_page(&db, &channel, SnapPage(2)).await.unwrap().is_none());
+        let activity = activity_page(&db, &channel, ActivityPage(1)).await.unwrap().unwrap();
+        assert_eq!(activity.messages.len(), 1);
+        assert!(activity.messages[0].message.0.verify(&sender).is_ok());
+        assert_eq!(
+            activity_latest_all(&db, &account("a")).await.unwrap().get(&channel).map(|o| o.0),
+            Some(PAGE_SIZE)
+        );
+        assert_eq!(
+            snap_page_containing_time(&db, &channel, Timestamp::now()).await.unwrap(),
+            Some(SnapPage(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_not_duplicated() {
+        let db = test_db().await;
+        let (sender, channel) = owned_channel(&db, account("a")).await;
+        assert!(push(&db, None, &account("a"), message(&channel, &sender, "x")).await.unwrap().unwrap().is_some());
+        assert!(push(&db, None, &account("a"), message(&channel, &sender, "x")).await.unwrap().unwrap().is_none());
+        assert_eq!(snap_page(&db, &channel, SnapPage(0)).await.unwrap().unwrap().messages.len(), 1);
+        assert!(
+            snap_by_client_id(&db, &channel, &sender, &MessageClientId("x".to_string())).await.unwrap().is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn non_member_rejected() {
+        let db = test_db().await;
+        let (sender, channel) = owned_channel(&db, account("a")).await;
+        assert!(push(&db, None, &account("b"), message(&channel, &sender, "x")).await.unwrap().is_err());
+        assert!(snap_page(&db, &channel, SnapPage(0)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn edited_and_deleted() {
+        let db = test_db().await;
+        let (sender, channel) = owned_channel(&db, account("a")).await;
+        let id = push(&db, None, &account("a"), message(&channel, &sender, "x")).await.unwrap().unwrap().unwrap().body.id;
+        edit(&db, None, &account("a"), MessageEdit {
+            channel: channel.clone(),
+            id: id.clone(),
+            body: format!("edited"),
+        }).await.unwrap().unwrap();
+        let snap = snap_page(&db, &channel, SnapPage(0)).await.unwrap().unwrap();
+        assert_eq!(snap.messages[0].message.body, "edited");
+        assert_eq!(snap.messages[0].original_id.message, id);
+        delete(&db, None, &account("a"), MessageDelete {
+            channel: channel.clone(),
+            id: id.clone(),
+        }).await.unwrap().unwrap();
+        assert!(snap_page(&db, &channel, SnapPage(0)).await.unwrap().unwrap().messages.is_empty());
+        let activity = activity_page(&db, &channel, ActivityPage(0)).await.unwrap().unwrap();
+        assert_eq!(activity.messages.len(), 3);
+        let last = activity.messages[2].message.0.verify(&sender).ok().unwrap();
+        assert_eq!(last.id, id);
+        assert!(matches!(last.rel, MessageRel::DeleteOf(_)));
+
+        // Already deleted, and other accounts can't touch it
+        assert!(delete(&db, None, &account("a"), MessageDelete {
+            channel: channel.clone(),
+            id: id.clone(),
+        }).await.unwrap().is_err());
+        assert!(edit(&db, None, &account("b"), MessageEdit {
+            channel: channel.clone(),
+            id: id,
+            body: format!("other"),
+        }).await.unwrap().is_err());
+    }
+}
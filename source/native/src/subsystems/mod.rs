@@ -1 +1,21 @@
+pub mod accountexport;
+pub mod apitoken;
+pub mod federation;
+pub mod federationpolicy;
+pub mod health;
+pub mod identity;
+pub mod identitybundle;
+pub mod identitysecret;
+pub mod message;
 pub mod oidc;
+pub mod outgoingwebhook;
+pub mod ratelimit;
+pub mod remotepages;
+pub mod resolver;
+pub mod s2sauth;
+pub mod s2sclient;
+pub mod spagh;
+pub mod tls;
+pub mod webhook;
+pub mod webpush;
+pub mod websocket;
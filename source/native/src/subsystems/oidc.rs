@@ -2,14 +2,26 @@ use {
     crate::{
         cap_fn,
         interface::{
-            config::OidcConfig,
+            config::{
+                OidcConfig,
+                OidcRestrictions,
+            },
             AccountExternalId,
         },
+        metrics::METRICS,
+    },
+    base64::{
+        engine::general_purpose::URL_SAFE_NO_PAD,
+        Engine,
     },
     cookie::CookieBuilder,
     flowcontrol::shed,
     http::{
-        header::HOST,
+        header::{
+            CACHE_CONTROL,
+            HOST,
+            USER_AGENT,
+        },
         request::Parts,
         HeaderMap,
         Request,
@@ -34,10 +46,12 @@ use {
     },
     loga::{
         ea,
+        DebugDisplay,
         ErrContext,
         Log,
         ResultContext,
     },
+    jiff::Timestamp,
     moka::future::Cache,
     oauth2::{
         basic::{
@@ -51,28 +65,35 @@ use {
             CoreAuthDisplay,
             CoreAuthPrompt,
             CoreAuthenticationFlow,
-            CoreClient,
             CoreGenderClaim,
             CoreJsonWebKey,
             CoreJsonWebKeyType,
             CoreJsonWebKeyUse,
             CoreJweContentEncryptionAlgorithm,
+            CoreJsonWebKeySet,
             CoreJwsSigningAlgorithm,
-            CoreProviderMetadata,
         },
         AccessTokenHash,
         AuthorizationCode,
         ClientId,
+        AdditionalClaims,
         ClientSecret,
         CsrfToken,
-        EmptyAdditionalClaims,
         EmptyExtraTokenFields,
+        EndSessionUrl,
+        IdToken,
+        IdTokenClaims,
         IdTokenFields,
         IssuerUrl,
+        JsonWebKey,
+        JsonWebKeySetUrl,
+        LogoutRequest,
         Nonce,
         OAuth2TokenResponse,
         PkceCodeChallenge,
         PkceCodeVerifier,
+        PostLogoutRedirectUrl,
+        ProviderMetadataWithLogout,
         RedirectUrl,
         RevocationErrorResponseType,
         StandardErrorResponse,
@@ -84,15 +105,34 @@ use {
         Alphanumeric,
         SampleString,
     },
-    serde::Deserialize,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    shared::interface::{
+        shared::SessionId,
+        wire::c2s::{
+            SessionRes,
+            SessionRevokeTarget,
+            C2SV1_PREFIX,
+        },
+    },
     std::{
         borrow::Cow,
+        collections::{
+            BTreeMap,
+            HashMap,
+        },
         sync::{
             Arc,
             Mutex,
         },
-        time::Duration,
+        time::{
+            Duration,
+            Instant,
+        },
     },
+    tokio::sync::broadcast,
 };
 
 pub const COOKIE_SESSION: &str = "kwa_session";
@@ -141,16 +181,24 @@ async fn oidc_http_client(
 }
 
 struct OidcPreSession {
+    provider: String,
     original_url: Uri,
     pkce_verifier: Mutex<Option<PkceCodeVerifier>>,
     nonce: Nonce,
 }
 
-pub struct OidcState {
-    log: loga::Log,
-    client: openidconnect
-    ::Client<
-        EmptyAdditionalClaims,
+/// Captures non-standard claims for use in restrictions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OidcExtraClaims {
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+impl AdditionalClaims for OidcExtraClaims { }
+
+type OidcClient =
+    openidconnect::Client<
+        OidcExtraClaims,
         CoreAuthDisplay,
         CoreGenderClaim,
         CoreJweContentEncryptionAlgorithm,
@@ -162,7 +210,7 @@ pub struct OidcState {
         StandardErrorResponse<BasicErrorResponseType>,
         StandardTokenResponse<
             IdTokenFields<
-                EmptyAdditionalClaims,
+                OidcExtraClaims,
                 EmptyExtraTokenFields,
                 CoreGenderClaim,
                 CoreJweContentEncryptionAlgorithm,
@@ -175,29 +223,204 @@ pub struct OidcState {
         StandardTokenIntrospectionResponse<EmptyExtraTokenFields, BasicTokenType>,
         StandardRevocableToken,
         StandardErrorResponse<RevocationErrorResponseType>,
-    >,
+    >;
+
+type OidcIdToken =
+    IdToken<
+        OidcExtraClaims,
+        CoreGenderClaim,
+        CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm,
+        CoreJsonWebKeyType,
+    >;
+
+struct OidcProvider {
+    name: String,
+    client: OidcClient,
+    restrictions: OidcRestrictions,
+    issuer: IssuerUrl,
+    client_id: String,
+    // For RP-initiated logout
+    end_session_endpoint: Option<EndSessionUrl>,
+    // For verifying back-channel logout tokens. Refetched (at most every
+    // `JWKS_REFETCH_MIN`) when a token names a key we don't have, since providers
+    // rotate keys.
+    jwks: Mutex<CoreJsonWebKeySet>,
+    jwks_uri: JsonWebKeySetUrl,
+    jwks_fetched: Mutex<Instant>,
+    // Logout tokens must use one of these, whatever their header says
+    signing_algs: Vec<CoreJwsSigningAlgorithm>,
+}
+
+pub struct OidcSession {
+    pub id: SessionId,
+    pub account: AccountExternalId,
+    pub provider: String,
+    pub created: Timestamp,
+    pub last_seen: Mutex<Timestamp>,
+    pub user_agent: String,
+    // Sent as a hint during RP-initiated logout
+    id_token: OidcIdToken,
+    // Provider session id, if the provider supports back-channel logout
+    sid: Option<String>,
+}
+
+pub struct OidcState {
+    log: loga::Log,
+    // Ordered by config id, for a stable selection page
+    providers: BTreeMap<String, OidcProvider>,
     pre_sessions: Cache<String, Arc<OidcPreSession>>,
-    pub(crate) sessions: Cache<String, AccountExternalId>,
+    // Keyed by session cookie
+    pub(crate) sessions: Cache<String, Arc<OidcSession>>,
+    /// Fires whenever a session ends for any reason (logout, revocation, expiry) so
+    /// that resources tied to the session can be released. Push subscriptions are
+    /// the only such resource so far.
+    pub(crate) session_ended: broadcast::Sender<SessionId>,
 }
 
 pub async fn new_state(log: &Log, oidc_config: OidcConfig) -> Result<OidcState, loga::Error> {
     let log = log.fork(ea!(subsystem = "oidc"));
-    let client =
-        CoreClient::from_provider_metadata(
-            CoreProviderMetadata::discover_async(IssuerUrl::new(oidc_config.provider_url)?, cap_fn!((r)(log) {
-                return oidc_http_client(&log, r).await.map_err(|e| std::io::Error::other(e.to_string()));
-            })).await?,
-            ClientId::new(oidc_config.client_id.clone()),
-            oidc_config.client_secret.as_ref().map(|s| ClientSecret::new(s.clone())),
-        );
+    if oidc_config.providers.is_empty() {
+        return Err(loga::err("OIDC config has no providers"));
+    }
+    let mut providers = BTreeMap::new();
+    for provider_config in oidc_config.providers {
+        if providers.contains_key(&provider_config.id) {
+            return Err(loga::err_with("Duplicate OIDC provider id in config", ea!(id = provider_config.id)));
+        }
+        let metadata =
+            ProviderMetadataWithLogout::discover_async(
+                IssuerUrl::new(provider_config.provider_url.clone())?,
+                cap_fn!((r)(log) {
+                    return oidc_http_client(&log, r).await.map_err(|e| std::io::Error::other(e.to_string()));
+                }),
+            )
+                .await
+                .context_with(
+                    "Error discovering OIDC provider metadata",
+                    ea!(provider = provider_config.id, url = provider_config.provider_url),
+                )?;
+        let issuer = metadata.issuer().clone();
+        let end_session_endpoint = metadata.additional_metadata().end_session_endpoint.clone();
+        let jwks = metadata.jwks().clone();
+        let jwks_uri = metadata.jwks_uri().clone();
+        let signing_algs = metadata.id_token_signing_alg_values_supported().clone();
+        let client =
+            OidcClient::from_provider_metadata(
+                metadata,
+                ClientId::new(provider_config.client_id.clone()),
+                provider_config.client_secret.as_ref().map(|s| ClientSecret::new(s.clone())),
+            );
+        providers.insert(provider_config.id, OidcProvider {
+            name: provider_config.name,
+            client: client,
+            restrictions: provider_config.restrictions,
+            issuer: issuer,
+            client_id: provider_config.client_id,
+            end_session_endpoint: end_session_endpoint,
+            jwks: Mutex::new(jwks),
+            jwks_uri: jwks_uri,
+            jwks_fetched: Mutex::new(Instant::now()),
+            signing_algs: signing_algs,
+        });
+    }
+    let (session_ended, _) = broadcast::channel(100);
     return Ok(OidcState {
         log: log,
-        client: client,
+        providers: providers,
         pre_sessions: Cache::builder().max_capacity(10).time_to_live(Duration::from_secs(60 * 10)).build(),
-        sessions: Cache::builder().time_to_idle(Duration::from_secs(60 * 60 * 24 * 7)).build(),
+        sessions: Cache::builder()
+            .time_to_idle(Duration::from_secs(60 * 60 * 24 * 7))
+            .eviction_listener({
+                let session_ended = session_ended.clone();
+                move |_k, v: Arc<OidcSession>, _cause| {
+                    _ = session_ended.send(v.id.clone());
+                }
+            })
+            .build(),
+        session_ended: session_ended,
     });
 }
 
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    return out;
+}
+
+/// Minimal standalone page, used before the user has a session (so the wasm client
+/// can't be involved).
+fn response_html_page(status: http::StatusCode, title: &str, body: &str) -> Response<Body> {
+    return http::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(
+            body_full(
+                format!(
+                    "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>{}</title></head><body><h1>{}</h1>{}</body></html>",
+                    html_escape(title),
+                    html_escape(title),
+                    body
+                ).into_bytes(),
+            ),
+        )
+        .unwrap();
+}
+
+/// Returns a user-facing reason if the login should be rejected.
+fn check_restrictions(
+    restrictions: &OidcRestrictions,
+    claims: &IdTokenClaims<OidcExtraClaims, CoreGenderClaim>,
+) -> Option<String> {
+    if let Some(allowed) = &restrictions.allowed_subjects {
+        if !allowed.iter().any(|s| s.as_str() == claims.subject().as_str()) {
+            return Some(format!("This account isn't on the list of allowed users."));
+        }
+    }
+    if let Some(domains) = &restrictions.allowed_email_domains {
+        let Some(email) = claims.email() else {
+            return Some(format!("Your identity provider didn't share an email address."));
+        };
+        if claims.email_verified() != Some(true) {
+            return Some(format!("Your email address [{}] hasn't been verified.", email.as_str()));
+        }
+        let domain = email.as_str().rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+        if !domains.iter().any(|d| d.eq_ignore_ascii_case(domain)) {
+            return Some(format!("Accounts with email addresses at [{}] aren't allowed.", domain));
+        }
+    }
+    if let Some(required) = &restrictions.required_claim_value {
+        let found = match claims.additional_claims().other.get(&required.claim) {
+            Some(serde_json::Value::String(v)) => *v == required.value,
+            Some(serde_json::Value::Array(vs)) => vs.iter().any(|v| v.as_str() == Some(required.value.as_str())),
+            _ => false,
+        };
+        if !found {
+            return Some(format!("This account doesn't have the required [{}] membership.", required.claim));
+        }
+    }
+    return None;
+}
+
+/// Counts a completed OIDC callback when dropped, as failed unless the result was
+/// set first, so early error returns are counted.
+struct LoginResultMetric(&'static str);
+
+impl Drop for LoginResultMetric {
+    fn drop(&mut self) {
+        METRICS.oidc_logins.with_label_values(&[self.0]).inc();
+    }
+}
+
 pub async fn handle_oidc(state: &OidcState, head: Parts) -> Result<Response<Body>, VisErr<loga::Error>> {
     let log = state.log.clone();
     let Some(query) = head.uri.query() else {
@@ -215,13 +438,22 @@ pub async fn handle_oidc(state: &OidcState, head: Parts) -> Result<Response<Body
         let Ok(params) = serde_urlencoded::from_str::<Params>(query) else {
             break;
         };
+        let mut login_result = LoginResultMetric("failed");
         let Some(pre_session_state) = state.pre_sessions.remove(&params.state).await else {
             log.log_with(loga::DEBUG, "Missing pre-session state for state", ea!(state = params.state));
             break;
         };
+        let Some(provider) = state.providers.get(&pre_session_state.provider) else {
+            log.log_with(
+                loga::DEBUG,
+                "Pre-session refers to unknown provider",
+                ea!(provider = pre_session_state.provider),
+            );
+            break;
+        };
         let pkce_verifier = pre_session_state.pkce_verifier.lock().unwrap().take().unwrap();
         let token_response =
-            state
+            provider
                 .client
                 .exchange_code(AuthorizationCode::new(params.code))
                 .set_pkce_verifier(pkce_verifier)
@@ -234,7 +466,7 @@ pub async fn handle_oidc(state: &OidcState, head: Parts) -> Result<Response<Body
         let id_token = token_response.id_token().context("OIDC server response missing ID token").err_internal()?;
         let claims =
             id_token
-                .claims(&state.client.id_token_verifier(), &pre_session_state.nonce)
+                .claims(&provider.client.id_token_verifier(), &pre_session_state.nonce)
                 .context("Error getting claims from OIDC server response")
                 .err_internal()?;
         if let Some(expected_access_token_hash) = claims.access_token_hash() {
@@ -254,8 +486,39 @@ pub async fn handle_oidc(state: &OidcState, head: Parts) -> Result<Response<Body
                 break;
             }
         }
+        if let Some(reason) = check_restrictions(&provider.restrictions, claims) {
+            login_result.0 = "rejected";
+            log.log_with(
+                loga::DEBUG,
+                "Rejected login due to restrictions",
+                ea!(provider = pre_session_state.provider, subject = claims.subject().as_str(), reason = reason),
+            );
+            return Ok(
+                response_html_page(
+                    http::StatusCode::FORBIDDEN,
+                    "Login not allowed",
+                    &format!("<p>{}</p><p>Contact the server administrator if you think this is a mistake.</p>", html_escape(&reason)),
+                ),
+            );
+        }
         let session_cookie = Alphanumeric.sample_string(&mut rand::rng(), 32);
-        state.sessions.insert(session_cookie.clone(), AccountExternalId(claims.subject().to_string())).await;
+        let now = Timestamp::now();
+        state.sessions.insert(session_cookie.clone(), Arc::new(OidcSession {
+            id: SessionId(Alphanumeric.sample_string(&mut rand::rng(), 16)),
+            created: now,
+            last_seen: Mutex::new(now),
+            user_agent: summarize_user_agent(
+                head.headers.get(USER_AGENT).and_then(|v| v.to_str().ok()).unwrap_or_default(),
+            ),
+            account: AccountExternalId {
+                issuer: claims.issuer().to_string(),
+                subject: claims.subject().to_string(),
+            },
+            provider: pre_session_state.provider.clone(),
+            id_token: id_token.clone(),
+            sid: claims.additional_claims().other.get("sid").and_then(|s| s.as_str()).map(|s| s.to_string()),
+        })).await;
+        login_result.0 = "ok";
         return Ok(
             http::Response::builder()
                 .status(http::StatusCode::TEMPORARY_REDIRECT)
@@ -279,6 +542,8 @@ pub async fn handle_oidc(state: &OidcState, head: Parts) -> Result<Response<Body
     struct Params {
         #[serde(with = "http_serde::uri")]
         url: Uri,
+        #[serde(default)]
+        provider: Option<String>,
     }
 
     let params = match serde_urlencoded::from_str::<Params>(query) {
@@ -291,14 +556,41 @@ pub async fn handle_oidc(state: &OidcState, head: Parts) -> Result<Response<Body
             return Ok(response_400("Invalid query params"));
         },
     };
+    let provider_id = match params.provider {
+        Some(p) => p,
+        None => {
+            if state.providers.len() == 1 {
+                state.providers.keys().next().unwrap().clone()
+            } else {
+                let mut links = vec![];
+                for (id, provider) in &state.providers {
+                    links.push(
+                        format!(
+                            "<li><a href=\"?{}\">{}</a></li>",
+                            html_escape(&serde_urlencoded::to_string(&[
+                                //. .
+                                ("url", params.url.to_string()),
+                                ("provider", id.clone()),
+                            ]).unwrap()),
+                            html_escape(&provider.name)
+                        ),
+                    );
+                }
+                return Ok(response_html_page(http::StatusCode::OK, "Log in with", &format!("<ul>{}</ul>", links.join(""))));
+            }
+        },
+    };
+    let Some(provider) = state.providers.get(&provider_id) else {
+        return Ok(response_400("Unknown provider"));
+    };
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
     let (auth_url, csrf_token, nonce) =
-        state
+        provider
             .client
             .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
             .set_redirect_uri(
                 Cow::Owned(
-                    RedirectUrl::new(params.url.join("../oidc").to_string())
+                    RedirectUrl::new(params.url.join(&format!("{}oidc", C2SV1_PREFIX)).to_string())
                         .context("Error creating redirect url from current state url")
                         .err_internal()?,
                 ),
@@ -306,6 +598,7 @@ pub async fn handle_oidc(state: &OidcState, head: Parts) -> Result<Response<Body
             .set_pkce_challenge(pkce_challenge)
             .url();
     state.pre_sessions.insert(csrf_token.secret().clone(), Arc::new(OidcPreSession {
+        provider: provider_id,
         original_url: params.url,
         pkce_verifier: Mutex::new(Some(pkce_verifier)),
         nonce: nonce,
@@ -338,8 +631,370 @@ pub fn get_req_session<'a>(log: &Log, headers: &HeaderMap) -> Option<String> {
     return None;
 }
 
+/// Local-only logout, the session remains active at the provider.
 pub async fn handle_logout(state: &OidcState, log: &Log, head: Parts) -> () {
     if let Some(session) = get_req_session(log, &head.headers) {
         state.sessions.remove(&session).await;
     }
 }
+
+/// RP-initiated logout: ends the local session then sends the user to the
+/// provider's end session endpoint (if it has one) to log out there too. The
+/// provider returns the user to `url` afterwards.
+pub async fn handle_logout_redirect(state: &OidcState, head: Parts) -> Result<Response<Body>, VisErr<loga::Error>> {
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(with = "http_serde::uri")]
+        url: Uri,
+    }
+
+    let Some(query) = head.uri.query() else {
+        return Ok(response_400("Missing query"));
+    };
+    let params = match serde_urlencoded::from_str::<Params>(query) {
+        Ok(p) => p,
+        Err(e) => {
+            state
+                .log
+                .log_err(loga::DEBUG, e.context(format!("Received logout request with invalid query string: [{}]", query)));
+            return Ok(response_400("Invalid query params"));
+        },
+    };
+    let mut location = params.url.to_string();
+    shed!{
+        let Some(session_id) = get_req_session(&state.log, &head.headers) else {
+            break;
+        };
+        let Some(session) = state.sessions.remove(&session_id).await else {
+            break;
+        };
+        let Some(provider) = state.providers.get(&session.provider) else {
+            break;
+        };
+        let Some(end_session_endpoint) = &provider.end_session_endpoint else {
+            break;
+        };
+        location =
+            LogoutRequest::from(end_session_endpoint.clone())
+                .set_client_id(ClientId::new(provider.client_id.clone()))
+                .set_id_token_hint(&session.id_token)
+                .set_post_logout_redirect_uri(
+                    PostLogoutRedirectUrl::new(params.url.to_string())
+                        .context("Error creating post-logout redirect url")
+                        .err_internal()?,
+                )
+                .http_get_url()
+                .to_string();
+    }
+    return Ok(
+        http::Response::builder()
+            .status(http::StatusCode::TEMPORARY_REDIRECT)
+            .header(
+                http::header::SET_COOKIE,
+                CookieBuilder::new(COOKIE_SESSION, "").http_only(true).secure(true).removal().build().to_string(),
+            )
+            .header(http::header::LOCATION, location)
+            .body(body_empty())
+            .unwrap(),
+    );
+}
+
+#[derive(Deserialize)]
+struct LogoutTokenHeader {
+    alg: CoreJwsSigningAlgorithm,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LogoutTokenAudience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct LogoutTokenClaims {
+    iss: String,
+    aud: LogoutTokenAudience,
+    iat: i64,
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    sid: Option<String>,
+    #[serde(default)]
+    nonce: Option<serde_json::Value>,
+    events: HashMap<String, serde_json::Value>,
+}
+
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+const BACKCHANNEL_LOGOUT_MAX_AGE: Duration = Duration::from_secs(60 * 5);
+const JWKS_REFETCH_MIN: Duration = Duration::from_secs(60);
+
+fn verify_with_jwks(
+    jwks: &CoreJsonWebKeySet,
+    header: &LogoutTokenHeader,
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    for key in jwks.keys() {
+        if let Some(kid) = &header.kid {
+            if key.key_id().map(|k| k.as_str()) != Some(kid.as_str()) {
+                continue;
+            }
+        }
+        if key.verify_signature(&header.alg, message, signature).is_ok() {
+            return true;
+        }
+    }
+    return false;
+}
+
+fn jwks_has_kid(jwks: &CoreJsonWebKeySet, kid: &str) -> bool {
+    return jwks.keys().iter().any(|k| k.key_id().map(|k| k.as_str()) == Some(kid));
+}
+
+/// Fetches the provider's current keys, unless they were fetched recently.
+/// Returns false if skipped.
+async fn refetch_jwks(log: &Log, provider: &OidcProvider) -> Result<bool, loga::Error> {
+    {
+        let mut fetched = provider.jwks_fetched.lock().unwrap();
+        if fetched.elapsed() < JWKS_REFETCH_MIN {
+            return Ok(false);
+        }
+        *fetched = Instant::now();
+    }
+    let jwks = CoreJsonWebKeySet::fetch_async(&provider.jwks_uri, cap_fn!((r)(log) {
+        return oidc_http_client(&log, r).await.map_err(|e| std::io::Error::other(e.to_string()));
+    })).await.context_with("Error fetching provider keys", ea!(url = provider.jwks_uri.as_str()))?;
+    *provider.jwks.lock().unwrap() = jwks;
+    return Ok(true);
+}
+
+/// Validates a back-channel logout token per OpenID Connect Back-Channel Logout
+/// 1.0 section 2.6. Returns the claims if valid.
+async fn verify_logout_token(
+    log: &Log,
+    provider: &OidcProvider,
+    token: &str,
+) -> Result<LogoutTokenClaims, loga::Error> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(loga::err("Logout token isn't a signed JWT"));
+        };
+    let header =
+        serde_json::from_slice::<LogoutTokenHeader>(
+            &URL_SAFE_NO_PAD.decode(header).context("Logout token header isn't valid base64")?,
+        ).context("Logout token header is invalid")?;
+
+    // Never let the token pick the algorithm: symmetric algorithms would verify
+    // against public key material
+    match header.alg {
+        CoreJwsSigningAlgorithm::None |
+        CoreJwsSigningAlgorithm::HmacSha256 |
+        CoreJwsSigningAlgorithm::HmacSha384 |
+        CoreJwsSigningAlgorithm::HmacSha512 => {
+            return Err(loga::err_with("Logout token uses a disallowed algorithm", ea!(alg = header.alg.dbg_str())));
+        },
+        _ => { },
+    }
+    if !provider.signing_algs.contains(&header.alg) {
+        return Err(
+            loga::err_with("Logout token algorithm isn't one the provider advertises", ea!(alg = header.alg.dbg_str())),
+        );
+    }
+    let signature = URL_SAFE_NO_PAD.decode(signature).context("Logout token signature isn't valid base64")?;
+    let message = &token[..token.rfind('.').unwrap()];
+    let mut verified =
+        verify_with_jwks(&provider.jwks.lock().unwrap(), &header, message.as_bytes(), &signature);
+    if !verified {
+        if let Some(kid) = &header.kid {
+            let known = jwks_has_kid(&provider.jwks.lock().unwrap(), kid);
+            if !known && refetch_jwks(log, provider).await? {
+                verified = verify_with_jwks(&provider.jwks.lock().unwrap(), &header, message.as_bytes(), &signature);
+            }
+        }
+    }
+    if !verified {
+        return Err(loga::err("Logout token signature doesn't match any provider key"));
+    }
+    let claims =
+        serde_json::from_slice::<LogoutTokenClaims>(
+            &URL_SAFE_NO_PAD.decode(payload).context("Logout token payload isn't valid base64")?,
+        ).context("Logout token claims are invalid")?;
+    if claims.iss != provider.issuer.as_str() {
+        return Err(loga::err_with("Logout token issuer mismatch", ea!(got = claims.iss)));
+    }
+    let aud_ok = match &claims.aud {
+        LogoutTokenAudience::One(a) => *a == provider.client_id,
+        LogoutTokenAudience::Many(a) => a.iter().any(|a| *a == provider.client_id),
+    };
+    if !aud_ok {
+        return Err(loga::err("Logout token audience doesn't include this client"));
+    }
+    let age = jiff::Timestamp::now().as_second() - claims.iat;
+    if age < -60 || age > BACKCHANNEL_LOGOUT_MAX_AGE.as_secs() as i64 {
+        return Err(loga::err_with("Logout token issued at unreasonable time", ea!(age = age)));
+    }
+    if !claims.events.contains_key(BACKCHANNEL_LOGOUT_EVENT) {
+        return Err(loga::err("Logout token is missing the back-channel logout event"));
+    }
+    if claims.nonce.is_some() {
+        return Err(loga::err("Logout token must not contain a nonce"));
+    }
+    if claims.sub.is_none() && claims.sid.is_none() {
+        return Err(loga::err("Logout token has neither sub nor sid"));
+    }
+    return Ok(claims);
+}
+
+/// Back-channel logout: the provider POSTs a `logout_token` here (configure
+/// `<base url>c/1/logout_backchannel/<provider id>` as the back-channel logout URI
+/// at the provider) when a user logs out there, and matching sessions are ended.
+pub async fn handle_backchannel_logout(
+    state: &OidcState,
+    provider_id: &str,
+    body: &[u8],
+) -> Result<Response<Body>, VisErr<loga::Error>> {
+    #[derive(Deserialize)]
+    struct Params {
+        logout_token: String,
+    }
+
+    let log = state.log.fork(ea!(provider = provider_id));
+    let Some(provider) = state.providers.get(provider_id) else {
+        return Ok(response_400("Unknown provider"));
+    };
+    let params = match serde_urlencoded::from_bytes::<Params>(body) {
+        Ok(p) => p,
+        Err(e) => {
+            log.log_err(loga::DEBUG, e.context("Received back-channel logout with invalid body"));
+            return Ok(response_400("Invalid body"));
+        },
+    };
+    let claims = match verify_logout_token(&log, provider, &params.logout_token).await {
+        Ok(c) => c,
+        Err(e) => {
+            log.log_err(loga::DEBUG, e.context("Rejected back-channel logout token"));
+            return Ok(response_400("Invalid logout token"));
+        },
+    };
+    let mut ended = vec![];
+    for (session_id, session) in state.sessions.iter() {
+        if session.provider != provider_id {
+            continue;
+        }
+        if let Some(sid) = &claims.sid {
+            if session.sid.as_ref() != Some(sid) {
+                continue;
+            }
+        }
+        if let Some(sub) = &claims.sub {
+            if session.account.subject != *sub {
+                continue;
+            }
+        }
+        ended.push(session_id);
+    }
+    log.log_with(loga::DEBUG, "Ending sessions from back-channel logout", ea!(count = ended.len()));
+    for session_id in ended {
+        state.sessions.invalidate(&*session_id).await;
+    }
+    return Ok(
+        http::Response::builder()
+            .status(http::StatusCode::OK)
+            .header(CACHE_CONTROL, "no-store")
+            .body(body_empty())
+            .unwrap(),
+    );
+}
+
+/// Produces a short description like `Firefox on Android` for listing sessions.
+pub fn summarize_user_agent(ua: &str) -> String {
+    let browser = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("SamsungBrowser/", "Samsung Internet"),
+        ("Firefox/", "Firefox"),
+        ("FxiOS/", "Firefox"),
+        ("CriOS/", "Chrome"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+    ].into_iter().find(|(needle, _)| ua.contains(needle)).map(|(_, name)| name);
+    let os = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("CrOS", "ChromeOS"),
+        ("Linux", "Linux"),
+    ].into_iter().find(|(needle, _)| ua.contains(needle)).map(|(_, name)| name);
+    match (browser, os) {
+        (Some(b), Some(o)) => return format!("{} on {}", b, o),
+        (Some(b), None) => return b.to_string(),
+        (None, Some(o)) => return format!("Unknown browser on {}", o),
+        (None, None) => {
+            if ua.is_empty() {
+                return format!("Unknown device");
+            }
+            return ua.chars().take(40).collect();
+        },
+    }
+}
+
+pub async fn list_sessions(
+    state: &OidcState,
+    account: &AccountExternalId,
+    current_session_cookie: Option<&String>,
+) -> Vec<SessionRes> {
+    let mut out = vec![];
+    for (cookie, session) in state.sessions.iter() {
+        if session.account != *account {
+            continue;
+        }
+        out.push(SessionRes {
+            id: session.id.clone(),
+            created: session.created,
+            last_seen: *session.last_seen.lock().unwrap(),
+            user_agent: session.user_agent.clone(),
+            current: current_session_cookie == Some(&*cookie),
+        });
+    }
+    out.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+    return out;
+}
+
+/// Ends the account's sessions matching `target`. Sessions belonging to other
+/// accounts are never affected. Ending a session removes its push subscriptions
+/// (see `session_ended`); nothing else is tied to sessions yet.
+pub async fn revoke_sessions(
+    state: &OidcState,
+    account: &AccountExternalId,
+    current_session_cookie: Option<&String>,
+    target: &SessionRevokeTarget,
+) {
+    let mut revoke = vec![];
+    for (cookie, session) in state.sessions.iter() {
+        if session.account != *account {
+            continue;
+        }
+        match target {
+            SessionRevokeTarget::Session(id) => {
+                if session.id != *id {
+                    continue;
+                }
+            },
+            SessionRevokeTarget::AllOthers => {
+                if current_session_cookie == Some(&*cookie) {
+                    continue;
+                }
+            },
+        }
+        revoke.push(cookie);
+    }
+    for cookie in revoke {
+        state.sessions.invalidate(&*cookie).await;
+    }
+}
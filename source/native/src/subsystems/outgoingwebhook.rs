@@ -0,0 +1,957 @@
+//! Outgoing webhooks: channel events are `POST`ed as signed JSON to registered
+//! URLs. Deliveries are queued in the database and retried with exponential
+//! backoff so they survive restarts and receiver outages.
+//!
+//! Receivers can verify a delivery by computing HMAC-SHA256 over
+//! `<X-Kwa-Timestamp>.<body>` with the webhook secret and comparing it to the hex
+//! in `X-Kwa-Signature` (after the `sha256=` prefix).
+use {
+    crate::{
+        dbutil::{
+            abortable_tx,
+            tx,
+            Txr,
+        },
+        interface::{
+            db::{
+                DbAccountExternalId,
+                DbChannelId,
+                DbIdentity,
+                DbOutgoingWebhookEvents,
+            },
+            AccountExternalId,
+        },
+        subsystems::s2sclient,
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query,
+        good_query_many,
+        good_query_opt,
+    },
+    hmac::{
+        Hmac,
+        Mac,
+    },
+    http::{
+        header::{
+            CONTENT_TYPE,
+            HOST,
+        },
+        Request,
+        Uri,
+    },
+    htwrap::{
+        htreq,
+        htserve::responses::body_full,
+    },
+    jiff::{
+        SignedDuration,
+        Timestamp,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    rand::distr::{
+        Alphanumeric,
+        SampleString,
+    },
+    serde::Serialize,
+    sha2::Sha256,
+    shared::interface::{
+        shared::{
+            MessageId,
+            OutgoingWebhookDeliveryId,
+            OutgoingWebhookId,
+            QualifiedChannelId,
+        },
+        wire::c2s::{
+            OutgoingWebhookCreate,
+            OutgoingWebhookDeliveryRes,
+            OutgoingWebhookDeliveryStatus,
+            OutgoingWebhookRes,
+        },
+    },
+    spaghettinuum::interface::identity::Identity,
+    std::{
+        sync::Arc,
+        time::Duration,
+    },
+    taskmanager::TaskManager,
+    tokio::{
+        select,
+        sync::Notify,
+        time::{
+            sleep,
+            timeout,
+        },
+    },
+};
+
+const STATUS_PENDING: &str = "pending";
+const STATUS_DELIVERED: &str = "delivered";
+const STATUS_FAILED: &str = "failed";
+
+/// Give up after this many attempts - with the backoff below that's roughly two
+/// days of retrying.
+const MAX_ATTEMPTS: i64 = 12;
+const BACKOFF_BASE: SignedDuration = SignedDuration::from_secs(30);
+const BACKOFF_MAX: SignedDuration = SignedDuration::from_secs(60 * 60 * 6);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Finished deliveries are kept this long for the delivery log.
+const LOG_RETENTION: SignedDuration = SignedDuration::from_secs(60 * 60 * 24 * 30);
+const BATCH_SIZE: i64 = 20;
+
+pub struct OutgoingWebhookState {
+    /// Wakes the delivery worker when something is queued.
+    wake: Arc<Notify>,
+    /// See `Config::outgoing_webhooks_allow_private`.
+    allow_private: bool,
+}
+
+pub fn new_state(allow_private: bool) -> OutgoingWebhookState {
+    return OutgoingWebhookState {
+        wake: Arc::new(Notify::new()),
+        allow_private: allow_private,
+    };
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutgoingWebhookEvent {
+    Ping,
+    MessageNew {
+        id: MessageId,
+        sender: Identity,
+        body: String,
+    },
+    MessageEdited {
+        id: MessageId,
+        sender: Identity,
+        body: String,
+    },
+    MessageDeleted {
+        id: MessageId,
+    },
+}
+
+impl OutgoingWebhookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            OutgoingWebhookEvent::Ping => return "ping",
+            OutgoingWebhookEvent::MessageNew { .. } => return "message_new",
+            OutgoingWebhookEvent::MessageEdited { .. } => return "message_edited",
+            OutgoingWebhookEvent::MessageDeleted { .. } => return "message_deleted",
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+struct OutgoingWebhookPayload<'a> {
+    webhook: OutgoingWebhookId,
+    channel: &'a QualifiedChannelId,
+    time: Timestamp,
+    event: &'a OutgoingWebhookEvent,
+}
+
+fn backoff(attempts: i64) -> SignedDuration {
+    let mut out = BACKOFF_BASE;
+    for _ in 1 .. attempts {
+        out = out * 2;
+        if out >= BACKOFF_MAX {
+            return BACKOFF_MAX;
+        }
+    }
+    return out;
+}
+
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    return hex::encode(mac.finalize().into_bytes());
+}
+
+fn parse_status(s: &str) -> OutgoingWebhookDeliveryStatus {
+    match s {
+        STATUS_DELIVERED => return OutgoingWebhookDeliveryStatus::Delivered,
+        STATUS_FAILED => return OutgoingWebhookDeliveryStatus::Failed,
+        _ => return OutgoingWebhookDeliveryStatus::Pending,
+    }
+}
+
+pub fn validate_url(url: &str) -> Result<(), loga::Error> {
+    match url.parse::<Uri>() {
+        Ok(u) if matches!(u.scheme_str(), Some("http") | Some("https")) && u.host().is_some() => {
+            return Ok(());
+        },
+        _ => return Err(loga::err_with("Webhook URL must be an absolute http or https URL", ea!(url = url))),
+    }
+}
+
+/// Returns `None` if the channel isn't owned by the account. Check the URL with
+/// `validate_url` first.
+pub async fn create(
+    db: &Pool,
+    account: &AccountExternalId,
+    req: OutgoingWebhookCreate,
+) -> Result<Option<OutgoingWebhookRes>, loga::Error> {
+    let account = account.clone();
+    let secret = Alphanumeric.sample_string(&mut rand::rng(), 32);
+    return Ok(abortable_tx(db, move |db_tx| {
+        let owned = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 channel.id
+               from
+                 channel
+                 join account on channel.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+                 and channel.identity = ${identity_id_t = DbIdentity(req.channel.identity.clone())}
+                 and channel.id = ${channel_id_t = DbChannelId(req.channel.channel.clone())}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        if owned.is_none() {
+            return Ok(Txr::Abort);
+        }
+        let rowid = good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"insert into
+                 outgoing_webhook
+                 (account, channel_identity, channel, url, secret, memo_short, events)
+               values (
+                 ${account_external_id_t = DbAccountExternalId(account.clone())},
+                 ${identity_id_t = DbIdentity(req.channel.identity.clone())},
+                 ${channel_id_t = DbChannelId(req.channel.channel.clone())},
+                 ${str = req.url.clone()},
+                 ${str = secret.clone()},
+                 ${str = req.memo_short.clone()},
+                 ${outgoing_webhook_events_t = DbOutgoingWebhookEvents(req.events)}
+               )
+               returning rowid
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(Txr::Ok(OutgoingWebhookRes {
+            id: OutgoingWebhookId(rowid as u64),
+            channel: req.channel,
+            url: req.url,
+            memo_short: req.memo_short,
+            events: req.events,
+            secret: secret,
+        }));
+    }).await?);
+}
+
+pub async fn list(
+    db: &Pool,
+    account: &AccountExternalId,
+    channel: QualifiedChannelId,
+) -> Result<Vec<OutgoingWebhookRes>, loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        let rows = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 rowid,
+                 url,
+                 secret,
+                 memo_short,
+                 events
+               from
+                 outgoing_webhook
+               where
+                 account = ${account_external_id_t = DbAccountExternalId(account)}
+                 and channel_identity = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(rows.into_iter().map(|r| OutgoingWebhookRes {
+            id: OutgoingWebhookId(r.rowid as u64),
+            channel: channel.clone(),
+            url: r.url,
+            memo_short: r.memo_short,
+            events: r.events.0,
+            secret: r.secret,
+        }).collect());
+    }).await?);
+}
+
+fn owns_webhook(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    account: &AccountExternalId,
+    id: OutgoingWebhookId,
+) -> Result<bool, loga::Error> {
+    return Ok(good_query_opt!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"select
+             rowid
+           from
+             outgoing_webhook
+           where
+             rowid = ${i64 = id.0 as i64}
+             and account = ${account_external_id_t = DbAccountExternalId(account.clone())}
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?.is_some());
+}
+
+pub async fn delete(db: &Pool, account: &AccountExternalId, id: OutgoingWebhookId) -> Result<(), loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        if !owns_webhook(db_tx, &account, id)? {
+            return Ok(());
+        }
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"delete from outgoing_webhook_delivery where webhook = ${i64 = id.0 as i64}"#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"delete from outgoing_webhook where rowid = ${i64 = id.0 as i64}"#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(());
+    }).await?);
+}
+
+/// Returns `None` if the webhook doesn't exist or belongs to another account.
+pub async fn list_deliveries(
+    db: &Pool,
+    account: &AccountExternalId,
+    id: OutgoingWebhookId,
+) -> Result<Option<Vec<OutgoingWebhookDeliveryRes>>, loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        if !owns_webhook(db_tx, &account, id)? {
+            return Ok(None);
+        }
+        let rows = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 rowid,
+                 event,
+                 created,
+                 status,
+                 attempts,
+                 next_attempt,
+                 last_error
+               from
+                 outgoing_webhook_delivery
+               where
+                 webhook = ${i64 = id.0 as i64}
+               order by
+                 rowid desc
+               limit
+                 100
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(Some(rows.into_iter().map(|r| {
+            let status = parse_status(&r.status);
+            return OutgoingWebhookDeliveryRes {
+                id: OutgoingWebhookDeliveryId(r.rowid as u64),
+                event: r.event,
+                created: r.created,
+                status: status,
+                attempts: r.attempts as u32,
+                next_attempt: if status == OutgoingWebhookDeliveryStatus::Pending {
+                    Some(r.next_attempt)
+                } else {
+                    None
+                },
+                last_error: r.last_error,
+            };
+        }).collect()));
+    }).await?);
+}
+
+fn insert_delivery(
+    db_tx: &mut crate::db::Db<rusqlite::Transaction<'_>>,
+    webhook: OutgoingWebhookId,
+    channel: &QualifiedChannelId,
+    event: &OutgoingWebhookEvent,
+) -> Result<(), loga::Error> {
+    let now = Timestamp::now();
+    let payload = serde_json::to_string(&OutgoingWebhookPayload {
+        webhook: webhook,
+        channel: channel,
+        time: now,
+        event: event,
+    }).unwrap();
+    good_query!(
+        crate::db,
+        //# genemichaels-external: sql-formatter-sqlite
+        r#"insert into
+             outgoing_webhook_delivery
+             (webhook, event, payload, created, status, attempts, next_attempt)
+           values (
+             ${i64 = webhook.0 as i64},
+             ${str = event.name().to_string()},
+             ${str = payload},
+             ${utctime_s_jiff = now},
+             ${str = STATUS_PENDING.to_string()},
+             ${i64 = 0},
+             ${utctime_s_jiff = now}
+           )
+           "#;
+        db_tx
+    ).map_err(|e| loga::err(e.0))?;
+    return Ok(());
+}
+
+/// Queues a `ping`. Returns `None` if the webhook doesn't exist or belongs to
+/// another account.
+pub async fn enqueue_test(
+    db: &Pool,
+    state: &OutgoingWebhookState,
+    account: &AccountExternalId,
+    id: OutgoingWebhookId,
+) -> Result<Option<()>, loga::Error> {
+    let account = account.clone();
+    let found = tx(db, move |db_tx| {
+        let Some(row) = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 channel_identity,
+                 channel
+               from
+                 outgoing_webhook
+               where
+                 rowid = ${i64 = id.0 as i64}
+                 and account = ${account_external_id_t = DbAccountExternalId(account)}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))? else {
+            return Ok(false);
+        };
+        insert_delivery(db_tx, id, &QualifiedChannelId {
+            identity: row.channel_identity.0,
+            channel: row.channel.0,
+        }, &OutgoingWebhookEvent::Ping)?;
+        return Ok(true);
+    }).await?;
+    if !found {
+        return Ok(None);
+    }
+    state.wake.notify_one();
+    return Ok(Some(()));
+}
+
+/// Queues a delivery of `event` to every webhook on the channel subscribed to it.
+/// Call this after the change has been committed.
+pub async fn enqueue_channel_event(
+    db: &Pool,
+    state: &OutgoingWebhookState,
+    channel: QualifiedChannelId,
+    event: OutgoingWebhookEvent,
+) -> Result<(), loga::Error> {
+    tx(db, move |db_tx| {
+        let hooks = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 rowid,
+                 events
+               from
+                 outgoing_webhook
+               where
+                 channel_identity = ${identity_id_t = DbIdentity(channel.identity.clone())}
+                 and channel = ${channel_id_t = DbChannelId(channel.channel.clone())}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        for hook in hooks {
+            let wanted = match &event {
+                OutgoingWebhookEvent::Ping => false,
+                OutgoingWebhookEvent::MessageNew { .. } => hook.events.0.message_new,
+                OutgoingWebhookEvent::MessageEdited { .. } => hook.events.0.message_edited,
+                OutgoingWebhookEvent::MessageDeleted { .. } => hook.events.0.message_deleted,
+            };
+            if !wanted {
+                continue;
+            }
+            insert_delivery(db_tx, OutgoingWebhookId(hook.rowid as u64), &channel, &event)?;
+        }
+        return Ok(());
+    }).await?;
+    state.wake.notify_one();
+    return Ok(());
+}
+
+async fn deliver(
+    log: &Log,
+    allow_private: bool,
+    url: &str,
+    secret: &str,
+    delivery: i64,
+    event: &str,
+    payload: String,
+) -> Result<(), loga::Error> {
+    let uri = url.parse::<Uri>().context("Invalid webhook URL")?;
+    let body = payload.into_bytes();
+    let timestamp = Timestamp::now().as_second().to_string();
+    let signature = sign(secret, &timestamp, &body);
+    let req =
+        Request::builder()
+            .method(http::Method::POST)
+            .uri(uri.clone())
+            .header(HOST, uri.authority().map(|a| a.as_str()).unwrap_or_default())
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Kwa-Event", event)
+            .header("X-Kwa-Delivery", delivery.to_string())
+            .header("X-Kwa-Timestamp", &timestamp)
+            .header("X-Kwa-Signature", format!("sha256={}", signature))
+            .body(body_full(body))
+            .unwrap();
+    let (code, _headers, continue_) = timeout(DELIVERY_TIMEOUT, async {
+        let mut conn = s2sclient::connect(allow_private, &uri).await?;
+        return Ok(htreq::send(log, htreq::Limits::default(), &mut conn, req).await?) as Result<_, loga::Error>;
+    }).await.map_err(|_| loga::err("Timed out waiting for receiver"))??;
+    if !code.is_success() {
+        let body = htreq::receive(htreq::Limits::default(), continue_).await.unwrap_or_default();
+        return Err(
+            loga::err_with(
+                "Receiver returned an error",
+                ea!(status = code, body = String::from_utf8_lossy(&body[..body.len().min(200)])),
+            ),
+        );
+    }
+    return Ok(());
+}
+
+/// Attempts all due deliveries, returning how many were attempted.
+async fn process_due(log: &Log, db: &Pool, allow_private: bool) -> Result<usize, loga::Error> {
+    let due = tx(db, |db_tx| {
+        return Ok(good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 outgoing_webhook_delivery.rowid,
+                 outgoing_webhook_delivery.event,
+                 outgoing_webhook_delivery.payload,
+                 outgoing_webhook_delivery.attempts,
+                 outgoing_webhook.url,
+                 outgoing_webhook.secret
+               from
+                 outgoing_webhook_delivery
+                 join outgoing_webhook on outgoing_webhook_delivery.webhook = outgoing_webhook.rowid
+               where
+                 outgoing_webhook_delivery.status = ${str = STATUS_PENDING.to_string()}
+                 and outgoing_webhook_delivery.next_attempt <= ${utctime_s_jiff = Timestamp::now()}
+               order by
+                 outgoing_webhook_delivery.next_attempt
+               limit
+                 ${i64 = BATCH_SIZE}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?);
+    }).await?;
+    let count = due.len();
+    for d in due {
+        let result = deliver(log, allow_private, &d.url, &d.secret, d.rowid, &d.event, d.payload).await;
+        let attempts = d.attempts + 1;
+        let (status, last_error, next_attempt) = match result {
+            Ok(_) => (STATUS_DELIVERED, None, Timestamp::now()),
+            Err(e) => {
+                log.log_err(
+                    loga::DEBUG,
+                    e.context_with("Outgoing webhook delivery failed", ea!(delivery = d.rowid, attempts = attempts)),
+                );
+                let e = e.to_string();
+                if attempts >= MAX_ATTEMPTS {
+                    (STATUS_FAILED, Some(e), Timestamp::now())
+                } else {
+                    (STATUS_PENDING, Some(e), Timestamp::now() + backoff(attempts))
+                }
+            },
+        };
+        let rowid = d.rowid;
+        tx(db, move |db_tx| {
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"update outgoing_webhook_delivery
+                   set
+                     status = ${str = status.to_string()},
+                     attempts = ${i64 = attempts},
+                     next_attempt = ${utctime_s_jiff = next_attempt},
+                     last_error = ${str? = last_error}
+                   where
+                     rowid = ${i64 = rowid}
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            return Ok(());
+        }).await?;
+    }
+    return Ok(count);
+}
+
+async fn prune_log(db: &Pool) -> Result<(), loga::Error> {
+    let cutoff = Timestamp::now() - LOG_RETENTION;
+    tx(db, move |db_tx| {
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"delete from
+                 outgoing_webhook_delivery
+               where
+                 status != ${str = STATUS_PENDING.to_string()}
+                 and created < ${utctime_s_jiff = cutoff}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(());
+    }).await?;
+    return Ok(());
+}
+
+/// Delivers queued events until shutdown. Anything in flight at shutdown is
+/// retried on the next start.
+pub fn spawn_worker(log: &Log, tm: &TaskManager, db: Pool, state: &OutgoingWebhookState) {
+    let log = log.fork(ea!(sys = "outgoing_webhook"));
+    tm.task("outgoing_webhook", {
+        let tm = tm.clone();
+        let wake = state.wake.clone();
+        let allow_private = state.allow_private;
+        async move {
+            let mut last_prune = None;
+            loop {
+                if last_prune
+                    .map(|t: Timestamp| Timestamp::now().duration_since(t) > SignedDuration::from_hours(1))
+                    .unwrap_or(true) {
+                    if let Err(e) = prune_log(&db).await {
+                        log.log_err(loga::WARN, e.context("Error pruning outgoing webhook delivery log"));
+                    }
+                    last_prune = Some(Timestamp::now());
+                }
+                let attempted = match process_due(&log, &db, allow_private).await {
+                    Ok(n) => n,
+                    Err(e) => {
+                        log.log_err(loga::WARN, e.context("Error processing outgoing webhook queue"));
+                        0
+                    },
+                };
+                if attempted as i64 >= BATCH_SIZE {
+                    // More may be due already
+                    continue;
+                }
+                select!{
+                    _ = tm.until_terminate() => {
+                        break;
+                    },
+                    _ = wake.notified() => { },
+                    // Pick up retries whose backoff has elapsed
+                    _ = sleep(Duration::from_secs(30)) => { },
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            backoff,
+            enqueue_channel_event,
+            enqueue_test,
+            list_deliveries,
+            new_state,
+            process_due,
+            OutgoingWebhookEvent,
+            BACKOFF_BASE,
+            BACKOFF_MAX,
+            MAX_ATTEMPTS,
+        },
+        crate::{
+            dbutil::{
+                test_db,
+                tx,
+            },
+            interface::{
+                db::{
+                    DbAccountExternalId,
+                    DbChannelId,
+                    DbIdentity,
+                    DbOutgoingWebhookEvents,
+                },
+                AccountExternalId,
+            },
+        },
+        deadpool_sqlite::Pool,
+        good_ormning::sqlite::good_query,
+        hmac::{
+            Hmac,
+            Mac,
+        },
+        htwrap::htserve::responses::body_empty,
+        http::{
+            HeaderMap,
+            Request,
+            Response,
+        },
+        http_body_util::BodyExt,
+        hyper::{
+            body::Incoming,
+            service::service_fn,
+        },
+        hyper_util::rt::TokioIo,
+        jiff::{
+            SignedDuration,
+            Timestamp,
+        },
+        loga::Log,
+        sha2::Sha256,
+        shared::interface::{
+            shared::{
+                ChannelId,
+                MessageId,
+                OutgoingWebhookId,
+                QualifiedChannelId,
+            },
+            wire::c2s::{
+                OutgoingWebhookDeliveryStatus,
+                OutgoingWebhookEvents,
+            },
+        },
+        spaghettinuum::interface::identity::LocalIdentitySecret,
+        std::{
+            sync::{
+                Arc,
+                Mutex,
+            },
+        },
+        tokio::net::TcpListener,
+    };
+
+    const SECRET: &str = "test-secret";
+
+    struct Receiver {
+        url: String,
+        received: Arc<Mutex<Vec<(HeaderMap, Vec<u8>)>>>,
+    }
+
+    /// An http server on loopback that records requests and always responds with
+    /// `status`.
+    async fn start_receiver(status: u16) -> Receiver {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(vec![]));
+        tokio::spawn({
+            let received = received.clone();
+            async move {
+                loop {
+                    let Ok((stream, _)) = listener.accept().await else {
+                        return;
+                    };
+                    let received = received.clone();
+                    tokio::spawn(async move {
+                        _ =
+                            hyper::server::conn::http1::Builder::new()
+                                .serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| {
+                                    let received = received.clone();
+                                    async move {
+                                        let (head, body) = req.into_parts();
+                                        let body = body.collect().await.unwrap().to_bytes().to_vec();
+                                        received.lock().unwrap().push((head.headers, body));
+                                        return Ok::<_, std::io::Error>(
+                                            Response::builder().status(status).body(body_empty()).unwrap(),
+                                        );
+                                    }
+                                }))
+                                .await;
+                    });
+                }
+            }
+        });
+        return Receiver {
+            url: url,
+            received: received,
+        };
+    }
+
+    fn account() -> AccountExternalId {
+        return AccountExternalId {
+            issuer: "https://issuer.example.org".to_string(),
+            subject: "test".to_string(),
+        };
+    }
+
+    /// Adds a webhook subscribed to `message_new` only, bypassing the channel
+    /// ownership check in `create`.
+    async fn add_webhook(db: &Pool, url: String) -> (OutgoingWebhookId, QualifiedChannelId) {
+        let (channel_identity, _) = LocalIdentitySecret::new();
+        let channel = QualifiedChannelId {
+            identity: channel_identity.clone(),
+            channel: ChannelId(1),
+        };
+        let rowid = tx(db, move |db_tx| {
+            return Ok(good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"insert into
+                     outgoing_webhook
+                     (account, channel_identity, channel, url, secret, memo_short, events)
+                   values (
+                     ${account_external_id_t = DbAccountExternalId(account())},
+                     ${identity_id_t = DbIdentity(channel_identity)},
+                     ${channel_id_t = DbChannelId(ChannelId(1))},
+                     ${str = url},
+                     ${str = SECRET.to_string()},
+                     ${str = String::new()},
+                     ${outgoing_webhook_events_t = DbOutgoingWebhookEvents(OutgoingWebhookEvents {
+                         message_new: true,
+                         message_edited: false,
+                         message_deleted: false,
+                     })}
+                   )
+                   returning rowid
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?);
+        }).await.unwrap();
+        return (OutgoingWebhookId(rowid as u64), channel);
+    }
+
+    /// Adds a webhook and queues a ping to it.
+    async fn queue_ping(db: &Pool, url: String) -> OutgoingWebhookId {
+        let (id, _) = add_webhook(db, url).await;
+        enqueue_test(db, &new_state(true), &account(), id).await.unwrap().unwrap();
+        return id;
+    }
+
+    #[test]
+    fn backoff_doubles_to_max() {
+        assert_eq!(backoff(1), BACKOFF_BASE);
+        assert_eq!(backoff(2), BACKOFF_BASE * 2);
+        assert_eq!(backoff(3), BACKOFF_BASE * 4);
+        assert_eq!(backoff(MAX_ATTEMPTS), BACKOFF_MAX);
+    }
+
+    #[tokio::test]
+    async fn delivery_signed() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        let receiver = start_receiver(200).await;
+        let id = queue_ping(&db, receiver.url.clone()).await;
+        assert_eq!(process_due(&log, &db, true).await.unwrap(), 1);
+        let received = receiver.received.lock().unwrap().clone();
+        assert_eq!(received.len(), 1);
+        let (headers, body) = &received[0];
+        assert_eq!(headers.get("X-Kwa-Event").unwrap(), "ping");
+        let timestamp = headers.get("X-Kwa-Timestamp").unwrap().to_str().unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+        assert_eq!(
+            headers.get("X-Kwa-Signature").unwrap().to_str().unwrap(),
+            format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+        );
+        let deliveries = list_deliveries(&db, &account(), id).await.unwrap().unwrap();
+        assert_eq!(deliveries[0].status, OutgoingWebhookDeliveryStatus::Delivered);
+        assert_eq!(deliveries[0].attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn delivery_private_refused() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        let receiver = start_receiver(200).await;
+        let id = queue_ping(&db, receiver.url.clone()).await;
+        assert_eq!(process_due(&log, &db, false).await.unwrap(), 1);
+        assert!(receiver.received.lock().unwrap().is_empty());
+        let deliveries = list_deliveries(&db, &account(), id).await.unwrap().unwrap();
+        assert_eq!(deliveries[0].status, OutgoingWebhookDeliveryStatus::Pending);
+        assert!(deliveries[0].last_error.is_some());
+    }
+
+    #[tokio::test]
+    async fn delivery_retried_with_backoff() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        let receiver = start_receiver(500).await;
+        let id = queue_ping(&db, receiver.url.clone()).await;
+        let before = Timestamp::now();
+        assert_eq!(process_due(&log, &db, true).await.unwrap(), 1);
+        let deliveries = list_deliveries(&db, &account(), id).await.unwrap().unwrap();
+        assert_eq!(deliveries[0].status, OutgoingWebhookDeliveryStatus::Pending);
+        assert_eq!(deliveries[0].attempts, 1);
+        assert!(deliveries[0].last_error.is_some());
+        assert!(deliveries[0].next_attempt.unwrap() >= before + BACKOFF_BASE - SignedDuration::from_secs(1));
+
+        // Not due again until the backoff passes
+        assert_eq!(process_due(&log, &db, true).await.unwrap(), 0);
+        assert_eq!(receiver.received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn delivery_gives_up() {
+        let log = Log::new_root(loga::DEBUG);
+        let db = test_db().await;
+        let receiver = start_receiver(500).await;
+        let id = queue_ping(&db, receiver.url.clone()).await;
+        tx(&db, |db_tx| {
+            good_query!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"update outgoing_webhook_delivery
+                   set attempts = ${i64 = MAX_ATTEMPTS - 1}
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?;
+            return Ok(());
+        }).await.unwrap();
+        assert_eq!(process_due(&log, &db, true).await.unwrap(), 1);
+        let deliveries = list_deliveries(&db, &account(), id).await.unwrap().unwrap();
+        assert_eq!(deliveries[0].status, OutgoingWebhookDeliveryStatus::Failed);
+        assert_eq!(deliveries[0].attempts, MAX_ATTEMPTS as u32);
+        assert_eq!(deliveries[0].next_attempt, None);
+        assert_eq!(process_due(&log, &db, true).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn channel_events_filtered() {
+        let db = test_db().await;
+        let (id, channel) = add_webhook(&db, "http://127.0.0.1:1/hook".to_string()).await;
+        let message = MessageId {
+            identity: channel.identity.clone(),
+            unique: 1,
+        };
+        enqueue_channel_event(&db, &new_state(true), channel.clone(), OutgoingWebhookEvent::MessageNew {
+            id: message.clone(),
+            sender: channel.identity.clone(),
+            body: "hi".to_string(),
+        }).await.unwrap();
+        enqueue_channel_event(&db, &new_state(true), channel.clone(), OutgoingWebhookEvent::MessageEdited {
+            id: message.clone(),
+            sender: channel.identity.clone(),
+            body: "hi!".to_string(),
+        }).await.unwrap();
+        enqueue_channel_event(&db, &new_state(true), channel, OutgoingWebhookEvent::MessageDeleted { id: message })
+            .await
+            .unwrap();
+        let deliveries = list_deliveries(&db, &account(), id).await.unwrap().unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].event, "message_new");
+    }
+}
@@ -0,0 +1,236 @@
+//! Token bucket rate limiting. Buckets live in memory and are dropped once idle
+//! (a full bucket carries no information), so limits reset on restart.
+use {
+    crate::interface::{
+        config::{
+            RateLimitBucketConfig,
+            RateLimitConfig,
+        },
+        AccountExternalId,
+    },
+    htwrap::htserve::responses::{
+        body_full,
+        Body,
+    },
+    http::{
+        header::RETRY_AFTER,
+        HeaderMap,
+        Response,
+    },
+    moka::future::Cache,
+    shared::interface::shared::ApiTokenId,
+    std::{
+        net::IpAddr,
+        sync::{
+            Arc,
+            Mutex,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
+    },
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Class {
+    MessagePush,
+    Login,
+    Get,
+    Api,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Key {
+    Account(String),
+    Session(String),
+    ApiToken(ApiTokenId),
+    Ip(IpAddr),
+}
+
+impl Key {
+    pub fn account(account: &AccountExternalId) -> Self {
+        return Key::Account(account.to_db());
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+pub struct RateLimitState {
+    config: RateLimitConfig,
+    buckets: Cache<(Class, Key), Arc<Mutex<Bucket>>>,
+}
+
+pub fn new_state(config: RateLimitConfig) -> RateLimitState {
+    return RateLimitState {
+        config: config,
+        // Long enough for any configured bucket to refill completely
+        buckets: Cache::builder().max_capacity(100_000).time_to_idle(Duration::from_secs(60 * 60)).build(),
+    };
+}
+
+impl RateLimitState {
+    fn bucket_config(&self, class: Class) -> RateLimitBucketConfig {
+        match class {
+            Class::MessagePush => return self.config.message_push,
+            Class::Login => return self.config.login,
+            Class::Get => return self.config.get,
+            Class::Api => return self.config.api,
+        }
+    }
+
+    /// The client IP, from the connection or `X-Forwarded-For` if configured. Only
+    /// the last address is used: the proxy appends the address it saw, everything
+    /// before that came from the client.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if self.config.trust_forwarded_for {
+            if let Some(ip) =
+                headers
+                    .get_all("X-Forwarded-For")
+                    .iter()
+                    .last()
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.rsplit(',').next())
+                    .and_then(|v| v.trim().parse::<IpAddr>().ok()) {
+                return ip;
+            }
+        }
+        return peer;
+    }
+
+    /// Takes a token from the bucket of every key. If any bucket is empty nothing is
+    /// taken and the time until it has a token again is returned.
+    pub async fn check(&self, class: Class, keys: Vec<Key>) -> Result<(), Duration> {
+        let config = self.bucket_config(class);
+        let per_sec = config.per_minute.max(1) as f64 / 60.;
+        let now = Instant::now();
+        let mut buckets = vec![];
+        for key in keys {
+            buckets.push(self.buckets.get_with((class, key), async {
+                return Arc::new(Mutex::new(Bucket {
+                    tokens: config.burst as f64,
+                    last: now,
+                }));
+            }).await);
+        }
+        let mut buckets = buckets.iter().map(|b| b.lock().unwrap()).collect::<Vec<_>>();
+        let mut wait = Duration::ZERO;
+        for b in &mut buckets {
+            b.tokens = (b.tokens + now.saturating_duration_since(b.last).as_secs_f64() * per_sec).min(config.burst as f64);
+            b.last = now;
+            if b.tokens < 1. {
+                wait = wait.max(Duration::from_secs_f64((1. - b.tokens) / per_sec));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for b in &mut buckets {
+            b.tokens -= 1.;
+        }
+        return Ok(());
+    }
+}
+
+pub fn response_429(retry_after: Duration) -> Response<Body> {
+    return Response::builder()
+        .status(429)
+        .header(RETRY_AFTER, retry_after.as_secs_f64().ceil().max(1.).to_string())
+        .body(body_full(b"Too many requests".to_vec()))
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            new_state,
+            Class,
+            Key,
+        },
+        crate::interface::config::{
+            RateLimitBucketConfig,
+            RateLimitConfig,
+        },
+        http::HeaderMap,
+        std::{
+            net::IpAddr,
+            time::Duration,
+        },
+    };
+
+    fn config(trust_forwarded_for: bool) -> RateLimitConfig {
+        return RateLimitConfig {
+            api: RateLimitBucketConfig {
+                burst: 2,
+                per_minute: 60,
+            },
+            trust_forwarded_for: trust_forwarded_for,
+            ..Default::default()
+        };
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        return s.parse().unwrap();
+    }
+
+    #[tokio::test]
+    async fn burst_then_limited() {
+        let state = new_state(config(false));
+        let key = Key::Ip(ip("192.0.2.1"));
+        assert!(state.check(Class::Api, vec![key.clone()]).await.is_ok());
+        assert!(state.check(Class::Api, vec![key.clone()]).await.is_ok());
+        let wait = state.check(Class::Api, vec![key.clone()]).await.unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+
+        // Separate keys and classes have their own buckets
+        assert!(state.check(Class::Api, vec![Key::Ip(ip("192.0.2.2"))]).await.is_ok());
+        assert!(state.check(Class::Get, vec![key]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn nothing_taken_when_limited() {
+        let state = new_state(config(false));
+        let a = Key::Account("a".to_string());
+        let b = Key::Account("b".to_string());
+        assert!(state.check(Class::Api, vec![a.clone()]).await.is_ok());
+        assert!(state.check(Class::Api, vec![a.clone()]).await.is_ok());
+        assert!(state.check(Class::Api, vec![a.clone(), b.clone()]).await.is_err());
+
+        // `b` wasn't charged for the refused request
+        assert!(state.check(Class::Api, vec![b.clone()]).await.is_ok());
+        assert!(state.check(Class::Api, vec![b.clone()]).await.is_ok());
+        assert!(state.check(Class::Api, vec![b]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refills() {
+        let state = new_state(RateLimitConfig {
+            api: RateLimitBucketConfig {
+                burst: 1,
+                per_minute: 60 * 20,
+            },
+            ..Default::default()
+        });
+        let key = Key::Ip(ip("192.0.2.1"));
+        assert!(state.check(Class::Api, vec![key.clone()]).await.is_ok());
+        let wait = state.check(Class::Api, vec![key.clone()]).await.unwrap_err();
+        tokio::time::sleep(wait + Duration::from_millis(10)).await;
+        assert!(state.check(Class::Api, vec![key]).await.is_ok());
+    }
+
+    #[test]
+    fn forwarded_for() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", "203.0.113.9, 198.51.100.7".parse().unwrap());
+        let peer = ip("10.0.0.1");
+        assert_eq!(new_state(config(false)).client_ip(peer, &headers), peer);
+        assert_eq!(new_state(config(true)).client_ip(peer, &headers), ip("198.51.100.7"));
+        headers.append("X-Forwarded-For", "198.51.100.8".parse().unwrap());
+        assert_eq!(new_state(config(true)).client_ip(peer, &headers), ip("198.51.100.8"));
+        assert_eq!(new_state(config(true)).client_ip(peer, &HeaderMap::new()), peer);
+    }
+}
@@ -0,0 +1,270 @@
+//! Snap and activity pages of channels owned by identities on other servers,
+//! fetched from the owner's server on behalf of local clients.
+//!
+//! Pages before the last are full and never change, so they're cached on disk in
+//! `cache_dir/remote_pages` indefinitely and served as immutable. Last pages and
+//! the last page numbers change as messages arrive, so they're only kept in memory
+//! for `public_http_resp_cache_duration` (or until the owner sends a `Notify`).
+use {
+    crate::{
+        dbutil::tx,
+        fsutil::create_dirs,
+        interface::{
+            db::{
+                DbChannelId,
+                DbIdentity,
+            },
+            s2s::{
+                s2sv1t::GetLastPageRes,
+                S2sGet,
+            },
+            AccountExternalId,
+        },
+        subsystems::{
+            federationpolicy::{
+                self,
+                FederationPolicyState,
+            },
+            identitysecret::{
+                self,
+                SecretKey,
+            },
+            resolver::{
+                self,
+                ResolverState,
+            },
+            s2sauth::{
+                self,
+                S2sAuthState,
+            },
+            s2sclient,
+        },
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::good_query_opt,
+    http::StatusCode,
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    moka::future::Cache,
+    sha2::{
+        Digest,
+        Sha256,
+    },
+    shared::interface::shared::QualifiedChannelId,
+    std::{
+        path::PathBuf,
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+pub struct RemotePagesState {
+    dir: PathBuf,
+    cache_duration: Duration,
+    /// See `s2sclient::connect`.
+    allow_private: bool,
+    recent: Cache<(QualifiedChannelId, String), Arc<Vec<u8>>>,
+}
+
+pub fn new_state(cache_dir: &PathBuf, cache_duration: Duration, allow_private: bool) -> RemotePagesState {
+    return RemotePagesState {
+        dir: cache_dir.join("remote_pages"),
+        cache_duration: cache_duration,
+        allow_private: allow_private,
+        recent: Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(cache_duration)
+            .support_invalidation_closures()
+            .build(),
+    };
+}
+
+pub struct Page {
+    /// JSON, the same as the c2s response
+    pub body: Vec<u8>,
+    /// Full page, will never change
+    pub immutable: bool,
+}
+
+/// `Cache-Control` for serving the page to local clients.
+pub fn cache_control(state: &RemotePagesState, page: &Page) -> String {
+    if page.immutable {
+        return format!("private, max-age=31536000, immutable");
+    }
+    return format!("private, max-age={}", state.cache_duration.as_secs());
+}
+
+fn disk_path(state: &RemotePagesState, req: &S2sGet) -> PathBuf {
+    return state.dir.join(format!("{}.json", hex::encode(Sha256::digest(req.serialize_path().as_bytes()))));
+}
+
+/// Drops cached last pages so the next request refetches, for when the owner says
+/// something changed.
+pub fn invalidate(state: &RemotePagesState, channel: &QualifiedChannelId) {
+    let channel = channel.clone();
+    _ = state.recent.invalidate_entries_if(move |k, _| k.0 == channel);
+}
+
+/// The page from the owner's server, authenticating as the identity the account
+/// is in the channel as (so the owner doesn't learn the account's other
+/// identities). Returns `None` if the account isn't in the channel, the owner can't
+/// be found or isn't allowed by federation policy, or the page doesn't exist.
+pub async fn get(
+    log: &Log,
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    auth_state: &S2sAuthState,
+    policy_state: &FederationPolicyState,
+    resolver_state: &ResolverState,
+    state: &RemotePagesState,
+    account: &AccountExternalId,
+    req: S2sGet,
+) -> Result<Option<Page>, loga::Error> {
+    let channel = req.channel().clone();
+
+    // Check membership before anything else, including the disk cache which is shared
+    // by all accounts
+    let secret = tx(db, {
+        let account = account.clone();
+        let channel = channel.clone();
+        move |db_tx| {
+            let Some(member) = good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     channel.own_identity
+                   from
+                     channel
+                     join account on channel.account_id = account.rowid
+                   where
+                     account.external_id = ${str = account.to_db()}
+                     and channel.identity = ${identity_id_t = DbIdentity(channel.identity)}
+                     and channel.id = ${channel_id_t = DbChannelId(channel.channel)}
+                     and channel.deleted is null
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))? else {
+                return Ok(None);
+            };
+            let Some(own_identity) = member else {
+                return Ok(None);
+            };
+            return Ok(good_query_opt!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     identity.secret
+                   from
+                     identity
+                     join account on identity.account_id = account.rowid
+                   where
+                     account.external_id = ${str = account.to_db()}
+                     and identity.id = ${identity_id_t = own_identity}
+                     and identity.soft_deleted_at is null
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?);
+        }
+    }).await?;
+    let Some(secret) = secret else {
+        return Ok(None);
+    };
+    let disk_path = disk_path(state, &req);
+    if let Ok(body) = tokio::fs::read(&disk_path).await {
+        return Ok(Some(Page {
+            body: body,
+            immutable: true,
+        }));
+    }
+    let Some(owner) = resolver::resolve(resolver_state, &channel.identity).await? else {
+        return Ok(None);
+    };
+    let server = owner.server.url;
+    if let Err(reason) = federationpolicy::check_outbound(db, policy_state, &channel.identity, &server).await? {
+        log.log_with(
+            loga::DEBUG,
+            "Not fetching page, owner blocked by policy",
+            ea!(owner = channel.identity, server = server, reason = reason),
+        );
+        return Ok(None);
+    }
+    let secret = identitysecret::open(secret_key, &secret.0)?;
+    let fetch = async |req: &S2sGet| -> Result<Option<Vec<u8>>, loga::Error> {
+        let key = (channel.clone(), req.serialize_path());
+        if let Some(body) = state.recent.get(&key).await {
+            return Ok(Some(body.as_ref().clone()));
+        }
+
+        // Retry once if the token expired early
+        for _ in 0 .. 2 {
+            let token = s2sauth::remote_token(log, auth_state, &server, &secret).await?;
+            let (code, body) = s2sclient::get(log, state.allow_private, &server, &token, req).await?;
+            match code {
+                StatusCode::OK => {
+                    state.recent.insert(key, Arc::new(body.clone())).await;
+                    return Ok(Some(body));
+                },
+                StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => {
+                    return Ok(None);
+                },
+                StatusCode::UNAUTHORIZED => {
+                    s2sauth::drop_remote_token(auth_state, &server, &secret.identity()).await;
+                },
+                _ => {
+                    return Err(
+                        loga::err_with(
+                            "Owner server returned an error",
+                            ea!(status = code, body = String::from_utf8_lossy(&body[..body.len().min(200)])),
+                        ),
+                    );
+                },
+            }
+        }
+        return Ok(None);
+    };
+
+    // Find out if the page is full (and can be kept forever)
+    let last_req = match &req {
+        S2sGet::SnapPage(r) => Some((r.page.0, S2sGet::LastSnapPage(channel.clone()))),
+        S2sGet::ActivityPage(r) => Some((r.page.0, S2sGet::LastActivityPage(channel.clone()))),
+        S2sGet::LastSnapPage(_) | S2sGet::LastActivityPage(_) => None,
+    };
+    let full = match last_req {
+        Some((page, last_req)) => {
+            let Some(last) = fetch(&last_req).await? else {
+                return Ok(None);
+            };
+            let last =
+                serde_json::from_slice::<GetLastPageRes>(
+                    &last,
+                ).context_with("Owner server sent an invalid last page response", ea!(server = server))?;
+            (page + 1) * last.page_size <= last.count
+        },
+        None => false,
+    };
+    if full {
+        // A copy from when this was the last page may be incomplete
+        state.recent.invalidate(&(channel.clone(), req.serialize_path())).await;
+    }
+    let Some(body) = fetch(&req).await? else {
+        return Ok(None);
+    };
+    if full {
+        // Failing to cache only costs a refetch
+        if let Err(e) = async {
+            create_dirs(&state.dir).await?;
+            tokio::fs::write(&disk_path, &body).await.context("Error writing page")?;
+            return Ok(()) as Result<(), loga::Error>;
+        }.await {
+            log.log_err(loga::WARN, e.context_with("Error caching remote page", ea!(path = req.serialize_path())));
+        }
+    }
+    return Ok(Some(Page {
+        body: body,
+        immutable: full,
+    }));
+}
@@ -0,0 +1,431 @@
+//! Finds the home server (and profile) of remote identities from their
+//! spaghettinuum publications (see `spagh` and `interface::spagh`).
+//!
+//! Results are cached in memory and in `cache_dir/resolver` so restarts don't
+//! cause a burst of lookups. Found identities are cached longer than missing ones.
+//! When lookups fail the identity backs off exponentially, serving the stale
+//! cached result (if any) until the next attempt is allowed.
+//!
+//! Lookups go through `ResolveBackend` so the resolver can be driven by an
+//! in-process stand-in instead of a real `Node`.
+use {
+    crate::{
+        fsutil::create_dirs,
+        interface::spagh::{
+            PublishedProfile,
+            PublishedServer,
+            KEY_PROFILE,
+            KEY_SERVER,
+        },
+    },
+    jiff::{
+        SignedDuration,
+        Timestamp,
+    },
+    loga::{
+        ea,
+        ResultContext,
+    },
+    moka::future::Cache,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    spaghettinuum::interface::identity::Identity,
+    spaghettinuum_native::service::node::Node,
+    std::{
+        collections::BTreeMap,
+        future::Future,
+        path::PathBuf,
+        pin::Pin,
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+const FOUND_TTL: SignedDuration = SignedDuration::from_hours(1);
+const MISSING_TTL: SignedDuration = SignedDuration::from_mins(5);
+const BACKOFF_START: Duration = Duration::from_secs(15);
+const BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// Raw record lookups. Returns the published value for each key that has one;
+/// an identity with no announcement returns an empty map.
+pub trait ResolveBackend: Send + Sync {
+    fn get_values<'a>(
+        &'a self,
+        identity: &'a Identity,
+        keys: &'a [&'a str],
+    ) -> Pin<Box<dyn 'a + Send + Future<Output = Result<BTreeMap<String, serde_json::Value>, loga::Error>>>>;
+}
+
+impl ResolveBackend for Node {
+    fn get_values<'a>(
+        &'a self,
+        identity: &'a Identity,
+        keys: &'a [&'a str],
+    ) -> Pin<Box<dyn 'a + Send + Future<Output = Result<BTreeMap<String, serde_json::Value>, loga::Error>>>> {
+        return Box::pin(async move {
+            return Ok(self.resolve_values(identity, keys).await?);
+        });
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ResolvedIdentity {
+    pub server: PublishedServer,
+    /// `None` if missing or unparsable, the identity is still usable.
+    pub profile: Option<PublishedProfile>,
+}
+
+/// Also the on-disk cache format.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct CacheEntry {
+    /// `None` if the identity isn't published (or doesn't publish a kwa server).
+    pub resolved: Option<ResolvedIdentity>,
+    pub fetched: Timestamp,
+    pub expires: Timestamp,
+}
+
+/// In-process stand-in for a node, for tests.
+#[cfg(test)]
+pub struct TestBackend {
+    pub values: std::sync::Mutex<std::collections::HashMap<String, BTreeMap<String, serde_json::Value>>>,
+    pub fail: std::sync::atomic::AtomicBool,
+    pub calls: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl TestBackend {
+    pub fn new() -> Arc<TestBackend> {
+        return Arc::new(TestBackend {
+            values: Default::default(),
+            fail: Default::default(),
+            calls: Default::default(),
+        });
+    }
+
+    /// Publishes `url` as the identity's server.
+    pub fn publish(&self, identity: &Identity, url: &str) {
+        self
+            .values
+            .lock()
+            .unwrap()
+            .insert(
+                identity.to_string(),
+                [(KEY_SERVER.to_string(), serde_json::to_value(&PublishedServer { url: url.to_string() }).unwrap())]
+                    .into_iter()
+                    .collect(),
+            );
+    }
+
+    pub fn calls(&self) -> usize {
+        return self.calls.load(std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl ResolveBackend for TestBackend {
+    fn get_values<'a>(
+        &'a self,
+        identity: &'a Identity,
+        keys: &'a [&'a str],
+    ) -> Pin<Box<dyn 'a + Send + Future<Output = Result<BTreeMap<String, serde_json::Value>, loga::Error>>>> {
+        return Box::pin(async move {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if self.fail.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(loga::err("Test backend failure"));
+            }
+            let values = self.values.lock().unwrap();
+            let Some(values) = values.get(&identity.to_string()) else {
+                return Ok(BTreeMap::new());
+            };
+            return Ok(
+                values.iter().filter(|(k, _)| keys.contains(&k.as_str())).map(|(k, v)| (k.clone(), v.clone())).collect(),
+            );
+        });
+    }
+}
+
+#[derive(Clone)]
+struct Failure {
+    count: u32,
+    retry_at: Timestamp,
+}
+
+pub struct ResolverState {
+    backend: Arc<dyn ResolveBackend>,
+    dir: PathBuf,
+    memory: Cache<String, CacheEntry>,
+    /// Forgotten after a while without failures, so identities looked up once don't
+    /// stay forever.
+    failures: Cache<String, Failure>,
+}
+
+pub fn new_state(backend: Arc<dyn ResolveBackend>, cache_dir: &PathBuf) -> ResolverState {
+    return ResolverState {
+        backend: backend,
+        dir: cache_dir.join("resolver"),
+        memory: Cache::builder().max_capacity(10_000).build(),
+        failures: Cache::builder().max_capacity(10_000).time_to_live(BACKOFF_MAX * 2).build(),
+    };
+}
+
+fn cache_path(dir: &PathBuf, key: &str) -> PathBuf {
+    return dir.join(format!("{}.json", key));
+}
+
+/// Reads the on-disk cache directly, for inspection without a running resolver.
+pub async fn read_disk_cache(cache_dir: &PathBuf, identity: &Identity) -> Option<CacheEntry> {
+    let Ok(data) = tokio::fs::read(cache_path(&cache_dir.join("resolver"), &identity.to_string())).await else {
+        return None;
+    };
+
+    // Outdated format or corrupt entries are treated as missing and refetched
+    return serde_json::from_slice::<CacheEntry>(&data).ok();
+}
+
+/// The cached entry from memory or disk, regardless of expiry.
+pub async fn get_cached(state: &ResolverState, identity: &Identity) -> Option<CacheEntry> {
+    let key = identity.to_string();
+    if let Some(e) = state.memory.get(&key).await {
+        return Some(e);
+    }
+    let Ok(data) = tokio::fs::read(cache_path(&state.dir, &key)).await else {
+        return None;
+    };
+    let Ok(e) = serde_json::from_slice::<CacheEntry>(&data) else {
+        return None;
+    };
+    state.memory.insert(key, e.clone()).await;
+    return Some(e);
+}
+
+/// Looks up the identity now, ignoring the cache and backoff, and caches the
+/// result.
+pub async fn resolve_fresh(state: &ResolverState, identity: &Identity) -> Result<CacheEntry, loga::Error> {
+    let key = identity.to_string();
+    let values = match state.backend.get_values(identity, &[KEY_SERVER, KEY_PROFILE]).await {
+        Ok(v) => v,
+        Err(e) => {
+            let count = state.failures.get(&key).await.map(|f| f.count).unwrap_or(0) + 1;
+            let backoff = BACKOFF_START.saturating_mul(1 << (count - 1).min(16)).min(BACKOFF_MAX);
+            state.failures.insert(key, Failure {
+                count: count,
+                retry_at: Timestamp::now() + SignedDuration::try_from(backoff).unwrap(),
+            }).await;
+            return Err(e.context_with("Error resolving identity", ea!(identity = identity, attempts = count)));
+        },
+    };
+    state.failures.invalidate(&key).await;
+    let resolved =
+        values
+            .get(KEY_SERVER)
+            .and_then(|v| serde_json::from_value::<PublishedServer>(v.clone()).ok())
+            .map(|server| ResolvedIdentity {
+                server: server,
+                profile: values
+                    .get(KEY_PROFILE)
+                    .and_then(|v| serde_json::from_value::<PublishedProfile>(v.clone()).ok()),
+            });
+    let fetched = Timestamp::now();
+    let entry = CacheEntry {
+        expires: fetched + if resolved.is_some() {
+            FOUND_TTL
+        } else {
+            MISSING_TTL
+        },
+        resolved: resolved,
+        fetched: fetched,
+    };
+    state.memory.insert(key.clone(), entry.clone()).await;
+    create_dirs(&state.dir).await?;
+    tokio::fs::write(cache_path(&state.dir, &key), serde_json::to_vec(&entry).unwrap())
+        .await
+        .context_with("Error writing resolver cache", ea!(identity = identity))?;
+    return Ok(entry);
+}
+
+/// Where the identity lives, `None` if it isn't published. Uses the cache when
+/// fresh; while backing off after failures, uses a stale cached result if there
+/// is one.
+pub async fn resolve(state: &ResolverState, identity: &Identity) -> Result<Option<ResolvedIdentity>, loga::Error> {
+    let cached = get_cached(state, identity).await;
+    if let Some(c) = &cached {
+        if c.expires > Timestamp::now() {
+            return Ok(c.resolved.clone());
+        }
+    }
+    let retry_at = state.failures.get(&identity.to_string()).await.map(|f| f.retry_at);
+    if let Some(retry_at) = retry_at {
+        if retry_at > Timestamp::now() {
+            if let Some(c) = cached {
+                return Ok(c.resolved);
+            }
+            return Err(
+                loga::err_with(
+                    "Recent attempts to resolve identity failed, waiting before retrying",
+                    ea!(identity = identity, retry_at = retry_at),
+                ),
+            );
+        }
+    }
+    match resolve_fresh(state, identity).await {
+        Ok(e) => return Ok(e.resolved),
+        Err(e) => {
+            if let Some(c) = cached {
+                return Ok(c.resolved);
+            }
+            return Err(e);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            get_cached,
+            new_state,
+            resolve,
+            Failure,
+            ResolverState,
+            TestBackend,
+            BACKOFF_START,
+            MISSING_TTL,
+        },
+        jiff::{
+            SignedDuration,
+            Timestamp,
+        },
+        rand::distr::{
+            Alphanumeric,
+            SampleString,
+        },
+        spaghettinuum::interface::identity::{
+            Identity,
+            LocalIdentitySecret,
+        },
+        std::{
+            path::PathBuf,
+            sync::atomic::Ordering,
+        },
+    };
+
+    fn cache_dir() -> PathBuf {
+        return std::env::temp_dir().join(format!("kwa-test-{}", Alphanumeric.sample_string(&mut rand::rng(), 16)));
+    }
+
+    fn identity() -> Identity {
+        return LocalIdentitySecret::new().0;
+    }
+
+    async fn url(state: &ResolverState, identity: &Identity) -> Option<String> {
+        return resolve(state, identity).await.unwrap().map(|r| r.server.url);
+    }
+
+    async fn expire(state: &ResolverState, identity: &Identity) {
+        let mut entry = get_cached(state, identity).await.unwrap();
+        entry.expires = Timestamp::now() - SignedDuration::from_secs(1);
+        state.memory.insert(identity.to_string(), entry).await;
+    }
+
+    /// Lets the next attempt through without waiting for the backoff.
+    async fn skip_backoff(state: &ResolverState, identity: &Identity) {
+        let mut f = state.failures.get(&identity.to_string()).await.unwrap();
+        f.retry_at = Timestamp::now() - SignedDuration::from_secs(1);
+        state.failures.insert(identity.to_string(), f).await;
+    }
+
+    async fn failure(state: &ResolverState, identity: &Identity) -> Option<Failure> {
+        return state.failures.get(&identity.to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn cached_until_expiry() {
+        let backend = TestBackend::new();
+        let state = new_state(backend.clone(), &cache_dir());
+        let id = identity();
+        backend.publish(&id, "https://a.example.org/");
+        assert_eq!(url(&state, &id).await.as_deref(), Some("https://a.example.org/"));
+        backend.publish(&id, "https://b.example.org/");
+        assert_eq!(url(&state, &id).await.as_deref(), Some("https://a.example.org/"));
+        assert_eq!(backend.calls(), 1);
+        expire(&state, &id).await;
+        assert_eq!(url(&state, &id).await.as_deref(), Some("https://b.example.org/"));
+        assert_eq!(backend.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn missing_cached_shorter() {
+        let backend = TestBackend::new();
+        let state = new_state(backend.clone(), &cache_dir());
+        let id = identity();
+        assert_eq!(url(&state, &id).await, None);
+        let entry = get_cached(&state, &id).await.unwrap();
+        assert_eq!(entry.expires.duration_since(entry.fetched), MISSING_TTL);
+        assert_eq!(url(&state, &id).await, None);
+        assert_eq!(backend.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn disk_cache() {
+        let backend = TestBackend::new();
+        let dir = cache_dir();
+        let id = identity();
+        backend.publish(&id, "https://a.example.org/");
+        assert!(url(&new_state(backend.clone(), &dir), &id).await.is_some());
+        assert_eq!(url(&new_state(backend.clone(), &dir), &id).await.as_deref(), Some("https://a.example.org/"));
+        assert_eq!(backend.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn backoff() {
+        let backend = TestBackend::new();
+        let state = new_state(backend.clone(), &cache_dir());
+        let id = identity();
+        backend.fail.store(true, Ordering::SeqCst);
+        assert!(resolve(&state, &id).await.is_err());
+        let first = failure(&state, &id).await.unwrap();
+        assert_eq!(first.count, 1);
+        assert!(first.retry_at > Timestamp::now());
+
+        // Waiting, not retried
+        assert!(resolve(&state, &id).await.is_err());
+        assert_eq!(backend.calls(), 1);
+
+        // Backs off longer after each failure
+        skip_backoff(&state, &id).await;
+        let before = Timestamp::now();
+        assert!(resolve(&state, &id).await.is_err());
+        assert_eq!(backend.calls(), 2);
+        let second = failure(&state, &id).await.unwrap();
+        assert_eq!(second.count, 2);
+        assert!(second.retry_at >= before + SignedDuration::try_from(BACKOFF_START * 2).unwrap());
+
+        // Success clears it
+        backend.fail.store(false, Ordering::SeqCst);
+        backend.publish(&id, "https://a.example.org/");
+        skip_backoff(&state, &id).await;
+        assert!(url(&state, &id).await.is_some());
+        assert!(failure(&state, &id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_while_failing() {
+        let backend = TestBackend::new();
+        let state = new_state(backend.clone(), &cache_dir());
+        let id = identity();
+        backend.publish(&id, "https://a.example.org/");
+        assert!(url(&state, &id).await.is_some());
+        expire(&state, &id).await;
+        backend.fail.store(true, Ordering::SeqCst);
+        assert_eq!(url(&state, &id).await.as_deref(), Some("https://a.example.org/"));
+        assert_eq!(backend.calls(), 2);
+
+        // Still stale while backing off, without trying again
+        assert_eq!(url(&state, &id).await.as_deref(), Some("https://a.example.org/"));
+        assert_eq!(backend.calls(), 2);
+    }
+}
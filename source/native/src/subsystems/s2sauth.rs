@@ -0,0 +1,230 @@
+//! Identity authentication between servers. A server proves it hosts an identity
+//! by signing a challenge from `StartIdentify`, along with the identity and this
+//! server's url, with the identity's secret and sending it back in `Identify`,
+//! which returns a short lived bearer token for GET requests (see
+//! `interface::s2s`).
+//!
+//! Both directions are here: checking incoming proofs and tokens, and getting
+//! tokens from other servers for local identities. Everything is in memory; after
+//! a restart remote servers just identify again.
+use {
+    crate::{
+        interface::s2s::{
+            s2sv1,
+            s2sv1t::{
+                Identify,
+                IdentifyChallenge,
+                StartIdentify,
+                StartIdentifyRes,
+            },
+        },
+        subsystems::s2sclient,
+    },
+    http::{
+        header::AUTHORIZATION,
+        HeaderMap,
+    },
+    loga::{
+        ea,
+        Log,
+        ResultContext,
+    },
+    moka::future::Cache,
+    rand::{
+        distr::{
+            Alphanumeric,
+            SampleString,
+        },
+        rng,
+        Rng,
+    },
+    spaghettinuum::{
+        byteszb32::BytesZb32,
+        interface::{
+            identity::{
+                Identity,
+                LocalIdentitySecret,
+            },
+            signature::Signature,
+        },
+    },
+    std::time::Duration,
+};
+
+const CHALLENGE_TTL: Duration = Duration::from_secs(60 * 5);
+const TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Remote tokens are dropped a bit before the remote server expires them.
+const REMOTE_TOKEN_TTL: Duration = Duration::from_secs(60 * 50);
+
+pub struct S2sAuthState {
+    /// Signed challenges must name this as the audience.
+    public_url: Option<String>,
+    /// See `s2sclient::connect`.
+    allow_private: bool,
+    challenges: Cache<Vec<u8>, Identity>,
+    tokens: Cache<String, Identity>,
+    /// Tokens for local identities on other servers, by server url.
+    remote_tokens: Cache<(String, Identity), String>,
+}
+
+pub fn new_state(public_url: Option<String>, allow_private: bool) -> S2sAuthState {
+    return S2sAuthState {
+        public_url: public_url,
+        allow_private: allow_private,
+        challenges: Cache::builder().max_capacity(10_000).time_to_live(CHALLENGE_TTL).build(),
+        tokens: Cache::builder().max_capacity(100_000).time_to_live(TOKEN_TTL).build(),
+        remote_tokens: Cache::builder().max_capacity(10_000).time_to_live(REMOTE_TOKEN_TTL).build(),
+    };
+}
+
+pub async fn start_identify(state: &S2sAuthState, req: StartIdentify) -> StartIdentifyRes {
+    let mut challenge = vec![0u8; 32];
+    rng().fill(challenge.as_mut_slice());
+    state.challenges.insert(challenge.clone(), req.identity).await;
+    return StartIdentifyRes { challenge: BytesZb32(challenge) };
+}
+
+/// Urls are compared loosely since they come from config on both sides.
+fn same_url(a: &str, b: &str) -> bool {
+    return a.trim().trim_end_matches('/').eq_ignore_ascii_case(b.trim().trim_end_matches('/'));
+}
+
+/// Returns the proven identity, or a message for the remote server if the proof is
+/// invalid. Check policy before issuing a token with `issue_token`.
+pub async fn identify(state: &S2sAuthState, req: Identify) -> Result<Identity, String> {
+    let Some(public_url) = &state.public_url else {
+        return Err(format!("This server has no public url, it can't identify remote servers"));
+    };
+    let Ok(signed) = req.challenge.verify(&req.identity) else {
+        return Err(format!("Invalid challenge signature"));
+    };
+    if signed.identity != req.identity {
+        return Err(format!("Challenge was signed for a different identity"));
+    }
+    if !same_url(&signed.audience, public_url) {
+        return Err(format!("Challenge was signed for a different server"));
+    }
+
+    // Each challenge can only be used once
+    if state.challenges.remove(&signed.challenge.0).await.as_ref() != Some(&req.identity) {
+        return Err(format!("Unknown or expired challenge"));
+    }
+    return Ok(req.identity);
+}
+
+pub async fn issue_token(state: &S2sAuthState, identity: Identity) -> String {
+    let token = Alphanumeric.sample_string(&mut rng(), 32);
+    state.tokens.insert(token.clone(), identity).await;
+    return token;
+}
+
+/// The remote identity the request's bearer token was issued to, if any.
+pub async fn authenticate(state: &S2sAuthState, headers: &HeaderMap) -> Option<Identity> {
+    let token = headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")?;
+    return state.tokens.get(token).await;
+}
+
+/// A token for `secret`'s identity on the remote server, identifying if there's no
+/// current one.
+pub async fn remote_token(
+    log: &Log,
+    state: &S2sAuthState,
+    server: &str,
+    secret: &LocalIdentitySecret,
+) -> Result<String, loga::Error> {
+    let identity = secret.identity();
+    let key = (server.to_string(), identity.clone());
+    if let Some(token) = state.remote_tokens.get(&key).await {
+        return Ok(token);
+    }
+    let start = s2sclient::post(log, state.allow_private, server, &s2sv1::Req::StartIdentify(StartIdentify {
+        identity: identity.clone(),
+    })).await?;
+    let start =
+        serde_json::from_slice::<StartIdentifyRes>(
+            &start,
+        ).context_with("Invalid StartIdentify response", ea!(server = server))?;
+    let token = s2sclient::post(log, state.allow_private, server, &s2sv1::Req::Identify(Identify {
+        identity: identity.clone(),
+        challenge: Signature::sign(secret, IdentifyChallenge {
+            audience: server.to_string(),
+            identity: identity,
+            challenge: start.challenge,
+        }),
+    })).await?;
+    let token =
+        serde_json::from_slice::<String>(&token).context_with("Invalid Identify response", ea!(server = server))?;
+    state.remote_tokens.insert(key, token.clone()).await;
+    return Ok(token);
+}
+
+/// Forget a token the remote server rejected.
+pub async fn drop_remote_token(state: &S2sAuthState, server: &str, identity: &Identity) {
+    state.remote_tokens.invalidate(&(server.to_string(), identity.clone())).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            identify,
+            new_state,
+            start_identify,
+        },
+        crate::interface::s2s::s2sv1t::{
+            Identify,
+            IdentifyChallenge,
+            StartIdentify,
+        },
+        spaghettinuum::interface::{
+            identity::LocalIdentitySecret,
+            signature::Signature,
+        },
+    };
+
+    const URL: &str = "https://a.example.org/";
+
+    async fn signed_for(audience: &str) -> (super::S2sAuthState, Identify) {
+        let state = new_state(Some(URL.to_string()), false);
+        let (identity, secret) = LocalIdentitySecret::new();
+        let start = start_identify(&state, StartIdentify { identity: identity.clone() }).await;
+        let req = Identify {
+            identity: identity.clone(),
+            challenge: Signature::sign(&secret, IdentifyChallenge {
+                audience: audience.to_string(),
+                identity: identity,
+                challenge: start.challenge,
+            }),
+        };
+        return (state, req);
+    }
+
+    #[tokio::test]
+    async fn accepted_once() {
+        let (state, req) = signed_for("https://A.example.org").await;
+        let identity = req.identity.clone();
+        let challenge = req.challenge.clone();
+        assert!(identify(&state, req).await.unwrap() == identity);
+        assert!(identify(&state, Identify {
+            identity: identity,
+            challenge: challenge,
+        }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn other_audience_rejected() {
+        let (state, req) = signed_for("https://b.example.org/").await;
+        assert!(identify(&state, req).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn other_identity_rejected() {
+        let (state, req) = signed_for(URL).await;
+        let (other, _) = LocalIdentitySecret::new();
+        assert!(identify(&state, Identify {
+            identity: other,
+            challenge: req.challenge,
+        }).await.is_err());
+    }
+}
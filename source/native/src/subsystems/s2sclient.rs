@@ -0,0 +1,267 @@
+//! Requests to other kwa servers. `server` is the base url an identity publishes
+//! (see `interface::spagh::PublishedServer`), so like webhook urls it's chosen by
+//! someone else and only public addresses are contacted unless `allow_private`.
+use {
+    crate::interface::s2s::{
+        s2sv1,
+        S2sGet,
+        S2SV1_PREFIX,
+    },
+    http::{
+        header::{
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            HOST,
+        },
+        Request,
+        StatusCode,
+        Uri,
+    },
+    htwrap::{
+        htreq,
+        htserve::responses::{
+            body_full,
+            Body,
+        },
+    },
+    loga::{
+        ea,
+        Log,
+        ResultContext,
+    },
+    std::{
+        net::{
+            IpAddr,
+            Ipv4Addr,
+        },
+        time::Duration,
+    },
+    tokio::time::timeout,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+fn url(server: &str, rel: &str) -> Result<Uri, loga::Error> {
+    let url = format!("{}/{}{}", server.trim_end_matches('/'), S2SV1_PREFIX.trim_end_matches('/'), rel);
+    return Ok(url.parse::<Uri>().context_with("Invalid published server url", ea!(url = url))?);
+}
+
+/// Whether the address is on the public internet. Hosts on loopback, private,
+/// link-local and other special-purpose ranges could be the server's own network
+/// (cloud metadata services, admin interfaces) so they're refused.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            return !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local() ||
+                ip.is_broadcast() ||
+                ip.is_multicast() ||
+                ip.is_documentation() ||
+                // 0.0.0.0/8, this network
+                o[0] == 0 ||
+                // 100.64.0.0/10, carrier-grade NAT
+                (o[0] == 100 && (o[1] & 0xc0) == 64) ||
+                // 192.0.0.0/24, protocol assignments
+                (o[0] == 192 && o[1] == 0 && o[2] == 0) ||
+                // 198.18.0.0/15, benchmarking
+                (o[0] == 198 && (o[1] & 0xfe) == 18) ||
+                // 240.0.0.0/4, reserved
+                o[0] >= 240);
+        },
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let s = ip.segments();
+            return !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() ||
+                // fc00::/7, unique local
+                (s[0] & 0xfe00) == 0xfc00 ||
+                // fe80::/10, link-local
+                (s[0] & 0xffc0) == 0xfe80 ||
+                // 2001:db8::/32, documentation
+                (s[0] == 0x2001 && s[1] == 0x0db8) ||
+                // 64:ff9b::/96 NAT64, judged by the embedded address
+                (s[0] == 0x64 && s[1] == 0xff9b && s[2 .. 6] == [0, 0, 0, 0] &&
+                    !is_public(IpAddr::V4(Ipv4Addr::from(((s[6] as u32) << 16) | s[7] as u32)))));
+        },
+    }
+}
+
+/// Resolves the host and connects to the resolved addresses, refusing non-public
+/// ones unless allowed (`outgoing_webhooks_allow_private`). The addresses checked
+/// are the ones connected to, so the host's DNS can't switch to an internal
+/// address in between. Used for everything that connects to a url someone else
+/// chose: webhook receivers and other servers.
+pub async fn connect(allow_private: bool, uri: &Uri) -> Result<htreq::Conn, loga::Error> {
+    let (scheme, host, port) = htreq::uri_parts(uri)?;
+    let ips = match &host {
+        htreq::Host::Ip(ip) => vec![*ip],
+        htreq::Host::Name(name) => tokio::net::lookup_host((name.as_str(), port))
+            .await
+            .context_with("Error resolving remote host", ea!(host = name))?
+            .map(|a| a.ip())
+            .collect::<Vec<_>>(),
+    };
+    if ips.is_empty() {
+        return Err(loga::err_with("Remote host has no addresses", ea!(uri = uri)));
+    }
+    if !allow_private {
+        for ip in &ips {
+            if !is_public(*ip) {
+                return Err(loga::err_with("Remote host address isn't public", ea!(uri = uri, ip = ip)));
+            }
+        }
+    }
+    return Ok(htreq::connect_ips(htreq::Limits::default(), ips, scheme, host, port).await?);
+}
+
+async fn send(
+    log: &Log,
+    allow_private: bool,
+    uri: Uri,
+    req: Request<Body>,
+) -> Result<(StatusCode, Vec<u8>), loga::Error> {
+    return Ok(timeout(REQUEST_TIMEOUT, async {
+        let mut conn = connect(allow_private, &uri).await?;
+        let (code, _headers, continue_) = htreq::send(log, htreq::Limits::default(), &mut conn, req).await?;
+        let body = htreq::receive(htreq::Limits::default(), continue_).await?;
+        return Ok((code, body)) as Result<_, loga::Error>;
+    }).await.map_err(|_| loga::err("Timed out waiting for remote server"))??);
+}
+
+fn check_status(code: StatusCode, body: &[u8]) -> Result<(), loga::Error> {
+    if !code.is_success() {
+        return Err(
+            loga::err_with(
+                "Remote server returned an error",
+                ea!(status = code, body = String::from_utf8_lossy(&body[..body.len().min(200)])),
+            ),
+        );
+    }
+    return Ok(());
+}
+
+async fn post_inner(
+    log: &Log,
+    allow_private: bool,
+    server: &str,
+    token: Option<&str>,
+    req: &s2sv1::Req,
+) -> Result<Vec<u8>, loga::Error> {
+    let uri = url(server, "")?;
+    let mut builder =
+        Request::builder()
+            .method(http::Method::POST)
+            .uri(uri.clone())
+            .header(HOST, uri.authority().map(|a| a.as_str()).unwrap_or_default())
+            .header(CONTENT_TYPE, "application/json");
+    if let Some(token) = token {
+        builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let (code, body) =
+        send(log, allow_private, uri.clone(), builder.body(body_full(serde_json::to_vec(req).unwrap())).unwrap()).await?;
+    check_status(code, &body)?;
+    return Ok(body);
+}
+
+/// Sends a POST request, returning the response body.
+pub async fn post(log: &Log, allow_private: bool, server: &str, req: &s2sv1::Req) -> Result<Vec<u8>, loga::Error> {
+    return post_inner(log, allow_private, server, None, req).await;
+}
+
+/// Like `post`, with a token from `Identify` for requests that need one.
+pub async fn post_with_token(
+    log: &Log,
+    allow_private: bool,
+    server: &str,
+    token: &str,
+    req: &s2sv1::Req,
+) -> Result<Vec<u8>, loga::Error> {
+    return post_inner(log, allow_private, server, Some(token), req).await;
+}
+
+/// Sends a GET request with a token from `Identify`. Unlike `post`, error statuses
+/// are returned rather than turned into errors so callers can tell rejection from
+/// absence.
+pub async fn get(
+    log: &Log,
+    allow_private: bool,
+    server: &str,
+    token: &str,
+    req: &S2sGet,
+) -> Result<(StatusCode, Vec<u8>), loga::Error> {
+    let uri = url(server, &req.serialize_path())?;
+    return Ok(
+        send(
+            log,
+            allow_private,
+            uri.clone(),
+            Request::builder()
+                .method(http::Method::GET)
+                .uri(uri.clone())
+                .header(HOST, uri.authority().map(|a| a.as_str()).unwrap_or_default())
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .body(body_full(vec![]))
+                .unwrap(),
+        ).await?,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            is_public,
+            post,
+        },
+        crate::interface::s2s::{
+            s2sv1,
+            s2sv1t::IsHome,
+        },
+        loga::Log,
+        spaghettinuum::interface::identity::LocalIdentitySecret,
+        std::{
+            net::IpAddr,
+            str::FromStr,
+            time::Duration,
+        },
+        tokio::{
+            net::TcpListener,
+            time::timeout,
+        },
+    };
+
+    #[test]
+    fn private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(IpAddr::from_str(ip).unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.215.14", "2606:4700::1111", "64:ff9b::5db8:d70e"] {
+            assert!(is_public(IpAddr::from_str(ip).unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn private_server_refused() {
+        let log = Log::new_root(loga::DEBUG);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = format!("http://{}/", listener.local_addr().unwrap());
+        let req = s2sv1::Req::IsHome(IsHome { identity: LocalIdentitySecret::new().0 });
+        assert!(post(&log, false, &server, &req).await.is_err());
+        assert!(timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+    }
+}
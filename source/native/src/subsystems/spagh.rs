@@ -0,0 +1,243 @@
+//! Spaghettinuum publishing for local identities. Each identity publishes the url
+//! of the kwa server it lives on and its public profile (see `interface::spagh`)
+//! so other servers can find it.
+//!
+//! Records are published when identities are created, modified or imported,
+//! withdrawn when they're deleted, and everything is republished periodically in
+//! case the publisher lost state or a publish failed.
+use {
+    crate::{
+        dbutil::tx,
+        interface::{
+            db::StoredIdentitySecret,
+            spagh::{
+                PublishedProfile,
+                PublishedServer,
+                KEY_PROFILE,
+                KEY_SERVER,
+            },
+        },
+        metrics::METRICS,
+        subsystems::{
+            health::HealthState,
+            identitysecret::{
+                self,
+                SecretKey,
+            },
+        },
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::good_query_many,
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    spaghettinuum::interface::identity::{
+        Identity,
+        LocalIdentitySecret,
+    },
+    spaghettinuum_native::service::{
+        node::Node,
+        publisher::Publisher,
+    },
+    std::{
+        collections::BTreeMap,
+        sync::Arc,
+        time::Duration,
+    },
+    taskmanager::TaskManager,
+    tokio::{
+        select,
+        time::sleep,
+    },
+};
+
+const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Until publishing first works, which may take a while if the node is still
+/// finding peers.
+const REPUBLISH_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct SpaghState {
+    pub node: Arc<Node>,
+    pub publisher: Arc<Publisher>,
+    pub public_url: Option<String>,
+}
+
+/// Announces the identity on this server's publisher and (re)sets its records,
+/// replacing anything published for it elsewhere.
+pub async fn publish_identity(
+    log: &Log,
+    state: &SpaghState,
+    secret: &LocalIdentitySecret,
+    profile: &PublishedProfile,
+) -> Result<(), loga::Error> {
+    let identity = secret.identity();
+    let Some(public_url) = &state.public_url else {
+        log.log_with(loga::WARN, "No `public_url` configured, not publishing identity", ea!(identity = identity));
+        return Ok(());
+    };
+    let mut values = BTreeMap::new();
+    values.insert(KEY_SERVER.to_string(), serde_json::to_value(&PublishedServer { url: public_url.clone() }).unwrap());
+    values.insert(KEY_PROFILE.to_string(), serde_json::to_value(profile).unwrap());
+    let res = state.publisher.publish(secret, values).await;
+    METRICS.spagh_publisher_up.set(if res.is_ok() {
+        1
+    } else {
+        0
+    });
+    res.context_with("Error publishing identity", ea!(identity = identity))?;
+    return Ok(());
+}
+
+/// Withdraws the announcement and records, so other servers stop sending things
+/// for the identity here.
+pub async fn unpublish_identity(state: &SpaghState, identity: &Identity) -> Result<(), loga::Error> {
+    state
+        .publisher
+        .unpublish(identity)
+        .await
+        .context_with("Error withdrawing identity publication", ea!(identity = identity))?;
+    return Ok(());
+}
+
+pub struct RepublishIdentity {
+    pub id: Identity,
+    pub memo_short: String,
+    pub secret: StoredIdentitySecret,
+}
+
+/// Identities that are live on this server. Deleted (including withdrawn after
+/// export) identities aren't republished.
+pub async fn republish_list(db: &Pool) -> Result<Vec<RepublishIdentity>, loga::Error> {
+    return Ok(tx(db, |db_tx| {
+        return Ok(good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 id,
+                 memo_short,
+                 secret
+               from
+                 identity
+               where
+                 soft_deleted_at is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?.into_iter().map(|r| RepublishIdentity {
+            id: r.id.0,
+            memo_short: r.memo_short,
+            secret: r.secret.0,
+        }).collect());
+    }).await?);
+}
+
+/// Returns the identities that were published, and errors for the rest. Nothing is
+/// published without a `public_url`.
+async fn republish_all(
+    log: &Log,
+    db: &Pool,
+    secret_key: Option<&SecretKey>,
+    state: &SpaghState,
+) -> Result<(Vec<Identity>, Vec<loga::Error>), loga::Error> {
+    if state.public_url.is_none() {
+        log.log(loga::WARN, "No `public_url` configured, not publishing identities");
+        return Ok((vec![], vec![]));
+    }
+    let rows = republish_list(db).await?;
+    let mut published = vec![];
+    let mut errors = vec![];
+    for row in rows {
+        match async {
+            let secret = identitysecret::open(secret_key, &row.secret)?;
+            publish_identity(log, state, &secret, &PublishedProfile {
+                memo_short: row.memo_short,
+            }).await?;
+            return Ok(()) as Result<(), loga::Error>;
+        }.await {
+            Ok(_) => published.push(row.id),
+            Err(e) => errors.push(e.context_with("Error republishing identity", ea!(identity = row.id))),
+        }
+    }
+    return Ok((published, errors));
+}
+
+/// Whether the node can find what we published: the identity resolves through the
+/// network to this server.
+async fn confirm_published(state: &SpaghState, identity: &Identity) -> Result<bool, loga::Error> {
+    let values =
+        state
+            .node
+            .resolve_values(identity, &[KEY_SERVER])
+            .await
+            .context_with("Error resolving published identity", ea!(identity = identity))?;
+    let Some(server) = values.get(KEY_SERVER) else {
+        return Ok(false);
+    };
+    let Ok(server) = serde_json::from_value::<PublishedServer>(server.clone()) else {
+        return Ok(false);
+    };
+    return Ok(Some(&server.url) == state.public_url.as_ref());
+}
+
+/// Republishes everything now and then periodically. The server is marked
+/// bootstrapped once a published identity resolves back to this server through the
+/// network, so a server without identities or without a `public_url` never becomes
+/// ready; until then republishing is retried frequently.
+pub fn spawn_republish(
+    log: &Log,
+    tm: &TaskManager,
+    db: Pool,
+    secret_key: Option<SecretKey>,
+    state: Arc<SpaghState>,
+    health: Arc<HealthState>,
+) {
+    let log = log.fork(ea!(sys = "spagh_republish"));
+    tm.task("spagh_republish", {
+        let tm = tm.clone();
+        async move {
+            let mut bootstrapped = false;
+            loop {
+                match republish_all(&log, &db, secret_key.as_ref(), &state).await {
+                    Ok((published, errors)) => {
+                        if !errors.is_empty() {
+                            log.log_err(loga::WARN, loga::agg_err("Errors republishing identities", errors));
+                        }
+                        if !bootstrapped {
+                            for identity in &published {
+                                match confirm_published(&state, identity).await {
+                                    Ok(true) => {
+                                        bootstrapped = true;
+                                        break;
+                                    },
+                                    Ok(false) => { },
+                                    Err(e) => {
+                                        log.log_err(loga::DEBUG, e);
+                                    },
+                                }
+                            }
+                            if bootstrapped {
+                                health.set_spagh_bootstrapped();
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        log.log_err(loga::WARN, e);
+                    },
+                }
+                select!{
+                    _ = tm.until_terminate() => {
+                        break;
+                    },
+                    _ = sleep(if bootstrapped {
+                        REPUBLISH_INTERVAL
+                    } else {
+                        REPUBLISH_RETRY_INTERVAL
+                    }) => { },
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,143 @@
+//! TLS termination for the main http listener. The certificate is read from disk
+//! at startup and re-read whenever the files change, so renewals (ex: by certbot)
+//! take effect without a restart or dropping connections.
+use {
+    crate::interface::config::TlsConfig,
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    rustls::{
+        crypto::ring::default_provider,
+        pki_types::{
+            pem::PemObject,
+            CertificateDer,
+            PrivateKeyDer,
+        },
+        server::{
+            ClientHello,
+            ResolvesServerCert,
+        },
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    std::{
+        path::PathBuf,
+        sync::{
+            Arc,
+            RwLock,
+        },
+        time::{
+            Duration,
+            SystemTime,
+        },
+    },
+    taskmanager::TaskManager,
+    tokio::{
+        select,
+        time::sleep,
+    },
+    tokio_rustls::TlsAcceptor,
+};
+
+const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        return Some(self.current.read().unwrap().clone());
+    }
+}
+
+async fn load(cert_path: &PathBuf, key_path: &PathBuf) -> Result<Arc<CertifiedKey>, loga::Error> {
+    let cert_pem =
+        tokio::fs::read(cert_path)
+            .await
+            .context_with("Error reading TLS certificate", ea!(path = cert_path.to_string_lossy()))?;
+    let certs =
+        CertificateDer::pem_slice_iter(&cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| loga::err(e.to_string()))
+            .context_with("Error parsing TLS certificate", ea!(path = cert_path.to_string_lossy()))?;
+    if certs.is_empty() {
+        return Err(loga::err_with("TLS certificate file contains no certificates", ea!(path = cert_path.to_string_lossy())));
+    }
+    let key_pem =
+        tokio::fs::read(key_path)
+            .await
+            .context_with("Error reading TLS key", ea!(path = key_path.to_string_lossy()))?;
+    let key =
+        PrivateKeyDer::from_pem_slice(&key_pem)
+            .map_err(|e| loga::err(e.to_string()))
+            .context_with("Error parsing TLS key", ea!(path = key_path.to_string_lossy()))?;
+    let key =
+        default_provider()
+            .key_provider
+            .load_private_key(key)
+            .map_err(|e| loga::err(e.to_string()))
+            .context_with("Unsupported TLS key", ea!(path = key_path.to_string_lossy()))?;
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match().map_err(|e| loga::err(e.to_string())).context("TLS certificate and key don't match")?;
+    return Ok(Arc::new(certified));
+}
+
+async fn mtimes(cert_path: &PathBuf, key_path: &PathBuf) -> Option<(SystemTime, SystemTime)> {
+    let cert = tokio::fs::metadata(cert_path).await.ok()?.modified().ok()?;
+    let key = tokio::fs::metadata(key_path).await.ok()?.modified().ok()?;
+    return Some((cert, key));
+}
+
+/// Loads the certificate and starts watching it for changes. Fails if the initial
+/// load fails; later failures (ex: a half-written renewal) are logged and the
+/// previous certificate is kept.
+pub async fn new_acceptor(log: &Log, tm: &TaskManager, config: TlsConfig) -> Result<TlsAcceptor, loga::Error> {
+    let log = log.fork(ea!(sys = "tls"));
+    let cert_path = PathBuf::from(&config.cert_path);
+    let key_path = PathBuf::from(&config.key_path);
+    let resolver = Arc::new(CertResolver { current: RwLock::new(load(&cert_path, &key_path).await?) });
+    let mut server_config =
+        ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .context("Error setting TLS protocol versions")?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let interval = config.reload_interval_secs.map(Duration::from_secs).unwrap_or(DEFAULT_RELOAD_INTERVAL);
+    tm.task("tls_reload", {
+        let tm = tm.clone();
+        async move {
+            let mut last_mtimes = mtimes(&cert_path, &key_path).await;
+            loop {
+                select!{
+                    _ = tm.until_terminate() => {
+                        break;
+                    },
+                    _ = sleep(interval) => { },
+                }
+                let new_mtimes = mtimes(&cert_path, &key_path).await;
+                if new_mtimes.is_none() || new_mtimes == last_mtimes {
+                    continue;
+                }
+                match load(&cert_path, &key_path).await {
+                    Ok(k) => {
+                        *resolver.current.write().unwrap() = k;
+                        last_mtimes = new_mtimes;
+                        log.log(loga::INFO, "Reloaded TLS certificate");
+                    },
+                    Err(e) => {
+                        // Retried next interval since `last_mtimes` is unchanged, in case the
+                        // cert and key were mid-replacement
+                        log.log_err(loga::WARN, e.context("Error reloading TLS certificate, keeping previous"));
+                    },
+                }
+            }
+        }
+    });
+    return Ok(TlsAcceptor::from(Arc::new(server_config)));
+}
@@ -0,0 +1,384 @@
+//! Incoming webhooks: a secret per-channel URL that posts whatever it receives as
+//! a message from a fixed identity. Only a hash of the secret is stored.
+use {
+    crate::{
+        dbutil::{
+            abortable_tx,
+            tx,
+            Txr,
+        },
+        interface::{
+            db::{
+                DbAccountExternalId,
+                DbChannelId,
+                DbIdentity,
+            },
+            AccountExternalId,
+        },
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query,
+        good_query_many,
+        good_query_opt,
+    },
+    http::{
+        header::{
+            CONTENT_TYPE,
+            RETRY_AFTER,
+        },
+        HeaderMap,
+        Response,
+    },
+    htwrap::htserve::responses::{
+        body_empty,
+        response_400,
+        response_404,
+        Body,
+    },
+    jiff::Timestamp,
+    moka::future::Cache,
+    rand::distr::{
+        Alphanumeric,
+        SampleString,
+    },
+    sha2::{
+        Digest,
+        Sha256,
+    },
+    shared::interface::{
+        shared::{
+            ChannelWebhookId,
+            ChannelWebhookToken,
+            MessageClientId,
+            QualifiedChannelId,
+        },
+        wire::c2s::{
+            ChannelWebhookCreate,
+            ChannelWebhookCreateRes,
+            ChannelWebhookModify,
+            ChannelWebhookRes,
+            MessagePush,
+        },
+    },
+    std::{
+        collections::HashMap,
+        sync::{
+            atomic::{
+                AtomicU32,
+                Ordering,
+            },
+            Arc,
+        },
+        time::Duration,
+    },
+};
+
+pub struct WebhookState {
+    /// Requests per webhook in the current one-minute window; entries expire a
+    /// minute after the first request in the window.
+    window_counts: Cache<ChannelWebhookId, Arc<AtomicU32>>,
+}
+
+pub fn new_state() -> WebhookState {
+    return WebhookState { window_counts: Cache::builder().time_to_live(Duration::from_secs(60)).build() };
+}
+
+fn hash_token(token: &str) -> String {
+    return hex::encode(Sha256::digest(token.as_bytes()));
+}
+
+pub enum Incoming {
+    Push(AccountExternalId, MessagePush),
+    Reject(Response<Body>),
+}
+
+/// Validates an incoming webhook request and turns it into a message push. Accepts
+/// JSON or form bodies with a `text` (Slack style) or `body` field.
+pub async fn handle_incoming(
+    db: &Pool,
+    state: &WebhookState,
+    token: String,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Incoming, loga::Error> {
+    let hash = hash_token(&token);
+    let Some(hook) = tx(db, move |db_tx| {
+        return Ok(good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 rowid,
+                 account,
+                 channel_identity,
+                 channel,
+                 identity,
+                 expiry,
+                 max_per_minute
+               from
+                 channel_webhook
+               where
+                 hash = ${str = hash}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?);
+    }).await? else {
+        return Ok(Incoming::Reject(response_404()));
+    };
+    if let Some(expiry) = hook.expiry {
+        if expiry < Timestamp::now() {
+            return Ok(Incoming::Reject(response_404()));
+        }
+    }
+
+    // Rate limit
+    let count =
+        state
+            .window_counts
+            .get_with(ChannelWebhookId(hook.rowid as u64), async {
+                Arc::new(AtomicU32::new(0))
+            })
+            .await
+            .fetch_add(1, Ordering::Relaxed);
+    if count as i64 >= hook.max_per_minute {
+        return Ok(
+            Incoming::Reject(
+                Response::builder().status(429).header(RETRY_AFTER, "60").body(body_empty()).unwrap(),
+            ),
+        );
+    }
+
+    // Extract message
+    let is_form =
+        headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with("application/x-www-form-urlencoded"))
+            .unwrap_or(false);
+    let text;
+    if is_form {
+        let Ok(mut fields) = serde_urlencoded::from_bytes::<HashMap<String, String>>(body) else {
+            return Ok(Incoming::Reject(response_400("Invalid form body")));
+        };
+        let Some(t) = fields.remove("text").or_else(|| fields.remove("body")) else {
+            return Ok(Incoming::Reject(response_400("Missing `text` field")));
+        };
+        text = t;
+    } else {
+        let Ok(mut fields) = serde_json::from_slice::<HashMap<String, serde_json::Value>>(body) else {
+            return Ok(Incoming::Reject(response_400("Invalid JSON body, expected an object")));
+        };
+        let Some(serde_json::Value::String(t)) = fields.remove("text").or_else(|| fields.remove("body")) else {
+            return Ok(Incoming::Reject(response_400("Missing string `text` field")));
+        };
+        text = t;
+    }
+    if text.trim().is_empty() {
+        return Ok(Incoming::Reject(response_400("Message is empty")));
+    }
+    return Ok(Incoming::Push(hook.account.0, MessagePush {
+        client_id: MessageClientId(format!("webhook-{}", Alphanumeric.sample_string(&mut rand::rng(), 16))),
+        channel: QualifiedChannelId {
+            identity: hook.channel_identity.0,
+            channel: hook.channel.0,
+        },
+        identity: hook.identity.0,
+        body: text,
+    }));
+}
+
+/// Returns `None` if the channel or identity don't belong to the account.
+pub async fn create(
+    db: &Pool,
+    account: &AccountExternalId,
+    req: ChannelWebhookCreate,
+) -> Result<Option<ChannelWebhookCreateRes>, loga::Error> {
+    let account = account.clone();
+    let token = ChannelWebhookToken(Alphanumeric.sample_string(&mut rand::rng(), 32));
+    let hash = hash_token(&token.0);
+    return Ok(abortable_tx(db, move |db_tx| {
+        // The sender must be the identity the account is a member of the channel as
+        let Some(member) = good_query_opt!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 channel.own_identity
+               from
+                 channel
+                 join account on channel.account_id = account.rowid
+               where
+                 account.external_id = ${str = account.to_db()}
+                 and channel.identity = ${identity_id_t = DbIdentity(req.channel.identity.clone())}
+                 and channel.id = ${channel_id_t = DbChannelId(req.channel.channel.clone())}
+                 and channel.deleted is null
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))? else {
+            return Ok(Txr::Abort);
+        };
+        if member.map(|m| m.0) != Some(req.identity.clone()) {
+            return Ok(Txr::Abort);
+        }
+        let rowid = good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"insert into
+                 channel_webhook
+                 (
+                   account,
+                   channel_identity,
+                   channel,
+                   identity,
+                   hash,
+                   memo_short,
+                   memo_long,
+                   expiry,
+                   max_per_minute
+                 )
+               values (
+                 ${account_external_id_t = DbAccountExternalId(account.clone())},
+                 ${identity_id_t = DbIdentity(req.channel.identity.clone())},
+                 ${channel_id_t = DbChannelId(req.channel.channel.clone())},
+                 ${identity_id_t = DbIdentity(req.identity.clone())},
+                 ${str = hash},
+                 ${str = req.memo_short.clone()},
+                 ${str = req.memo_long.clone()},
+                 ${utctime_s_jiff? = req.expiry},
+                 ${i64 = req.max_per_minute as i64}
+               )
+               returning rowid
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(Txr::Ok(ChannelWebhookCreateRes {
+            res: ChannelWebhookRes {
+                id: ChannelWebhookId(rowid as u64),
+                channel: req.channel,
+                identity: req.identity,
+                memo_short: req.memo_short,
+                memo_long: req.memo_long,
+                expiry: req.expiry,
+                max_per_minute: req.max_per_minute,
+            },
+            token: token,
+        }));
+    }).await?);
+}
+
+pub async fn list(db: &Pool, account: &AccountExternalId) -> Result<Vec<ChannelWebhookRes>, loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        let rows = good_query_many!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"select
+                 rowid,
+                 channel_identity,
+                 channel,
+                 identity,
+                 memo_short,
+                 memo_long,
+                 expiry,
+                 max_per_minute
+               from
+                 channel_webhook
+               where
+                 account = ${account_external_id_t = DbAccountExternalId(account)}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(rows.into_iter().map(|r| ChannelWebhookRes {
+            id: ChannelWebhookId(r.rowid as u64),
+            channel: QualifiedChannelId {
+                identity: r.channel_identity.0,
+                channel: r.channel.0,
+            },
+            identity: r.identity.0,
+            memo_short: r.memo_short,
+            memo_long: r.memo_long,
+            expiry: r.expiry,
+            max_per_minute: r.max_per_minute as u32,
+        }).collect());
+    }).await?);
+}
+
+/// Returns `None` if the webhook doesn't exist or belongs to another account.
+pub async fn modify(
+    db: &Pool,
+    account: &AccountExternalId,
+    req: ChannelWebhookModify,
+) -> Result<Option<ChannelWebhookRes>, loga::Error> {
+    let id = req.id;
+    {
+        let account = account.clone();
+        tx(db, move |db_tx| {
+            let rowid = id.0 as i64;
+            if let Some(v) = req.memo_short {
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"update channel_webhook
+                       set memo_short = ${str = v}
+                       where rowid = ${i64 = rowid} and account = ${account_external_id_t = DbAccountExternalId(account.clone())}
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+            }
+            if let Some(v) = req.memo_long {
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"update channel_webhook
+                       set memo_long = ${str = v}
+                       where rowid = ${i64 = rowid} and account = ${account_external_id_t = DbAccountExternalId(account.clone())}
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+            }
+            if let Some(v) = req.expiry {
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"update channel_webhook
+                       set expiry = ${utctime_s_jiff? = v.value}
+                       where rowid = ${i64 = rowid} and account = ${account_external_id_t = DbAccountExternalId(account.clone())}
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+            }
+            if let Some(v) = req.max_per_minute {
+                good_query!(
+                    crate::db,
+                    //# genemichaels-external: sql-formatter-sqlite
+                    r#"update channel_webhook
+                       set max_per_minute = ${i64 = v as i64}
+                       where rowid = ${i64 = rowid} and account = ${account_external_id_t = DbAccountExternalId(account.clone())}
+                       "#;
+                    &mut db_tx
+                ).map_err(|e| loga::err(e.0))?;
+            }
+            return Ok(());
+        }).await?;
+    }
+    return Ok(list(db, account).await?.into_iter().find(|x| x.id == id));
+}
+
+/// Revoking takes effect immediately since the token is checked against the
+/// database on every request.
+pub async fn delete(db: &Pool, account: &AccountExternalId, id: ChannelWebhookId) -> Result<(), loga::Error> {
+    let account = account.clone();
+    return Ok(tx(db, move |db_tx| {
+        good_query!(
+            crate::db,
+            //# genemichaels-external: sql-formatter-sqlite
+            r#"delete from
+                 channel_webhook
+               where
+                 rowid = ${i64 = id.0 as i64}
+                 and account = ${account_external_id_t = DbAccountExternalId(account)}
+               "#;
+            &mut db_tx
+        ).map_err(|e| loga::err(e.0))?;
+        return Ok(());
+    }).await?);
+}
@@ -0,0 +1,254 @@
+//! Web push: new messages are sent to the push subscriptions of the accounts in
+//! the channel, so the serviceworker can show a notification when the app isn't
+//! open. The payload is the same JSON `s2c::Notification` websockets get.
+//!
+//! Requests are signed with a VAPID key generated on first start and kept in the
+//! persistent dir; clients get the public half from `notification_server_key`.
+//! Pushes aren't retried - a missed one only means a missed popup, the messages
+//! are still there when the app is opened.
+use {
+    crate::{
+        dbutil::tx,
+        interface::db::{
+            DbAccountExternalId,
+            DbPushSubscription,
+        },
+        subsystems::{
+            federation::ChannelActivity,
+            s2sclient,
+        },
+    },
+    base64::{
+        engine::general_purpose::URL_SAFE_NO_PAD,
+        Engine,
+    },
+    deadpool_sqlite::Pool,
+    good_ormning::sqlite::{
+        good_query,
+        good_query_many,
+    },
+    http::{
+        header::HOST,
+        StatusCode,
+        Uri,
+    },
+    htwrap::{
+        htreq,
+        htserve::responses::body_full,
+    },
+    loga::{
+        ea,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    serde::Deserialize,
+    shared::interface::wire::s2c,
+    std::{
+        path::Path,
+        sync::Arc,
+        time::Duration,
+    },
+    taskmanager::TaskManager,
+    tokio::{
+        select,
+        sync::broadcast::{
+            self,
+            error::RecvError,
+        },
+        time::timeout,
+    },
+    web_push_native::{
+        jwt_simple::algorithms::ES256KeyPair,
+        p256::PublicKey,
+        Auth,
+        WebPushBuilder,
+    },
+};
+
+const VAPID_KEY_FILENAME: &str = "webpush_vapid.key";
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct WebPushState {
+    key: ES256KeyPair,
+    /// Push services contact this if the server misbehaves.
+    contact: String,
+    allow_private: bool,
+}
+
+/// Loads the VAPID key, creating it if this is the first start. Changing the key
+/// invalidates every existing subscription.
+pub async fn new_state(
+    persistent_dir: &Path,
+    public_url: Option<&str>,
+    allow_private: bool,
+) -> Result<WebPushState, loga::Error> {
+    let path = persistent_dir.join(VAPID_KEY_FILENAME);
+    let key = match tokio::fs::read(&path).await {
+        Ok(bytes) => ES256KeyPair::from_bytes(
+            &bytes,
+        ).map_err(|e| loga::err_with("Web push key is invalid", ea!(path = path.to_string_lossy(), err = e)))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = ES256KeyPair::generate();
+            tokio::fs::write(&path, key.to_bytes())
+                .await
+                .context_with("Error writing web push key", ea!(path = path.to_string_lossy()))?;
+            key
+        },
+        Err(e) => {
+            return Err(e).context_with("Error reading web push key", ea!(path = path.to_string_lossy()));
+        },
+    };
+    return Ok(WebPushState {
+        key: key,
+        contact: public_url.map(|u| u.to_string()).unwrap_or_else(|| format!("mailto:postmaster@localhost")),
+        allow_private: allow_private,
+    });
+}
+
+/// The VAPID public key as the browser wants it for `applicationServerKey`
+/// (uncompressed point, base64url without padding).
+pub fn public_key(state: &WebPushState) -> String {
+    return URL_SAFE_NO_PAD.encode(state.key.public_key().public_key().to_bytes_uncompressed());
+}
+
+/// What `PushSubscription.toJSON()` produces, the bits needed to send.
+#[derive(Deserialize)]
+struct SubscriptionJson {
+    endpoint: String,
+    keys: SubscriptionKeysJson,
+}
+
+#[derive(Deserialize)]
+struct SubscriptionKeysJson {
+    p256dh: String,
+    auth: String,
+}
+
+/// Returns `false` if the subscription is gone and should be deleted.
+async fn push(log: &Log, state: &WebPushState, subscription: &serde_json::Value, body: Vec<u8>) -> Result<bool, loga::Error> {
+    let subscription =
+        serde_json::from_value::<SubscriptionJson>(
+            subscription.clone(),
+        ).context("Push subscription is missing the endpoint or keys")?;
+    let uri = subscription.endpoint.parse::<Uri>().context("Push subscription endpoint is invalid")?;
+    let p256dh = URL_SAFE_NO_PAD.decode(subscription.keys.p256dh).context("Push subscription p256dh isn't base64")?;
+    let auth = URL_SAFE_NO_PAD.decode(subscription.keys.auth).context("Push subscription auth isn't base64")?;
+    if auth.len() != 16 {
+        return Err(loga::err("Push subscription auth must be 16 bytes"));
+    }
+    let req =
+        WebPushBuilder::new(
+            uri.clone(),
+            PublicKey::from_sec1_bytes(&p256dh).map_err(|_| loga::err("Push subscription p256dh is invalid"))?,
+            Auth::clone_from_slice(&auth),
+        )
+            .with_vapid(&state.key, &state.contact)
+            .build(body)
+            .map_err(|e| loga::err_with("Error encrypting push message", ea!(err = e)))?;
+    let (mut parts, body) = req.into_parts();
+    parts.headers.insert(HOST, uri.authority().map(|a| a.as_str()).unwrap_or_default().parse().unwrap());
+    let req = http::Request::from_parts(parts, body_full(body));
+    let (code, _headers, _continue) = timeout(PUSH_TIMEOUT, async {
+        let mut conn = s2sclient::connect(state.allow_private, &uri).await?;
+        return Ok(htreq::send(log, htreq::Limits::default(), &mut conn, req).await?) as Result<_, loga::Error>;
+    }).await.map_err(|_| loga::err("Timed out waiting for push service"))??;
+    if code == StatusCode::NOT_FOUND || code == StatusCode::GONE {
+        return Ok(false);
+    }
+    if !code.is_success() {
+        return Err(loga::err_with("Push service returned an error", ea!(status = code)));
+    }
+    return Ok(true);
+}
+
+async fn push_activity(log: &Log, db: &Pool, state: &WebPushState, activity: ChannelActivity) -> Result<(), loga::Error> {
+    let Some(push_body) = activity.push_body else {
+        return Ok(());
+    };
+    let body = serde_json::to_vec(&s2c::Notification {
+        channel: activity.channel,
+        offset: activity.offset,
+        body: push_body,
+    }).unwrap();
+    let accounts = activity.accounts;
+    let subscriptions = tx(db, move |db_tx| {
+        let mut out = vec![];
+        for account in accounts {
+            out.extend(good_query_many!(
+                crate::db,
+                //# genemichaels-external: sql-formatter-sqlite
+                r#"select
+                     data
+                   from
+                     push_subscription
+                   where
+                     account = ${account_external_id_t = DbAccountExternalId(account)}
+                   "#;
+                &mut db_tx
+            ).map_err(|e| loga::err(e.0))?);
+        }
+        return Ok(out);
+    }).await?;
+    for subscription in subscriptions {
+        match push(log, state, &subscription.0, body.clone()).await {
+            Ok(true) => { },
+            Ok(false) => {
+                tx(db, move |db_tx| {
+                    good_query!(
+                        crate::db,
+                        //# genemichaels-external: sql-formatter-sqlite
+                        r#"delete from
+                             push_subscription
+                           where
+                             data = ${push_subscription_t = subscription}
+                           "#;
+                        &mut db_tx
+                    ).map_err(|e| loga::err(e.0))?;
+                    return Ok(());
+                }).await?;
+            },
+            Err(e) => {
+                log.log_err(loga::DEBUG, e.context("Error sending push notification"));
+            },
+        }
+    }
+    return Ok(());
+}
+
+/// Pushes new messages from `activity` until shutdown.
+pub fn spawn_worker(
+    log: &Log,
+    tm: &TaskManager,
+    db: Pool,
+    state: Arc<WebPushState>,
+    mut activity: broadcast::Receiver<ChannelActivity>,
+) {
+    let log = log.fork(ea!(sys = "webpush"));
+    tm.task("webpush", {
+        let tm = tm.clone();
+        async move {
+            loop {
+                let a = select!{
+                    _ = tm.until_terminate() => {
+                        break;
+                    },
+                    a = activity.recv() => a,
+                };
+                let a = match a {
+                    Ok(a) => a,
+                    Err(RecvError::Lagged(n)) => {
+                        log.log_with(loga::WARN, "Missed channel activity, some pushes weren't sent", ea!(count = n));
+                        continue;
+                    },
+                    Err(RecvError::Closed) => {
+                        break;
+                    },
+                };
+                if let Err(e) = push_activity(&log, &db, &state, a).await {
+                    log.log_err(loga::WARN, e.context("Error sending push notifications"));
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,76 @@
+//! Live notifications for connected clients at `c/1/ws`. Each activity in a
+//! channel the account is in is sent as a JSON `s2c::Notification` text frame;
+//! the client then fetches the activity pages. Nothing is sent by the client.
+use {
+    crate::{
+        interface::AccountExternalId,
+        subsystems::federation::ChannelActivity,
+    },
+    futures::{
+        SinkExt,
+        StreamExt,
+    },
+    hyper_tungstenite::{
+        tungstenite::Message,
+        HyperWebsocket,
+    },
+    loga::ResultContext,
+    shared::interface::{
+        shared::QualifiedChannelId,
+        wire::s2c,
+    },
+    tokio::{
+        select,
+        sync::broadcast::{
+            self,
+            error::RecvError,
+        },
+    },
+};
+
+/// Forwards activity to the client until either side closes. `allows` limits the
+/// channels for API tokens. Activity missed because the client fell behind isn't
+/// resent, clients poll `ActivityLatestAll` when they regain focus.
+pub async fn serve(
+    mut activity: broadcast::Receiver<ChannelActivity>,
+    account: AccountExternalId,
+    allows: impl Fn(&QualifiedChannelId) -> bool,
+    websocket: HyperWebsocket,
+) -> Result<(), loga::Error> {
+    let mut websocket = websocket.await.context("Error completing websocket handshake")?;
+    loop {
+        select!{
+            a = activity.recv() => {
+                let a = match a {
+                    Ok(a) => a,
+                    Err(RecvError::Lagged(_)) => {
+                        continue;
+                    },
+                    Err(RecvError::Closed) => {
+                        return Ok(());
+                    },
+                };
+                if !a.accounts.contains(&account) || !allows(&a.channel) {
+                    continue;
+                }
+                websocket.send(Message::text(serde_json::to_string(&s2c::Notification {
+                    channel: a.channel,
+                    offset: a.offset,
+                    body: a.push_body.unwrap_or_default(),
+                }).unwrap())).await.context("Error sending notification")?;
+            },
+            m = websocket.next() => {
+                match m {
+                    None | Some(Ok(Message::Close(_))) => {
+                        return Ok(());
+                    },
+                    Some(Err(e)) => {
+                        return Err(e).context("Error reading from websocket");
+                    },
+                    // Pings are answered by tungstenite
+                    Some(Ok(_)) => { },
+                }
+            },
+        }
+    }
+}
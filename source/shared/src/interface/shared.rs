@@ -19,6 +19,15 @@ pub struct AccountId(pub u64);
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ChannelGroupId(pub u64);
 
+/// Public id of a login session, distinct from the secret session cookie.
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct SessionId(pub String);
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ApiTokenId(pub u64);
+
 #[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct IdentityInviteToken(pub String);
@@ -49,6 +58,27 @@ pub struct QualifiedChannelInviteToken {
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ChannelInviteId(pub u64);
 
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ChannelWebhookToken(pub String);
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ChannelWebhookId(pub u64);
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookId(pub u64);
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookDeliveryId(pub u64);
+
+/// Random, so export download urls can't be guessed.
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AccountExportId(pub String);
+
 #[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ChannelId(pub u64);
@@ -95,6 +125,8 @@ pub enum MessageRel {
     None,
     ReplyTo(MessageId),
     EditOf(MessageId),
+    /// Only appears in the activity, the message is removed from the snap.
+    DeleteOf(MessageId),
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
@@ -1,17 +1,24 @@
 use {
     crate::interface::shared::{
+        AccountExportId,
+        ApiTokenId,
         ChannelGroupId,
         ChannelId,
         ChannelInviteId,
+        ChannelWebhookId,
+        ChannelWebhookToken,
         IdentityInviteId,
         Message,
         MessageBody,
         MessageClientId,
         MessageId,
+        OutgoingWebhookDeliveryId,
+        OutgoingWebhookId,
         QualifiedChannelId,
         QualifiedChannelInviteToken,
         QualifiedIdentityInviteToken,
         QualifiedMessageId,
+        SessionId,
     },
     glove::reqresp,
     jiff::Timestamp,
@@ -29,6 +36,34 @@ use {
     },
 };
 
+// # Versioning
+/// Version of the c2s protocol (`proto`, the path requests, and notifications)
+/// this build speaks. Bump on incompatible changes.
+pub const C2S_VERSION: u32 = 1;
+
+/// Prefix of the version 1 endpoints, relative to the server's base url.
+pub const C2SV1_PREFIX: &str = "c/1/";
+
+/// Unversioned so that clients of any version can tell what the server speaks.
+pub const C2S_VERSION_PATH: &str = "c/version";
+
+/// Deliberately doesn't deny unknown fields, so new fields don't break old clients.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct C2sVersionRes {
+    /// Protocol versions the server can serve, see `C2S_VERSION`.
+    pub versions: Vec<u32>,
+    /// Optional features enabled on this server.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl C2sVersionRes {
+    pub fn supports(&self, version: u32) -> bool {
+        return self.versions.contains(&version);
+    }
+}
+
 // POST/JSON data
 #[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -46,6 +81,98 @@ pub struct NotificationRegister {
     pub data: serde_json::Value,
 }
 
+// # Session
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct SessionRes {
+    pub id: SessionId,
+    pub created: Timestamp,
+    pub last_seen: Timestamp,
+    /// Short browser and OS description, ex: `Firefox on Linux`
+    pub user_agent: String,
+    /// This is the session making the request
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct SessionList;
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum SessionRevokeTarget {
+    Session(SessionId),
+    /// All sessions except the current one
+    AllOthers,
+}
+
+/// Logs out the matching sessions and removes their push subscriptions.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct SessionRevoke {
+    pub target: SessionRevokeTarget,
+}
+
+// # Api tokens
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ApiTokenScope {
+    /// Channels the token may access, owned by or joined as the token's identity.
+    pub channels: Vec<ChannelId>,
+    /// Allows reading the channels: the `snap_page`/`activity_page` GETs and
+    /// `ChannelMemberList`.
+    #[serde(default)]
+    pub read: bool,
+    /// Allows posting messages to the channels.
+    #[serde(default)]
+    pub post: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ApiTokenCreate {
+    pub identity: Identity,
+    pub memo_short: String,
+    pub scope: ApiTokenScope,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ApiTokenRes {
+    pub id: ApiTokenId,
+    pub identity: Identity,
+    pub memo_short: String,
+    pub scope: ApiTokenScope,
+    pub created: Timestamp,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ApiTokenCreateRes {
+    pub res: ApiTokenRes,
+    /// The secret to send as `Authorization: Bearer <token>`. Only the hash is
+    /// stored, so this is the only time it's available.
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ApiTokenList {
+    pub identity: Identity,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ApiTokenDelete {
+    pub id: ApiTokenId,
+}
+
+/// Describes the token used to authenticate the request, for clients that only
+/// have a token and need to know what they can do with it.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ApiTokenSelf;
+
 // # Identity
 #[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -237,6 +364,15 @@ pub enum ForeignMemoShort {
     Unconfirmed(String),
 }
 
+/// Adds an identity on another server to a channel you own. Its server is sent
+/// `Notify` when the channel changes and may read the channel pages.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ChannelMemberAdd {
+    pub channel: QualifiedChannelId,
+    pub member: Identity,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ChannelMemberList {
@@ -299,6 +435,226 @@ pub struct ChannelInviteDelete {
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct ChannelInviteList;
 
+// # Channel webhook
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ChannelWebhookCreate {
+    pub channel: QualifiedChannelId,
+    /// The identity messages are posted as.
+    pub identity: Identity,
+    pub memo_short: String,
+    pub memo_long: String,
+    #[serde(default)]
+    pub expiry: Option<Timestamp>,
+    pub max_per_minute: u32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ChannelWebhookRes {
+    pub id: ChannelWebhookId,
+    pub channel: QualifiedChannelId,
+    pub identity: Identity,
+    pub memo_short: String,
+    pub memo_long: String,
+    pub expiry: Option<Timestamp>,
+    pub max_per_minute: u32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ChannelWebhookCreateRes {
+    pub res: ChannelWebhookRes,
+    /// Secret, requests are accepted at `webhook/<token>`. Only the hash is stored,
+    /// so this is the only time it's available.
+    pub token: ChannelWebhookToken,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ChannelWebhookModify {
+    pub id: ChannelWebhookId,
+    #[serde(default)]
+    pub memo_short: Option<String>,
+    #[serde(default)]
+    pub memo_long: Option<String>,
+    #[serde(default)]
+    pub expiry: Option<ModifyOption<Timestamp>>,
+    #[serde(default)]
+    pub max_per_minute: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ChannelWebhookDelete {
+    pub id: ChannelWebhookId,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ChannelWebhookList;
+
+// # Outgoing webhook
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookEvents {
+    #[serde(default)]
+    pub message_new: bool,
+    #[serde(default)]
+    pub message_edited: bool,
+    #[serde(default)]
+    pub message_deleted: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookCreate {
+    pub channel: QualifiedChannelId,
+    /// Deliveries are `POST`ed here
+    pub url: String,
+    pub memo_short: String,
+    pub events: OutgoingWebhookEvents,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookRes {
+    pub id: OutgoingWebhookId,
+    pub channel: QualifiedChannelId,
+    pub url: String,
+    pub memo_short: String,
+    pub events: OutgoingWebhookEvents,
+    /// HMAC-SHA256 key used to sign deliveries, see `X-Kwa-Signature`
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookDelete {
+    pub id: OutgoingWebhookId,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookList {
+    pub channel: QualifiedChannelId,
+}
+
+/// Queues a `ping` event, to check the receiver is set up correctly.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookTest {
+    pub id: OutgoingWebhookId,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum OutgoingWebhookDeliveryStatus {
+    /// Waiting for the first attempt or a retry
+    Pending,
+    Delivered,
+    /// Gave up after too many attempts
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookDeliveryRes {
+    pub id: OutgoingWebhookDeliveryId,
+    pub event: String,
+    pub created: Timestamp,
+    pub status: OutgoingWebhookDeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt: Option<Timestamp>,
+    /// Error or unexpected response from the most recent attempt
+    pub last_error: Option<String>,
+}
+
+/// Most recent deliveries first.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct OutgoingWebhookDeliveryList {
+    pub id: OutgoingWebhookId,
+}
+
+// Account export
+/// Starts building an archive of the account's data in the background. Poll
+/// `AccountExportList` until it's ready, then download it with
+/// `AccountExportDownload`.
+///
+/// Messages are included for channels your identities own. Contacts and invites
+/// aren't stored server-side, so they aren't in the archive; the manifest lists
+/// them under `unavailable`.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AccountExportCreate {
+    /// Include identity secrets, encrypted with this passphrase. Anyone with the
+    /// archive and the passphrase can act as your identities.
+    #[serde(default)]
+    pub secrets_passphrase: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum AccountExportStatus {
+    Pending,
+    Ready,
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AccountExportRes {
+    pub id: AccountExportId,
+    pub created: Timestamp,
+    pub status: AccountExportStatus,
+    pub includes_secrets: bool,
+    /// Exports are deleted after this.
+    pub expires: Timestamp,
+}
+
+/// Most recent first.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AccountExportList;
+
+// Identity portability
+/// Produces a bundle that can be imported on another server with
+/// `IdentityImport`. If `withdraw` is turned off the identity keeps working here
+/// until deleted, and both servers will claim it until then.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct IdentityExport {
+    pub id: Identity,
+    /// The bundle contains the identity secret encrypted with this.
+    pub passphrase: String,
+    /// Delete the identity here once the bundle is made, on by default. The bundle
+    /// can still be imported back here.
+    #[serde(default = "default_identity_export_withdraw")]
+    pub withdraw: bool,
+}
+
+fn default_identity_export_withdraw() -> bool {
+    return true;
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct IdentityExportRes {
+    /// Opaque, pass unmodified to `IdentityImport`.
+    pub bundle: String,
+}
+
+/// Adds an identity exported from another server (or deleted from this one) and
+/// publishes this server as its home.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct IdentityImport {
+    pub idem: Option<String>,
+    pub bundle: String,
+    pub passphrase: String,
+}
+
 // Contacts
 #[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -331,13 +687,38 @@ pub struct MessagePush {
     pub body: String,
 }
 
+/// Only the sender can edit their messages.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MessageEdit {
+    pub channel: QualifiedChannelId,
+    pub id: MessageId,
+    pub body: String,
+}
+
+/// Only the sender can delete their messages.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MessageDelete {
+    pub channel: QualifiedChannelId,
+    pub id: MessageId,
+}
+
 reqresp!(pub proto {
     Logout(Logout) =>(),
     NotificationRegister(NotificationRegister) =>(),
+    SessionList(SessionList) => Vec < SessionRes >,
+    SessionRevoke(SessionRevoke) =>(),
+    ApiTokenCreate(ApiTokenCreate) => ApiTokenCreateRes,
+    ApiTokenList(ApiTokenList) => Vec < ApiTokenRes >,
+    ApiTokenDelete(ApiTokenDelete) =>(),
+    ApiTokenSelf(ApiTokenSelf) => ApiTokenRes,
     IdentityCreate(IdentityCreate) => IdentityRes,
     IdentityModify(IdentityModify) => IdentityRes,
     IdentityDelete(IdentityDelete) =>(),
     IdentityList(IdentityList) => Vec < IdentityRes >,
+    IdentityExport(IdentityExport) => IdentityExportRes,
+    IdentityImport(IdentityImport) => IdentityRes,
     IdentityInviteCreate(IdentityInviteCreate) => IdentityInviteRes,
     IdentityInviteModify(IdentityInviteModify) => IdentityInviteRes,
     IdentityInviteDelete(IdentityInviteDelete) =>(),
@@ -352,15 +733,29 @@ reqresp!(pub proto {
     ChannelModify(ChannelModify) => ChannelRes,
     ChannelDelete(ChannelDelete) =>(),
     ChannelList(ChannelList) => Vec < ChannelRes >,
+    ChannelMemberAdd(ChannelMemberAdd) =>(),
     ChannelMemberList(ChannelMemberList) => Vec < Identity >,
     ChannelMemberDelete(ChannelMemberDelete) =>(),
     ChannelInviteCreate(ChannelInviteCreate) => ChannelInviteRes,
     ChannelInviteModify(ChannelInviteModify) => ChannelInviteRes,
     ChannelInviteDelete(ChannelInviteDelete) =>(),
     ChannelInviteList(ChannelInviteList) => Vec < ChannelInviteRes >,
+    ChannelWebhookCreate(ChannelWebhookCreate) => ChannelWebhookCreateRes,
+    ChannelWebhookModify(ChannelWebhookModify) => ChannelWebhookRes,
+    ChannelWebhookDelete(ChannelWebhookDelete) =>(),
+    ChannelWebhookList(ChannelWebhookList) => Vec < ChannelWebhookRes >,
+    OutgoingWebhookCreate(OutgoingWebhookCreate) => OutgoingWebhookRes,
+    OutgoingWebhookDelete(OutgoingWebhookDelete) =>(),
+    OutgoingWebhookList(OutgoingWebhookList) => Vec < OutgoingWebhookRes >,
+    OutgoingWebhookTest(OutgoingWebhookTest) =>(),
+    OutgoingWebhookDeliveryList(OutgoingWebhookDeliveryList) => Vec < OutgoingWebhookDeliveryRes >,
+    AccountExportCreate(AccountExportCreate) => AccountExportRes,
+    AccountExportList(AccountExportList) => Vec < AccountExportRes >,
     ContactList(ContactList) => Vec < ContactRes >,
     ContactModify(ContactModify) => ContactRes,
     MessagePush(MessagePush) =>(),
+    MessageEdit(MessageEdit) =>(),
+    MessageDelete(MessageDelete) =>(),
 });
 
 #[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
@@ -752,3 +1147,42 @@ impl PathReqTrait for GetPortrait {
         return serialize_path([PATH_PREFIX_PORTRAIT.to_string(), self.identity.to_string()]);
     }
 }
+
+/// A finished account export archive (`.tar.gz`).
+pub struct AccountExportDownload {
+    pub id: AccountExportId,
+}
+
+const PATH_PREFIX_ACCOUNT_EXPORT: &str = "account_export";
+
+impl PathReqTrait for AccountExportDownload {
+    type Resp = Vec<u8>;
+
+    fn deserialize_path(path: &str) -> Result<Self, String> {
+        let mut parts = deserialize_path(path);
+        confirm_path_const(&mut parts, "")?;
+        confirm_path_const(&mut parts, PATH_PREFIX_ACCOUNT_EXPORT)?;
+        let out = AccountExportDownload { id: AccountExportId(confirm_path_element(&mut parts, "export id")?) };
+        confirm_path_empty(&mut parts)?;
+        return Ok(out);
+    }
+
+    fn serialize_path(&self) -> String {
+        return serialize_path([PATH_PREFIX_ACCOUNT_EXPORT.to_string(), self.id.0.clone()]);
+    }
+}
+
+/// The first path segment of every `PathReqTrait` route, ex: for grouping requests
+/// in metrics.
+pub const PATH_REQ_PREFIXES: &[&str] = &[
+    //. .
+    PATH_PREFIX_NOTIFICATION_SERVER_KEY,
+    PATH_PREFIX_SNAP_BY_ID,
+    PATH_PREFIX_SNAP_BY_CLIENT_ID,
+    PATH_PREFIX_SNAP_PAGE_CONTAINING_TIME,
+    PATH_PREFIX_SNAP_PAGE,
+    PATH_PREFIX_ACTIVITY_LATEST_ALL,
+    PATH_PREFIX_ACTIVITY_PAGE,
+    PATH_PREFIX_PORTRAIT,
+    PATH_PREFIX_ACCOUNT_EXPORT,
+];
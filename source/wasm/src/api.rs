@@ -7,6 +7,11 @@ use {
             state,
         },
     },
+    client::{
+        browser::BrowserTransport,
+        path_rel,
+        Client,
+    },
     gloo::{
         storage::{
             LocalStorage,
@@ -22,9 +27,9 @@ use {
     shared::interface::wire::c2s::{
         self,
         PathReqTrait,
+        C2SV1_PREFIX,
     },
     spaghettinuum::interface::identity::Identity,
-    wasm_bindgen::UnwrapThrowExt,
     web_sys::Url,
 };
 
@@ -53,7 +58,7 @@ pub fn redirect_login() -> ! {
     window()
         .location()
         .set_href(
-            &Url::new_with_base(&format!("oidc?url={}", urlencoding::encode(&base_url)), base_url).unwrap().href(),
+            &Url::new_with_base(&format!("{}oidc?url={}", C2SV1_PREFIX, urlencoding::encode(&base_url)), base_url).unwrap().href(),
         )
         .unwrap();
     unreachable!();
@@ -70,7 +75,7 @@ pub fn redirect_logout() -> ! {
     window()
         .location()
         .set_href(
-            &Url::new_with_base(&format!("logout?url={}", urlencoding::encode(&base_url)), base_url).unwrap().href(),
+            &Url::new_with_base(&format!("{}logout?url={}", C2SV1_PREFIX, urlencoding::encode(&base_url)), base_url).unwrap().href(),
         )
         .unwrap();
     unreachable!();
@@ -81,6 +86,11 @@ async fn read_resp(resp: Response) -> Result<Vec<u8>, String> {
     if status == 401 && want_logged_in() {
         redirect_login();
     }
+    if status == 429 {
+        return Err(map_client_err(client::Error::RateLimited {
+            retry_after: resp.headers().get("Retry-After").and_then(|v| v.parse().ok()),
+        }));
+    }
     let body = match resp.binary().await {
         Err(e) => {
             return Err(format!("Got error response, got additional error trying to read body [{}]: {}", status, e));
@@ -93,29 +103,24 @@ async fn read_resp(resp: Response) -> Result<Vec<u8>, String> {
     return Ok(body);
 }
 
-async fn post(req: Request) -> Result<Vec<u8>, String> {
-    let resp = match req.send().await {
-        Ok(r) => r,
-        Err(e) => {
-            return Err(format!("Failed to send request: {}", e));
+fn map_client_err(e: client::Error) -> String {
+    match &e {
+        client::Error::Status { status: 401, .. } => {
+            if want_logged_in() {
+                redirect_login();
+            }
         },
-    };
-    return read_resp(resp).await;
+        client::Error::RateLimited { retry_after } => match retry_after {
+            Some(s) => return format!("You're doing that too quickly, slow down and try again in {} seconds.", s),
+            None => return format!("You're doing that too quickly, slow down and try again in a bit."),
+        },
+        _ => { },
+    }
+    return e.to_string();
 }
 
 pub async fn req_post_json<T: c2s::proto::ReqTrait>(base_url: &str, req: T) -> Result<T::Resp, String> {
-    let req =
-        Request::post(&format!("{}api", base_url))
-            .header("Content-type", "application/json")
-            .body(serde_json::to_string(&req.to_enum()).unwrap_throw());
-    let body = post(req).await?;
-    return Ok(
-        serde_json::from_slice::<T::Resp>(
-            &body,
-        ).map_err(
-            |e| format!("Error parsing JSON response from server: {}\nBody: {}", e, String::from_utf8_lossy(&body)),
-        )?,
-    );
+    return Client::new(BrowserTransport::new(base_url)).req(req).await.map_err(map_client_err);
 }
 
 pub async fn req_file(url: &str) -> Result<Vec<u8>, String> {
@@ -129,27 +134,14 @@ pub async fn req_file(url: &str) -> Result<Vec<u8>, String> {
 }
 
 pub async fn req_get<T: c2s::PathReqTrait>(req: T) -> Result<T::Resp, String> {
-    let req = Request::get(&format!("{}{}", state().env.base_url, req.serialize_path()));
-    let body = match req.send().await {
-        Ok(r) => r,
-        Err(e) => {
-            return Err(format!("Failed to send request: {}", e));
-        },
-    };
-    let body = read_resp(body).await?;
-    return Ok(
-        serde_json::from_slice::<T::Resp>(
-            &body,
-        ).map_err(
-            |e| format!(
-                "Error parsing JSON response from server response: {}\nBody: {}",
-                e,
-                String::from_utf8_lossy(&body)
-            ),
-        )?,
-    );
+    return Client::new(BrowserTransport::new(&state().env.base_url)).get(req).await.map_err(map_client_err);
 }
 
 pub fn portrait_url(identity: &Identity) -> String {
-    return format!("{}{}", state().env.base_url, c2s::GetPortrait { identity: identity.clone() }.serialize_path());
+    return format!(
+        "{}{}{}",
+        state().env.base_url,
+        C2SV1_PREFIX,
+        path_rel(&c2s::GetPortrait { identity: identity.clone() }.serialize_path())
+    );
 }
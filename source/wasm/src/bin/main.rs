@@ -1,5 +1,10 @@
 use {
+    client::{
+        browser::BrowserTransport,
+        Client,
+    },
     flowcontrol::shed,
+    futures::StreamExt,
     gloo::{
         events::EventListener,
         storage::{
@@ -17,13 +22,14 @@ use {
         EventGraph,
         Prim,
     },
-    rooting::set_root,
-    serde::Deserialize,
-    shared::interface::{
-        wire::{
-            s2c,
-        },
+    rooting::{
+        scope_any,
+        set_root,
+        spawn_rooted,
+        ScopeValue,
     },
+    serde::Deserialize,
+    shared::interface::wire::c2s::C2S_VERSION,
     std::{
         cell::RefCell,
         panic,
@@ -50,6 +56,7 @@ use {
             get_stored_api_channels,
         },
         page_top,
+        page_update_required,
         serviceworker_proto::FromSw,
         state::{
             LOCALSTORAGE_PWA_MINISTATE,
@@ -61,9 +68,10 @@ use {
             merge_top,
             read_ministate,
             record_replace_ministate,
+            set_page,
+            spawn_log,
             state,
         },
-        websocket::Ws,
     },
     wasm_bindgen::{
         JsCast,
@@ -152,6 +160,7 @@ pub fn main() {
             bg_pushing: Default::default(),
             bg_pulling_interval: Default::default(),
             bg_pulling: Default::default(),
+            update_required: Default::default(),
         })));
         let cs = get_stored_api_channels(None);
         let cgs = get_stored_api_channelgroups(None);
@@ -160,6 +169,18 @@ pub fn main() {
         // Load initial view
         build_ministate(pc, &state().ministate.borrow());
 
+        // Stop before requests start failing with confusing errors if this is a stale
+        // cached client and the server has moved on
+        spawn_log("Checking server protocol version", async {
+            let version =
+                Client::new(BrowserTransport::new(&state().env.base_url)).version().await.map_err(|e| e.to_string())?;
+            if !version.supports(C2S_VERSION) {
+                *state().update_required.borrow_mut() = Some(version.versions.clone());
+                set_page(page_update_required::build(&version.versions));
+            }
+            return Ok(());
+        });
+
         // React to further state changes
         EventListener::new(&window(), "popstate", {
             let eg = pc.eg();
@@ -196,17 +217,20 @@ pub fn main() {
             }
         }).forget();
         schedule_trigger_pull(pc.eg());
-        let ws: Rc<RefCell<Option<Ws<(), s2c::Notification>>>> = Rc::new(RefCell::new(None));
+        let ws: Rc<RefCell<Option<ScopeValue>>> = Rc::new(RefCell::new(None));
         let create_ws = {
             let ws = ws.clone();
             let eg = pc.eg();
             move || {
-                *ws.borrow_mut() = Some(Ws::new(state().log.clone(), &state().env.base_url, "", {
+                let mut notifications = Client::new(BrowserTransport::new(&state().env.base_url)).notifications();
+                *ws.borrow_mut() = Some(scope_any(spawn_rooted({
                     let eg = eg.clone();
-                    move |_ws, m: s2c::Notification| {
-                        handle_notification(&eg, vec![(m.channel, m.offset)]);
+                    async move {
+                        while let Some(m) = notifications.next().await {
+                            handle_notification(&eg, vec![(m.channel, m.offset)]);
+                        }
                     }
-                }));
+                })));
             }
         };
         create_ws();
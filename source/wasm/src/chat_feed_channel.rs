@@ -46,6 +46,7 @@ use {
     shared::interface::{
         shared::{
             MessageClientId,
+            MessageRel,
             QualifiedChannelId,
             QualifiedMessageId,
         },
@@ -157,6 +158,10 @@ impl ChannelFeed {
                                     continue;
                                 };
                                 let e = exenum!(&e.int, ChatEntryInternal:: Message(e) => e).unwrap();
+                                if let MessageRel::DeleteOf(_) = &entry1.rel {
+                                    e.internal.set(pc, ChatEntryMessageInternal::Deleted);
+                                    continue;
+                                }
                                 let e_int = e.internal.borrow();
                                 match &*e_int {
                                     ChatEntryMessageInternal::Obviated => { },
@@ -15,6 +15,7 @@ use {
         Array,
         JSON,
         Object,
+        Uint8Array,
     },
     rooting::{
         El,
@@ -608,10 +609,27 @@ pub fn copy(log: &Rc<dyn Log>, data: impl serde::Serialize) {
 }
 
 pub fn download(filename: String, data: impl serde::Serialize) {
+    download_blob(filename, &as_blob(data));
+}
+
+/// For binary data, ex: archives fetched from the server.
+pub fn download_bytes(filename: String, mime: &str, data: &[u8]) {
+    let blob = Blob::new_with_u8_array_sequence_and_options(&JsValue::from(vec![
+        //. .
+        JsValue::from(Uint8Array::from(data))
+    ]), &{
+        let p = BlobPropertyBag::new();
+        p.set_type(mime);
+        p
+    }).unwrap();
+    download_blob(filename, &blob);
+}
+
+fn download_blob(filename: String, blob: &Blob) {
     let document = document();
     let body = document.body().unwrap();
     let a = document.create_element("a").unwrap().dyn_into::<HtmlElement>().unwrap();
-    let url = Url::create_object_url_with_blob(&as_blob(data)).unwrap();
+    let url = Url::create_object_url_with_blob(blob).unwrap();
     a.set_attribute("href", &url).unwrap();
     a.set_attribute("download", &filename).unwrap();
     body.append_child(&a).unwrap();
@@ -1,15 +1,17 @@
 pub mod js;
-pub mod websocket;
 pub mod async_;
 pub mod state;
 pub mod localdata;
 pub mod page_top;
 pub mod page_settings;
+pub mod page_account_export_new;
 pub mod page_identities;
 pub mod page_identity;
 pub mod page_identity_new;
 pub mod page_identity_edit;
 pub mod page_identity_delete;
+pub mod page_identity_export;
+pub mod page_identity_import;
 pub mod page_identityinvites;
 pub mod page_identityinvite_new;
 pub mod page_identityinvite;
@@ -18,6 +20,7 @@ pub mod page_identityinvite_delete;
 pub mod page_channel;
 pub mod page_channel_new;
 pub mod page_top_add;
+pub mod page_update_required;
 pub mod page_channel_join_url;
 pub mod page_channel_menu;
 pub mod page_channel_edit;
@@ -31,6 +34,14 @@ pub mod page_channelinvite_new;
 pub mod page_channelinvite;
 pub mod page_channelinvite_edit;
 pub mod page_channelinvite_delete;
+pub mod page_channelwebhooks;
+pub mod page_channelwebhook_new;
+pub mod page_channelwebhook;
+pub mod page_channelwebhook_edit;
+pub mod page_channelwebhook_delete;
+pub mod page_channeloutgoingwebhooks;
+pub mod page_channeloutgoingwebhook_new;
+pub mod page_channeloutgoingwebhook_log;
 pub mod page_channelgroup_new;
 pub mod page_channelgroup;
 pub mod page_channelgroup_menu;
@@ -28,6 +28,7 @@ use {
         shared::{
             ChannelGroupId,
             ChannelInviteId,
+            ChannelWebhookId,
             IdentityInviteId,
             QualifiedChannelId,
         },
@@ -36,6 +37,7 @@ use {
             ChannelGroupRes,
             ChannelInviteRes,
             ChannelRes,
+            ChannelWebhookRes,
             ContactRes,
             IdentityInviteRes,
             IdentityRes,
@@ -380,6 +382,36 @@ pub async fn delete_channelinvite(v: ChannelInviteRes) {
     delete_api_value(LOCALSTORAGE_CHANNEL_INVITES, |x| x.id.clone(), v).await;
 }
 
+// Channel webhooks
+const LOCALSTORAGE_CHANNEL_WEBHOOKS: &str = "channel_webhooks";
+pub type LocalChannelWebhook = LocalValue<ChannelWebhookRes>;
+
+pub fn get_stored_api_channelwebhooks(touch: Option<&ChannelWebhookId>) -> Vec<LocalChannelWebhook> {
+    return get_stored_values(LOCALSTORAGE_CHANNEL_WEBHOOKS, |x| x.id, touch);
+}
+
+pub async fn req_api_channelwebhooks(touch: Option<&ChannelWebhookId>) -> Result<Vec<LocalChannelWebhook>, String> {
+    return req_api_values(LOCALSTORAGE_CHANNEL_WEBHOOKS, c2s::ChannelWebhookList, |x| x.id, touch).await;
+}
+
+pub fn get_or_req_api_channelwebhook(id: &ChannelWebhookId, touch: bool) -> NowOrLater<LocalChannelWebhook> {
+    return get_or_req_api_value(
+        LOCALSTORAGE_CHANNEL_WEBHOOKS,
+        c2s::ChannelWebhookList,
+        |x| x.id.clone(),
+        id.clone(),
+        touch,
+    );
+}
+
+pub async fn ensure_channelwebhook(v: ChannelWebhookRes) {
+    ensure_api_value(LOCALSTORAGE_CHANNEL_WEBHOOKS, |x| x.id, v).await;
+}
+
+pub async fn delete_channelwebhook(v: ChannelWebhookRes) {
+    delete_api_value(LOCALSTORAGE_CHANNEL_WEBHOOKS, |x| x.id.clone(), v).await;
+}
+
 // Contacts
 const LOCALSTORAGE_CONTACTS: &str = "contacts";
 pub type LocalContact = LocalValue<ContactRes>;
@@ -0,0 +1,51 @@
+use {
+    crate::{
+        api::req_post_json,
+        pageutil::build_form,
+        state::{
+            Ministate,
+            goto_replace_ministate,
+            state,
+        },
+    },
+    lunk::ProcessingContext,
+    rooting::El,
+    rooting_forms::Form,
+    shared::interface::wire::c2s,
+    std::rc::Rc,
+};
+
+#[derive(rooting_forms::Form)]
+struct Form_ {
+    #[title("Identity secrets passphrase")]
+    secrets_passphrase: Option<String>,
+}
+
+pub fn build(pc: &mut ProcessingContext) -> El {
+    let eg = pc.eg();
+    let (form_els, form_state) = Form_::new_form("", Some(&Form_ { secrets_passphrase: None }));
+    let form_state = Rc::new(form_state);
+    return build_form(
+        //. .
+        pc,
+        format!("New account export"),
+        Ministate::Settings,
+        form_els.error.unwrap(),
+        form_els.elements,
+        async move |_idem| {
+            let Ok(new_values) = form_state.parse() else {
+                return Ok(());
+            };
+            req_post_json(
+                &state().env.base_url,
+                c2s::AccountExportCreate {
+                    secrets_passphrase: new_values.secrets_passphrase.filter(|p| !p.is_empty()),
+                },
+            ).await?;
+            eg.event(|pc| {
+                goto_replace_ministate(pc, &state().log, &Ministate::Settings);
+            }).unwrap();
+            return Ok(());
+        },
+    );
+}
@@ -45,6 +45,16 @@ pub fn build(pc: &mut ProcessingContext, s: &MinistateChannelSub) -> El {
                         link: ministate_octothorpe(&Ministate::ChannelMembers(local.id.clone())),
                         image: None,
                     }).root);
+                    children.push(style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+                        text: format!("Webhooks"),
+                        link: ministate_octothorpe(&Ministate::ChannelWebhooks(local.id.clone())),
+                        image: None,
+                    }).root);
+                    children.push(style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+                        text: format!("Outgoing webhooks"),
+                        link: ministate_octothorpe(&Ministate::ChannelOutgoingWebhooks(local.id.clone())),
+                        image: None,
+                    }).root);
                 }
                 children.push(style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
                     text: format!("Delete"),
@@ -0,0 +1,77 @@
+use {
+    crate::{
+        api::req_post_json,
+        js::{
+            el_async,
+            style_export,
+        },
+        state::{
+            ministate_octothorpe,
+            state,
+            Ministate,
+            MinistateChannelOutgoingWebhook,
+        },
+    },
+    flowcontrol::ta_return,
+    rooting::El,
+    shared::interface::wire::c2s::{
+        self,
+        OutgoingWebhookDeliveryStatus,
+    },
+};
+
+pub fn build(s: &MinistateChannelOutgoingWebhook) -> El {
+    return style_export::cont_page_menu(style_export::ContPageMenuArgs {
+        head_bar: style_export::cont_nonchat_head_bar(style_export::ContNonchatHeadBarArgs {
+            back_link: ministate_octothorpe(&Ministate::ChannelOutgoingWebhooks(s.channel.clone())),
+            center: style_export::leaf_nonchat_head_bar_center(style_export::LeafNonchatHeadBarCenterArgs {
+                text: format!("Delivery log"),
+                link: None,
+            }).root,
+            right: None,
+        }).root,
+        children: vec![el_async({
+            let id = s.webhook;
+            async move {
+                ta_return!(Vec < El >, String);
+                let deliveries = req_post_json(&state().env.base_url, c2s::OutgoingWebhookDeliveryList { id: id }).await?;
+                if deliveries.is_empty() {
+                    return Ok(
+                        vec![style_export::leaf_form_text(style_export::LeafFormTextArgs { text: format!("No deliveries yet") }).root],
+                    );
+                }
+                let mut out = vec![];
+                for d in deliveries {
+                    let mut text = format!(
+                        "{} - {}\n{}",
+                        d.event,
+                        d.created.strftime("%Y-%m-%d %H:%M:%S"),
+                        match d.status {
+                            OutgoingWebhookDeliveryStatus::Delivered => format!("Delivered"),
+                            OutgoingWebhookDeliveryStatus::Failed => format!("Failed after {} attempts", d.attempts),
+                            OutgoingWebhookDeliveryStatus::Pending => match d.next_attempt {
+                                Some(t) => format!(
+                                    "Pending, {} attempts so far, next at {}",
+                                    d.attempts,
+                                    t.strftime("%Y-%m-%d %H:%M:%S")
+                                ),
+                                None => format!("Pending"),
+                            },
+                        }
+                    );
+                    if let Some(e) = d.last_error {
+                        text = format!("{}\nLast error: {}", text, e);
+                    }
+                    out.push(
+                        style_export::cont_group(
+                            style_export::ContGroupArgs {
+                                children: vec![style_export::leaf_form_text(style_export::LeafFormTextArgs { text: text }).root],
+                            },
+                        ).root,
+                    );
+                }
+                return Ok(out);
+            }
+        })],
+    }).root;
+}
@@ -0,0 +1,78 @@
+use {
+    crate::{
+        api::req_post_json,
+        pageutil::build_form,
+        state::{
+            Ministate,
+            goto_replace_ministate,
+            state,
+        },
+    },
+    lunk::ProcessingContext,
+    rooting::El,
+    rooting_forms::Form,
+    shared::interface::{
+        shared::QualifiedChannelId,
+        wire::c2s::{
+            self,
+            OutgoingWebhookEvents,
+        },
+    },
+    std::rc::Rc,
+};
+
+#[derive(rooting_forms::Form)]
+struct Form_ {
+    #[title("Short memo")]
+    memo_short: String,
+    #[title("URL")]
+    url: String,
+    #[title("New messages")]
+    message_new: bool,
+    #[title("Edited messages")]
+    message_edited: bool,
+    #[title("Deleted messages")]
+    message_deleted: bool,
+}
+
+pub fn build(pc: &mut ProcessingContext, channel: &QualifiedChannelId) -> El {
+    let eg = pc.eg();
+    let (form_els, form_state) = Form_::new_form("", Some(&Form_ {
+        memo_short: String::new(),
+        url: String::new(),
+        message_new: true,
+        message_edited: false,
+        message_deleted: false,
+    }));
+    let form_state = Rc::new(form_state);
+    return build_form(
+        //. .
+        pc,
+        format!("New outgoing webhook"),
+        Ministate::ChannelOutgoingWebhooks(channel.clone()),
+        form_els.error.unwrap(),
+        form_els.elements,
+        {
+            let channel = channel.clone();
+            async move |_idem| {
+                let Ok(new_values) = form_state.parse() else {
+                    return Ok(());
+                };
+                req_post_json(&state().env.base_url, c2s::OutgoingWebhookCreate {
+                    channel: channel.clone(),
+                    url: new_values.url,
+                    memo_short: new_values.memo_short,
+                    events: OutgoingWebhookEvents {
+                        message_new: new_values.message_new,
+                        message_edited: new_values.message_edited,
+                        message_deleted: new_values.message_deleted,
+                    },
+                }).await?;
+                eg.event(|pc| {
+                    goto_replace_ministate(pc, &state().log, &Ministate::ChannelOutgoingWebhooks(channel.clone()));
+                }).unwrap();
+                return Ok(());
+            }
+        },
+    );
+}
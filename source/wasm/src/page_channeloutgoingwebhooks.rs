@@ -0,0 +1,120 @@
+use {
+    crate::{
+        api::req_post_json,
+        js::{
+            configure_async_button_once,
+            el_async,
+            style_export,
+        },
+        state::{
+            ministate_octothorpe,
+            state,
+            Ministate,
+            MinistateChannelOutgoingWebhook,
+            MinistateChannelSub,
+        },
+    },
+    flowcontrol::ta_return,
+    rooting::El,
+    shared::interface::{
+        shared::QualifiedChannelId,
+        wire::c2s::{
+            self,
+            OutgoingWebhookRes,
+        },
+    },
+};
+
+fn build_action_button(text: &str, done_text: String, action: impl 'static + Clone + AsyncFnOnce() -> Result<(), String>) -> El {
+    let button = style_export::leaf_menu_button(style_export::LeafMenuButtonArgs { text: text.to_string() }).root;
+    configure_async_button_once(&button, {
+        let button = button.weak();
+        async move || {
+            let replacement = match action().await {
+                Ok(_) => style_export::leaf_form_text(style_export::LeafFormTextArgs { text: done_text }).root,
+                Err(e) => style_export::leaf_err_block(style_export::LeafErrBlockArgs { data: e }).root,
+            };
+            let Some(button) = button.upgrade() else {
+                return;
+            };
+            button.ref_replace(vec![replacement]);
+        }
+    });
+    return button;
+}
+
+fn build_webhook(channel: &QualifiedChannelId, hook: OutgoingWebhookRes) -> El {
+    let mut events = vec![];
+    if hook.events.message_new {
+        events.push("new");
+    }
+    if hook.events.message_edited {
+        events.push("edited");
+    }
+    if hook.events.message_deleted {
+        events.push("deleted");
+    }
+    let id = hook.id;
+    return style_export::cont_group(style_export::ContGroupArgs { children: vec![
+        //. .
+        style_export::leaf_form_text(style_export::LeafFormTextArgs { text: format!(
+            "{}\n{}\nEvents: {}",
+            hook.memo_short,
+            hook.url,
+            if events.is_empty() {
+                format!("none")
+            } else {
+                events.join(", ")
+            }
+        ) }).root,
+        style_export::leaf_form_text(style_export::LeafFormTextArgs { text: format!("Signing secret") }).root,
+        style_export::leaf_menu_code(style_export::LeafMenuCodeArgs { text: hook.secret }).root,
+        style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+            text: format!("Delivery log"),
+            link: ministate_octothorpe(&Ministate::ChannelOutgoingWebhookLog(MinistateChannelOutgoingWebhook {
+                channel: channel.clone(),
+                webhook: id,
+            })),
+            image: None,
+        }).root,
+        build_action_button("Send test event", format!("Test event queued"), async move || {
+            req_post_json(&state().env.base_url, c2s::OutgoingWebhookTest { id: id }).await?;
+            return Ok(());
+        }),
+        build_action_button("Delete", format!("Deleted"), async move || {
+            req_post_json(&state().env.base_url, c2s::OutgoingWebhookDelete { id: id }).await?;
+            return Ok(());
+        })
+    ] }).root;
+}
+
+pub fn build(channel: &QualifiedChannelId) -> El {
+    return style_export::cont_page_menu(style_export::ContPageMenuArgs {
+        head_bar: style_export::cont_nonchat_head_bar(style_export::ContNonchatHeadBarArgs {
+            back_link: ministate_octothorpe(&Ministate::ChannelMenu(MinistateChannelSub {
+                id: channel.clone(),
+                own_identity: channel.identity.clone(),
+            })),
+            center: style_export::leaf_nonchat_head_bar_center(style_export::LeafNonchatHeadBarCenterArgs {
+                text: format!("Outgoing webhooks"),
+                link: None,
+            }).root,
+            right: Some(
+                style_export::leaf_menu_head_bar_right_add(
+                    style_export::LeafMenuHeadBarRightAddArgs {
+                        link: ministate_octothorpe(&Ministate::ChannelOutgoingWebhookNew(channel.clone())),
+                    },
+                ).root,
+            ),
+        }).root,
+        children: vec![el_async({
+            let channel = channel.clone();
+            async move {
+                ta_return!(Vec < El >, String);
+                let hooks =
+                    req_post_json(&state().env.base_url, c2s::OutgoingWebhookList { channel: channel.clone() }).await?;
+                return Ok(hooks.into_iter().map(|h| build_webhook(&channel, h)).collect());
+            }
+        })],
+    }).root;
+}
@@ -0,0 +1,92 @@
+use {
+    crate::{
+        js::style_export,
+        localdata::get_or_req_api_channelwebhook,
+        pageutil::{
+            LazyPage,
+            build_nol_menu,
+        },
+        state::{
+            Ministate,
+            MinistateChannelWebhook,
+            ministate_octothorpe,
+            state,
+        },
+    },
+    lunk::ProcessingContext,
+    rooting::El,
+    shared::interface::{
+        shared::{
+            ChannelWebhookId,
+            ChannelWebhookToken,
+            QualifiedChannelId,
+        },
+        wire::c2s::C2SV1_PREFIX,
+    },
+    std::{
+        cell::RefCell,
+        collections::HashMap,
+    },
+};
+
+thread_local!{
+    /// Tokens of webhooks created in this session. The server only keeps the hash,
+    /// so this is the only place the url can be shown from.
+    static NEW_TOKENS: RefCell<HashMap<ChannelWebhookId, ChannelWebhookToken>> = RefCell::new(HashMap::new());
+}
+
+pub fn remember_new_token(id: ChannelWebhookId, token: ChannelWebhookToken) {
+    NEW_TOKENS.with(|x| x.borrow_mut().insert(id, token));
+}
+
+pub fn build(pc: &mut ProcessingContext, channel: &QualifiedChannelId, id: &ChannelWebhookId) -> El {
+    return build_nol_menu(
+        //. .
+        pc,
+        &Ministate::ChannelWebhooks(channel.clone()),
+        get_or_req_api_channelwebhook(id, true),
+        {
+            let channel = channel.clone();
+            move |local| {
+                let url_text;
+                if let Some(token) = NEW_TOKENS.with(|x| x.borrow().get(&local.res.id).cloned()) {
+                    url_text = style_export::leaf_menu_code(style_export::LeafMenuCodeArgs {
+                        text: format!("{}{}webhook/{}", state().env.base_url, C2SV1_PREFIX, token.0),
+                    }).root;
+                } else {
+                    url_text = style_export::leaf_form_text(style_export::LeafFormTextArgs {
+                        text: format!(
+                            "The webhook url is only shown when the webhook is created. Delete and recreate the webhook if it was lost."
+                        ),
+                    }).root;
+                }
+                return LazyPage {
+                    center: style_export::leaf_nonchat_head_bar_center(style_export::LeafNonchatHeadBarCenterArgs {
+                        text: local.res.memo_short.clone(),
+                        link: None,
+                    }).root,
+                    body: vec![
+                        //. .
+                        url_text,
+                        style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+                            text: format!("Edit"),
+                            link: ministate_octothorpe(&Ministate::ChannelWebhookEdit(MinistateChannelWebhook {
+                                channel: channel.clone(),
+                                webhook: local.res.id.clone(),
+                            })),
+                            image: None,
+                        }).root,
+                        style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+                            text: format!("Delete"),
+                            link: ministate_octothorpe(&Ministate::ChannelWebhookDelete(MinistateChannelWebhook {
+                                channel: channel.clone(),
+                                webhook: local.res.id.clone(),
+                            })),
+                            image: None,
+                        }).root,
+                    ],
+                };
+            }
+        },
+    );
+}
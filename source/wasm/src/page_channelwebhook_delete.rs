@@ -0,0 +1,71 @@
+use {
+    crate::{
+        api::req_post_json,
+        js::style_export,
+        localdata::{
+            self,
+            get_or_req_api_channelwebhook,
+        },
+        pageutil::build_nol_form,
+        state::{
+            goto_replace_ministate,
+            state,
+            Ministate,
+            MinistateChannelWebhook,
+        },
+    },
+    lunk::ProcessingContext,
+    rooting::{
+        el,
+        El,
+    },
+    shared::interface::{
+        shared::{
+            ChannelWebhookId,
+            QualifiedChannelId,
+        },
+        wire::c2s::{
+            self,
+        },
+    },
+};
+
+pub fn build(pc: &mut ProcessingContext, channel: &QualifiedChannelId, id: &ChannelWebhookId) -> El {
+    return build_nol_form(
+        //. .
+        pc,
+        &Ministate::ChannelWebhook(MinistateChannelWebhook {
+            channel: channel.clone(),
+            webhook: id.clone(),
+        }),
+        "Delete webhook",
+        get_or_req_api_channelwebhook(id, false).map({
+            let eg = pc.eg();
+            move |local| (
+                el("div"),
+                vec![
+                    style_export::leaf_form_text(
+                        style_export::LeafFormTextArgs {
+                            text: format!("Are you sure you want to delete webhook [{}]", local.res.memo_short),
+                        },
+                    ).root
+                ],
+                async move |_idem| {
+                    req_post_json(
+                        &state().env.base_url,
+                        c2s::ChannelWebhookDelete { id: local.res.id.clone() },
+                    ).await?;
+                    localdata::delete_channelwebhook(local.res.clone()).await;
+                    eg.event(|pc| {
+                        goto_replace_ministate(
+                            pc,
+                            &state().log,
+                            &Ministate::ChannelWebhooks(local.res.channel.clone()),
+                        );
+                    }).unwrap();
+                    return Ok(());
+                },
+            )
+        }),
+    );
+}
@@ -0,0 +1,104 @@
+use {
+    crate::{
+        api::req_post_json,
+        localdata::{
+            self,
+            get_or_req_api_channelwebhook,
+        },
+        pageutil::build_nol_form,
+        state::{
+            goto_replace_ministate,
+            state,
+            Ministate,
+            MinistateChannelWebhook,
+        },
+    },
+    jiff::Timestamp,
+    lunk::ProcessingContext,
+    rooting::El,
+    rooting_forms::Form,
+    shared::interface::{
+        shared::{
+            ChannelWebhookId,
+            QualifiedChannelId,
+        },
+        wire::c2s::{
+            self,
+            ModifyOption,
+        },
+    },
+    std::rc::Rc,
+};
+
+#[derive(rooting_forms::Form)]
+struct Form_ {
+    #[title("Short memo")]
+    memo_short: String,
+    #[title("Extra memo")]
+    memo_long: String,
+    #[title("Expiry")]
+    expiry: Option<Timestamp>,
+    #[title("Max messages per minute")]
+    max_per_minute: u32,
+}
+
+pub fn build(pc: &mut ProcessingContext, channel: &QualifiedChannelId, id: &ChannelWebhookId) -> El {
+    return build_nol_form(
+        //. .
+        pc,
+        &Ministate::ChannelWebhook(MinistateChannelWebhook {
+            channel: channel.clone(),
+            webhook: id.clone(),
+        }),
+        "Edit webhook",
+        get_or_req_api_channelwebhook(id, true).map({
+            let eg = pc.eg();
+            let channel = channel.clone();
+            move |local| {
+                let (form_els, form_state) = Form_::new_form("", Some(&Form_ {
+                    memo_short: local.res.memo_short.clone(),
+                    memo_long: local.res.memo_long.clone(),
+                    expiry: local.res.expiry.clone(),
+                    max_per_minute: local.res.max_per_minute,
+                }));
+                let form_state = Rc::new(form_state);
+                return (form_els.error.unwrap(), form_els.elements, async move |_idem| {
+                    let Ok(new_values) = form_state.parse() else {
+                        return Ok(());
+                    };
+                    let res = req_post_json(&state().env.base_url, c2s::ChannelWebhookModify {
+                        id: local.res.id.clone(),
+                        memo_short: if new_values.memo_short == local.res.memo_short {
+                            None
+                        } else {
+                            Some(new_values.memo_short)
+                        },
+                        memo_long: if new_values.memo_long == local.res.memo_long {
+                            None
+                        } else {
+                            Some(new_values.memo_long)
+                        },
+                        expiry: if new_values.expiry == local.res.expiry {
+                            None
+                        } else {
+                            Some(ModifyOption { value: new_values.expiry })
+                        },
+                        max_per_minute: if new_values.max_per_minute == local.res.max_per_minute {
+                            None
+                        } else {
+                            Some(new_values.max_per_minute)
+                        },
+                    }).await?;
+                    localdata::ensure_channelwebhook(res.clone()).await;
+                    eg.event(|pc| {
+                        goto_replace_ministate(pc, &state().log, &Ministate::ChannelWebhook(MinistateChannelWebhook {
+                            channel: channel.clone(),
+                            webhook: res.id,
+                        }));
+                    }).unwrap();
+                    return Ok(());
+                });
+            }
+        }),
+    );
+}
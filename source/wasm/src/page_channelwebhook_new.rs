@@ -0,0 +1,83 @@
+use {
+    crate::{
+        api::req_post_json,
+        localdata::{
+            self,
+        },
+        page_channelwebhook,
+        pageutil::build_form,
+        state::{
+            Ministate,
+            MinistateChannelWebhook,
+            goto_replace_ministate,
+            state,
+        },
+    },
+    jiff::Timestamp,
+    lunk::ProcessingContext,
+    rooting::El,
+    rooting_forms::Form,
+    shared::interface::{
+        shared::QualifiedChannelId,
+        wire::c2s::{
+            self,
+        },
+    },
+    std::rc::Rc,
+};
+
+#[derive(rooting_forms::Form)]
+struct Form_ {
+    #[title("Short memo")]
+    memo_short: String,
+    #[title("Extra memo")]
+    memo_long: String,
+    #[title("Expiry")]
+    expiry: Option<Timestamp>,
+    #[title("Max messages per minute")]
+    max_per_minute: u32,
+}
+
+pub fn build(pc: &mut ProcessingContext, channel: &QualifiedChannelId) -> El {
+    let eg = pc.eg();
+    let (form_els, form_state) = Form_::new_form("", Some(&Form_ {
+        memo_short: String::new(),
+        memo_long: String::new(),
+        expiry: None,
+        max_per_minute: 30,
+    }));
+    let form_state = Rc::new(form_state);
+    return build_form(
+        //. .
+        pc,
+        format!("New webhook"),
+        Ministate::ChannelWebhooks(channel.clone()),
+        form_els.error.unwrap(),
+        form_els.elements,
+        {
+            let channel = channel.clone();
+            async move |_idem| {
+                let Ok(new_values) = form_state.parse() else {
+                    return Ok(());
+                };
+                let res = req_post_json(&state().env.base_url, c2s::ChannelWebhookCreate {
+                    channel: channel.clone(),
+                    identity: channel.identity.clone(),
+                    memo_short: new_values.memo_short,
+                    memo_long: new_values.memo_long,
+                    expiry: new_values.expiry,
+                    max_per_minute: new_values.max_per_minute,
+                }).await?;
+                page_channelwebhook::remember_new_token(res.res.id, res.token);
+                localdata::ensure_channelwebhook(res.res.clone()).await;
+                eg.event(|pc| {
+                    goto_replace_ministate(pc, &state().log, &Ministate::ChannelWebhook(MinistateChannelWebhook {
+                        channel: channel.clone(),
+                        webhook: res.res.id,
+                    }));
+                }).unwrap();
+                return Ok(());
+            }
+        },
+    );
+}
@@ -0,0 +1,138 @@
+use {
+    crate::{
+        js::{
+            el_async,
+            style_export,
+        },
+        localdata::{
+            get_stored_api_channelwebhooks,
+            req_api_channelwebhooks,
+        },
+        state::{
+            ministate_octothorpe,
+            state,
+            Ministate,
+            MinistateChannelSub,
+            MinistateChannelWebhook,
+        },
+    },
+    flowcontrol::ta_return,
+    rooting::{
+        spawn_rooted,
+        El,
+    },
+    shared::interface::shared::{
+        QualifiedChannelId,
+    },
+    std::{
+        cell::RefCell,
+        collections::HashMap,
+        rc::Rc,
+    },
+};
+
+pub fn build(channel: &QualifiedChannelId) -> El {
+    let webhook_elements = style_export::cont_group(style_export::ContGroupArgs { children: vec![] }).root;
+    let old_webhooks =
+        get_stored_api_channelwebhooks(None)
+            .into_iter()
+            .filter(|x| x.res.channel == *channel)
+            .collect::<Vec<_>>();
+    let lookup_el_webhooks = Rc::new(RefCell::new(HashMap::new()));
+
+    // Build the immediately available options
+    for old_webhook in old_webhooks.clone() {
+        let out = style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+            text: old_webhook.res.memo_short.clone(),
+            link: ministate_octothorpe(&Ministate::ChannelWebhook(MinistateChannelWebhook {
+                channel: channel.clone(),
+                webhook: old_webhook.res.id,
+            })),
+            image: None,
+        });
+        lookup_el_webhooks.borrow_mut().insert(old_webhook.res.id, out.root.clone());
+        webhook_elements.ref_push(out.root);
+    }
+
+    // Pull new elements in the background
+    let start_empty = old_webhooks.is_empty();
+    let bg_refresh = {
+        let old_webhooks1 = old_webhooks;
+        let lookup_el_webhooks = lookup_el_webhooks.clone();
+        let channel = channel.clone();
+        async move {
+            ta_return!(Vec < El >, String);
+            let new_webhooks =
+                req_api_channelwebhooks(None)
+                    .await?
+                    .into_iter()
+                    .filter(|x| x.res.channel == channel)
+                    .collect::<Vec<_>>();
+
+            // Diff level 1 webhooks
+            let mut new_els1 = vec![];
+            {
+                let mut old_webhooks = HashMap::new();
+                for old_webhook in old_webhooks1 {
+                    old_webhooks.insert(old_webhook.res.id, old_webhook);
+                }
+                for new_webhook in new_webhooks {
+                    if let Some(_) = old_webhooks.remove(&new_webhook.res.id) {
+                        // nop
+                    } else {
+                        let next_el1 = style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+                            text: new_webhook.res.memo_short,
+                            link: ministate_octothorpe(&Ministate::ChannelWebhook(MinistateChannelWebhook {
+                                channel: channel.clone(),
+                                webhook: new_webhook.res.id,
+                            })),
+                            image: None,
+                        });
+                        new_els1.push(next_el1.root);
+                    }
+                }
+                for (id, _) in old_webhooks {
+                    let Some(channel_el) = lookup_el_webhooks.borrow_mut().remove(&id) else {
+                        continue;
+                    };
+                    channel_el.ref_replace(vec![]);
+                }
+            }
+            return Ok(new_els1);
+        }
+    };
+    if start_empty {
+        webhook_elements.ref_push(el_async(bg_refresh));
+    } else {
+        webhook_elements.ref_own(move |_| spawn_rooted(async move {
+            if let Err(e) = bg_refresh.await {
+                state().log.log(&format!("Refreshing channel webhooks failed: {}", e));
+            }
+        }));
+    }
+
+    // Other widgets, assemble and return
+    let out = style_export::cont_page_menu(style_export::ContPageMenuArgs {
+        head_bar: style_export::cont_nonchat_head_bar(style_export::ContNonchatHeadBarArgs {
+            back_link: ministate_octothorpe(&Ministate::ChannelMenu(MinistateChannelSub {
+                id: channel.clone(),
+                own_identity: channel.identity.clone(),
+            })),
+            center: style_export::leaf_nonchat_head_bar_center(style_export::LeafNonchatHeadBarCenterArgs {
+                text: format!("Webhooks"),
+                link: None,
+            }).root,
+            right: Some(
+                style_export::leaf_menu_head_bar_right_add(
+                    style_export::LeafMenuHeadBarRightAddArgs {
+                        link: ministate_octothorpe(&Ministate::ChannelWebhookNew(channel.clone())),
+                    },
+                ).root,
+            ),
+        }).root,
+        children: vec![webhook_elements],
+    });
+
+    // Assemble and return
+    return out.root;
+}
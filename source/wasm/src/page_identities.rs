@@ -215,7 +215,15 @@ pub fn build(_pc: &mut ProcessingContext) -> El {
                 ).root,
             ),
         }).root,
-        children: vec![identity_elements],
+        children: vec![
+            //. .
+            identity_elements,
+            style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+                text: format!("Import identity"),
+                link: ministate_octothorpe(&Ministate::IdentitiesImport),
+                image: None,
+            }).root
+        ],
     });
 
     // Assemble and return
@@ -39,6 +39,11 @@ pub fn build(pc: &mut ProcessingContext, identity: &Identity) -> El {
                     link: ministate_octothorpe(&Ministate::IdentityInvites(local.res.id.clone())),
                     image: None,
                 }).root,
+                style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+                    text: format!("Export (move to another server)"),
+                    link: ministate_octothorpe(&Ministate::IdentityExport(local.res.id.clone())),
+                    image: None,
+                }).root,
                 style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
                     text: format!("Delete"),
                     link: ministate_octothorpe(&Ministate::IdentityDelete(local.res.id.clone())),
@@ -0,0 +1,73 @@
+use {
+    crate::{
+        api::req_post_json,
+        js,
+        localdata,
+        pageutil::build_form,
+        state::{
+            Ministate,
+            goto_replace_ministate,
+            state,
+        },
+    },
+    lunk::ProcessingContext,
+    rooting::El,
+    rooting_forms::Form,
+    shared::interface::wire::c2s,
+    spaghettinuum::interface::identity::Identity,
+    std::rc::Rc,
+};
+
+#[derive(rooting_forms::Form)]
+struct Form_ {
+    #[title("Passphrase")]
+    passphrase: String,
+    #[title("Delete from this server")]
+    withdraw: bool,
+}
+
+pub fn build(pc: &mut ProcessingContext, identity: &Identity) -> El {
+    let eg = pc.eg();
+    let (form_els, form_state) = Form_::new_form("", Some(&Form_ {
+        passphrase: String::new(),
+        withdraw: true,
+    }));
+    let form_state = Rc::new(form_state);
+    return build_form(
+        //. .
+        pc,
+        format!("Export identity"),
+        Ministate::Identity(identity.clone()),
+        form_els.error.unwrap(),
+        form_els.elements,
+        {
+            let identity = identity.clone();
+            async move |_idem| {
+                let Ok(new_values) = form_state.parse() else {
+                    return Ok(());
+                };
+                if new_values.passphrase.is_empty() {
+                    return Err(format!("A passphrase is required to protect the identity secret"));
+                }
+                let withdraw = new_values.withdraw;
+                let res = req_post_json(&state().env.base_url, c2s::IdentityExport {
+                    id: identity.clone(),
+                    passphrase: new_values.passphrase,
+                    withdraw: withdraw,
+                }).await?;
+                js::download_bytes(format!("kwa-identity-{}.json", identity), "application/json", res.bundle.as_bytes());
+                if withdraw {
+                    localdata::req_api_identities(None).await?;
+                }
+                eg.event(|pc| {
+                    goto_replace_ministate(pc, &state().log, &if withdraw {
+                        Ministate::Identities
+                    } else {
+                        Ministate::Identity(identity.clone())
+                    });
+                }).unwrap();
+                return Ok(());
+            }
+        },
+    );
+}
@@ -0,0 +1,54 @@
+use {
+    crate::{
+        api::req_post_json,
+        localdata,
+        pageutil::build_form,
+        state::{
+            Ministate,
+            goto_replace_ministate,
+            state,
+        },
+    },
+    lunk::ProcessingContext,
+    rooting::El,
+    rooting_forms::Form,
+    shared::interface::wire::c2s,
+    std::rc::Rc,
+};
+
+#[derive(rooting_forms::Form)]
+struct Form_ {
+    #[title("Identity bundle (file contents)")]
+    bundle: String,
+    #[title("Passphrase")]
+    passphrase: String,
+}
+
+pub fn build(pc: &mut ProcessingContext) -> El {
+    let eg = pc.eg();
+    let (form_els, form_state) = Form_::new_form("", None);
+    let form_state = Rc::new(form_state);
+    return build_form(
+        //. .
+        pc,
+        format!("Import identity"),
+        Ministate::Identities,
+        form_els.error.unwrap(),
+        form_els.elements,
+        async move |idem| {
+            let Ok(new_values) = form_state.parse() else {
+                return Ok(());
+            };
+            let res = req_post_json(&state().env.base_url, c2s::IdentityImport {
+                idem: Some(idem.to_string()),
+                bundle: new_values.bundle.trim().to_string(),
+                passphrase: new_values.passphrase,
+            }).await?;
+            localdata::ensure_identity(res.clone()).await;
+            eg.event(|pc| {
+                goto_replace_ministate(pc, &state().log, &Ministate::Identity(res.id));
+            }).unwrap();
+            return Ok(());
+        },
+    );
+}
@@ -1,10 +1,12 @@
 use {
     crate::{
         api::{
+            req_file,
             req_get,
             req_post_json,
         },
         js::{
+            self,
             Engine,
             configure_async_button_once,
             el_async,
@@ -21,8 +23,17 @@ use {
         ta_return,
     },
     js_sys::JSON,
+    client::path_rel,
     rooting::El,
-    shared::interface::wire::c2s,
+    shared::interface::wire::c2s::{
+        self,
+        AccountExportRes,
+        AccountExportStatus,
+        PathReqTrait,
+        SessionRes,
+        SessionRevokeTarget,
+        C2SV1_PREFIX,
+    },
     wasm_bindgen::JsValue,
     wasm_bindgen_futures::JsFuture,
     web_sys::{
@@ -34,6 +45,85 @@ use {
     },
 };
 
+fn build_revoke_button(text: &str, target: SessionRevokeTarget, done_text: String) -> El {
+    let button = style_export::leaf_menu_button(style_export::LeafMenuButtonArgs { text: text.to_string() }).root;
+    configure_async_button_once(&button, {
+        let button = button.weak();
+        async move || {
+            let replacement =
+                match req_post_json(&state().env.base_url, c2s::SessionRevoke { target: target }).await {
+                    Ok(_) => style_export::leaf_form_text(style_export::LeafFormTextArgs { text: done_text }).root,
+                    Err(e) => style_export::leaf_err_block(style_export::LeafErrBlockArgs { data: e }).root,
+                };
+            let Some(button) = button.upgrade() else {
+                return;
+            };
+            button.ref_replace(vec![replacement]);
+        }
+    });
+    return button;
+}
+
+fn build_session(session: SessionRes) -> El {
+    let mut text = format!(
+        "{}\nSigned in {}\nLast active {}",
+        session.user_agent,
+        session.created.strftime("%Y-%m-%d %H:%M"),
+        session.last_seen.strftime("%Y-%m-%d %H:%M")
+    );
+    if session.current {
+        text = format!("{}\n(this device)", text);
+    }
+    let mut children = vec![style_export::leaf_form_text(style_export::LeafFormTextArgs { text: text }).root];
+    if !session.current {
+        children.push(
+            build_revoke_button("Log out", SessionRevokeTarget::Session(session.id), format!("Logged out")),
+        );
+    }
+    return style_export::cont_group(style_export::ContGroupArgs { children: children }).root;
+}
+
+fn build_account_export(export: AccountExportRes) -> El {
+    let status = match &export.status {
+        AccountExportStatus::Pending => format!("In progress"),
+        AccountExportStatus::Ready => format!("Ready, available until {}", export.expires.strftime("%Y-%m-%d %H:%M")),
+        AccountExportStatus::Failed(e) => format!("Failed: {}", e),
+    };
+    let mut text = format!("Export {}\n{}", export.created.strftime("%Y-%m-%d %H:%M"), status);
+    if export.includes_secrets {
+        text = format!("{}\n(includes identity secrets)", text);
+    }
+    let mut children = vec![style_export::leaf_form_text(style_export::LeafFormTextArgs { text: text }).root];
+    if export.status == AccountExportStatus::Ready {
+        let button = style_export::leaf_menu_button(style_export::LeafMenuButtonArgs { text: format!("Download") }).root;
+        configure_async_button_once(&button, {
+            let button = button.weak();
+            let id = export.id.clone();
+            let filename = format!("account-export-{}.tar.gz", export.created.strftime("%Y%m%d-%H%M%S"));
+            async move || {
+                let replacement = match req_file(&format!(
+                    "{}{}{}",
+                    state().env.base_url,
+                    C2SV1_PREFIX,
+                    path_rel(&c2s::AccountExportDownload { id: id }.serialize_path())
+                )).await {
+                    Ok(data) => {
+                        js::download_bytes(filename, "application/gzip", &data);
+                        style_export::leaf_form_text(style_export::LeafFormTextArgs { text: format!("Downloaded") }).root
+                    },
+                    Err(e) => style_export::leaf_err_block(style_export::LeafErrBlockArgs { data: e }).root,
+                };
+                let Some(button) = button.upgrade() else {
+                    return;
+                };
+                button.ref_replace(vec![replacement]);
+            }
+        });
+        children.push(button);
+    }
+    return style_export::cont_group(style_export::ContGroupArgs { children: children }).root;
+}
+
 pub fn build() -> El {
     return style_export::cont_page_menu(style_export::ContPageMenuArgs {
         head_bar: style_export::cont_nonchat_head_bar(style_export::ContNonchatHeadBarArgs {
@@ -191,6 +281,39 @@ pub fn build() -> El {
 
                 return Ok(vec![build_notification_button(pm).await]);
             }),
+            el_async(async move {
+                ta_return!(Vec < El >, String);
+                let sessions = req_post_json(&state().env.base_url, c2s::SessionList).await?;
+                let mut out = vec![];
+                let has_others = sessions.iter().any(|s| !s.current);
+                for session in sessions {
+                    out.push(build_session(session));
+                }
+                if has_others {
+                    out.push(
+                        build_revoke_button(
+                            "Log out all other devices",
+                            SessionRevokeTarget::AllOthers,
+                            format!("Logged out all other devices"),
+                        ),
+                    );
+                }
+                return Ok(out);
+            }),
+            el_async(async move {
+                ta_return!(Vec < El >, String);
+                let exports = req_post_json(&state().env.base_url, c2s::AccountExportList).await?;
+                let mut out = vec![];
+                for export in exports {
+                    out.push(build_account_export(export));
+                }
+                out.push(style_export::leaf_menu_link(style_export::LeafMenuLinkArgs {
+                    text: format!("Export account data"),
+                    link: ministate_octothorpe(&Ministate::AccountExportNew),
+                    image: None,
+                }).root);
+                return Ok(out);
+            }),
         ],
     }).root;
 }
@@ -0,0 +1,46 @@
+use {
+    crate::{
+        js::{
+            style_export,
+            LogJsErr,
+        },
+        state::{
+            ministate_octothorpe,
+            state,
+            Ministate,
+        },
+    },
+    gloo::utils::window,
+    rooting::El,
+    shared::interface::wire::c2s::C2S_VERSION,
+};
+
+/// Shown in place of everything else when the server doesn't speak this client's
+/// protocol version, usually because an old client was served from cache.
+pub fn build(server_versions: &[u32]) -> El {
+    return style_export::cont_page_menu(style_export::ContPageMenuArgs {
+        head_bar: style_export::cont_nonchat_head_bar(style_export::ContNonchatHeadBarArgs {
+            back_link: ministate_octothorpe(&Ministate::Top),
+            center: style_export::leaf_nonchat_head_bar_center(style_export::LeafNonchatHeadBarCenterArgs {
+                text: format!("Update required"),
+                link: None,
+            }).root,
+            right: None,
+        }).root,
+        children: vec![
+            //. .
+            style_export::leaf_form_text(style_export::LeafFormTextArgs {
+                text: format!(
+                    "The server has been updated and no longer supports this version of the app (protocol version {}, server supports {}). Reload to get the new version.",
+                    C2S_VERSION,
+                    server_versions.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+                ),
+            }).root,
+            style_export::leaf_menu_button(style_export::LeafMenuButtonArgs { text: format!("Reload") })
+                .root
+                .on("click", |_| {
+                    window().location().reload().log(&state().log, &"Error reloading for update");
+                }),
+        ],
+    }).root;
+}
@@ -22,6 +22,7 @@ use {
             req_api_channelgroups,
             req_api_channels,
         },
+        page_account_export_new,
         page_channel,
         page_channel_delete,
         page_channel_edit,
@@ -42,10 +43,20 @@ use {
         page_channelmember_delete,
         page_channelmember_edit,
         page_channelmembers,
+        page_channeloutgoingwebhook_log,
+        page_channeloutgoingwebhook_new,
+        page_channeloutgoingwebhooks,
+        page_channelwebhook,
+        page_channelwebhook_delete,
+        page_channelwebhook_edit,
+        page_channelwebhook_new,
+        page_channelwebhooks,
         page_identities,
         page_identity,
         page_identity_delete,
         page_identity_edit,
+        page_identity_export,
+        page_identity_import,
         page_identity_new,
         page_identityinvite,
         page_identityinvite_delete,
@@ -54,6 +65,7 @@ use {
         page_identityinvites,
         page_settings,
         page_top_add,
+        page_update_required,
     },
     flowcontrol::{
         shed,
@@ -88,6 +100,8 @@ use {
         shared::{
             ChannelGroupId,
             ChannelInviteId,
+            ChannelWebhookId,
+            OutgoingWebhookId,
             IdentityInviteId,
             QualifiedChannelId,
             QualifiedMessageId,
@@ -124,6 +138,20 @@ pub struct MinistateChannelInvite {
     pub invite: ChannelInviteId,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MinistateChannelWebhook {
+    pub channel: QualifiedChannelId,
+    pub webhook: ChannelWebhookId,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MinistateChannelOutgoingWebhook {
+    pub channel: QualifiedChannelId,
+    pub webhook: OutgoingWebhookId,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct MinistateIdentityInvite {
@@ -172,15 +200,18 @@ pub struct MinistateChannelGroup {
 pub enum Ministate {
     Top,
     Settings,
+    AccountExportNew,
     TopAdd,
     ChannelJoinUrl,
     ChannelNew,
     ChannelGroupNew,
     Identities,
     IdentitiesNew,
+    IdentitiesImport,
     Identity(Identity),
     IdentityEdit(Identity),
     IdentityDelete(Identity),
+    IdentityExport(Identity),
     IdentityInvites(Identity),
     IdentityInviteNew(Identity),
     IdentityInvite(MinistateIdentityInvite),
@@ -199,6 +230,14 @@ pub enum Ministate {
     ChannelInvite(MinistateChannelInvite),
     ChannelInviteEdit(MinistateChannelInvite),
     ChannelInviteDelete(MinistateChannelInvite),
+    ChannelWebhooks(QualifiedChannelId),
+    ChannelWebhookNew(QualifiedChannelId),
+    ChannelWebhook(MinistateChannelWebhook),
+    ChannelWebhookEdit(MinistateChannelWebhook),
+    ChannelWebhookDelete(MinistateChannelWebhook),
+    ChannelOutgoingWebhooks(QualifiedChannelId),
+    ChannelOutgoingWebhookNew(QualifiedChannelId),
+    ChannelOutgoingWebhookLog(MinistateChannelOutgoingWebhook),
     ChannelGroup(MinistateChannelGroup),
     ChannelGroupMenu(ChannelGroupId),
     ChannelGroupEdit(ChannelGroupId),
@@ -303,6 +342,9 @@ pub struct State_ {
     pub bg_pushing: RefCell<Option<oneshot::Receiver<()>>>,
     pub bg_pulling_interval: RefCell<Option<Interval>>,
     pub bg_pulling: RefCell<Option<oneshot::Receiver<()>>>,
+    /// Set to the versions the server supports if it doesn't support ours, in which
+    /// case every page is replaced with an update prompt.
+    pub update_required: RefCell<Option<Vec<u32>>>,
 }
 
 thread_local!{
@@ -320,6 +362,10 @@ pub fn set_page(body: El) {
 }
 
 pub fn build_ministate(pc: &mut ProcessingContext, s: &Ministate) {
+    if let Some(versions) = &*state().update_required.borrow() {
+        set_page(page_update_required::build(versions));
+        return;
+    }
     let body;
     match s {
         Ministate::Top => {
@@ -328,6 +374,9 @@ pub fn build_ministate(pc: &mut ProcessingContext, s: &Ministate) {
         Ministate::Settings => {
             body = page_settings::build();
         },
+        Ministate::AccountExportNew => {
+            body = page_account_export_new::build(pc);
+        },
         Ministate::TopAdd => {
             body = page_top_add::build();
         },
@@ -337,6 +386,9 @@ pub fn build_ministate(pc: &mut ProcessingContext, s: &Ministate) {
         Ministate::IdentitiesNew => {
             body = page_identity_new::build(pc);
         },
+        Ministate::IdentitiesImport => {
+            body = page_identity_import::build(pc);
+        },
         Ministate::Identity(id) => {
             body = page_identity::build(pc, id);
         },
@@ -346,6 +398,9 @@ pub fn build_ministate(pc: &mut ProcessingContext, s: &Ministate) {
         Ministate::IdentityDelete(id) => {
             body = page_identity_delete::build(pc, id);
         },
+        Ministate::IdentityExport(id) => {
+            body = page_identity_export::build(pc, id);
+        },
         Ministate::IdentityInvites(id) => {
             body = page_identityinvites::build(pc, id);
         },
@@ -406,6 +461,30 @@ pub fn build_ministate(pc: &mut ProcessingContext, s: &Ministate) {
         Ministate::ChannelInviteDelete(s) => {
             body = page_channelinvite_delete::build(pc, &s.channel, &s.invite);
         },
+        Ministate::ChannelWebhooks(s) => {
+            body = page_channelwebhooks::build(s);
+        },
+        Ministate::ChannelWebhookNew(s) => {
+            body = page_channelwebhook_new::build(pc, s);
+        },
+        Ministate::ChannelWebhook(s) => {
+            body = page_channelwebhook::build(pc, &s.channel, &s.webhook);
+        },
+        Ministate::ChannelWebhookEdit(s) => {
+            body = page_channelwebhook_edit::build(pc, &s.channel, &s.webhook);
+        },
+        Ministate::ChannelWebhookDelete(s) => {
+            body = page_channelwebhook_delete::build(pc, &s.channel, &s.webhook);
+        },
+        Ministate::ChannelOutgoingWebhooks(s) => {
+            body = page_channeloutgoingwebhooks::build(s);
+        },
+        Ministate::ChannelOutgoingWebhookNew(s) => {
+            body = page_channeloutgoingwebhook_new::build(pc, s);
+        },
+        Ministate::ChannelOutgoingWebhookLog(s) => {
+            body = page_channeloutgoingwebhook_log::build(s);
+        },
         Ministate::ChannelGroupNew => {
             body = page_channelgroup_new::build(pc);
         },